@@ -0,0 +1,37 @@
+// Copy buttons of the recent uploads page, which copy the url of a file, or a markdown link to it.
+(function () {
+  "use strict";
+
+  function markdown(name, url, isImage) {
+    const link = "[" + name + "](" + url + ")";
+    return isImage ? "!" + link : link;
+  }
+
+  async function copy(text) {
+    try {
+      await navigator.clipboard.writeText(text);
+    } catch (e) {
+      // The clipboard API needs a secure context, so fall back to a temporary text field.
+      const input = document.createElement("textarea");
+      input.value = text;
+      document.body.appendChild(input);
+      input.select();
+      document.execCommand("copy");
+      input.remove();
+    }
+  }
+
+  for (const button of document.querySelectorAll("[data-copy]")) {
+    button.addEventListener("click", async () => {
+      const url = new URL(button.dataset.url, document.baseURI).href;
+      const text =
+        button.dataset.copy === "markdown"
+          ? markdown(button.dataset.name, url, button.dataset.image === "true")
+          : url;
+      await copy(text);
+      const label = button.textContent;
+      button.textContent = "Copied";
+      setTimeout(() => (button.textContent = label), 2000);
+    });
+  }
+})();
@@ -0,0 +1,54 @@
+// Lightbox of the gallery page, which shows images in a modal instead of leaving the page. The
+// arrow keys move to the previous and next image, and escape or a click outside closes it. Without
+// scripts, the thumbnails are plain links to the files.
+(function () {
+  "use strict";
+
+  const lightbox = document.getElementById("lightbox");
+  const image = lightbox.querySelector("img");
+  const name = lightbox.querySelector(".name");
+  const links = Array.from(document.querySelectorAll("[data-lightbox]"));
+  let current = -1;
+
+  function show(index) {
+    current = (index + links.length) % links.length;
+    const link = links[current];
+    image.src = link.href;
+    image.alt = link.title;
+    name.href = link.href;
+    name.textContent = link.title;
+    lightbox.classList.add("is-active");
+  }
+
+  function close() {
+    lightbox.classList.remove("is-active");
+    image.removeAttribute("src");
+    current = -1;
+  }
+
+  links.forEach((link, index) => {
+    link.addEventListener("click", (event) => {
+      // Let modified clicks open the file in a new tab as usual.
+      if (event.ctrlKey || event.metaKey || event.shiftKey || event.button !== 0) {
+        return;
+      }
+      event.preventDefault();
+      show(index);
+    });
+  });
+
+  lightbox.querySelector(".modal-background").addEventListener("click", close);
+  lightbox.querySelector(".modal-close").addEventListener("click", close);
+  document.addEventListener("keydown", (event) => {
+    if (current < 0) {
+      return;
+    }
+    if (event.key === "Escape") {
+      close();
+    } else if (event.key === "ArrowLeft") {
+      show(current - 1);
+    } else if (event.key === "ArrowRight") {
+      show(current + 1);
+    }
+  });
+})();
@@ -0,0 +1,113 @@
+// Upload form of the index page. Every chosen or dropped file is uploaded by its own request, with
+// a row showing its progress, and its url with a copy button once it is stored.
+(function () {
+  "use strict";
+
+  const form = document.getElementById("upload");
+  const fileInput = document.getElementById("file");
+  const dropZone = document.getElementById("drop-zone");
+  const uploads = document.getElementById("uploads");
+  const originalFilename = document.getElementById("use-original-filename");
+
+  function addRow(file) {
+    const row = document.createElement("div");
+    row.className = "box";
+    row.innerHTML =
+      '<p class="has-text-weight-semibold name"></p>' +
+      '<progress class="progress is-info" max="100" value="0"></progress>' +
+      '<div class="field has-addons is-hidden result">' +
+      '  <div class="control is-expanded"><input class="input url" type="text" readonly></div>' +
+      '  <div class="control"><button type="button" class="button copy">Copy</button></div>' +
+      "</div>" +
+      '<p class="has-text-danger is-hidden error"></p>';
+    row.querySelector(".name").textContent = file.name;
+    uploads.prepend(row);
+    return row;
+  }
+
+  function showUrl(row, url) {
+    row.querySelector(".progress").classList.add("is-hidden");
+    const input = row.querySelector(".url");
+    input.value = url;
+    row.querySelector(".result").classList.remove("is-hidden");
+    const button = row.querySelector(".copy");
+    button.addEventListener("click", async () => {
+      try {
+        await navigator.clipboard.writeText(url);
+      } catch (e) {
+        // The clipboard API needs a secure context, so fall back to selecting the text.
+        input.select();
+        document.execCommand("copy");
+      }
+      button.textContent = "Copied";
+      setTimeout(() => (button.textContent = "Copy"), 2000);
+    });
+  }
+
+  function showError(row, message) {
+    const progress = row.querySelector(".progress");
+    progress.classList.remove("is-info");
+    progress.classList.add("is-danger");
+    const error = row.querySelector(".error");
+    error.textContent = "Upload failed: " + message;
+    error.classList.remove("is-hidden");
+  }
+
+  function upload(file) {
+    const row = addRow(file);
+    const progress = row.querySelector(".progress");
+    const data = new FormData();
+    data.append("file", file);
+    data.append(
+      "options",
+      JSON.stringify({ useOriginalFilename: originalFilename.checked, redirect: false })
+    );
+
+    const request = new XMLHttpRequest();
+    request.open("POST", form.action);
+    request.responseType = "json";
+    request.setRequestHeader("Accept", "application/json");
+    request.upload.addEventListener("progress", (event) => {
+      if (event.lengthComputable) {
+        progress.value = (event.loaded / event.total) * 100;
+      }
+    });
+    request.addEventListener("load", () => {
+      if (request.status >= 200 && request.status < 300 && request.response) {
+        showUrl(row, request.response.url);
+      } else {
+        const error = request.response && request.response.error;
+        showError(row, error || request.statusText || "status " + request.status);
+      }
+    });
+    request.addEventListener("error", () => showError(row, "connection lost"));
+    request.send(data);
+  }
+
+  function uploadAll(files) {
+    for (const file of files) {
+      upload(file);
+    }
+  }
+
+  // Upload files as soon as they are chosen, like when they are dropped. Without scripts, the
+  // form is submitted as a plain upload instead.
+  fileInput.addEventListener("change", () => {
+    uploadAll(fileInput.files);
+    fileInput.value = "";
+  });
+
+  for (const name of ["dragenter", "dragover"]) {
+    dropZone.addEventListener(name, (event) => {
+      event.preventDefault();
+      dropZone.classList.add("has-background-info-light");
+    });
+  }
+  for (const name of ["dragleave", "drop"]) {
+    dropZone.addEventListener(name, (event) => {
+      event.preventDefault();
+      dropZone.classList.remove("has-background-info-light");
+    });
+  }
+  dropZone.addEventListener("drop", (event) => uploadAll(event.dataTransfer.files));
+})();
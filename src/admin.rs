@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use axum::extract::{Path, State};
+use axum::http::{header::LOCATION, StatusCode};
+use axum::response::IntoResponse;
+use axum::Json;
+use serde::Serialize;
+
+use crate::WebError;
+
+use super::{
+    helpers::{public_path, user_dir},
+    storage,
+    thumbnail::store_thumbnail,
+    users::User,
+    Opt,
+};
+
+/// Number of jobs to keep track of before forgetting those that have finished.
+const PRUNE_THRESHOLD: usize = 64;
+
+/**
+ * Background jobs started through the admin endpoints, by id.
+ */
+#[derive(Debug, Default)]
+pub struct Jobs {
+    next_id: AtomicU64,
+    jobs: Mutex<HashMap<u64, Arc<Job>>>,
+}
+
+/**
+ * Progress of a background job. Jobs are only visible to the user that started them.
+ */
+#[derive(Debug)]
+struct Job {
+    user: User,
+    total: usize,
+    processed: AtomicUsize,
+    done: AtomicBool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JobStatus {
+    id: u64,
+    processed: usize,
+    total: usize,
+    done: bool,
+}
+
+impl Jobs {
+    fn start(&self, job: Job) -> (u64, Arc<Job>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let job = Arc::new(job);
+
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.len() >= PRUNE_THRESHOLD {
+            jobs.retain(|_, job| !job.done.load(Ordering::Relaxed));
+        }
+        jobs.insert(id, job.clone());
+        (id, job)
+    }
+
+    fn get(&self, id: u64) -> Option<Arc<Job>> {
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+}
+
+impl Job {
+    fn status(&self, id: u64) -> JobStatus {
+        JobStatus {
+            id,
+            processed: self.processed.load(Ordering::Relaxed),
+            total: self.total,
+            done: self.done.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/**
+ * Starts regenerating the thumbnails of every uploaded file of the current user with the current
+ * thumbnail settings, replacing existing thumbnails. Responds right away with the id of the job.
+ */
+pub async fn regenerate_thumbnails(
+    State(opt): State<Opt>,
+    user: User,
+) -> Result<impl IntoResponse, WebError> {
+    // Listing isn't recursive, so thumbnails and metadata are not included.
+    let files = storage::backend(&opt).list(user_dir(&user)).await?;
+
+    let (id, job) = opt.jobs.start(Job {
+        user: user.clone(),
+        total: files.len(),
+        processed: AtomicUsize::new(0),
+        done: AtomicBool::new(false),
+    });
+    log::info!(
+        "regenerating thumbnails of {} files as job {}",
+        files.len(),
+        id
+    );
+
+    let status = job.status(id);
+    let location = public_path(&format!("admin/jobs/{}", id), &opt)?;
+    tokio::task::spawn(async move {
+        // One file at a time, so that regenerating doesn't starve uploads of blocking threads.
+        for file in files {
+            // Files that aren't images or videos are skipped by store_thumbnail.
+            if let Err(e) = store_thumbnail(file.name, user.clone(), opt.clone()).await {
+                log::warn!("could not regenerate thumbnail of {}: {}", file.key, e);
+            }
+            job.processed.fetch_add(1, Ordering::Relaxed);
+        }
+        job.done.store(true, Ordering::Relaxed);
+        log::info!("job {} done", id);
+    });
+
+    Ok((StatusCode::ACCEPTED, [(LOCATION, location)], Json(status)))
+}
+
+/**
+ * Returns the progress of a job started by the current user.
+ */
+pub async fn job_status(
+    State(opt): State<Opt>,
+    user: User,
+    Path(id): Path<u64>,
+) -> Result<impl IntoResponse, WebError> {
+    match opt.jobs.get(id) {
+        Some(job) if job.user == user => Ok(Json(job.status(id)).into_response()),
+        _ => Ok((StatusCode::NOT_FOUND, "no such job").into_response()),
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::WebError;
+
+use super::helpers::user_dir;
+use super::meta::{read_meta, remove_meta};
+use super::storage::{self, join_key, StoredObject};
+use super::thumbnail::{remove_thumbnails, thumbnail_source};
+use super::trash::purge_trash;
+use super::tus;
+use super::users::User;
+use super::{Opt, META_SUBDIR, THUMBNAIL_SUBDIR};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/**
+ * Periodically deletes files older than the configured max age, or whose own expiry has passed,
+ * and files that have been in the trash for longer than its max age. Never returns.
+ */
+pub async fn cleanup_task(opt: Opt) {
+    let max_age = opt
+        .max_age_days
+        .map(|days| Duration::from_secs(days * SECONDS_PER_DAY));
+    let trash_max_age = Duration::from_secs(opt.trash_max_age_days * SECONDS_PER_DAY);
+
+    let mut interval = tokio::time::interval(Duration::from_secs(opt.cleanup_interval_secs));
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = tus::remove_stale(SystemTime::now(), &opt).await {
+            log::error!("error when removing stale partial uploads: {}", e);
+        }
+
+        // Every user has their own directory, with its own metadata.
+        let users = std::iter::once(None).chain(opt.users.keys().cloned().map(Some));
+        for user in users.map(User) {
+            if let Err(e) = remove_expired(max_age, SystemTime::now(), &user, &opt).await {
+                log::error!("error when removing expired files: {}", e);
+            }
+            // Files may remain in the trash after it has been disabled, so always purge it.
+            if let Err(e) = purge_trash(trash_max_age, SystemTime::now(), &user, &opt).await {
+                log::error!("error when purging trash: {}", e);
+            }
+        }
+    }
+}
+
+/**
+ * Removes files left in the staging directory by uploads that didn't finish, such as when they
+ * were cut off by shutting down. Partial tus uploads are kept, since they can be resumed.
+ */
+pub async fn remove_staged(opt: &Opt) -> Result<(), WebError> {
+    let staging_dir = storage::backend(opt).staging_dir();
+    let mut entries = match tokio::fs::read_dir(&staging_dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let is_tus = entry.file_name().to_str().is_some_and(tus::is_tus_file);
+        if is_tus || !entry.metadata().await?.is_file() {
+            continue;
+        }
+        log::info!("deleting unfinished upload {:?}", entry.path());
+        tokio::fs::remove_file(entry.path()).await?;
+    }
+
+    Ok(())
+}
+
+/**
+ * Removes every uploaded file last modified before `now - max_age`, with an expiry before `now` in
+ * its metadata, or downloaded as many times as allowed, together with its thumbnail and metadata.
+ * Thumbnails and metadata whose file no longer exists are removed as well. A file that can't be
+ * checked or removed is logged and kept, and doesn't stop the others from being removed.
+ */
+async fn remove_expired(
+    max_age: Option<Duration>,
+    now: SystemTime,
+    user: &User,
+    opt: &Opt,
+) -> Result<(), WebError> {
+    let cutoff = max_age.map(|max_age| now.checked_sub(max_age).unwrap_or(SystemTime::UNIX_EPOCH));
+    let storage = storage::backend(opt);
+
+    // Listing isn't recursive, so only uploaded files are considered here.
+    let mut remaining = HashSet::new();
+    for file in storage.list(user_dir(user)).await? {
+        match remove_if_expired(&file, cutoff, now, user, opt).await {
+            Ok(true) => {}
+            Ok(false) => {
+                remaining.insert(file.name);
+            }
+            Err(e) => {
+                log::error!("error when removing expired file {}: {}", file.key, e);
+                remaining.insert(file.name);
+            }
+        }
+    }
+
+    remove_orphans(&remaining, THUMBNAIL_SUBDIR, thumbnail_source, user, opt).await?;
+    remove_orphans(&remaining, META_SUBDIR, meta_source, user, opt).await
+}
+
+/**
+ * Removes the file together with its thumbnail and metadata, if it is older than `cutoff`, or has
+ * expired or been used up according to its metadata. Returns whether it was removed.
+ */
+async fn remove_if_expired(
+    file: &StoredObject,
+    cutoff: Option<SystemTime>,
+    now: SystemTime,
+    user: &User,
+    opt: &Opt,
+) -> Result<bool, WebError> {
+    let too_old = cutoff.is_some_and(|cutoff| file.modified < cutoff);
+    let meta_expired = read_meta(&file.name, user, opt)
+        .await?
+        .is_some_and(|meta| meta.is_expired(now.into()) || meta.is_used_up());
+    if !too_old && !meta_expired {
+        return Ok(false);
+    }
+
+    log::info!("deleting expired file {}", file.key);
+    storage::backend(opt).delete(&file.key).await?;
+    opt.recent_cache.invalidate(user);
+    remove_meta(&file.name, user, opt).await?;
+    remove_thumbnails(&file.name, user, opt).await?;
+    Ok(true)
+}
+
+/**
+ * Returns the name of the upload a metadata file belongs to, which is the name of the metadata
+ * file without its extension.
+ */
+fn meta_source(name: &str) -> Option<&str> {
+    Path::new(name).file_stem().and_then(|s| s.to_str())
+}
+
+/**
+ * Removes every object in the subdirectory `dir` whose corresponding upload no longer exists.
+ * The name of the upload is found from the name of the object with `source`.
+ */
+async fn remove_orphans(
+    uploads: &HashSet<String>,
+    dir: &str,
+    source: fn(&str) -> Option<&str>,
+    user: &User,
+    opt: &Opt,
+) -> Result<(), WebError> {
+    let storage = storage::backend(opt);
+    for object in storage.list(&join_key(user_dir(user), dir)).await? {
+        let Some(filename) = source(&object.name) else {
+            continue;
+        };
+        if !uploads.contains(filename) {
+            log::info!("deleting orphaned {}", object.key);
+            if let Err(e) = storage.delete(&object.key).await {
+                log::error!("error when deleting orphaned {}: {}", object.key, e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helpers::thumbnail_key;
+    use clap::Parser;
+
+    #[tokio::test]
+    async fn removes_only_expired_files_and_orphans() {
+        let base_dir = std::env::temp_dir().join("i-test-cleanup");
+        let opt = Opt::parse_from(["i", "--base-dir", base_dir.to_str().unwrap()]);
+        let user = User::default();
+
+        let old = base_dir.join("old.txt");
+        let new = base_dir.join("new.txt");
+        std::fs::create_dir_all(base_dir.join(THUMBNAIL_SUBDIR)).unwrap();
+        std::fs::write(&old, "old").unwrap();
+        std::fs::write(&new, "new").unwrap();
+        let old_thumb = base_dir.join(thumbnail_key("old.txt", 150, &user, &opt));
+        let new_thumb = base_dir.join(thumbnail_key("new.txt", 150, &user, &opt));
+        let orphan_thumb = base_dir.join(thumbnail_key("orphan.txt", 150, &user, &opt));
+        std::fs::write(&old_thumb, "thumb").unwrap();
+        std::fs::write(&new_thumb, "thumb").unwrap();
+        std::fs::write(&orphan_thumb, "thumb").unwrap();
+
+        let now = SystemTime::now();
+        let two_days = Duration::from_secs(2 * SECONDS_PER_DAY);
+        std::fs::File::options()
+            .write(true)
+            .open(&old)
+            .unwrap()
+            .set_modified(now - two_days)
+            .unwrap();
+
+        remove_expired(Some(Duration::from_secs(SECONDS_PER_DAY)), now, &user, &opt)
+            .await
+            .unwrap();
+
+        assert!(!old.exists());
+        assert!(new.exists());
+        assert!(!old_thumb.exists());
+        assert!(new_thumb.exists());
+        assert!(!orphan_thumb.exists());
+    }
+
+    #[tokio::test]
+    async fn broken_metadata_does_not_stop_cleanup() {
+        let base_dir = std::env::temp_dir().join("i-test-cleanup-broken");
+        std::fs::remove_dir_all(&base_dir).ok();
+        let opt = Opt::parse_from(["i", "--base-dir", base_dir.to_str().unwrap()]);
+        let user = User::default();
+
+        let broken_meta = base_dir.join(META_SUBDIR).join("broken.txt.json");
+        // Reading a directory fails, as an unreadable file would.
+        std::fs::create_dir_all(&broken_meta).unwrap();
+        let now = SystemTime::now();
+        for name in ["a.txt", "broken.txt", "z.txt"] {
+            let path = base_dir.join(name);
+            std::fs::write(&path, name).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(now - Duration::from_secs(2 * SECONDS_PER_DAY))
+                .unwrap();
+        }
+
+        remove_expired(Some(Duration::from_secs(SECONDS_PER_DAY)), now, &user, &opt)
+            .await
+            .unwrap();
+
+        assert!(!base_dir.join("a.txt").exists());
+        assert!(!base_dir.join("z.txt").exists());
+        // The file whose metadata can't be read is kept, with its metadata, for a later look.
+        assert!(base_dir.join("broken.txt").exists());
+        assert!(broken_meta.exists());
+    }
+
+    #[tokio::test]
+    async fn removes_unfinished_uploads_but_not_tus() {
+        let base_dir = std::env::temp_dir().join("i-test-remove-staged");
+        let opt = Opt::parse_from(["i", "--base-dir", base_dir.to_str().unwrap()]);
+        let staging_dir = storage::backend(&opt).staging_dir();
+        std::fs::create_dir_all(&staging_dir).unwrap();
+        std::fs::write(staging_dir.join("Uake9Um7"), "").unwrap();
+        std::fs::write(staging_dir.join("tus-abc"), "partial").unwrap();
+        std::fs::write(staging_dir.join("tus-abc.json"), "{}").unwrap();
+
+        remove_staged(&opt).await.unwrap();
+
+        assert!(!staging_dir.join("Uake9Um7").exists());
+        assert!(staging_dir.join("tus-abc").exists());
+        assert!(staging_dir.join("tus-abc.json").exists());
+    }
+}
@@ -0,0 +1,205 @@
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+
+use axum::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::request::Parts;
+use axum::http::{Extensions, HeaderMap, HeaderName};
+use ipnet::IpNet;
+
+use super::Opt;
+
+static X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+static FORWARDED: HeaderName = HeaderName::from_static("forwarded");
+
+/**
+ * Parses a trusted proxy, given as a network such as `10.0.0.0/8`, or a single address.
+ */
+pub fn parse_trusted_proxy(proxy: &str) -> Result<IpNet, String> {
+    let proxy = proxy.trim();
+    proxy
+        .parse()
+        .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("invalid address or network: {}", proxy))
+}
+
+/**
+ * The address of the client that sent the request. Behind a trusted proxy, this is the address
+ * the proxy forwarded the request for, and otherwise the address of the connection. It is `None`
+ * when the connection address is unknown, such as in tests.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[async_trait]
+impl FromRequestParts<Opt> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, opt: &Opt) -> Result<Self, Self::Rejection> {
+        Ok(ClientIp(client_ip(
+            &parts.extensions,
+            &parts.headers,
+            &opt.trusted_proxies,
+        )))
+    }
+}
+
+/**
+ * Returns the address of the client, looking through the proxies in `trusted_proxies`. Forwarded
+ * headers are only believed when the connection comes from a trusted proxy, since anyone else can
+ * send them with any address they like.
+ */
+pub fn client_ip(
+    extensions: &Extensions,
+    headers: &HeaderMap,
+    trusted_proxies: &[IpNet],
+) -> Option<IpAddr> {
+    let peer = extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip())?;
+    Some(forwarded_for(peer, headers, trusted_proxies))
+}
+
+fn forwarded_for(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| {
+        trusted_proxies
+            .iter()
+            .any(|net| net.contains(&canonical(*ip)))
+    };
+    if !is_trusted(&peer) {
+        return peer;
+    }
+
+    // Every proxy appends the address it received the request from, so walk back from the last
+    // one, until reaching an address that isn't one of our proxies.
+    let hops = if headers.contains_key(&FORWARDED) {
+        forwarded_hops(headers)
+    } else {
+        x_forwarded_for_hops(headers)
+    };
+    let mut client = peer;
+    for hop in hops.iter().rev() {
+        // Anything before a hop that can't be parsed may have been made up by the client.
+        let Some(ip) = hop else {
+            break;
+        };
+        client = *ip;
+        if !is_trusted(ip) {
+            break;
+        }
+    }
+    client
+}
+
+/**
+ * Addresses in the `for` parameters of the standard `Forwarded` header, in the order they were
+ * added. Hidden or unknown addresses are `None`.
+ */
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all(&FORWARDED)
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or_default().split(','))
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                .and_then(|(_, value)| parse_hop(value))
+        })
+        .collect()
+}
+
+/**
+ * Addresses in the `X-Forwarded-For` headers, in the order they were added.
+ */
+fn x_forwarded_for_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all(&X_FORWARDED_FOR)
+        .iter()
+        .flat_map(|value| value.to_str().unwrap_or_default().split(','))
+        .map(parse_hop)
+        .collect()
+}
+
+/**
+ * Parses a forwarded address, which may be quoted, have a port, or be an IPv6 address in brackets.
+ */
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+    if let Some(bracketed) = hop.strip_prefix('[') {
+        return bracketed.split(']').next()?.parse().ok();
+    }
+    hop.parse()
+        .or_else(|_| hop.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+}
+
+/**
+ * IPv4 clients of a dual-stack socket are seen as IPv4-mapped IPv6 addresses, which should match
+ * IPv4 networks.
+ */
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn client(peer: &str, headers: &[(&HeaderName, &str)], trusted: &[&str]) -> IpAddr {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, HeaderValue::from_str(value).unwrap());
+        }
+        let trusted: Vec<IpNet> = trusted
+            .iter()
+            .map(|proxy| parse_trusted_proxy(proxy).unwrap())
+            .collect();
+        forwarded_for(peer.parse().unwrap(), &map, &trusted)
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let spoofed = [(&X_FORWARDED_FOR, "192.0.2.1")];
+        let ip: IpAddr = "198.51.100.7".parse().unwrap();
+        assert_eq!(ip, client("198.51.100.7", &spoofed, &[]));
+        assert_eq!(ip, client("198.51.100.7", &spoofed, &["10.0.0.0/8"]));
+    }
+
+    #[test]
+    fn takes_last_untrusted_hop() {
+        let trusted = ["10.0.0.0/8", "2001:db8::1"];
+        let headers = [(&X_FORWARDED_FOR, "203.0.113.9, 192.0.2.1, 10.0.0.2")];
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        assert_eq!(ip, client("10.0.0.1", &headers, &trusted));
+        assert_eq!(ip, client("2001:db8::1", &headers, &trusted));
+        assert_eq!(ip, client("::ffff:10.0.0.1", &headers, &trusted));
+
+        let split = [
+            (&X_FORWARDED_FOR, "192.0.2.1"),
+            (&X_FORWARDED_FOR, "10.0.0.2"),
+        ];
+        assert_eq!(ip, client("10.0.0.1", &split, &trusted));
+    }
+
+    #[test]
+    fn parses_forwarded_header() {
+        let trusted = ["10.0.0.1"];
+        let headers = [(
+            &FORWARDED,
+            r#"for=192.0.2.60;proto=http, For="[2001:db8:cafe::17]:4711""#,
+        )];
+        let ip: IpAddr = "2001:db8:cafe::17".parse().unwrap();
+        assert_eq!(ip, client("10.0.0.1", &headers, &trusted));
+
+        // A hidden address stops the walk at the last known hop.
+        let hidden = [(&FORWARDED, "for=192.0.2.60, for=_hidden")];
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(ip, client("10.0.0.1", &hidden, &trusted));
+    }
+}
@@ -0,0 +1,121 @@
+use std::ffi::OsString;
+use std::path::Path;
+
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches};
+
+use crate::WebError;
+
+use super::Opt;
+
+/**
+ * Parses the options from the command line and the environment, and from the config file given
+ * with `--config`, if any. Options given on the command line take precedence over environment
+ * variables, which take precedence over the config file, which takes precedence over the
+ * defaults. Exits with a usage message if the options are invalid, like `Opt::parse`.
+ */
+pub fn parse() -> Result<Opt, WebError> {
+    parse_from(std::env::args_os())
+}
+
+pub fn parse_from<I, T>(args: I) -> Result<Opt, WebError>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let mut args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+    let matches = Opt::command().get_matches_from(&args);
+    if let Some(path) = matches.get_one::<std::path::PathBuf>("config") {
+        args.extend(config_args(path, &matches)?);
+    }
+
+    // Parse again, so that the values of the config file are validated like any other.
+    let matches = Opt::command().get_matches_from(&args);
+    Ok(Opt::from_arg_matches(&matches).unwrap_or_else(|e| e.exit()))
+}
+
+/**
+ * Reads the config file, a TOML table with the same names as the long command line options, and
+ * returns the command line arguments for the options that were not already given.
+ */
+fn config_args(path: &Path, matches: &ArgMatches) -> Result<Vec<OsString>, WebError> {
+    let error = |message: String| {
+        std::io::Error::other(format!("invalid config file {:?}: {}", path, message))
+    };
+    let data = std::fs::read_to_string(path)?;
+    let table: toml::Table = data.parse().map_err(|e| error(format!("{}", e)))?;
+
+    let command = Opt::command();
+    let mut args = Vec::new();
+    for (key, value) in table {
+        let id = key.replace('-', "_");
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == id.as_str() && id != "config")
+            .ok_or_else(|| error(format!("unknown option {}", key)))?;
+        let explicit = matches!(
+            matches.value_source(&id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        );
+        if explicit {
+            continue;
+        }
+        // Every option that isn't positional has a long name.
+        let flag = format!("--{}", arg.get_long().unwrap_or(&id));
+
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            match (value, arg.get_action()) {
+                (toml::Value::Boolean(true), ArgAction::SetTrue) => args.push(flag.clone().into()),
+                (toml::Value::Boolean(false), ArgAction::SetTrue) => {}
+                (toml::Value::String(value), _) => args.push(format!("{}={}", flag, value).into()),
+                (value @ (toml::Value::Integer(_) | toml::Value::Float(_)), _) => {
+                    args.push(format!("{}={}", flag, value).into())
+                }
+                _ => return Err(error(format!("unsupported value for {}", key)).into()),
+            }
+        }
+    }
+
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_file_is_overridden_by_command_line() {
+        let path = std::env::temp_dir().join("i-test-config.toml");
+        std::fs::write(
+            &path,
+            r#"
+port = 9000
+base-dir = "/srv/i"
+recents = 30
+thumbnail_sizes = [150, 320]
+insecure_delete = true
+"#,
+        )
+        .unwrap();
+
+        let opt = parse_from(["i", "--config", path.to_str().unwrap(), "--recents", "5"]).unwrap();
+
+        assert_eq!(9000, opt.port);
+        assert_eq!("/srv/i", opt.base_dir);
+        assert_eq!(5, opt.recents);
+        assert_eq!(vec![150, 320], opt.thumbnail_sizes);
+        assert!(opt.insecure_delete);
+    }
+
+    #[test]
+    fn unknown_options_are_rejected() {
+        let path = std::env::temp_dir().join("i-test-config-unknown.toml");
+        std::fs::write(&path, "no_such_option = 1\n").unwrap();
+
+        assert!(parse_from(["i", "--config", path.to_str().unwrap()]).is_err());
+    }
+}
@@ -0,0 +1,40 @@
+use axum::http::HeaderMap;
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+
+use super::{constant_time_eq, upload::generate_random_string, Opt};
+
+/// Name of the cookie holding the CSRF token of a browser.
+const CSRF_COOKIE: &str = "i_csrf";
+
+const CSRF_TOKEN_LENGTH: usize = 32;
+
+/**
+ * Returns the CSRF token of the browser, to embed in the forms of a page, and sets it as a cookie
+ * if the browser has none yet. A form is only accepted if it contains the same token as the
+ * cookie, which other sites can't read.
+ */
+pub fn csrf_token(jar: CookieJar, opt: &Opt) -> (CookieJar, String) {
+    if let Some(cookie) = jar.get(CSRF_COOKIE) {
+        let token = cookie.value().to_string();
+        return (jar, token);
+    }
+
+    let token = generate_random_string(CSRF_TOKEN_LENGTH);
+    let cookie = Cookie::build((CSRF_COOKIE, token.clone()))
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .secure(opt.server_url.starts_with("https://"));
+    (jar.add(cookie), token)
+}
+
+/**
+ * Checks that the CSRF token of a submitted form matches the cookie of the browser.
+ */
+pub fn csrf_valid(headers: &HeaderMap, token: Option<&str>) -> bool {
+    let jar = CookieJar::from_headers(headers);
+    match (jar.get(CSRF_COOKIE), token) {
+        (Some(cookie), Some(token)) => constant_time_eq(cookie.value(), token),
+        _ => false,
+    }
+}
@@ -1,31 +1,295 @@
 use axum::{
-    extract::State,
-    http::{header::LOCATION, StatusCode},
-    response::IntoResponse,
-    Form,
+    extract::{FromRequest, Request, State},
+    http::{
+        header::{CONTENT_TYPE, LOCATION},
+        StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
 };
-use serde::Deserialize;
+use axum_extra::extract::Form;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use utoipa::ToSchema;
 
 use crate::WebError;
 
-use super::{helpers::filename_path, helpers::thumbnail_filename_path, Opt};
+use super::{
+    client_ip::ClientIp,
+    csrf::csrf_valid,
+    helpers::{file_key, file_url_path, public_path, reject_path_traversal},
+    meta::{find_by_delete_token, release_delete_token, remove_meta},
+    metrics::METRICS,
+    storage,
+    thumbnail::remove_thumbnails,
+    trash::move_to_trash,
+    users::User,
+    webhook::{Event, EventKind},
+    Opt,
+};
 
-#[derive(Deserialize)]
+/// Identifies the file to delete, either by its secret deletion token, or, if `--insecure-delete`
+/// is enabled, by its filename.
+#[derive(Deserialize, ToSchema)]
 pub struct DeleteRequest {
-    pub filename: String,
+    pub token: Option<String>,
+    pub filename: Option<String>,
+    /// CSRF token, required when submitted as a form.
+    pub csrf: Option<String>,
+}
+
+/// Identifies several files to delete, by their deletion tokens, or, if `--insecure-delete` is
+/// enabled, by their filenames.
+#[derive(Deserialize, ToSchema)]
+pub struct BulkDeleteRequest {
+    #[serde(default)]
+    pub tokens: Vec<String>,
+    #[serde(default)]
+    pub filenames: Vec<String>,
+    /// CSRF token, required when submitted as a form.
+    pub csrf: Option<String>,
+}
+
+/// Requests that may be submitted as forms, and then must contain a CSRF token.
+pub trait CsrfProtected {
+    fn csrf(&self) -> Option<&str>;
+}
+
+impl CsrfProtected for DeleteRequest {
+    fn csrf(&self) -> Option<&str> {
+        self.csrf.as_deref()
+    }
+}
+
+impl CsrfProtected for BulkDeleteRequest {
+    fn csrf(&self) -> Option<&str> {
+        self.csrf.as_deref()
+    }
+}
+
+/// Which of the files of a bulk delete were deleted, and which were not.
+#[derive(Default, Serialize, ToSchema)]
+pub struct BulkDeleteResponse {
+    pub deleted: Vec<String>,
+    pub failed: Vec<FailedDelete>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct FailedDelete {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    pub error: String,
+}
+
+/**
+ * Parses a delete request, which is JSON from scripts, or a form from the recent page. Returns
+ * whether it was JSON. Forms are rejected unless their CSRF token matches the cookie, since
+ * another site could submit a form on behalf of a logged in user. Such sites can't send JSON
+ * without the browser asking for permission first.
+ */
+pub async fn parse_request<T>(request: Request) -> Result<(T, bool), WebError>
+where
+    T: DeserializeOwned + CsrfProtected,
+{
+    let is_json = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    if is_json {
+        let Json(form) = Json::from_request(request, &())
+            .await
+            .map_err(|_| WebError::BadRequest)?;
+        Ok((form, true))
+    } else {
+        let headers = request.headers().clone();
+        // Forms may repeat a field, such as for every checked file on the recent page.
+        let Form(form): Form<T> = Form::from_request(request, &())
+            .await
+            .map_err(|_| WebError::BadRequest)?;
+        if !csrf_valid(&headers, form.csrf()) {
+            return Err(WebError::Forbidden);
+        }
+        Ok((form, false))
+    }
+}
+
+/**
+ * Deletes an uploaded file, its thumbnails, and its metadata, or moves them to the trash if
+ * `--trash` is enabled. Returns false if there is no such file.
+ */
+pub async fn delete_file(filename: &str, user: &User, opt: &Opt) -> Result<bool, WebError> {
+    if !sanitize_filename::is_sanitized(filename) {
+        return Ok(false);
+    }
+
+    let storage = storage::backend(opt);
+    if !storage.exists(&file_key(filename, user)).await? {
+        return Ok(false);
+    }
+
+    if opt.trash {
+        move_to_trash(filename, user, opt).await?;
+    } else {
+        // We should delete the file, its thumbnails, and its metadata.
+        storage.delete(&file_key(filename, user)).await?;
+        remove_thumbnails(filename, user, opt).await?;
+        remove_meta(filename, user, opt).await?;
+    }
+    opt.recent_cache.invalidate(user);
+    METRICS.record_delete();
+    let url = public_path(&file_url_path(filename, user, opt), opt)?;
+    opt.webhooks
+        .push(Event::new(EventKind::Delete, url, filename, None));
+
+    Ok(true)
 }
 
+/**
+ * Deletes the file with the given deletion token, or only takes back that upload of it, if others
+ * uploaded the same file.
+ */
+async fn delete_or_release(
+    filename: &str,
+    token: &str,
+    user: &User,
+    opt: &Opt,
+) -> Result<bool, WebError> {
+    if release_delete_token(filename, token, user, opt).await? {
+        return Ok(true);
+    }
+    delete_file(filename, user, opt).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/delete",
+    tag = "files",
+    summary = "Delete a file",
+    request_body(content(
+        (DeleteRequest = "application/json"),
+        (DeleteRequest = "application/x-www-form-urlencoded"),
+    )),
+    responses(
+        (status = 303, description = "File deleted, redirecting to the recent uploads"),
+        (status = 400, description = "No file with this deletion token"),
+        (status = 403, description = "Form without a valid CSRF token"),
+    )
+)]
 pub async fn handle_delete(
     State(opt): State<Opt>,
-    Form(form): Form<DeleteRequest>,
+    user: User,
+    ClientIp(client_ip): ClientIp,
+    request: Request,
 ) -> Result<impl IntoResponse, WebError> {
-    if !sanitize_filename::is_sanitized(&form.filename) {
+    let (form, _): (DeleteRequest, _) = parse_request(request).await?;
+
+    let deleted = match (form.token, form.filename) {
+        (Some(token), _) => {
+            let filename = find_by_delete_token(&token, &user, &opt)
+                .await?
+                .ok_or(WebError::BadRequest)?;
+            delete_or_release(&filename, &token, &user, &opt).await?
+        }
+        (None, Some(filename)) if opt.insecure_delete => {
+            reject_path_traversal(&filename, client_ip)?;
+            delete_file(&filename, &user, &opt).await?
+        }
+        _ => return Err(WebError::BadRequest),
+    };
+
+    if !deleted {
         return Err(WebError::BadRequest);
     }
 
-    // We should delete both file and thumbnail.
-    std::fs::remove_file(filename_path(&form.filename, &opt)?)?;
-    std::fs::remove_file(thumbnail_filename_path(&form.filename, &opt)?).ok();
-
     Ok((StatusCode::SEE_OTHER, [(LOCATION, "recent")], "deleted"))
 }
+
+/**
+ * Deletes several files at once. A file that can't be deleted doesn't stop the others from being
+ * deleted, and the response tells which files were deleted.
+ */
+#[utoipa::path(
+    post,
+    path = "/delete-bulk",
+    tag = "files",
+    summary = "Delete several files",
+    description = "A file that can't be deleted doesn't stop the others from being deleted, and the response tells which files were deleted.",
+    request_body(content(
+        (BulkDeleteRequest = "application/json"),
+        (BulkDeleteRequest = "application/x-www-form-urlencoded"),
+    )),
+    responses(
+        (status = 200, description = "Which files were deleted, for JSON requests", body = BulkDeleteResponse),
+        (status = 303, description = "Which files were deleted, redirecting forms to the recent uploads", body = BulkDeleteResponse),
+        (status = 400, description = "Filenames given without `--insecure-delete`"),
+        (status = 403, description = "Form without a valid CSRF token"),
+    )
+)]
+pub async fn handle_bulk_delete(
+    State(opt): State<Opt>,
+    user: User,
+    ClientIp(client_ip): ClientIp,
+    request: Request,
+) -> Result<Response, WebError> {
+    let (form, is_json): (BulkDeleteRequest, _) = parse_request(request).await?;
+    if !form.filenames.is_empty() && !opt.insecure_delete {
+        return Err(WebError::BadRequest);
+    }
+
+    let mut response = BulkDeleteResponse::default();
+    for token in form.tokens {
+        let error = match find_by_delete_token(&token, &user, &opt).await {
+            Ok(Some(filename)) => match delete_or_release(&filename, &token, &user, &opt).await {
+                Ok(true) => {
+                    response.deleted.push(filename);
+                    continue;
+                }
+                Ok(false) => "no such file".to_string(),
+                Err(e) => e.to_string(),
+            },
+            Ok(None) => "no file with this deletion token".to_string(),
+            Err(e) => e.to_string(),
+        };
+        response.failed.push(FailedDelete {
+            token: Some(token),
+            filename: None,
+            error,
+        });
+    }
+    for filename in form.filenames {
+        if let Err(e) = reject_path_traversal(&filename, client_ip) {
+            response.failed.push(FailedDelete {
+                token: None,
+                filename: Some(filename),
+                error: e.to_string(),
+            });
+            continue;
+        }
+        let error = match delete_file(&filename, &user, &opt).await {
+            Ok(true) => {
+                response.deleted.push(filename);
+                continue;
+            }
+            Ok(false) => "no such file".to_string(),
+            Err(e) => e.to_string(),
+        };
+        response.failed.push(FailedDelete {
+            token: None,
+            filename: Some(filename),
+            error,
+        });
+    }
+
+    if is_json {
+        Ok(Json(response).into_response())
+    } else {
+        Ok((
+            StatusCode::SEE_OTHER,
+            [(LOCATION, "recent")],
+            Json(response),
+        )
+            .into_response())
+    }
+}
@@ -0,0 +1,67 @@
+use std::path::{Path, PathBuf};
+
+use crate::WebError;
+
+use super::{storage, Opt};
+
+/// Free space is checked again every time this many bytes of an upload have been received.
+const CHECK_INTERVAL: usize = 16 * 1024 * 1024;
+
+/**
+ * Returns the number of bytes available to unprivileged users on the filesystem of `path`.
+ */
+#[cfg(unix)]
+fn free_bytes(path: &Path) -> std::io::Result<u64> {
+    let stat = nix::sys::statvfs::statvfs(path)?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+/**
+ * Free space can't be queried on this platform, so uploads are never rejected.
+ */
+#[cfg(not(unix))]
+fn free_bytes(_path: &Path) -> std::io::Result<u64> {
+    Ok(u64::MAX)
+}
+
+/**
+ * Rejects an upload once the free space of the filesystem it is received on drops below
+ * `--min-free-bytes`. The free space is checked before anything is written, and then again for
+ * every few MiB received.
+ */
+pub struct SpaceGuard {
+    dir: PathBuf,
+    min_free_bytes: u64,
+    next_check: usize,
+}
+
+impl SpaceGuard {
+    pub fn new(opt: &Opt) -> Self {
+        SpaceGuard {
+            dir: storage::backend(opt).staging_dir(),
+            min_free_bytes: opt.min_free_bytes,
+            next_check: 0,
+        }
+    }
+
+    /**
+     * Checks the free space, if `received` bytes is far enough from the previous check.
+     */
+    pub fn check(&mut self, received: usize) -> Result<(), WebError> {
+        if self.min_free_bytes == 0 || received < self.next_check {
+            return Ok(());
+        }
+        self.next_check = received + CHECK_INTERVAL;
+        let free = free_bytes(&self.dir)?;
+        if free < self.min_free_bytes {
+            log::warn!(
+                "rejecting upload, only {} bytes free in {}, below the minimum of {}",
+                free,
+                self.dir.display(),
+                self.min_free_bytes
+            );
+            return Err(WebError::InsufficientStorage);
+        }
+        Ok(())
+    }
+}
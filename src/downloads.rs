@@ -0,0 +1,66 @@
+use tokio::sync::Mutex;
+
+use crate::WebError;
+
+use super::helpers::file_key;
+use super::meta::{read_meta, remove_meta, write_meta};
+use super::users::User;
+use super::{storage, thumbnail::remove_thumbnails, Opt};
+
+/// Held while counting a download, so that concurrent requests can't serve more than allowed.
+static DOWNLOADS_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// Outcome of trying to count a download of a file with limited downloads.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Download {
+    /// The file may be served, and this many downloads are left after it.
+    Allowed(u64),
+    /// The file has been downloaded as many times as allowed.
+    UsedUp,
+}
+
+/**
+ * Counts a download of a file with limited downloads, if there are any left.
+ */
+pub async fn take_download(filename: &str, user: &User, opt: &Opt) -> Result<Download, WebError> {
+    let _guard = DOWNLOADS_LOCK.lock().await;
+    let Some(mut meta) = read_meta(filename, user, opt).await? else {
+        return Ok(Download::UsedUp);
+    };
+    let Some(max_downloads) = meta.max_downloads else {
+        return Ok(Download::Allowed(u64::MAX));
+    };
+    if meta.downloads >= max_downloads {
+        return Ok(Download::UsedUp);
+    }
+
+    meta.downloads += 1;
+    write_meta(filename, &meta, user, opt).await?;
+    Ok(Download::Allowed(max_downloads - meta.downloads))
+}
+
+/**
+ * Gives back a download that was counted, but failed, so that it can be tried again.
+ */
+pub async fn give_back_download(filename: &str, user: &User, opt: &Opt) -> Result<(), WebError> {
+    let _guard = DOWNLOADS_LOCK.lock().await;
+    let Some(mut meta) = read_meta(filename, user, opt).await? else {
+        return Ok(());
+    };
+    meta.downloads = meta.downloads.saturating_sub(1);
+    write_meta(filename, &meta, user, opt).await
+}
+
+/**
+ * Deletes a file that has been downloaded as many times as allowed, together with its thumbnails
+ * and metadata. The last download is still served, since the file is already open.
+ */
+pub async fn remove_used_up(filename: &str, user: &User, opt: &Opt) -> Result<(), WebError> {
+    log::info!("deleting {} after its last download", filename);
+    storage::backend(opt)
+        .delete(&file_key(filename, user))
+        .await?;
+    opt.recent_cache.invalidate(user);
+    remove_thumbnails(filename, user, opt).await?;
+    remove_meta(filename, user, opt).await
+}
@@ -0,0 +1,219 @@
+use std::collections::HashSet;
+use std::io::Cursor;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_zip::base::write::ZipFileWriter;
+use async_zip::error::ZipError;
+use async_zip::{
+    AttributeCompatibility, Compression, ZipDateTime, ZipDateTimeBuilder, ZipEntryBuilder,
+};
+use axum::body::Body;
+use axum::extract::{Query, State};
+use axum::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use axum::http::HeaderValue;
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Datelike, Local, Timelike, Utc};
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio_util::compat::TokioAsyncReadCompatExt;
+use tokio_util::io::ReaderStream;
+
+use crate::WebError;
+
+use super::{
+    helpers::user_dir,
+    meta::attachment_disposition,
+    storage::{self, join_key, Storage},
+    users::User,
+    Opt, META_SUBDIR, THUMBNAIL_SUBDIR,
+};
+
+/// Bytes of the archive buffered between writing it and sending it.
+const BUFFER_SIZE: usize = 64 * 1024;
+/// Regular file readable by everyone.
+const FILE_MODE: u16 = 0o100644;
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    /// Also include the thumbnails, in the thumbnails folder of the archive.
+    #[serde(default)]
+    thumbnails: bool,
+    /// Only include files modified after this time, in RFC 3339 format or as a unix timestamp.
+    since: Option<String>,
+}
+
+/**
+ * A file to put in the archive.
+ */
+struct ExportEntry {
+    name: String,
+    key: String,
+    modified: SystemTime,
+}
+
+/**
+ * Sends all uploaded files of the current user as a zip archive, with their metadata in the meta
+ * folder. The archive is written while it is sent, a file at a time, so that it is never held in
+ * memory.
+ */
+pub async fn handle_export(
+    State(opt): State<Opt>,
+    user: User,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, WebError> {
+    let since = query.since.as_deref().map(parse_since).transpose()?;
+    let storage = storage::backend(&opt);
+
+    // Listing isn't recursive, so thumbnails, metadata, and the trash are not included.
+    let mut entries = Vec::new();
+    for file in storage.list(user_dir(&user)).await? {
+        entries.push(ExportEntry {
+            name: file.name,
+            key: file.key,
+            modified: file.modified,
+        });
+    }
+    entries.retain(|entry| since.is_none_or(|since| entry.modified > since));
+
+    // The metadata goes with its file, so that passwords, expiries, download limits, and deletion
+    // tokens are restored by an import.
+    let exported: HashSet<String> = entries.iter().map(|entry| entry.name.clone()).collect();
+    let meta_dir = join_key(user_dir(&user), META_SUBDIR);
+    for meta in storage.list(&meta_dir).await? {
+        let has_file = meta
+            .name
+            .strip_suffix(".json")
+            .is_some_and(|name| exported.contains(name));
+        if has_file {
+            entries.push(ExportEntry {
+                name: format!("{}/{}", META_SUBDIR, meta.name),
+                key: meta.key,
+                modified: meta.modified,
+            });
+        }
+    }
+    if query.thumbnails {
+        let thumbnail_dir = join_key(user_dir(&user), THUMBNAIL_SUBDIR);
+        for thumbnail in storage.list(&thumbnail_dir).await? {
+            if since.is_some_and(|since| thumbnail.modified <= since) {
+                continue;
+            }
+            entries.push(ExportEntry {
+                name: format!("{}/{}", THUMBNAIL_SUBDIR, thumbnail.name),
+                key: thumbnail.key,
+                modified: thumbnail.modified,
+            });
+        }
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let filename = match user.name() {
+        Some(user) => format!("i-{}-{}.zip", user, Utc::now().format("%Y-%m-%d")),
+        None => format!("i-{}.zip", Utc::now().format("%Y-%m-%d")),
+    };
+    let (writer, reader) = tokio::io::duplex(BUFFER_SIZE);
+    tokio::spawn(async move {
+        // The response has been started, so all that can be done is to end it early.
+        if let Err(e) = write_zip(writer, entries, storage.as_ref()).await {
+            log::error!("could not export files: {}", e);
+        }
+    });
+
+    Ok((
+        [
+            (CONTENT_TYPE, HeaderValue::from_static("application/zip")),
+            (CONTENT_DISPOSITION, attachment_disposition(&filename)),
+        ],
+        Body::from_stream(ReaderStream::new(reader)),
+    )
+        .into_response())
+}
+
+fn parse_since(since: &str) -> Result<SystemTime, WebError> {
+    if let Ok(secs) = since.parse::<u64>() {
+        return Ok(UNIX_EPOCH + Duration::from_secs(secs));
+    }
+    DateTime::parse_from_rfc3339(since)
+        .map(SystemTime::from)
+        .map_err(|_| WebError::BadRequest)
+}
+
+async fn write_zip<W>(
+    out: W,
+    entries: Vec<ExportEntry>,
+    storage: &dyn Storage,
+) -> Result<(), WebError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut zip = ZipFileWriter::with_tokio(out);
+    for entry in entries {
+        let content: Box<dyn AsyncRead + Send + Unpin> = match storage.local_path(&entry.key) {
+            Some(path) => match tokio::fs::File::open(path).await {
+                Ok(file) => Box::new(file),
+                // Deleted since it was listed.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            },
+            None => match storage.get(&entry.key).await? {
+                Some(data) => Box::new(Cursor::new(data)),
+                None => continue,
+            },
+        };
+        let builder = ZipEntryBuilder::new(entry.name.into(), Compression::Deflate)
+            .last_modification_date(dos_time(entry.modified))
+            .attribute_compatibility(AttributeCompatibility::Unix)
+            .unix_permissions(FILE_MODE);
+        let mut writer = zip.write_entry_stream(builder).await.map_err(zip_error)?;
+        futures::io::copy(content.compat(), &mut writer).await?;
+        writer.close().await.map_err(zip_error)?;
+    }
+    zip.close()
+        .await
+        .map_err(zip_error)?
+        .into_inner()
+        .shutdown()
+        .await?;
+    Ok(())
+}
+
+fn zip_error(e: ZipError) -> WebError {
+    std::io::Error::other(e).into()
+}
+
+/**
+ * Returns the local time in the format of zip archives, which has two seconds precision and starts
+ * in 1980.
+ */
+fn dos_time(time: SystemTime) -> ZipDateTime {
+    let time: DateTime<Local> = time.into();
+    if time.year() < 1980 {
+        return ZipDateTimeBuilder::new().year(1980).month(1).day(1).build();
+    }
+    ZipDateTimeBuilder::new()
+        .year(time.year())
+        .month(time.month())
+        .day(time.day())
+        .hour(time.hour())
+        .minute(time.minute())
+        .second(time.second())
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn since_is_a_timestamp_or_a_date() {
+        assert_eq!(
+            UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            parse_since("1700000000").unwrap()
+        );
+        assert_eq!(
+            UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            parse_since("2023-11-14T22:13:20Z").unwrap()
+        );
+        assert!(parse_since("yesterday").is_err());
+    }
+}
@@ -0,0 +1,306 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::http::header::{CONTENT_TYPE, LOCATION};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use futures::TryStreamExt;
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use url::{Host, Url};
+
+use crate::WebError;
+
+use super::client_ip::ClientIp;
+use super::diskspace::SpaceGuard;
+use super::limits::size_limit;
+use super::sniff::ContentCheck;
+use super::upload::{
+    check_custom_filename, detect_content_type, discard, extension_for, generate_random_filename,
+    is_blocked_extension, staging_path, store_file, upload_response, write_staged, FileUpload,
+    Options,
+};
+use super::users::User;
+use super::Opt;
+
+/// Maximum number of redirects followed when fetching a url.
+const MAX_REDIRECTS: usize = 5;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a remote server may go without sending anything before the fetch is aborted.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long fetching a url may take in all, so that a server sending slowly can't hold it open.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/**
+ * A url to fetch and store, with the same options as a regular upload.
+ */
+#[derive(Deserialize)]
+pub struct FetchRequest {
+    url: String,
+    #[serde(flatten)]
+    options: Options,
+}
+
+pub async fn handle_upload_url(
+    State(opt): State<Opt>,
+    user: User,
+    ClientIp(client_ip): ClientIp,
+    Json(request): Json<FetchRequest>,
+) -> Result<impl IntoResponse, WebError> {
+    let url = Url::parse(&request.url)
+        .map_err(|e| WebError::UrlNotAllowed(format!("invalid url: {}", e)))?;
+    let options = request.options;
+
+    let file = fetch_file(url, &opt).await?;
+    if let Some(filename) = &options.filename {
+        if let Err(e) =
+            check_custom_filename(filename, 1, options.overwrite, client_ip, &user, &opt).await
+        {
+            discard(&[file]);
+            return Err(e);
+        }
+    }
+
+    let uploaded = store_file(file, &options, &user, &opt).await?;
+    upload_response(vec![uploaded], &options, &opt)
+}
+
+/**
+ * Downloads the url to the staging directory, under a random filename with an extension matching
+ * the content type of the response.
+ */
+async fn fetch_file(url: Url, opt: &Opt) -> Result<FileUpload, WebError> {
+    let response = fetch(url, opt).await?;
+    if response.status() != StatusCode::OK {
+        return Err(WebError::FetchFailed(format!(
+            "remote server responded with {}",
+            response.status()
+        )));
+    }
+    if response
+        .content_length()
+        .is_some_and(|length| length > opt.max_upload_size as u64)
+    {
+        return Err(WebError::PayloadTooLarge);
+    }
+
+    let claimed_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_ascii_lowercase());
+    let original_filename = url_filename(response.url());
+    let extension = extension_for(claimed_type.as_deref(), original_filename.as_deref());
+    if let Some(ext) = &extension {
+        if is_blocked_extension(ext, opt) {
+            log::info!(
+                "rejected fetch of {} due to blocked extension",
+                response.url()
+            );
+            return Err(WebError::BlockedExtension(ext.to_string()));
+        }
+    }
+    let random_filename = generate_random_filename(extension.as_deref(), opt);
+    let content_type = detect_content_type(&random_filename, claimed_type.as_deref());
+
+    let staging_path = staging_path(&random_filename, opt).await?;
+    let chunks = response
+        .bytes_stream()
+        .map_err(|e| WebError::FetchFailed(e.to_string()));
+    let limit = size_limit(extension.as_deref(), &content_type, opt);
+    let check = ContentCheck::new(extension.as_deref(), &content_type, opt);
+    let (size, hash) =
+        write_staged(&staging_path, chunks, &limit, check, SpaceGuard::new(opt)).await?;
+    if size == 0 {
+        std::fs::remove_file(&staging_path)?;
+        return Err(WebError::EmptyUpload);
+    }
+
+    Ok(FileUpload {
+        original_filename: original_filename.unwrap_or_else(|| random_filename.clone()),
+        random_filename,
+        staging_path,
+        size,
+        content_type,
+        hash,
+    })
+}
+
+/**
+ * Requests the url, following redirects. Every url is checked before it is requested.
+ */
+async fn fetch(mut url: Url, opt: &Opt) -> Result<reqwest::Response, WebError> {
+    for _ in 0..=MAX_REDIRECTS {
+        let response = client_for(&url, opt)
+            .await?
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(|e| WebError::FetchFailed(e.to_string()))?;
+        if !response.status().is_redirection() {
+            return Ok(response);
+        }
+
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| WebError::FetchFailed("redirect without location".to_string()))?;
+        url = url
+            .join(location)
+            .map_err(|e| WebError::FetchFailed(format!("invalid redirect: {}", e)))?;
+    }
+
+    Err(WebError::FetchFailed("too many redirects".to_string()))
+}
+
+/**
+ * Builds a client for requesting the url, after checking that the url may be requested. The host
+ * is resolved here, and the client only connects to the checked addresses, so that a second DNS
+ * lookup can't send the request somewhere else.
+ */
+async fn client_for(url: &Url, opt: &Opt) -> Result<reqwest::Client, WebError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(WebError::UrlNotAllowed(format!(
+            "unsupported scheme {}",
+            url.scheme()
+        )));
+    }
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| WebError::UrlNotAllowed("missing port".to_string()))?;
+    let addrs: Vec<SocketAddr> = match url.host() {
+        Some(Host::Domain(domain)) => tokio::net::lookup_host((domain, port))
+            .await
+            .map_err(|e| WebError::FetchFailed(format!("could not resolve {}: {}", domain, e)))?
+            .collect(),
+        Some(Host::Ipv4(ip)) => vec![SocketAddr::new(ip.into(), port)],
+        Some(Host::Ipv6(ip)) => vec![SocketAddr::new(ip.into(), port)],
+        None => return Err(WebError::UrlNotAllowed("missing host".to_string())),
+    };
+    if !opt.allow_private_urls && addrs.iter().any(|addr| !is_public(addr.ip())) {
+        return Err(WebError::UrlNotAllowed(format!(
+            "{} is a private address",
+            url.host_str().unwrap_or_default()
+        )));
+    }
+
+    let mut builder = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .connect_timeout(CONNECT_TIMEOUT)
+        .read_timeout(READ_TIMEOUT)
+        .timeout(FETCH_TIMEOUT)
+        .no_proxy();
+    if let Some(domain) = url.domain() {
+        builder = builder.resolve_to_addrs(domain, &addrs);
+    }
+    builder
+        .build()
+        .map_err(|e| WebError::FetchFailed(e.to_string()))
+}
+
+/**
+ * Returns false for loopback, private, link-local, multicast, and other addresses that aren't
+ * reachable on the internet. IPv6 addresses that lead to an IPv4 address, through NAT64 or 6to4,
+ * are only public if that address is.
+ */
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            let shared = a == 100 && (64..128).contains(&b);
+            // 240.0.0.0/4 is reserved, and includes the broadcast address.
+            let reserved = a >= 240;
+            !(a == 0
+                || ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || shared
+                || reserved)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(ip.into());
+            }
+            let segments = ip.segments();
+            let embedded =
+                |high: u16, low: u16| Ipv4Addr::from((u32::from(high) << 16) | u32::from(low));
+            // NAT64 gateways translate 64:ff9b::/96 to the address in its last 32 bits.
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                return is_public(embedded(segments[6], segments[7]).into());
+            }
+            // 6to4 relays reach the address in 2002::/16.
+            if segments[0] == 0x2002 {
+                return is_public(embedded(segments[1], segments[2]).into());
+            }
+            let unique_local = segments[0] & 0xfe00 == 0xfc00;
+            let link_local = segments[0] & 0xffc0 == 0xfe80;
+            // Local-use NAT64 prefixes are only translated within a network.
+            let local_nat64 = segments[..3] == [0x64, 0xff9b, 1];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || unique_local
+                || link_local
+                || local_nat64)
+        }
+    }
+}
+
+/**
+ * Returns the last path segment of the url, to be used as the original filename.
+ */
+fn url_filename(url: &Url) -> Option<String> {
+    let segment = url.path_segments()?.next_back()?;
+    let filename = sanitize_filename::sanitize(percent_decode_str(segment).decode_utf8_lossy());
+    (!filename.is_empty()).then_some(filename)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_addresses_are_not_public() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "192.168.1.1",
+            "169.254.169.254",
+            "::1",
+            "fd00::1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        assert!(!is_public("::ffff:127.0.0.1".parse().unwrap()));
+        assert!(is_public("93.184.215.14".parse().unwrap()));
+        assert!(is_public("2606:4700::1111".parse().unwrap()));
+    }
+
+    #[test]
+    fn reserved_and_translated_addresses_are_not_public() {
+        for ip in [
+            "224.0.0.1",
+            "239.255.255.250",
+            "240.0.0.1",
+            "255.255.255.255",
+            "100.64.0.1",
+            "ff02::1",
+            "64:ff9b::a9fe:a9fe",
+            "64:ff9b::7f00:1",
+            "64:ff9b:1::c0a8:101",
+            "2002:a00:1::1",
+            "2002:7f00:1::",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        assert!(is_public("64:ff9b::5db8:d70e".parse().unwrap()));
+        assert!(is_public("2002:5db8:d70e::1".parse().unwrap()));
+    }
+}
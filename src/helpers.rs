@@ -1,12 +1,175 @@
-use std::io::Result;
-use std::path::PathBuf;
+use std::net::IpAddr;
 
-use super::{get_base_dir, get_thumbnail_dir, Opt};
+use crate::WebError;
 
-pub fn filename_path(filename: &str, opt: &Opt) -> Result<PathBuf> {
-    Ok(get_base_dir(opt)?.join(sanitize_filename::sanitize(filename)))
+use super::storage::join_key;
+use super::thumbnail::thumbnail_filename;
+use super::users::User;
+use super::{Opt, META_SUBDIR, THUMBNAIL_SUBDIR, TRASH_SUBDIR};
+
+/**
+ * Returns the storage directory of the current user, which is the root if there are no users.
+ */
+pub fn user_dir(user: &User) -> &str {
+    user.name().unwrap_or("")
+}
+
+pub fn file_key(filename: &str, user: &User) -> String {
+    user_url_path(&sanitize_filename::sanitize(filename), user)
+}
+
+pub fn thumbnail_key(filename: &str, size: u32, user: &User, opt: &Opt) -> String {
+    let filename = thumbnail_filename(&sanitize_filename::sanitize(filename), size, opt);
+    user_url_path(&format!("{}/{}", THUMBNAIL_SUBDIR, filename), user)
+}
+
+pub fn meta_key(filename: &str, user: &User) -> String {
+    let filename = format!("{}.json", sanitize_filename::sanitize(filename));
+    user_url_path(&format!("{}/{}", META_SUBDIR, filename), user)
+}
+
+/**
+ * Returns the key that an object of the current user is moved to when it is put in the trash,
+ * which is the same path below the trash directory of the user.
+ */
+pub fn trash_key(key: &str, user: &User) -> String {
+    let dir = user_dir(user);
+    let relative = key
+        .strip_prefix(dir)
+        .map(|key| key.trim_start_matches('/'))
+        .unwrap_or(key);
+    join_key(&join_key(dir, TRASH_SUBDIR), relative)
+}
+
+/**
+ * Returns the url path of the given file relative to the server url, which includes the directory
+ * of the current user, if any.
+ */
+pub fn user_url_path(filename: &str, user: &User) -> String {
+    match user.name() {
+        Some(user) => format!("{}/{}", user, filename),
+        None => filename.to_string(),
+    }
 }
 
-pub fn thumbnail_filename_path(filename: &str, opt: &Opt) -> Result<PathBuf> {
-    Ok(get_thumbnail_dir(opt)?.join(sanitize_filename::sanitize(filename)))
+/**
+ * Returns the url path of an uploaded file relative to the server url, which is below the
+ * `--files-prefix` and the directory of the current user.
+ */
+pub fn file_url_path(filename: &str, user: &User, opt: &Opt) -> String {
+    let path = user_url_path(filename, user);
+    match opt.files_prefix.is_empty() {
+        true => path,
+        false => format!("{}/{}", opt.files_prefix.trim_start_matches('/'), path),
+    }
+}
+
+/**
+ * Whether a filename given by a client tries to reach outside of its directory: it has `..` as a
+ * path component, is an absolute path, or has a null byte, which would cut the path short.
+ */
+pub fn is_path_traversal(filename: &str) -> bool {
+    let bytes = filename.as_bytes();
+    let has_drive = matches!(bytes, [drive, b':', b'/' | b'\\', ..] if drive.is_ascii_alphabetic());
+    filename.starts_with(['/', '\\'])
+        || has_drive
+        || filename.contains('\0')
+        || filename
+            .split(['/', '\\'])
+            .any(|component| component == "..")
+}
+
+/**
+ * Rejects a filename that tries to reach outside of its directory, and logs who tried. Such names
+ * are never sent by accident, unlike other names that are just sanitized.
+ */
+pub fn reject_path_traversal(filename: &str, client_ip: Option<IpAddr>) -> Result<(), WebError> {
+    if !is_path_traversal(filename) {
+        return Ok(());
+    }
+    tracing::warn!(
+        filename,
+        client_ip = client_ip.map(tracing::field::display),
+        "rejected filename with a path traversal"
+    );
+    Err(WebError::PathTraversal)
+}
+
+/**
+ * Returns the public url of `path`, which is relative to the server url and the `--base-path`.
+ */
+pub fn public_path(path: &str, opt: &Opt) -> std::result::Result<String, url::ParseError> {
+    let mut public_base = url::Url::parse(&opt.server_url)?;
+    if !opt.base_path.is_empty() {
+        let base = format!(
+            "{}{}/",
+            public_base.path().trim_end_matches('/'),
+            opt.base_path
+        );
+        public_base.set_path(&base);
+    }
+    Ok(public_base.join(path)?.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thumbnail::get_thumbnail_url;
+    use clap::Parser;
+
+    #[test]
+    fn urls_and_keys_use_forward_slashes() {
+        let mut opt = Opt::parse_from(["i", "--server-url", "https://example.com/"]);
+        let user = User(Some("alice".to_string()));
+
+        // Built from strings, so they look the same on every platform.
+        assert_eq!("alice/image.png", user_url_path("image.png", &user));
+        assert_eq!(
+            "thumbnail/alice/image.png?size=150",
+            get_thumbnail_url("image.png", Some(150), &user, &opt)
+        );
+        assert_eq!(
+            "alice/thumbnails/image.png_150.webp",
+            thumbnail_key("image.png", 150, &user, &opt)
+        );
+        assert_eq!(
+            "https://example.com/thumbnail/alice/image.png?size=150",
+            public_path(
+                &get_thumbnail_url("image.png", Some(150), &user, &opt),
+                &opt
+            )
+            .unwrap()
+        );
+        assert_eq!("alice/image.png", file_url_path("image.png", &user, &opt));
+        opt.files_prefix = "/f".to_string();
+        assert_eq!("f/alice/image.png", file_url_path("image.png", &user, &opt));
+        assert_eq!("alice/image.png", file_key("image.png", &user));
+        // Backslashes can't sneak in through the filename either.
+        assert!(!file_key("..\\image.png", &user).contains('\\'));
+    }
+
+    #[test]
+    fn path_traversal_is_detected() {
+        for filename in [
+            "../../etc/passwd",
+            "..",
+            "uploads/../../secret",
+            "..\\boot.ini",
+            "/etc/passwd",
+            "\\\\server\\share",
+            "C:\\Windows\\win.ini",
+            "report.pdf\0.txt",
+        ] {
+            assert!(is_path_traversal(filename), "{:?}", filename);
+        }
+        for filename in [
+            "report.pdf",
+            "..report.pdf",
+            "report..pdf",
+            "a:b.txt",
+            ".hidden",
+        ] {
+            assert!(!is_path_traversal(filename), "{:?}", filename);
+        }
+    }
 }
@@ -0,0 +1,185 @@
+use std::path::Path;
+use std::sync::OnceLock;
+
+use askama_axum::Template;
+use axum::body::to_bytes;
+use axum::extract::{Request, State};
+use axum::http::header::{
+    ACCEPT, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_NONE_MATCH, VARY,
+};
+use axum::http::{HeaderValue, StatusCode};
+use axum::middleware;
+use axum::response::{IntoResponse, Response};
+use percent_encoding::percent_decode_str;
+use syntect::highlighting::ThemeSet;
+use syntect::html::highlighted_html_for_string;
+use syntect::parsing::{SyntaxReference, SyntaxSet};
+use url::form_urlencoded;
+
+use super::{helpers::public_path, Opt};
+
+/// Files larger than this are served as they are, since highlighting them would be slow.
+const MAX_HIGHLIGHT_SIZE: usize = 512 * 1024;
+
+/// Extensions that browsers already show as intended, or that aren't code.
+const UNHIGHLIGHTED_EXTENSIONS: &[&str] = &["htm", "html", "svg", "txt"];
+
+const THEME: &str = "InspiredGitHub";
+
+#[derive(Template)]
+#[template(path = "highlight.html")]
+struct HighlightTemplate {
+    filename: String,
+    bulma_url: String,
+    /// Link to the file as it is, keeping the query string, which may have a password.
+    raw_url: String,
+    /// Highlighted code, as HTML.
+    code: String,
+}
+
+fn syntax_set() -> &'static SyntaxSet {
+    static SYNTAX_SET: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAX_SET.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme_set() -> &'static ThemeSet {
+    static THEME_SET: OnceLock<ThemeSet> = OnceLock::new();
+    THEME_SET.get_or_init(ThemeSet::load_defaults)
+}
+
+/**
+ * Returns the syntax of a file with a known code extension.
+ */
+fn find_syntax(filename: &str) -> Option<&'static SyntaxReference> {
+    let extension = Path::new(filename)
+        .extension()?
+        .to_str()?
+        .to_ascii_lowercase();
+    if UNHIGHLIGHTED_EXTENSIONS.contains(&extension.as_str()) {
+        return None;
+    }
+    syntax_set().find_syntax_by_extension(&extension)
+}
+
+/**
+ * Returns true if the request is from a browser navigating to the file, rather than from a script
+ * or an element of a page that embeds it.
+ */
+fn wants_html(request: &Request) -> bool {
+    request
+        .headers()
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"))
+}
+
+/**
+ * Middleware in front of the served files, which shows code files with syntax highlighting when
+ * they are opened in a browser. Add `?raw=1` to get the file as it is. Binary and large files are
+ * always served as they are.
+ */
+pub async fn highlight_code(
+    State(opt): State<Opt>,
+    mut request: Request,
+    next: middleware::Next,
+) -> Response {
+    let path = percent_decode_str(request.uri().path())
+        .decode_utf8_lossy()
+        .into_owned();
+    let filename = path.rsplit('/').next().unwrap_or_default().to_string();
+    let query = request.uri().query().unwrap_or_default().to_string();
+    let raw = form_urlencoded::parse(query.as_bytes()).any(|(key, _)| key == "raw");
+    let Some(syntax) = find_syntax(&filename).filter(|_| !raw) else {
+        return next.run(request).await;
+    };
+    if !wants_html(&request) {
+        let mut response = next.run(request).await;
+        // Caches must not give the page to scripts, or the file to browsers.
+        response
+            .headers_mut()
+            .insert(VARY, HeaderValue::from_static("accept"));
+        return response;
+    }
+
+    // The page is a different representation than the file, so it can't be validated by its ETag.
+    request.headers_mut().remove(IF_NONE_MATCH);
+    let response = next.run(request).await;
+    // Files that are downloaded aren't shown, so there is nothing to highlight.
+    let attachment = response
+        .headers()
+        .get(CONTENT_DISPOSITION)
+        .is_some_and(|v| v.as_bytes().starts_with(b"attachment"));
+    let too_large = response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<usize>().ok())
+        .is_some_and(|length| length > MAX_HIGHLIGHT_SIZE);
+    if response.status() != StatusCode::OK || too_large || attachment {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let Ok(bytes) = to_bytes(body, MAX_HIGHLIGHT_SIZE).await else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let Ok(code) = String::from_utf8(bytes.to_vec()) else {
+        // Not text after all, so serve it as it is.
+        return Response::from_parts(parts, bytes.into());
+    };
+
+    // Highlighting is slow for large files, so keep it off the async workers.
+    let highlighted = tokio::task::spawn_blocking(move || {
+        highlighted_html_for_string(&code, syntax_set(), syntax, &theme_set().themes[THEME])
+    })
+    .await;
+    let page = highlighted
+        .map_err(|e| e.to_string())
+        .and_then(|highlighted| highlighted.map_err(|e| e.to_string()))
+        .and_then(|code| {
+            let bulma_url = public_path("recent/bulma.min.css", &opt).map_err(|e| e.to_string())?;
+            let raw_url = match query.as_str() {
+                "" => "?raw=1".to_string(),
+                query => format!("?{}&raw=1", query),
+            };
+            HighlightTemplate {
+                filename,
+                bulma_url,
+                raw_url,
+                code,
+            }
+            .render()
+            .map_err(|e| e.to_string())
+        });
+    let page = match page {
+        Ok(page) => page,
+        Err(e) => {
+            log::error!("could not highlight {}: {}", path, e);
+            return Response::from_parts(parts, bytes.into());
+        }
+    };
+
+    let headers = &mut parts.headers;
+    headers.remove(ETAG);
+    headers.remove(CONTENT_LENGTH);
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    headers.insert(VARY, HeaderValue::from_static("accept"));
+    Response::from_parts(parts, page.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_code_is_highlighted() {
+        assert!(find_syntax("main.rs").is_some());
+        assert!(find_syntax("script.PY").is_some());
+        assert!(find_syntax("notes.txt").is_none());
+        assert!(find_syntax("page.html").is_none());
+        assert!(find_syntax("cat.png").is_none());
+        assert!(find_syntax("Makefile").is_none());
+    }
+}
@@ -0,0 +1,482 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_compression::tokio::bufread::GzipDecoder;
+use async_zip::base::read::stream::ZipFileReader;
+use async_zip::error::ZipError;
+use async_zip::ZipDateTime;
+use axum::body::Body;
+use axum::extract::{Query, State};
+use axum::Json;
+use chrono::{Local, TimeZone};
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, BufReader, ReadBuf};
+use tokio_tar::Archive;
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::WebError;
+
+use super::{
+    client_ip::ClientIp,
+    diskspace::SpaceGuard,
+    helpers::{file_key, reject_path_traversal},
+    limits::size_limit,
+    meta::{write_meta, FileMeta},
+    sniff::ContentCheck,
+    storage,
+    upload::{
+        check_custom_filename, detect_content_type, generate_random_filename,
+        get_extension_from_filename, is_upload_name, staging_path, store_file, write_staged,
+        FileUpload, Options,
+    },
+    users::User,
+    Opt, META_SUBDIR,
+};
+
+/// Largest metadata file read from the meta folder of an archive.
+const MAX_META_ENTRY: u64 = 64 * 1024;
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    /// Replace existing files of the same name, instead of skipping them.
+    #[serde(default)]
+    overwrite: bool,
+}
+
+/// Which of the files of an archive were imported, and which were not.
+#[derive(Debug, Default, Serialize)]
+pub struct ImportResponse {
+    imported: Vec<String>,
+    skipped: Vec<String>,
+    failed: Vec<FailedImport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FailedImport {
+    /// Name of the entry in the archive, missing if the archive itself couldn't be read further.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    error: String,
+}
+
+impl ImportResponse {
+    fn is_empty(&self) -> bool {
+        self.imported.is_empty() && self.skipped.is_empty() && self.failed.is_empty()
+    }
+}
+
+/**
+ * Restores the files of a tar archive, which may be gzipped, or a zip archive, as sent in the
+ * request body. The archive is read as it arrives, and each file is stored like an upload, so that
+ * its thumbnails are generated again. Files that already exist are skipped unless `overwrite` is
+ * set, and so are entries in folders, such as thumbnails. The metadata of an imported file, from
+ * the meta folder, is restored with it.
+ */
+pub async fn handle_import(
+    State(opt): State<Opt>,
+    user: User,
+    ClientIp(client_ip): ClientIp,
+    Query(query): Query<ImportQuery>,
+    body: Body,
+) -> Result<Json<ImportResponse>, WebError> {
+    let body = body.into_data_stream().map_err(std::io::Error::other);
+    let mut body = StreamReader::new(body);
+    let mut magic = [0; 4];
+    body.read_exact(&mut magic)
+        .await
+        .map_err(|_| WebError::InvalidArchive("archive is empty".to_string()))?;
+    let archive = BufReader::new(Cursor::new(magic).chain(body));
+
+    let mut import = Import {
+        overwrite: query.overwrite,
+        client_ip,
+        user: &user,
+        opt: &opt,
+        response: ImportResponse::default(),
+        imported: HashSet::new(),
+        sidecars: HashMap::new(),
+    };
+    let result = match magic {
+        [b'P', b'K', 3, 4] => read_zip(archive, &mut import).await,
+        [0x1f, 0x8b, ..] => read_tar(BufReader::new(GzipDecoder::new(archive)), &mut import).await,
+        _ => read_tar(archive, &mut import).await,
+    };
+
+    // Metadata of files that weren't imported isn't restored.
+    let mut response = import.response;
+    let mut unused: Vec<_> = import
+        .sidecars
+        .into_values()
+        .map(|(name, _)| name)
+        .collect();
+    unused.sort();
+    response.skipped.extend(unused);
+
+    // Files imported before the archive broke are kept, and reported.
+    if let Err(e) = result {
+        if response.is_empty() {
+            return Err(e);
+        }
+        response.failed.push(FailedImport {
+            name: None,
+            error: e.to_string(),
+        });
+    }
+    Ok(Json(response))
+}
+
+struct Import<'a> {
+    overwrite: bool,
+    client_ip: Option<IpAddr>,
+    user: &'a User,
+    opt: &'a Opt,
+    response: ImportResponse,
+    /// Names of the files imported so far.
+    imported: HashSet<String>,
+    /// Metadata read before its file, by the name of the file, with the name of its entry.
+    sidecars: HashMap<String, (String, FileMeta)>,
+}
+
+impl Import<'_> {
+    /**
+     * Imports a file of the archive, and reads the rest of its content, so that the next entry can
+     * be read. Only errors reading the archive are returned, others are reported as failed.
+     */
+    async fn entry<R>(
+        &mut self,
+        name: String,
+        modified: Option<SystemTime>,
+        content: &mut R,
+    ) -> Result<(), WebError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let path = name.trim_start_matches("./");
+        if let Some(filename) = sidecar_filename(path) {
+            let filename = filename.to_string();
+            if let Err(e) = self.sidecar(&name, filename, content).await {
+                if let WebError::InvalidArchive(_) = e {
+                    return Err(e);
+                }
+                self.response.failed.push(FailedImport {
+                    name: Some(name),
+                    error: e.to_string(),
+                });
+            }
+            return drain(content).await;
+        }
+
+        match import_file(
+            &name,
+            modified,
+            content,
+            self.overwrite,
+            self.client_ip,
+            self.user,
+            self.opt,
+        )
+        .await
+        {
+            Ok(true) => {
+                let filename = path.to_string();
+                if let Some((sidecar, meta)) = self.sidecars.remove(&filename) {
+                    if let Err(e) = write_meta(&filename, &meta, self.user, self.opt).await {
+                        self.response.failed.push(FailedImport {
+                            name: Some(sidecar),
+                            error: e.to_string(),
+                        });
+                    }
+                }
+                self.imported.insert(filename);
+                self.response.imported.push(name);
+            }
+            Ok(false) => self.response.skipped.push(name),
+            Err(e @ WebError::InvalidArchive(_)) => return Err(e),
+            Err(e) => self.response.failed.push(FailedImport {
+                name: Some(name),
+                error: e.to_string(),
+            }),
+        }
+        drain(content).await
+    }
+
+    /**
+     * Restores the metadata of a file, such as its password, expiry, and deletion token, replacing
+     * what was written when the file was imported. Metadata that comes before its file is kept
+     * until the file has been imported.
+     */
+    async fn sidecar<R>(
+        &mut self,
+        name: &str,
+        filename: String,
+        content: &mut R,
+    ) -> Result<(), WebError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        reject_path_traversal(name, self.client_ip)?;
+        let mut data = Vec::new();
+        content
+            .take(MAX_META_ENTRY + 1)
+            .read_to_end(&mut data)
+            .await
+            .map_err(invalid_archive)?;
+        let meta = match data.len() as u64 {
+            len if len > MAX_META_ENTRY => Err("metadata is too large".to_string()),
+            _ => serde_json::from_slice::<FileMeta>(&data)
+                .map_err(|e| format!("invalid metadata: {}", e)),
+        };
+        let meta = match meta {
+            Ok(meta) => meta,
+            Err(error) => {
+                self.response.failed.push(FailedImport {
+                    name: Some(name.to_string()),
+                    error,
+                });
+                return Ok(());
+            }
+        };
+
+        if self.imported.contains(&filename) {
+            write_meta(&filename, &meta, self.user, self.opt).await
+        } else {
+            self.sidecars.insert(filename, (name.to_string(), meta));
+            Ok(())
+        }
+    }
+}
+
+/**
+ * Returns the name of the file that an entry of the meta folder is the metadata of, if it is one.
+ */
+fn sidecar_filename(path: &str) -> Option<&str> {
+    let filename = path
+        .strip_prefix(META_SUBDIR)?
+        .strip_prefix('/')?
+        .strip_suffix(".json")?;
+    (!filename.contains('/') && is_upload_name(filename)).then_some(filename)
+}
+
+/**
+ * Stores a file of the archive under its name. Returns false if it is skipped, because it is in a
+ * folder, or a file of that name exists and may not be replaced.
+ */
+async fn import_file<R>(
+    path: &str,
+    modified: Option<SystemTime>,
+    content: &mut R,
+    overwrite: bool,
+    client_ip: Option<IpAddr>,
+    user: &User,
+    opt: &Opt,
+) -> Result<bool, WebError>
+where
+    R: AsyncRead + Unpin,
+{
+    reject_path_traversal(path, client_ip)?;
+    // Archives made with `tar -C <dir> .` name their files ./<name>.
+    let name = path.trim_start_matches("./");
+    if name.contains('/') || !is_upload_name(name) {
+        return Ok(false);
+    }
+    match check_custom_filename(name, 1, overwrite, client_ip, user, opt).await {
+        Ok(()) => {}
+        Err(WebError::Conflict(_)) => return Ok(false),
+        Err(e) => return Err(e),
+    }
+
+    let extension = get_extension_from_filename(name);
+    let random_filename = generate_random_filename(extension, opt);
+    let content_type = detect_content_type(name, None);
+    let staging_path = staging_path(&random_filename, opt).await?;
+    let chunks = ReaderStream::new(content).map_err(invalid_archive);
+    let limit = size_limit(extension, &content_type, opt);
+    let check = ContentCheck::new(extension, &content_type, opt);
+    let (size, hash) =
+        write_staged(&staging_path, chunks, &limit, check, SpaceGuard::new(opt)).await?;
+
+    let file = FileUpload {
+        original_filename: name.to_string(),
+        random_filename,
+        staging_path,
+        size,
+        content_type,
+        hash,
+    };
+    let options = Options {
+        filename: Some(name.to_string()),
+        overwrite: true,
+        redirect: false,
+        ..Default::default()
+    };
+    store_file(file, &options, user, opt).await?;
+
+    // Keep the time of the file, so that the recent uploads are in the same order as before.
+    let storage = storage::backend(opt);
+    if let (Some(modified), Some(path)) = (modified, storage.local_path(&file_key(name, user))) {
+        std::fs::File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(modified)?;
+        opt.recent_cache.invalidate(user);
+    }
+    Ok(true)
+}
+
+fn invalid_archive(e: std::io::Error) -> WebError {
+    WebError::InvalidArchive(e.to_string())
+}
+
+/**
+ * Reads the rest of an entry, which isn't needed.
+ */
+async fn drain<R: AsyncRead + Unpin>(content: &mut R) -> Result<(), WebError> {
+    tokio::io::copy(content, &mut tokio::io::sink())
+        .await
+        .map_err(invalid_archive)?;
+    Ok(())
+}
+
+/**
+ * Reads a tar archive with the entries of its files, whose long names, from GNU long name entries
+ * or pax headers, are put together by the reader.
+ */
+async fn read_tar<R>(reader: R, import: &mut Import<'_>) -> Result<(), WebError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut archive = Archive::new(reader);
+    let mut entries = archive.entries().map_err(invalid_archive)?;
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry.map_err(invalid_archive)?;
+        let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        let header = entry.header();
+        let kind = header.entry_type();
+        // The size in the header, which pax headers may have replaced with a larger one.
+        let size = header.entry_size().map_err(invalid_archive)?;
+        // Times too far in the future for the system are left out.
+        let modified = header
+            .mtime()
+            .ok()
+            .and_then(|secs| UNIX_EPOCH.checked_add(Duration::from_secs(secs)));
+        if kind.is_file() || kind.is_contiguous() {
+            import
+                .entry(name, modified, &mut Exact::new(&mut entry, size))
+                .await?;
+        } else if !kind.is_dir() && !kind.is_pax_global_extensions() {
+            // Links, devices, and pipes.
+            import.response.skipped.push(name);
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Reads a zip archive from the local header in front of each entry, without its central directory
+ * at the end. Entries are stored or deflated.
+ */
+async fn read_zip<R>(reader: R, import: &mut Import<'_>) -> Result<(), WebError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut zip = ZipFileReader::with_tokio(reader);
+    while let Some(mut entry) = zip.next_with_entry().await.map_err(invalid_zip)? {
+        let file = entry.reader().entry();
+        let name = String::from_utf8_lossy(file.filename().as_bytes()).into_owned();
+        let modified = dos_time(file.last_modification_date());
+        // Entries followed by a data descriptor have no size in their header.
+        let size = file.uncompressed_size();
+        if !file.dir().map_err(invalid_zip)? {
+            let mut content = Exact::new(entry.reader_mut().compat(), size);
+            import.entry(name, modified, &mut content).await?;
+        }
+        zip = entry.skip().await.map_err(invalid_zip)?;
+    }
+    Ok(())
+}
+
+fn invalid_zip(e: ZipError) -> WebError {
+    WebError::InvalidArchive(e.to_string())
+}
+
+/**
+ * Reads the content of an entry, and fails if the archive ends before as much as its header tells
+ * has been read, so that a truncated file isn't stored.
+ */
+struct Exact<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R> Exact<R> {
+    fn new(inner: R, size: u64) -> Self {
+        Exact {
+            inner,
+            remaining: size,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Exact<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let read = (buf.filled().len() - filled) as u64;
+        if read == 0 && buf.remaining() > 0 && self.remaining > 0 {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "archive is truncated",
+            )));
+        }
+        self.remaining = self.remaining.saturating_sub(read);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/**
+ * Converts the local date and time of a zip entry, with two seconds precision.
+ */
+fn dos_time(datetime: &ZipDateTime) -> Option<SystemTime> {
+    let datetime = Local
+        .with_ymd_and_hms(
+            datetime.year(),
+            datetime.month(),
+            datetime.day(),
+            datetime.hour(),
+            datetime.minute(),
+            datetime.second(),
+        )
+        .single()?;
+    Some(datetime.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn short_entries_are_truncated() {
+        let mut data = Vec::new();
+        let mut whole = Exact::new(&b"whole"[..], 5);
+        whole.read_to_end(&mut data).await.unwrap();
+        assert_eq!(b"whole", &data[..]);
+
+        let mut cut = Exact::new(&b"cut"[..], 5);
+        let e = cut.read_to_end(&mut data).await.unwrap_err();
+        assert_eq!(std::io::ErrorKind::UnexpectedEof, e.kind());
+
+        // Entries may be longer than their header tells, such as with a size in a pax header.
+        let mut longer = Exact::new(&b"longer"[..], 0);
+        longer.read_to_end(&mut data).await.unwrap();
+    }
+}
@@ -0,0 +1,332 @@
+use std::io::{BufRead, Cursor, Seek};
+
+use axum::extract::{Path as UrlPath, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::NaiveDateTime;
+use image::{ImageDecoder, ImageReader};
+use serde::{Deserialize, Serialize};
+
+use crate::WebError;
+
+use super::{
+    helpers::file_key,
+    meta::{read_meta, write_meta},
+    storage,
+    upload::{detect_content_type, is_upload_name},
+    users::User,
+    Opt,
+};
+
+/// EXIF tags that are read, in the first IFD or in the EXIF IFD it points to.
+const TAG_MAKE: u16 = 0x010F;
+const TAG_MODEL: u16 = 0x0110;
+const TAG_DATE_TIME: u16 = 0x0132;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+
+/**
+ * What is known about an uploaded file without downloading it.
+ */
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileInfo {
+    size: u64,
+    content_type: String,
+    /// Set if the file is an image in a format that can be read.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    image: Option<ImageInfo>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageInfo {
+    width: u32,
+    height: u32,
+    /// Format of the image as read from its content, such as `jpeg` or `png`.
+    format: String,
+    /// Channels and bits per channel of the pixels, such as `Rgb8`.
+    color_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    camera: Option<String>,
+    /// When the photo was taken according to the camera, which doesn't tell the time zone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    taken_at: Option<NaiveDateTime>,
+}
+
+/**
+ * Returns the size and content type of an uploaded file, and the dimensions, format, and some
+ * EXIF fields of images. Images are only read as far as their headers. The result is kept in the
+ * metadata of the file, which is rewritten whenever the file is replaced.
+ */
+pub async fn handle_info(
+    State(opt): State<Opt>,
+    user: User,
+    UrlPath(name): UrlPath<String>,
+) -> Result<Response, WebError> {
+    let filename = sanitize_filename::sanitize(&name);
+    if !is_upload_name(&filename) {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    let meta = read_meta(&filename, &user, &opt).await?;
+    if let Some(info) = meta.as_ref().and_then(|meta| meta.info.clone()) {
+        return Ok(Json(info).into_response());
+    }
+
+    let content_type = meta
+        .as_ref()
+        .and_then(|meta| meta.content_type.clone())
+        .unwrap_or_else(|| detect_content_type(&filename, None));
+    let storage = storage::backend(&opt);
+    let key = file_key(&filename, &user);
+    let info = match storage.local_path(&key) {
+        Some(path) => {
+            let size = match tokio::fs::metadata(&path).await {
+                Ok(metadata) if metadata.is_file() => metadata.len(),
+                Ok(_) => return Ok(StatusCode::NOT_FOUND.into_response()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return Ok(StatusCode::NOT_FOUND.into_response())
+                }
+                Err(e) => return Err(e.into()),
+            };
+            // Decoders are blocking, use threadpool
+            let image = tokio::task::spawn_blocking(move || {
+                ImageReader::open(path).ok().and_then(image_info)
+            })
+            .await?;
+            FileInfo {
+                size,
+                content_type,
+                image,
+            }
+        }
+        None => {
+            let Some(data) = storage.get(&key).await? else {
+                return Ok(StatusCode::NOT_FOUND.into_response());
+            };
+            let size = data.len() as u64;
+            let image = tokio::task::spawn_blocking(move || {
+                image_info(ImageReader::new(Cursor::new(data)))
+            })
+            .await?;
+            FileInfo {
+                size,
+                content_type,
+                image,
+            }
+        }
+    };
+
+    // Files without metadata weren't uploaded through i, and are left as they are.
+    if let Some(mut meta) = meta {
+        meta.info = Some(info.clone());
+        write_meta(&filename, &meta, &user, &opt).await?;
+    }
+    Ok(Json(info).into_response())
+}
+
+/**
+ * Reads the headers of an image. Returns `None` if it isn't an image in a known format.
+ */
+fn image_info<R: BufRead + Seek>(reader: ImageReader<R>) -> Option<ImageInfo> {
+    let reader = reader.with_guessed_format().ok()?;
+    let format = reader.format()?;
+    let mut decoder = reader.into_decoder().ok()?;
+    let (width, height) = decoder.dimensions();
+    let color_type = format!("{:?}", decoder.color_type());
+    let exif = decoder.exif_metadata().ok().flatten().unwrap_or_default();
+    let (camera, taken_at) = match Exif::new(&exif) {
+        Some(exif) => (exif.camera(), exif.taken_at()),
+        None => (None, None),
+    };
+    Some(ImageInfo {
+        width,
+        height,
+        format: format!("{:?}", format).to_lowercase(),
+        color_type,
+        camera,
+        taken_at,
+    })
+}
+
+/**
+ * Reads tags of an EXIF chunk, which is laid out like a TIFF file: a header telling the byte
+ * order, followed by directories (IFDs) of 12 byte entries.
+ */
+struct Exif<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Exif<'a> {
+    fn new(data: &'a [u8]) -> Option<Self> {
+        let little_endian = match data.get(..4)? {
+            [b'I', b'I', 42, 0] => true,
+            [b'M', b'M', 0, 42] => false,
+            _ => return None,
+        };
+        Some(Exif {
+            data,
+            little_endian,
+        })
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes = self
+            .data
+            .get(offset..offset.checked_add(2)?)?
+            .try_into()
+            .ok()?;
+        Some(match self.little_endian {
+            true => u16::from_le_bytes(bytes),
+            false => u16::from_be_bytes(bytes),
+        })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self
+            .data
+            .get(offset..offset.checked_add(4)?)?
+            .try_into()
+            .ok()?;
+        Some(match self.little_endian {
+            true => u32::from_le_bytes(bytes),
+            false => u32::from_be_bytes(bytes),
+        })
+    }
+
+    /// Returns the offset of the entry of `tag` in the IFD at `ifd`.
+    fn find(&self, ifd: usize, tag: u16) -> Option<usize> {
+        let entries = self.u16(ifd)? as usize;
+        (0..entries)
+            .map(|i| ifd + 2 + i * 12)
+            .find(|&entry| self.u16(entry) == Some(tag))
+    }
+
+    /// Returns the text of an ASCII entry, without its terminating zeros.
+    fn ascii(&self, ifd: usize, tag: u16) -> Option<String> {
+        const ASCII: u16 = 2;
+        let entry = self.find(ifd, tag)?;
+        if self.u16(entry + 2)? != ASCII {
+            return None;
+        }
+        let count = self.u32(entry + 4)? as usize;
+        // Values of up to four bytes are stored in the entry itself.
+        let offset = match count {
+            0..=4 => entry + 8,
+            _ => self.u32(entry + 8)? as usize,
+        };
+        let text = self.data.get(offset..offset.checked_add(count)?)?;
+        let text = String::from_utf8_lossy(text);
+        let text = text.trim_end_matches('\0').trim();
+        (!text.is_empty()).then(|| text.to_string())
+    }
+
+    fn first_ifd(&self) -> Option<usize> {
+        Some(self.u32(4)? as usize)
+    }
+
+    /// The make and model of the camera, without the make if the model already includes it.
+    fn camera(&self) -> Option<String> {
+        let ifd = self.first_ifd()?;
+        match (self.ascii(ifd, TAG_MAKE), self.ascii(ifd, TAG_MODEL)) {
+            (Some(make), Some(model)) if model.starts_with(&make) => Some(model),
+            (Some(make), Some(model)) => Some(format!("{} {}", make, model)),
+            (make, model) => make.or(model),
+        }
+    }
+
+    /// When the photo was taken, or else when the file was last changed.
+    fn taken_at(&self) -> Option<NaiveDateTime> {
+        let ifd = self.first_ifd()?;
+        let exif_ifd = self
+            .find(ifd, TAG_EXIF_IFD)
+            .and_then(|entry| self.u32(entry + 8));
+        exif_ifd
+            .and_then(|exif_ifd| self.ascii(exif_ifd as usize, TAG_DATE_TIME_ORIGINAL))
+            .or_else(|| self.ascii(ifd, TAG_DATE_TIME))
+            .and_then(|date| NaiveDateTime::parse_from_str(&date, "%Y:%m:%d %H:%M:%S").ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a little endian EXIF chunk with a make and model, and an EXIF IFD with the time
+    /// the photo was taken.
+    fn exif_chunk() -> Vec<u8> {
+        fn entry(tag: u16, count: u32, value: u32) -> Vec<u8> {
+            let mut entry = tag.to_le_bytes().to_vec();
+            entry.extend_from_slice(&2u16.to_le_bytes());
+            entry.extend_from_slice(&count.to_le_bytes());
+            entry.extend_from_slice(&value.to_le_bytes());
+            entry
+        }
+        let make = b"Fuji\0";
+        let model = b"X100V\0";
+        let taken = b"2024:05:01 10:30:00\0";
+        // Header, first IFD with three entries, EXIF IFD with one entry, then the values.
+        let first_ifd = 8;
+        let exif_ifd = first_ifd + 2 + 3 * 12 + 4;
+        let values = exif_ifd + 2 + 12 + 4;
+        let mut chunk = b"II\x2a\x00".to_vec();
+        chunk.extend_from_slice(&(first_ifd as u32).to_le_bytes());
+        chunk.extend_from_slice(&3u16.to_le_bytes());
+        chunk.extend(entry(TAG_MAKE, make.len() as u32, values as u32));
+        chunk.extend(entry(
+            TAG_MODEL,
+            model.len() as u32,
+            (values + make.len()) as u32,
+        ));
+        let mut pointer = entry(TAG_EXIF_IFD, 1, exif_ifd as u32);
+        pointer[2..4].copy_from_slice(&4u16.to_le_bytes());
+        chunk.extend(pointer);
+        chunk.extend_from_slice(&0u32.to_le_bytes());
+        chunk.extend_from_slice(&1u16.to_le_bytes());
+        chunk.extend(entry(
+            TAG_DATE_TIME_ORIGINAL,
+            taken.len() as u32,
+            (values + make.len() + model.len()) as u32,
+        ));
+        chunk.extend_from_slice(&0u32.to_le_bytes());
+        chunk.extend_from_slice(make);
+        chunk.extend_from_slice(model);
+        chunk.extend_from_slice(taken);
+        chunk
+    }
+
+    #[test]
+    fn reads_dimensions_and_exif_of_jpeg() {
+        let img = image::RgbImage::from_pixel(64, 48, image::Rgb([200, 100, 50]));
+        let mut jpeg = Vec::new();
+        img.write_to(&mut Cursor::new(&mut jpeg), image::ImageFormat::Jpeg)
+            .unwrap();
+        let exif = exif_chunk();
+        let mut app1 = vec![0xFF, 0xE1];
+        app1.extend_from_slice(&(exif.len() as u16 + 8).to_be_bytes());
+        app1.extend_from_slice(b"Exif\0\0");
+        app1.extend_from_slice(&exif);
+        jpeg.splice(2..2, app1);
+
+        let info = image_info(ImageReader::new(Cursor::new(jpeg))).unwrap();
+
+        assert_eq!((64, 48), (info.width, info.height));
+        assert_eq!("jpeg", info.format);
+        assert_eq!("Rgb8", info.color_type);
+        assert_eq!(Some("Fuji X100V".to_string()), info.camera);
+        let taken_at = NaiveDateTime::parse_from_str("2024-05-01 10:30:00", "%Y-%m-%d %H:%M:%S");
+        assert_eq!(taken_at.ok(), info.taken_at);
+    }
+
+    #[test]
+    fn ignores_broken_exif() {
+        let exif = exif_chunk();
+        // Offsets that point past the end are ignored, rather than read.
+        let truncated = Exif::new(&exif[..40]).unwrap();
+        assert_eq!(None, truncated.taken_at());
+        assert!(Exif::new(b"not exif").is_none());
+        assert!(image_info(ImageReader::new(Cursor::new(b"plain text".to_vec()))).is_none());
+    }
+}
@@ -0,0 +1,149 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::WebError;
+
+use super::Opt;
+
+/// Maximum size of uploads of a certain type, given as `<pattern>=<size>`. The pattern is a content
+/// type such as `image/png`, a wildcard such as `image/*`, or a file extension such as `mp4`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TypeLimit {
+    pattern: String,
+    size: String,
+    bytes: usize,
+}
+
+impl TypeLimit {
+    fn matches(&self, extension: Option<&str>, content_type: &str) -> bool {
+        match self.pattern.split_once('/') {
+            Some((kind, "*")) => content_type
+                .split_once('/')
+                .is_some_and(|(content_kind, _)| content_kind.eq_ignore_ascii_case(kind)),
+            Some(_) => content_type.eq_ignore_ascii_case(&self.pattern),
+            None => {
+                extension.is_some_and(|extension| extension.eq_ignore_ascii_case(&self.pattern))
+            }
+        }
+    }
+}
+
+impl fmt::Display for TypeLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} for {}", self.size, self.pattern)
+    }
+}
+
+impl FromStr for TypeLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, size) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <pattern>=<size>, got {}", s))?;
+        let pattern = pattern.trim().trim_start_matches('.');
+        if pattern.is_empty() {
+            return Err(format!("missing pattern in {}", s));
+        }
+        Ok(TypeLimit {
+            pattern: pattern.to_ascii_lowercase(),
+            size: size.trim().to_string(),
+            bytes: parse_size(size.trim())?,
+        })
+    }
+}
+
+/**
+ * Parses a number of bytes, optionally followed by one of the binary units K, M, G, or T.
+ */
+fn parse_size(size: &str) -> Result<usize, String> {
+    let (number, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => size.split_at(i),
+        None => (size, ""),
+    };
+    let shift = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KIB" => 10,
+        "M" | "MIB" => 20,
+        "G" | "GIB" => 30,
+        "T" | "TIB" => 40,
+        _ => return Err(format!("unknown unit {} in size {}", unit, size)),
+    };
+    let number: usize = number
+        .parse()
+        .map_err(|_| format!("invalid size {}", size))?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size {} is too large", size))
+}
+
+/**
+ * Largest size of a single upload, and the type limit that set it, if any.
+ */
+pub struct SizeLimit<'a> {
+    pub bytes: usize,
+    type_limit: Option<&'a TypeLimit>,
+}
+
+impl SizeLimit<'_> {
+    /// The error to return when an upload exceeds this limit.
+    pub fn exceeded(&self) -> WebError {
+        match self.type_limit {
+            Some(type_limit) => WebError::TooLarge(type_limit.to_string()),
+            None => WebError::PayloadTooLarge,
+        }
+    }
+}
+
+/**
+ * Returns the size limit of an upload with the given extension and content type, which is the first
+ * matching type limit, but never more than the maximum upload size.
+ */
+pub fn size_limit<'a>(extension: Option<&str>, content_type: &str, opt: &'a Opt) -> SizeLimit<'a> {
+    match opt
+        .type_limits
+        .iter()
+        .find(|type_limit| type_limit.matches(extension, content_type))
+    {
+        Some(type_limit) if type_limit.bytes < opt.max_upload_size => SizeLimit {
+            bytes: type_limit.bytes,
+            type_limit: Some(type_limit),
+        },
+        _ => SizeLimit {
+            bytes: opt.max_upload_size,
+            type_limit: None,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(Ok(512), parse_size("512"));
+        assert_eq!(Ok(20 << 20), parse_size("20M"));
+        assert_eq!(Ok(2 << 30), parse_size("2GiB"));
+        assert!(parse_size("20X").is_err());
+        assert!(parse_size("M").is_err());
+    }
+
+    #[test]
+    fn matches_types_and_extensions() {
+        let images: TypeLimit = "image/*=20M".parse().unwrap();
+        assert!(images.matches(Some("png"), "image/png"));
+        assert!(!images.matches(Some("mp4"), "video/mp4"));
+
+        let png: TypeLimit = "image/png=1M".parse().unwrap();
+        assert!(png.matches(None, "IMAGE/PNG"));
+        assert!(!png.matches(None, "image/jpeg"));
+
+        let mp4: TypeLimit = ".MP4=2G".parse().unwrap();
+        assert!(mp4.matches(Some("mp4"), "application/octet-stream"));
+        assert!(!mp4.matches(None, "video/mp4"));
+
+        assert!("image/*".parse::<TypeLimit>().is_err());
+        assert!("=20M".parse::<TypeLimit>().is_err());
+    }
+}
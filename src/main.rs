@@ -1,39 +1,102 @@
 use askama_axum::Template;
 use axum::{
+    body::Body,
     extract::{DefaultBodyLimit, Request, State},
-    handler::HandlerWithoutStateExt,
+    handler::{Handler, HandlerWithoutStateExt},
     http::{
-        header::{CONTENT_TYPE, WWW_AUTHENTICATE},
-        StatusCode,
+        header::{
+            ACCEPT, ACCEPT_RANGES, AUTHORIZATION, CONTENT_LENGTH, CONTENT_LOCATION, CONTENT_RANGE,
+            CONTENT_TYPE, IF_NONE_MATCH, LOCATION, RANGE, RETRY_AFTER, VARY, WWW_AUTHENTICATE,
+        },
+        Extensions, HeaderMap, HeaderValue, Method, StatusCode, Uri, Version,
     },
     middleware,
     response::{IntoResponse, Response},
-    routing::{get, post},
+    routing::{get, patch, post, put},
     Router,
 };
 use axum_extra::{
-    headers::{authorization::Basic, Authorization},
+    headers::{
+        authorization::{Basic, Bearer},
+        Authorization, HeaderMapExt, IfModifiedSince, LastModified,
+    },
     TypedHeader,
 };
-use clap::Parser;
 use image::ImageError;
+use percent_encoding::percent_decode_str;
+use sha2::{Digest, Sha256};
+use std::convert::Infallible;
+use std::future::IntoFuture;
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime};
+use subtle::ConstantTimeEq;
+use tokio::sync::Notify;
 use tokio::task::JoinError;
+use tower::{util::BoxCloneService, ServiceBuilder, ServiceExt};
 use tower_http::{
+    compression::{predicate::SizeAbove, CompressionLayer, Predicate},
+    cors::{AllowOrigin, CorsLayer},
     services::ServeDir,
-    trace::{DefaultMakeSpan, TraceLayer},
+    trace::TraceLayer,
 };
+use tracing::Span;
 use tracing_subscriber::EnvFilter;
 
+use client_ip::ClientIp;
+use helpers::public_path;
+
+mod admin;
+mod cleanup;
+mod client_ip;
+mod config;
+mod csrf;
 mod delete;
+mod diskspace;
+mod downloads;
+mod export;
+mod fetch;
 mod helpers;
+mod highlight;
+mod import;
+mod info;
+mod limits;
+mod manifest;
+mod meta;
+mod metrics;
+mod openapi;
+mod paste;
+mod protect;
+mod qr;
+mod rate_limit;
 mod recent;
+mod sniff;
+mod stats;
+mod storage;
+mod strip;
+mod svg;
 mod thumbnail;
+mod tls;
+mod trash;
+mod tus;
 mod upload;
+mod users;
+mod webdav;
+mod webhook;
 
 #[derive(clap::Parser, Clone, Debug)]
 #[command(name = "i", about = "i is a simple file uploader web service.")]
 pub struct Opt {
+    /// TOML file with options, named like the long command line options. Command line options and
+    /// environment variables take precedence over the file.
+    #[arg(short = 'c', long, env)]
+    config: Option<PathBuf>,
+
+    /// Address to listen on, either IPv4 or IPv6, such as 127.0.0.1 or ::1.
+    #[arg(short = 'H', long, default_value = "0.0.0.0", env, value_parser = parse_host)]
+    host: IpAddr,
+
     /// Port to listen on.
     #[arg(short = 'P', long, default_value = "8088", env)]
     port: u16,
@@ -42,10 +105,53 @@ pub struct Opt {
     #[arg(short, long, env, default_value = "./tmp")]
     base_dir: String,
 
-    /// The complete server URL base which should be used when generating links.
-    #[arg(short, long, env, default_value = "http://localhost:8088")]
+    /// Where to store uploaded files. S3 requires building with the "s3" feature.
+    #[arg(long, env, value_enum, default_value = "local")]
+    storage: storage::StorageKind,
+
+    /// Bucket to store files in, when using S3 storage
+    #[arg(long, env, required_if_eq("storage", "s3"))]
+    s3_bucket: Option<String>,
+
+    /// Region of the S3 bucket (default: from the AWS configuration)
+    #[arg(long, env)]
+    s3_region: Option<String>,
+
+    /// Endpoint of an S3 compatible service, if not AWS
+    #[arg(long, env)]
+    s3_endpoint: Option<String>,
+
+    /// Storage backend selected on startup.
+    #[arg(skip)]
+    storage_backend: storage::Backend,
+
+    /// The complete server URL base which should be used when generating links. Defaults to
+    /// https://localhost:8088 if TLS is enabled.
+    #[arg(short, long, env, default_value = DEFAULT_SERVER_URL)]
     server_url: String,
 
+    /// Path that every route is served below, such as /i, when a reverse proxy passes on requests
+    /// without removing the prefix. Also added to the links below the server url
+    #[arg(long, env, default_value = "/", value_parser = parse_base_path)]
+    base_path: String,
+
+    /// Path that uploaded files are served below, such as /f, so that files can have the same name
+    /// as a route, like recent. Files are served at the root by default
+    #[arg(long, env, default_value = "/", value_parser = parse_base_path)]
+    files_prefix: String,
+
+    /// Certificate chain in PEM format, to serve over HTTPS. Requires --tls-key.
+    #[arg(long, env, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// Private key in PEM format, to serve over HTTPS. Requires --tls-cert.
+    #[arg(long, env, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Also listen for plain HTTP on this port, redirecting every request to the server url
+    #[arg(long, env, requires = "tls_cert")]
+    https_redirect_port: Option<u16>,
+
     /// Username for basic auth, if you want to require authentication to upload files
     #[arg(short = 'u', long, env)]
     auth_user: Option<String>,
@@ -54,20 +160,344 @@ pub struct Opt {
     #[arg(short = 'p', long, env)]
     auth_pass: Option<String>,
 
+    /// Bcrypt hash of the password for basic auth, as an alternative to giving it in plaintext
+    #[arg(long, env, conflicts_with = "auth_pass")]
+    auth_pass_hash: Option<String>,
+
+    /// Maximum number of uploads per minute from a single IP address. Unlimited if not set.
+    #[arg(long, env)]
+    rate_limit: Option<u32>,
+
+    /// Number of uploads a single IP address may make in a burst (default: the rate limit)
+    #[arg(long, env)]
+    rate_limit_burst: Option<u32>,
+
+    /// Comma-separated addresses or networks of reverse proxies, such as "10.0.0.0/8,::1", whose
+    /// X-Forwarded-For and Forwarded headers are trusted to tell the address of the client
+    #[arg(long, env, value_delimiter = ',', value_parser = client_ip::parse_trusted_proxy)]
+    trusted_proxies: Vec<ipnet::IpNet>,
+
+    /// Upload rate limits of each client.
+    #[arg(skip)]
+    rate_limiter: Arc<rate_limit::RateLimiter>,
+
+    /// Wrong view passwords given by each client.
+    #[arg(skip)]
+    password_limiter: Arc<rate_limit::RateLimiter>,
+
+    /// Background jobs started through the admin endpoints.
+    #[arg(skip)]
+    jobs: Arc<admin::Jobs>,
+
+    /// How long to reuse the storage stats of /admin/stats before computing them again, in seconds
+    #[arg(long, env, default_value_t = 60)]
+    stats_cache_secs: u64,
+
+    /// Recently computed storage stats of each user.
+    #[arg(skip)]
+    stats_cache: Arc<stats::StatsCache>,
+
+    /// How long to reuse the listing of uploads of the recent pages, in seconds. Uploads and
+    /// deletes are seen immediately, other changes to the storage after at most this long
+    #[arg(long, env, default_value_t = 60)]
+    recent_cache_ttl: u64,
+
+    /// Recent listings of the uploads of each user.
+    #[arg(skip)]
+    recent_cache: Arc<recent::RecentCache>,
+
+    /// Comma-separated list of API tokens, which can be used with bearer authentication
+    #[arg(long, env, value_delimiter = ',')]
+    api_tokens: Vec<String>,
+
+    /// JSON file mapping usernames to bcrypt password hashes. Each user gets their own directory.
+    #[arg(long, env, conflicts_with_all = ["auth_user", "auth_pass", "auth_pass_hash"])]
+    users_file: Option<PathBuf>,
+
+    /// Users loaded from the users file.
+    #[arg(skip)]
+    users: users::Users,
+
     /// Number of entries to show in the list of recent uploads
     #[arg(short = 'r', long, env, default_value_t = 15)]
     recents: usize,
 
-    /// Thumbnail size
-    #[arg(short, long, env, default_value_t = 150)]
-    thumbnail_size: u32,
+    /// Require credentials for uploading, but not for viewing files and the recent uploads, unless
+    /// --auth-view is also given
+    #[arg(long, env)]
+    auth_upload: bool,
+
+    /// Require credentials for viewing files, their thumbnails, and the recent uploads, but not for
+    /// uploading, unless --auth-upload is also given
+    #[arg(long, env)]
+    auth_view: bool,
+
+    /// Don't serve the list of recent uploads at /recent, /recent.json, and /recent.atom, so that
+    /// nobody can browse the uploaded files
+    #[arg(long, env)]
+    disable_recent: bool,
+
+    /// Manage files with WebDAV clients: PUT and DELETE of a file, and PROPFIND to list the files.
+    /// Always requires credentials, if any are configured
+    #[arg(long, env)]
+    enable_webdav: bool,
+
+    /// Comma-separated list of thumbnail sizes, e.g. "150,320,640"
+    #[arg(
+        short,
+        long,
+        env,
+        value_delimiter = ',',
+        default_value = "150",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    thumbnail_sizes: Vec<u32>,
+
+    /// Single thumbnail size, replaced by --thumbnail-sizes
+    #[arg(
+        long,
+        env,
+        hide = true,
+        conflicts_with = "thumbnail_sizes",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    thumbnail_size: Option<u32>,
+
+    /// Image format of generated thumbnails
+    #[arg(long, env, value_enum, default_value = "webp")]
+    thumbnail_format: thumbnail::ThumbnailFormat,
+
+    /// Whether thumbnails are cropped to a square (fill), or keep the aspect ratio of the image (fit)
+    #[arg(long, env, value_enum, default_value = "fill")]
+    thumbnail_mode: thumbnail::ThumbnailMode,
+
+    /// Filter used to scale images down to thumbnails, trading speed (nearest) for quality (lanczos3)
+    #[arg(long, env, value_enum, default_value = "triangle")]
+    thumbnail_filter: thumbnail::ThumbnailFilter,
+
+    /// Quality (1-100) of thumbnails with --thumbnail-format jpeg
+    #[arg(long, env, default_value_t = 75, value_parser = clap::value_parser!(u8).range(1..=100))]
+    thumbnail_jpeg_quality: u8,
+
+    /// Generate thumbnails right after upload, instead of when they are first requested
+    #[arg(long, env)]
+    eager_thumbnails: bool,
+
+    /// Number of thumbnails generated at the same time with --eager-thumbnails
+    #[arg(
+        long,
+        env,
+        default_value_t = 2,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    thumbnail_workers: usize,
+
+    /// What to do when more uploads wait for --eager-thumbnails than the queue holds: skip them,
+    /// so their thumbnails are generated when first requested, or wait a few seconds for room
+    #[arg(long, env, value_enum, default_value = "skip")]
+    thumbnail_queue_full: thumbnail::QueueFull,
+
+    /// Queue of files to generate thumbnails of, with --eager-thumbnails.
+    #[arg(skip)]
+    thumbnail_queue: thumbnail::ThumbnailQueue,
+
+    /// Maximum number of frames kept in thumbnails of animated GIFs
+    #[arg(
+        long,
+        env,
+        default_value_t = 50,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    max_thumbnail_frames: usize,
+
+    /// Images with more pixels than this (width times height) get the placeholder instead of a
+    /// thumbnail, since decoding them takes too much memory
+    #[arg(long, env, default_value_t = 100_000_000)]
+    max_thumbnail_pixels: u64,
+
+    /// Remove EXIF and other metadata, such as GPS coordinates, from uploaded JPEG, PNG, and WebP
+    /// images by re-encoding them
+    #[arg(long, env)]
+    strip_metadata: bool,
+
+    /// Remove scripts, event handlers, and external references from uploaded SVG images, which
+    /// could otherwise run in the browsers of everyone who views them
+    #[arg(long, env)]
+    sanitize_svg: bool,
+
+    /// What to do with an SVG image that has scripts or external references, with --sanitize-svg:
+    /// remove them, or reject the upload
+    #[arg(long, env, value_enum, default_value_t = svg::SvgPolicy::Sanitize)]
+    svg_policy: svg::SvgPolicy,
+
+    /// Quality (1-100) used when re-encoding JPEG images
+    #[arg(long, env, default_value_t = 90, value_parser = clap::value_parser!(u8).range(1..=100))]
+    jpeg_quality: u8,
+
+    /// Path to ffmpeg, used to generate thumbnails of videos. Videos get no thumbnail if not set.
+    #[arg(long, env)]
+    ffmpeg_path: Option<String>,
+
+    /// Maximum time in seconds to wait for ffmpeg when generating a video thumbnail
+    #[arg(long, env, default_value_t = 10)]
+    ffmpeg_timeout_secs: u64,
+
+    /// Allow uploading from urls that resolve to private, loopback, or link-local addresses
+    #[arg(long, env)]
+    allow_private_urls: bool,
 
     /// Maximum upload size in bytes (default 2 GiB)
     #[arg(short, long, env, default_value_t = 2_147_483_648)]
     max_upload_size: usize,
+
+    /// Maximum size in bytes of a multipart field other than a file, such as the upload options
+    #[arg(long, env, default_value_t = 64 * 1024)]
+    max_field_size: usize,
+
+    /// Maximum number of fields in a multipart upload, including the files
+    #[arg(long, env, default_value_t = 100)]
+    max_fields: usize,
+
+    /// Comma-separated maximum upload sizes by content type or extension, such as
+    /// "image/*=20M,video/*=2G,pdf=50M". The first matching limit applies, and no limit can
+    /// exceed --max-upload-size
+    #[arg(long, env, value_delimiter = ',')]
+    type_limits: Vec<limits::TypeLimit>,
+
+    /// Reject uploads when the free space of the disk they are received on is below this many
+    /// bytes, also aborting uploads in progress. 0 disables the check
+    #[arg(long, env, default_value_t = 0)]
+    min_free_bytes: u64,
+
+    /// Reject uploads whose content, as sniffed from its first bytes, doesn't match their
+    /// extension or content type. Given without a value, the lenient check is used
+    #[arg(
+        long,
+        env,
+        value_enum,
+        default_value_t = sniff::ContentTypeCheck::Off,
+        num_args = 0..=1,
+        default_missing_value = "lenient"
+    )]
+    enforce_content_type: sniff::ContentTypeCheck,
+
+    /// How to name uploaded files that aren't given a filename in the upload options
+    #[arg(long, env, value_enum, default_value_t = upload::Naming::Random)]
+    naming: upload::Naming,
+
+    /// Number of characters in random filenames, or of the hash with --naming content-hash, not
+    /// counting the extension
+    #[arg(
+        long,
+        env,
+        default_value_t = 8,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..=64)
+    )]
+    slug_length: usize,
+
+    /// What to do when a file is uploaded under its original filename, and a file of that name
+    /// already exists: add a number to the name, reject the upload, or overwrite the file
+    #[arg(long, env, value_enum, default_value_t = upload::OnConflict::Rename)]
+    on_conflict: upload::OnConflict,
+
+    /// Longest filename in bytes that files are stored under. Longer original filenames are
+    /// shortened, keeping the extension, and longer requested filenames are rejected
+    #[arg(
+        long,
+        env,
+        default_value_t = 200,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(16..=255)
+    )]
+    max_filename_length: usize,
+
+    /// Characters of random filenames. Use lowercase-hex or base32-lowercase on case-insensitive
+    /// filesystems, with a longer --slug-length, since they have fewer characters
+    #[arg(long, env, value_enum, default_value_t = upload::SlugAlphabet::Alphanumeric)]
+    slug_alphabet: upload::SlugAlphabet,
+
+    /// Random generator of random filenames, which tests seed to get the same names every time.
+    #[arg(skip)]
+    slug_rng: upload::SlugRng,
+
+    /// Comma-separated list of file extensions that may not be uploaded, e.g. "exe,php,svg"
+    #[arg(long, env, value_delimiter = ',')]
+    blocked_extensions: Vec<String>,
+
+    /// Delete uploaded files older than this many days. Files are kept forever if not set,
+    /// unless an expiry was given when uploading.
+    #[arg(long, env)]
+    max_age_days: Option<u64>,
+
+    /// How often to look for expired files, in seconds
+    #[arg(long, env, default_value_t = 3600)]
+    cleanup_interval_secs: u64,
+
+    /// How long to wait for uploads in progress to finish when shutting down, in seconds
+    #[arg(long, env, default_value_t = 30)]
+    shutdown_timeout_secs: u64,
+
+    /// Allow deleting files by filename instead of by their deletion token
+    #[arg(long, env)]
+    insecure_delete: bool,
+
+    /// Move deleted files to a trash directory, from which they can be restored, instead of
+    /// deleting them right away
+    #[arg(long, env)]
+    trash: bool,
+
+    /// Permanently delete files that have been in the trash for this many days
+    #[arg(long, env, default_value_t = 30)]
+    trash_max_age_days: u64,
+
+    /// URL to post a JSON notification to whenever a file is uploaded or deleted, such as a Slack
+    /// or Discord webhook
+    #[arg(long, env)]
+    webhook_url: Option<url::Url>,
+
+    /// Number of times to try delivering a webhook notification before giving up
+    #[arg(
+        long,
+        env,
+        default_value_t = 3,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    webhook_attempts: u32,
+
+    /// Queue of notifications to deliver to --webhook-url.
+    #[arg(skip)]
+    webhooks: webhook::WebhookQueue,
+
+    /// Format of the log, either readable text or JSON lines for log aggregation
+    #[arg(long, env, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Expose Prometheus metrics at /metrics (without authentication)
+    #[arg(long, env)]
+    enable_metrics: bool,
+
+    /// Compress text responses, such as pages, stylesheets, JSON, and text files, with gzip or
+    /// brotli when the client accepts it
+    #[arg(long, env)]
+    compression: bool,
+
+    /// Origins allowed to make cross-origin requests, such as https://app.example.com, or * for
+    /// any origin. No CORS headers are sent if not set.
+    #[arg(long, env, value_delimiter = ',', value_parser = parse_cors_origin)]
+    cors_allow_origin: Vec<String>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum LogFormat {
+    #[default]
+    Text,
+    Json,
 }
 
+const DEFAULT_SERVER_URL: &str = "http://localhost:8088";
+
 pub const THUMBNAIL_SUBDIR: &str = "thumbnails";
+pub const META_SUBDIR: &str = "meta";
+pub const TRASH_SUBDIR: &str = ".trash";
 
 #[derive(Debug, thiserror::Error)]
 pub enum WebError {
@@ -83,13 +513,114 @@ pub enum WebError {
     InvalidUrl(#[from] url::ParseError),
     #[error("bad request")]
     BadRequest,
+    #[error("no file field found in the upload")]
+    MissingFile,
+    #[error("malformed multipart body: {0}")]
+    MalformedMultipart(String),
+    #[error("invalid upload options: {0}")]
+    InvalidOptions(String),
+    #[error("upload was interrupted before it was complete")]
+    IncompleteBody,
+    #[error("filename is longer than {0} bytes")]
+    FilenameTooLong(usize),
+    #[error("filename must be a plain name, without a path")]
+    PathTraversal,
+    #[error("invalid archive: {0}")]
+    InvalidArchive(String),
+    #[error("multipart field is larger than {0} bytes")]
+    FieldTooLarge(usize),
+    #[error("multipart body has more than {0} fields")]
+    TooManyFields(usize),
     #[error("image error")]
     InvalidImage(#[from] ImageError),
+    #[error("uploading files with extension .{0} is not allowed")]
+    BlockedExtension(String),
+    #[error("too many requests, try again in {0} seconds")]
+    TooManyRequests(u64),
+    #[error("storage error: {0}")]
+    StorageError(String),
+    #[error("a file named {0} already exists")]
+    Conflict(String),
+    #[error("url not allowed: {0}")]
+    UrlNotAllowed(String),
+    #[error("could not fetch url: {0}")]
+    FetchFailed(String),
+    #[error("upload is larger than the maximum upload size")]
+    PayloadTooLarge,
+    #[error("upload is larger than the limit of {0}")]
+    TooLarge(String),
+    #[error("content of type {0} does not match {1}")]
+    ContentMismatch(String, String),
+    #[error("upload offset does not match, expected {0}")]
+    OffsetMismatch(u64),
+    #[error("unsupported tus version, expected {0}")]
+    UnsupportedTusVersion(&'static str),
+    #[error("unsupported content type, expected {0}")]
+    UnsupportedContentType(&'static str),
+    #[error("forbidden")]
+    Forbidden,
+    #[error("could not find a free filename")]
+    NoFreeFilename,
+    #[error("not enough free storage space")]
+    InsufficientStorage,
+    #[error("SVG image has scripts or external references, or can't be parsed")]
+    UnsafeSvg,
+    #[error("metadata can't be removed from an image that can't be decoded")]
+    UnstrippableImage,
+    #[error("template error: {0}")]
+    TemplateError(#[from] askama::Error),
+}
+
+impl WebError {
+    /**
+     * A stable name of the error, for clients that want to tell errors apart.
+     */
+    fn code(&self) -> &'static str {
+        match self {
+            WebError::AuthenticationFailed => "AUTHENTICATION_FAILED",
+            WebError::EmptyUpload => "EMPTY_UPLOAD",
+            WebError::IoError(_) => "IO_ERROR",
+            WebError::ThreadPoolError(_) | WebError::TemplateError(_) => "INTERNAL_ERROR",
+            WebError::InvalidUrl(_) => "INVALID_URL",
+            WebError::BadRequest => "BAD_REQUEST",
+            WebError::MissingFile => "MISSING_FILE",
+            WebError::MalformedMultipart(_) => "MALFORMED_MULTIPART",
+            WebError::InvalidOptions(_) => "INVALID_OPTIONS",
+            WebError::IncompleteBody => "INCOMPLETE_BODY",
+            WebError::FilenameTooLong(_) => "FILENAME_TOO_LONG",
+            WebError::PathTraversal => "PATH_TRAVERSAL",
+            WebError::InvalidArchive(_) => "INVALID_ARCHIVE",
+            WebError::FieldTooLarge(_) => "FIELD_TOO_LARGE",
+            WebError::TooManyFields(_) => "TOO_MANY_FIELDS",
+            WebError::InvalidImage(_) => "INVALID_IMAGE",
+            WebError::BlockedExtension(_) => "BLOCKED_EXTENSION",
+            WebError::TooManyRequests(_) => "TOO_MANY_REQUESTS",
+            WebError::StorageError(_) => "STORAGE_ERROR",
+            WebError::Conflict(_) => "CONFLICT",
+            WebError::UrlNotAllowed(_) => "URL_NOT_ALLOWED",
+            WebError::FetchFailed(_) => "FETCH_FAILED",
+            WebError::PayloadTooLarge | WebError::TooLarge(_) => "PAYLOAD_TOO_LARGE",
+            WebError::ContentMismatch(..) => "CONTENT_MISMATCH",
+            WebError::OffsetMismatch(_) => "OFFSET_MISMATCH",
+            WebError::UnsupportedTusVersion(_) => "UNSUPPORTED_TUS_VERSION",
+            WebError::UnsupportedContentType(_) => "UNSUPPORTED_CONTENT_TYPE",
+            WebError::Forbidden => "FORBIDDEN",
+            WebError::NoFreeFilename => "NO_FREE_FILENAME",
+            WebError::InsufficientStorage => "INSUFFICIENT_STORAGE",
+            WebError::UnsafeSvg => "UNSAFE_SVG",
+            WebError::UnstrippableImage => "UNSTRIPPABLE_IMAGE",
+        }
+    }
 }
 
+/// Code of the error a response was made from, for `json_errors`.
+#[derive(Clone, Copy, Debug)]
+struct ErrorCode(&'static str);
+
 impl axum::response::IntoResponse for WebError {
     fn into_response(self) -> Response {
-        match self {
+        let code = ErrorCode(self.code());
+        let mut response = match self {
             WebError::AuthenticationFailed => (
                 StatusCode::UNAUTHORIZED,
                 [(WWW_AUTHENTICATE, "Basic realm=\"i: file upload\"")],
@@ -107,31 +638,216 @@ impl axum::response::IntoResponse for WebError {
                 (StatusCode::INTERNAL_SERVER_ERROR, "invalid url").into_response()
             }
             WebError::BadRequest => (StatusCode::BAD_REQUEST, "bad request").into_response(),
+            WebError::MissingFile
+            | WebError::MalformedMultipart(_)
+            | WebError::InvalidOptions(_)
+            | WebError::IncompleteBody
+            | WebError::FilenameTooLong(_)
+            | WebError::PathTraversal
+            | WebError::InvalidArchive(_)
+            | WebError::FieldTooLarge(_)
+            | WebError::TooManyFields(_) => {
+                (StatusCode::BAD_REQUEST, self.to_string()).into_response()
+            }
             WebError::InvalidImage(_) => (StatusCode::BAD_REQUEST, "invalid image").into_response(),
-        }
+            WebError::BlockedExtension(_) => {
+                (StatusCode::UNSUPPORTED_MEDIA_TYPE, self.to_string()).into_response()
+            }
+            WebError::TooManyRequests(retry_after) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, retry_after.to_string())],
+                self.to_string(),
+            )
+                .into_response(),
+            WebError::StorageError(_) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "storage error").into_response()
+            }
+            WebError::Conflict(_) => (StatusCode::CONFLICT, self.to_string()).into_response(),
+            WebError::UrlNotAllowed(_) => {
+                (StatusCode::BAD_REQUEST, self.to_string()).into_response()
+            }
+            WebError::FetchFailed(_) => (StatusCode::BAD_GATEWAY, self.to_string()).into_response(),
+            WebError::PayloadTooLarge | WebError::TooLarge(_) => {
+                (StatusCode::PAYLOAD_TOO_LARGE, self.to_string()).into_response()
+            }
+            WebError::OffsetMismatch(_) => (StatusCode::CONFLICT, self.to_string()).into_response(),
+            WebError::UnsupportedTusVersion(version) => (
+                StatusCode::PRECONDITION_FAILED,
+                [(tus::TUS_VERSION_HEADER.clone(), version)],
+                self.to_string(),
+            )
+                .into_response(),
+            WebError::UnsupportedContentType(_)
+            | WebError::ContentMismatch(..)
+            | WebError::UnsafeSvg
+            | WebError::UnstrippableImage => {
+                (StatusCode::UNSUPPORTED_MEDIA_TYPE, self.to_string()).into_response()
+            }
+            WebError::Forbidden => (StatusCode::FORBIDDEN, "forbidden").into_response(),
+            WebError::NoFreeFilename => {
+                (StatusCode::INTERNAL_SERVER_ERROR, self.to_string()).into_response()
+            }
+            WebError::InsufficientStorage => {
+                (StatusCode::INSUFFICIENT_STORAGE, self.to_string()).into_response()
+            }
+            WebError::TemplateError(_) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "internal error").into_response()
+            }
+        };
+        response.extensions_mut().insert(code);
+        response
+    }
+}
+
+/**
+ * Turns errors into JSON, with the message in `error` and the code of the error in `code`, for
+ * clients that accept JSON. Other clients get the message as plain text.
+ */
+async fn json_errors(request: Request, next: middleware::Next) -> Response {
+    let wants_json = request
+        .headers()
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"));
+    let response = next.run(request).await;
+    let Some(ErrorCode(code)) = response.extensions().get::<ErrorCode>().copied() else {
+        return response;
+    };
+    if !wants_json {
+        return response;
     }
+
+    let (mut parts, body) = response.into_parts();
+    // Error messages are short, anything longer is not an error message.
+    let Ok(message) = axum::body::to_bytes(body, 64 * 1024).await else {
+        return (parts.status, "internal error").into_response();
+    };
+    parts.headers.remove(CONTENT_TYPE);
+    parts.headers.remove(CONTENT_LENGTH);
+    let error = serde_json::json!({
+        "error": String::from_utf8_lossy(&message),
+        "code": code,
+    });
+    (parts, axum::Json(error)).into_response()
 }
 
 #[derive(Template)]
 #[template(path = "notfound.html")]
 struct NotFoundTemplate {}
 
-async fn bulma() -> impl IntoResponse {
-    let placeholder = include_bytes!("../dist/bulma.min.css");
-    ([(CONTENT_TYPE, "text/css")], placeholder)
+/// Bundled files only change with a new version of i.
+const BUNDLED_ETAG: &str = concat!("i-", env!("CARGO_PKG_VERSION"));
+
+/**
+ * Serves a file bundled into the binary, with the version of i as its ETag. As far as clients can
+ * tell, bundled files were last modified when the server started. Clients that already have the
+ * file, as told by If-None-Match or If-Modified-Since, get 304 Not Modified instead.
+ */
+fn bundled_file(
+    request_headers: &HeaderMap,
+    content_type: &'static str,
+    contents: &'static [u8],
+) -> Response {
+    static STARTED: OnceLock<SystemTime> = OnceLock::new();
+    let last_modified = LastModified::from(*STARTED.get_or_init(SystemTime::now));
+    // If-Modified-Since is ignored when If-None-Match is given.
+    let not_modified = !request_headers.contains_key(IF_NONE_MATCH)
+        && request_headers
+            .typed_get::<IfModifiedSince>()
+            .is_some_and(|since| !since.is_modified(last_modified.into()));
+
+    let response = ([(CONTENT_TYPE, content_type)], contents).into_response();
+    let mut response = meta::apply_caching(response, request_headers, Some(BUNDLED_ETAG), false);
+    if not_modified && response.status() == StatusCode::OK {
+        *response.status_mut() = StatusCode::NOT_MODIFIED;
+        *response.body_mut() = Body::empty();
+        response.headers_mut().remove(CONTENT_TYPE);
+    }
+    response.headers_mut().typed_insert(last_modified);
+    response
+}
+
+async fn bulma(headers: HeaderMap) -> Response {
+    let bulma = include_bytes!("../dist/bulma.min.css");
+    bundled_file(&headers, "text/css", bulma)
+}
+
+async fn copy_script(headers: HeaderMap) -> Response {
+    let script = include_bytes!("../dist/copy.js");
+    bundled_file(&headers, "text/javascript", script)
+}
+
+async fn gallery_script(headers: HeaderMap) -> Response {
+    let script = include_bytes!("../dist/gallery.js");
+    bundled_file(&headers, "text/javascript", script)
+}
+
+async fn upload_script(headers: HeaderMap) -> Response {
+    let script = include_bytes!("../dist/upload.js");
+    bundled_file(&headers, "text/javascript", script)
+}
+
+#[derive(Template)]
+#[template(path = "index.html")]
+struct IndexTemplate {
+    bulma_url: String,
+    upload_url: String,
+    /// Link to the recent uploads, unless they are disabled.
+    recent_url: Option<String>,
+    script_url: String,
+}
+
+/**
+ * Shows an upload form to browsers, and a short greeting to everything else, such as curl.
+ */
+async fn index(State(opt): State<Opt>, headers: HeaderMap) -> Result<Response, WebError> {
+    let wants_html = headers
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"));
+    let mut response = if wants_html {
+        IndexTemplate {
+            bulma_url: public_path("recent/bulma.min.css", &opt)?,
+            upload_url: public_path("", &opt)?,
+            recent_url: match opt.disable_recent {
+                true => None,
+                false => Some(public_path("recent", &opt)?),
+            },
+            script_url: public_path("recent/upload.js", &opt)?,
+        }
+        .into_response()
+    } else {
+        "i API ready!".into_response()
+    };
+    response
+        .headers_mut()
+        .insert(VARY, HeaderValue::from_static("accept"));
+    Ok(response)
 }
 
-async fn index() -> impl IntoResponse {
-    "i API ready!"
+async fn healthz(State(opt): State<Opt>) -> impl IntoResponse {
+    // Check that files can still be stored, by writing and removing a probe file.
+    let storage = storage::backend(&opt);
+    let probe = async {
+        storage.put(".healthz", b"ok".to_vec()).await?;
+        storage.delete(".healthz").await
+    };
+    match probe.await {
+        Ok(()) => (StatusCode::OK, "ok"),
+        Err(e) => {
+            log::warn!("health check failed: {}", e);
+            (StatusCode::SERVICE_UNAVAILABLE, "storage not writable")
+        }
+    }
 }
 
-async fn handle_404() -> impl IntoResponse {
+pub async fn handle_404() -> impl IntoResponse {
     (StatusCode::NOT_FOUND, NotFoundTemplate {})
 }
 
-async fn placeholder_thumbnail() -> impl IntoResponse {
+async fn placeholder_thumbnail(headers: HeaderMap) -> Response {
     let placeholder = include_bytes!("../dist/placeholder.png");
-    ([(CONTENT_TYPE, "image/png")], placeholder)
+    bundled_file(&headers, "image/png", placeholder)
 }
 
 fn get_base_dir(opt: &Opt) -> std::io::Result<PathBuf> {
@@ -142,79 +858,674 @@ fn get_base_dir(opt: &Opt) -> std::io::Result<PathBuf> {
     Ok(path.to_path_buf())
 }
 
-fn get_thumbnail_dir(opt: &Opt) -> std::io::Result<PathBuf> {
-    // Create directory where thumbnails should be uploaded.
-    let path = std::path::Path::new(&opt.base_dir);
-    let path = path.join(THUMBNAIL_SUBDIR);
-    std::fs::create_dir_all(&path)?;
+/**
+ * Serves uploaded files from a storage backend that isn't the local file system. Files are
+ * streamed from the backend, and a single range of them can be requested.
+ */
+async fn serve_from_storage(
+    State(opt): State<Opt>,
+    method: Method,
+    headers: HeaderMap,
+    uri: Uri,
+) -> Result<Response, WebError> {
+    let key = percent_decode_str(uri.path().trim_start_matches('/')).decode_utf8_lossy();
+    let range = headers.get(RANGE).and_then(|v| v.to_str().ok());
+    let Some(object) = storage::backend(&opt).get_stream(&key, range).await? else {
+        return Ok(handle_404().await.into_response());
+    };
+    let content_type = mime_guess::from_path(&*key).first_or_octet_stream();
+    let status = match object.content_range {
+        Some(_) => StatusCode::PARTIAL_CONTENT,
+        None => StatusCode::OK,
+    };
+    // The body of a HEAD request is dropped unread.
+    let body = match method {
+        Method::HEAD => Body::empty(),
+        _ => object.body,
+    };
+    let mut response = (
+        status,
+        [
+            (CONTENT_TYPE, content_type.to_string()),
+            (CONTENT_LENGTH, object.length.to_string()),
+            (ACCEPT_RANGES, "bytes".to_string()),
+        ],
+        body,
+    )
+        .into_response();
+    if let Some(range) = object
+        .content_range
+        .and_then(|r| HeaderValue::from_str(&r).ok())
+    {
+        response.headers_mut().insert(CONTENT_RANGE, range);
+    }
+    Ok(response)
+}
 
-    Ok(path)
+/**
+ * Removes the `--base-path` from the path of a request, so that the routes match as if they were
+ * served at the root. Anything outside of the base path is not found.
+ */
+async fn strip_base_path(
+    State(base_path): State<String>,
+    mut request: Request,
+    next: middleware::Next,
+) -> Result<Response, WebError> {
+    let uri = request.uri();
+    let rest = match uri.path().strip_prefix(base_path.as_str()) {
+        Some("") => "/",
+        Some(rest) if rest.starts_with('/') => rest,
+        _ => return Ok(handle_404().await.into_response()),
+    };
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}", rest, query),
+        None => rest.to_string(),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().map_err(|_| WebError::BadRequest)?);
+    *request.uri_mut() = Uri::from_parts(parts).map_err(|_| WebError::BadRequest)?;
+    Ok(next.run(request).await)
 }
 
 async fn auth_validator(
     State(opt): State<Opt>,
-    creds: Option<TypedHeader<Authorization<Basic>>>,
-    request: Request,
+    ClientIp(client_ip): ClientIp,
+    basic: Option<TypedHeader<Authorization<Basic>>>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+    mut request: Request,
     next: middleware::Next,
 ) -> Result<Response, WebError> {
-    if let (Some(euser), Some(epass)) = (opt.auth_user.as_ref(), opt.auth_pass.as_ref()) {
+    // Browsers first ask without credentials, which is not worth logging.
+    let has_credentials = basic.is_some() || bearer.is_some();
+    match authenticate(&opt, basic, bearer).await {
+        Ok(user) => {
+            if let Some(user) = user {
+                request.extensions_mut().insert(user);
+            }
+            Ok(next.run(request).await)
+        }
+        Err(WebError::AuthenticationFailed) => {
+            if has_credentials {
+                let client = client_ip.map_or("unknown address".to_string(), |ip| ip.to_string());
+                log::info!("failed authentication from {}", client);
+            }
+            let mut response = WebError::AuthenticationFailed.into_response();
+            if !opt.api_tokens.is_empty() {
+                // Tell the client that API tokens work too.
+                response.headers_mut().append(
+                    WWW_AUTHENTICATE,
+                    HeaderValue::from_static("Bearer realm=\"i: file upload\""),
+                );
+            }
+            Ok(response)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/**
+ * Checks the credentials of a request, if authentication is required. Returns the authenticated
+ * user if there are multiple users, and they logged in using basic auth.
+ */
+async fn authenticate(
+    opt: &Opt,
+    basic: Option<TypedHeader<Authorization<Basic>>>,
+    bearer: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<Option<users::AuthenticatedUser>, WebError> {
+    // A valid API token is always enough, no matter how basic auth is configured.
+    if let Some(TypedHeader(Authorization(bearer))) = bearer {
+        if api_token_valid(bearer.token(), opt) {
+            return Ok(None);
+        }
+    }
+
+    if opt.users_file.is_some() {
+        // Every user has their own password, and their own directory to store files in.
+        let Some(TypedHeader(Authorization(creds))) = basic else {
+            return Err(WebError::AuthenticationFailed);
+        };
+        let hash = opt.users.get(creds.username()).cloned();
+        if !user_password_matches(creds.password(), hash).await? {
+            return Err(WebError::AuthenticationFailed);
+        }
+        Ok(Some(users::AuthenticatedUser(creds.username().to_string())))
+    } else if let (Some(euser), true) = (opt.auth_user.as_ref(), auth_pass_configured(opt)) {
         // Since both user and pass are given, we now require authentication. Check that they match.
-        if let Some(TypedHeader(Authorization(creds))) = creds {
-            match (creds.username(), creds.password()) {
-                (auser, apass) if auser == euser && apass == epass => Ok(next.run(request).await),
-                _ => Err(WebError::AuthenticationFailed),
+        if let Some(TypedHeader(Authorization(creds))) = basic {
+            // Check both, so that a wrong username takes as long as a wrong password.
+            let user_ok = constant_time_eq(creds.username(), euser);
+            let pass_ok = password_matches(creds.password(), opt).await?;
+            if user_ok & pass_ok {
+                Ok(None)
+            } else {
+                Err(WebError::AuthenticationFailed)
             }
         } else {
             Err(WebError::AuthenticationFailed)
         }
+    } else if !opt.api_tokens.is_empty() {
+        // Only API tokens are accepted, and no valid one was given.
+        Err(WebError::AuthenticationFailed)
     } else {
-        Ok(next.run(request).await)
+        Ok(None)
+    }
+}
+
+fn api_token_valid(token: &str, opt: &Opt) -> bool {
+    // Compare against every token, so that the time taken doesn't reveal which one was close.
+    opt.api_tokens.iter().fold(false, |valid, api_token| {
+        valid | constant_time_eq(token, api_token)
+    })
+}
+
+fn auth_pass_configured(opt: &Opt) -> bool {
+    opt.auth_pass.is_some() || opt.auth_pass_hash.is_some()
+}
+
+/**
+ * Compares two strings in constant time. The strings are hashed first, so that neither the
+ * position of the first difference nor the lengths leak through timing.
+ */
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    Sha256::digest(a).ct_eq(&Sha256::digest(b)).into()
+}
+
+async fn password_matches(password: &str, opt: &Opt) -> Result<bool, WebError> {
+    match (opt.auth_pass.as_ref(), opt.auth_pass_hash.clone()) {
+        (Some(epass), _) => Ok(constant_time_eq(password, epass)),
+        (None, Some(hash)) => {
+            // bcrypt is deliberately slow, use threadpool
+            let password = password.to_string();
+            Ok(
+                tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash))
+                    .await?
+                    .unwrap_or(false),
+            )
+        }
+        (None, None) => Ok(false),
     }
 }
 
+async fn user_password_matches(password: &str, hash: Option<String>) -> Result<bool, WebError> {
+    // Verify against a dummy hash for unknown users, so that they take as long as known users.
+    static UNKNOWN_USER_HASH: OnceLock<String> = OnceLock::new();
+    let known_user = hash.is_some();
+    // bcrypt is deliberately slow, use threadpool
+    let password = password.to_string();
+    let matches = tokio::task::spawn_blocking(move || {
+        let hash = hash.unwrap_or_else(|| {
+            UNKNOWN_USER_HASH
+                .get_or_init(|| {
+                    bcrypt::hash("unknown user", bcrypt::DEFAULT_COST).unwrap_or_default()
+                })
+                .clone()
+        });
+        bcrypt::verify(password, &hash)
+    })
+    .await?
+    .unwrap_or(false);
+    Ok(known_user && matches)
+}
+
+/**
+ * Creates the span of a request, with the fields of the access log. Uploads also record the
+ * `filename` and `size` of the stored file.
+ */
+fn request_span(request: &Request, trusted_proxies: &[ipnet::IpNet]) -> Span {
+    let client_ip = client_ip::client_ip(request.extensions(), request.headers(), trusted_proxies);
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        path = %request.uri().path(),
+        client_ip = client_ip.map(tracing::field::display),
+        filename = tracing::field::Empty,
+        size = tracing::field::Empty,
+    )
+}
+
+fn log_response(response: &Response, latency: Duration, _span: &Span) {
+    let bytes = response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse::<u64>().ok());
+    tracing::info!(
+        status = response.status().as_u16(),
+        duration_ms = latency.as_millis() as u64,
+        bytes,
+        "finished request"
+    );
+}
+
 fn router(base_dir: PathBuf, opt: Opt) -> Router {
     let max_upload = opt.max_upload_size;
-    let serve_dir = ServeDir::new(&base_dir).not_found_service(handle_404.into_service());
-    let tracing_layer =
-        TraceLayer::new_for_http().make_span_with(DefaultMakeSpan::new().include_headers(true));
+    let serve_files: BoxCloneService<Request, Response, Infallible> =
+        if opt.storage_backend.is_local() {
+            let serve_dir = ServeDir::new(&base_dir).not_found_service(handle_404.into_service());
+            ServiceExt::<Request>::map_response(serve_dir, IntoResponse::into_response)
+                .boxed_clone()
+        } else {
+            serve_from_storage.with_state(opt.clone()).boxed_clone()
+        };
+    // Without --auth-upload or --auth-view, uploading and listing files require credentials, but
+    // viewing files doesn't. Managing files always does.
+    let auth_upload = opt.auth_upload || !opt.auth_view;
+    let auth_recent = opt.auth_view || !opt.auth_upload;
+    let auth_files = opt.auth_view;
+    let auth = middleware::from_fn_with_state(opt.clone(), auth_validator);
+
+    let serve_files = ServiceBuilder::new()
+        .layer(middleware::from_fn_with_state(
+            opt.clone(),
+            highlight::highlight_code,
+        ))
+        .layer(middleware::from_fn_with_state(
+            opt.clone(),
+            meta::apply_meta,
+        ))
+        .service(serve_files);
+    let serve_files: BoxCloneService<Request, Response, Infallible> = match auth_files {
+        true => ServiceBuilder::new()
+            .layer(auth.clone())
+            .service(serve_files)
+            .boxed_clone(),
+        false => serve_files.boxed_clone(),
+    };
+    let trusted_proxies = opt.trusted_proxies.clone();
+    let tracing_layer = TraceLayer::new_for_http()
+        .make_span_with(move |request: &Request| request_span(request, &trusted_proxies))
+        .on_response(log_response);
+
+    let enable_metrics = opt.enable_metrics;
+    let disable_recent = opt.disable_recent;
 
-    Router::new()
+    let uploads = Router::new()
         .route("/", get(index))
-        .route("/", post(upload::handle_upload))
+        .route(
+            "/",
+            post(upload::handle_upload)
+                .layer(middleware::from_fn_with_state(
+                    opt.clone(),
+                    rate_limit::limit_uploads,
+                ))
+                .layer(middleware::from_fn(metrics::track_upload)),
+        )
+        .route(
+            "/",
+            put(upload::handle_raw_upload)
+                .layer(middleware::from_fn_with_state(
+                    opt.clone(),
+                    rate_limit::limit_uploads,
+                ))
+                .layer(middleware::from_fn(metrics::track_upload)),
+        )
+        .route(
+            "/upload-url",
+            post(fetch::handle_upload_url)
+                .layer(middleware::from_fn_with_state(
+                    opt.clone(),
+                    rate_limit::limit_uploads,
+                ))
+                .layer(middleware::from_fn(metrics::track_upload)),
+        )
+        .route(
+            "/paste",
+            post(paste::handle_paste)
+                .layer(middleware::from_fn_with_state(
+                    opt.clone(),
+                    rate_limit::limit_uploads,
+                ))
+                .layer(middleware::from_fn(metrics::track_upload)),
+        )
+        .route(
+            "/files",
+            post(tus::create)
+                .layer(middleware::from_fn_with_state(
+                    opt.clone(),
+                    rate_limit::limit_uploads,
+                ))
+                .options(tus::options),
+        )
+        .route("/files/:id", patch(tus::append).head(tus::offset))
+        .route("/exists/:name", get(upload::handle_exists));
+    let uploads = match auth_upload {
+        true => uploads.route_layer(auth.clone()),
+        false => uploads,
+    };
+
+    let manage = Router::new()
         .route("/delete", post(delete::handle_delete))
+        .route("/delete-bulk", post(delete::handle_bulk_delete))
+        .route("/restore", post(trash::handle_restore))
+        .route(
+            "/admin/regenerate-thumbnails",
+            post(admin::regenerate_thumbnails),
+        )
+        .route("/admin/jobs/:id", get(admin::job_status))
+        .route("/admin/stats", get(stats::handle_stats))
+        .route("/admin/manifest", get(manifest::handle_manifest))
+        .route("/admin/import", post(import::handle_import))
+        .route("/admin/export.zip", get(export::handle_export))
+        .route("/meta/:name", get(meta::handle_meta))
+        .route("/info/:name", get(info::handle_info))
+        .route_layer(auth.clone());
+
+    let recent = Router::new()
         .route("/recent", get(recent::recent))
-        .route_layer(middleware::from_fn_with_state(opt.clone(), auth_validator)) // every route above covered by auth
+        .route("/gallery", get(recent::gallery))
+        .route("/recent.json", get(recent::recent_json))
+        .route("/recent.atom", get(recent::recent_atom));
+    let recent = match (disable_recent, auth_recent) {
+        (true, _) => Router::new(),
+        (false, true) => recent.route_layer(auth.clone()),
+        (false, false) => recent,
+    };
+
+    let files = Router::new()
+        .route("/thumbnail/*path", get(thumbnail::serve_thumbnail))
+        .route("/qr/*path", get(qr::serve_qr));
+    let files = match auth_files {
+        true => files.route_layer(auth.clone()),
+        false => files,
+    };
+
+    let router = Router::new()
+        .merge(uploads)
+        .merge(manage)
+        .merge(recent)
+        .merge(files)
+        .route("/healthz", get(healthz))
+        .route("/openapi.json", get(openapi::openapi_json))
         .route("/recent/bulma.min.css", get(bulma))
+        .route("/recent/upload.js", get(upload_script))
+        .route("/recent/copy.js", get(copy_script))
+        .route("/recent/gallery.js", get(gallery_script))
         .route("/recent/placeholder.png", get(placeholder_thumbnail))
-        .fallback_service(serve_dir)
-        .with_state(opt)
+        .route("/recent/icons/:name", get(thumbnail::serve_icon));
+
+    let router = if enable_metrics {
+        router.route("/metrics", get(metrics::metrics))
+    } else {
+        router
+    };
+
+    let cors = cors_layer(&opt);
+    let compression = opt.compression;
+    let base_path = opt.base_path.clone();
+    let files_prefix = opt.files_prefix.clone();
+    let router = match files_prefix.is_empty() {
+        true => router.fallback_service(serve_files),
+        false => router
+            .nest_service(&files_prefix, serve_files)
+            .fallback(handle_404),
+    };
+    let webdav = match opt.enable_webdav {
+        true => Some(
+            Router::new()
+                .fallback(webdav::handle)
+                .layer(auth.clone())
+                .with_state(opt.clone()),
+        ),
+        false => None,
+    };
+    let router = router.with_state(opt);
+    let router = match webdav {
+        Some(webdav) => router.layer(middleware::from_fn_with_state(webdav, webdav::dispatch)),
+        None => router,
+    };
+    let router = if base_path.is_empty() {
+        router
+    } else {
+        Router::new()
+            .fallback_service(router)
+            .layer(middleware::from_fn_with_state(base_path, strip_base_path))
+    };
+    let router = router
+        .layer(middleware::from_fn(json_errors))
         .layer(tracing_layer)
-        .layer(DefaultBodyLimit::max(max_upload))
+        .layer(DefaultBodyLimit::max(max_upload));
+    let router = match compression {
+        true => router.layer(
+            CompressionLayer::new().compress_when(SizeAbove::default().and(is_compressible)),
+        ),
+        false => router,
+    };
+
+    // Outermost, so that preflight requests are answered before authentication.
+    match cors {
+        Some(cors) => router.layer(cors),
+        None => router,
+    }
+}
+
+/**
+ * Whether a response is text, which is worth compressing. Images, videos, and archives are already
+ * compressed, and compressing them again would only cost time.
+ */
+fn is_compressible(_: StatusCode, _: Version, headers: &HeaderMap, _: &Extensions) -> bool {
+    let Some(content_type) = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(
+            essence.as_str(),
+            "application/json" | "application/javascript" | "application/xml"
+        )
+}
+
+/**
+ * Parses the address to listen on. IPv6 addresses may be written in brackets, as in URLs.
+ */
+fn parse_host(host: &str) -> Result<IpAddr, String> {
+    let unbracketed = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    unbracketed
+        .parse()
+        .map_err(|_| format!("invalid IP address: {}", host))
+}
+
+/**
+ * Normalizes the base path to start with a slash but not end with one, so that the root is empty.
+ */
+fn parse_base_path(path: &str) -> Result<String, String> {
+    let trimmed = path.trim_matches('/');
+    if trimmed.is_empty() {
+        return Ok(String::new());
+    }
+    if trimmed.contains(['?', '#', ':', '*']) || trimmed.split('/').any(|s| s.is_empty()) {
+        return Err(format!("invalid base path: {}", path));
+    }
+    Ok(format!("/{}", trimmed))
+}
+
+fn parse_cors_origin(origin: &str) -> Result<String, String> {
+    if origin == "*" || (HeaderValue::from_str(origin).is_ok() && url::Url::parse(origin).is_ok()) {
+        Ok(origin.to_string())
+    } else {
+        Err(format!("invalid origin: {}", origin))
+    }
+}
+
+/**
+ * Returns a layer allowing uploads and listing recent uploads from the origins given with
+ * `--cors-allow-origin`, or `None` if there are none.
+ */
+fn cors_layer(opt: &Opt) -> Option<CorsLayer> {
+    if opt.cors_allow_origin.is_empty() {
+        return None;
+    }
+    let origins = if opt.cors_allow_origin.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        // Origins are validated when parsing the options.
+        AllowOrigin::list(
+            opt.cors_allow_origin
+                .iter()
+                .filter_map(|origin| HeaderValue::from_str(origin).ok()),
+        )
+    };
+
+    Some(
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods([Method::GET, Method::POST, Method::PUT])
+            .allow_headers([AUTHORIZATION, CONTENT_TYPE, upload::X_FILENAME.clone()])
+            .expose_headers([LOCATION, CONTENT_LOCATION, RETRY_AFTER]),
+    )
+}
+
+#[cfg(feature = "s3")]
+async fn s3_backend(opt: &Opt) -> Result<storage::Backend, WebError> {
+    // The bucket is required by clap when S3 storage is selected.
+    let bucket = opt
+        .s3_bucket
+        .clone()
+        .ok_or_else(|| WebError::StorageError("no S3 bucket given".to_string()))?;
+    let s3 = storage::S3Storage::new(bucket, opt.s3_region.clone(), opt.s3_endpoint.clone()).await;
+    Ok(storage::Backend::new(Arc::new(s3)))
+}
+
+#[cfg(not(feature = "s3"))]
+async fn s3_backend(_opt: &Opt) -> Result<storage::Backend, WebError> {
+    Err(WebError::StorageError(
+        "i was built without S3 support, rebuild with --features s3".to_string(),
+    ))
 }
 
 #[tokio::main]
 async fn main() -> Result<(), WebError> {
-    let opt = Opt::parse();
+    let mut opt = config::parse()?;
+    if let Some(size) = opt.thumbnail_size {
+        opt.thumbnail_sizes = vec![size];
+    }
+    if let Some(users_file) = &opt.users_file {
+        opt.users = users::load_users(users_file)?;
+    }
+    if opt.storage == storage::StorageKind::S3 {
+        opt.storage_backend = s3_backend(&opt).await?;
+    }
+    if opt.eager_thumbnails {
+        opt.thumbnail_queue = thumbnail::start_workers(&opt);
+    }
+    opt.webhooks = webhook::start_worker(&opt);
+    let tls_config = tls::load_config(&opt).await?;
+    if tls_config.is_some() && opt.server_url == DEFAULT_SERVER_URL {
+        opt.server_url = DEFAULT_SERVER_URL.replace("http://", "https://");
+    }
 
     // Configure tracing
     let default = "i=info".parse().unwrap();
     let filter = EnvFilter::builder()
         .with_default_directive(default)
         .from_env_lossy();
-    tracing_subscriber::fmt().with_env_filter(filter).init();
+    match opt.log_format {
+        LogFormat::Text => tracing_subscriber::fmt().with_env_filter(filter).init(),
+        LogFormat::Json => tracing_subscriber::fmt()
+            .json()
+            .with_env_filter(filter)
+            .init(),
+    }
 
-    let host = "0.0.0.0";
-    let bind_string = format!("{}:{}", host, opt.port);
+    let addr = SocketAddr::new(opt.host, opt.port);
 
     let base_dir = get_base_dir(&opt)?;
 
-    log::info!("listening on {}", bind_string);
-    log::info!("serving and storing files in: {:?}", base_dir);
+    log::info!("listening on {}", addr);
+    match &opt.s3_bucket {
+        Some(bucket) if opt.storage == storage::StorageKind::S3 => {
+            log::info!("serving and storing files in S3 bucket: {}", bucket)
+        }
+        _ => log::info!("serving and storing files in: {:?}", base_dir),
+    }
+
+    tokio::task::spawn(cleanup::cleanup_task(opt.clone()));
+
+    let app = router(base_dir, opt.clone());
 
-    let app = router(base_dir, opt);
+    match tls_config {
+        Some(tls_config) => tls::serve(addr, app, tls_config, &opt).await?,
+        None => serve(addr, app, &opt).await?,
+    }
+
+    cleanup::remove_staged(&opt).await?;
+    log::info!("shut down");
+    Ok(())
+}
+
+/**
+ * Serves the app over plain HTTP until the process is asked to stop. Uploads in progress are then
+ * given `--shutdown-timeout-secs` to finish.
+ */
+async fn serve(addr: SocketAddr, app: Router, opt: &Opt) -> Result<(), WebError> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let shutdown = Arc::new(Notify::new());
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown_signal().await;
+            log::info!("shutting down, waiting for uploads in progress to finish");
+            shutdown.notify_one();
+        }
+    });
+
+    // Connections are kept open until they are done, so give up on slow uploads eventually.
+    let timeout = Duration::from_secs(opt.shutdown_timeout_secs);
+    tokio::select! {
+        result = server.into_future() => result?,
+        _ = async {
+            shutdown.notified().await;
+            tokio::time::sleep(timeout).await;
+        } => log::warn!("uploads did not finish within {} seconds", opt.shutdown_timeout_secs),
+    }
+    Ok(())
+}
+
+/**
+ * Waits until the process is asked to stop, by Ctrl-C or, on Unix, by SIGTERM.
+ */
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            log::error!("could not listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                log::error!("could not listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-    let listener = tokio::net::TcpListener::bind(bind_string).await.unwrap();
-    Ok(axum::serve(listener, app).await?)
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
 }
 
 #[cfg(test)]
@@ -223,8 +1534,15 @@ mod tests {
     use super::*;
     use axum::{
         body::Body,
-        http::{header::LOCATION, Request, StatusCode},
+        http::{
+            header::{
+                HeaderName, ACCEPT, ACCEPT_ENCODING, CONTENT_DISPOSITION, CONTENT_ENCODING, COOKIE,
+                ETAG, IF_MODIFIED_SINCE, LAST_MODIFIED, LOCATION, SET_COOKIE,
+            },
+            Request, StatusCode,
+        },
     };
+    use clap::Parser;
     use http_body_util::BodyExt; // for `collect`
     use serde_json::Value;
 
@@ -232,14 +1550,87 @@ mod tests {
 
     fn make_test_opt() -> Opt {
         Opt {
+            config: None,
+            host: [127, 0, 0, 1].into(),
             port: 1337,
             base_dir: "/tmp".into(),
+            storage: storage::StorageKind::Local,
+            s3_bucket: None,
+            s3_region: None,
+            s3_endpoint: None,
+            storage_backend: Default::default(),
             server_url: "http://test.example.com".into(),
+            base_path: String::new(),
+            files_prefix: String::new(),
+            tls_cert: None,
+            tls_key: None,
+            https_redirect_port: None,
             auth_user: None,
             auth_pass: None,
+            auth_pass_hash: None,
+            rate_limit: None,
+            rate_limit_burst: None,
+            trusted_proxies: vec![],
+            rate_limiter: Default::default(),
+            password_limiter: Default::default(),
+            jobs: Default::default(),
+            stats_cache_secs: 60,
+            stats_cache: Default::default(),
+            recent_cache_ttl: 60,
+            recent_cache: Default::default(),
+            api_tokens: vec![],
+            users_file: None,
+            users: Default::default(),
             recents: 1,
-            thumbnail_size: 150,
+            disable_recent: false,
+            enable_webdav: false,
+            auth_upload: false,
+            auth_view: false,
+            thumbnail_sizes: vec![150],
+            thumbnail_size: None,
+            thumbnail_format: thumbnail::ThumbnailFormat::Webp,
+            thumbnail_mode: thumbnail::ThumbnailMode::Fill,
+            thumbnail_filter: thumbnail::ThumbnailFilter::Triangle,
+            thumbnail_jpeg_quality: 75,
+            eager_thumbnails: false,
+            thumbnail_workers: 2,
+            thumbnail_queue_full: thumbnail::QueueFull::Skip,
+            thumbnail_queue: Default::default(),
+            max_thumbnail_frames: 50,
+            max_thumbnail_pixels: 100_000_000,
+            strip_metadata: false,
+            sanitize_svg: false,
+            svg_policy: svg::SvgPolicy::Sanitize,
+            jpeg_quality: 90,
+            ffmpeg_path: None,
+            ffmpeg_timeout_secs: 10,
+            allow_private_urls: false,
             max_upload_size: 30 * 1024 * 1024,
+            max_field_size: 64 * 1024,
+            max_fields: 100,
+            type_limits: vec![],
+            min_free_bytes: 0,
+            enforce_content_type: sniff::ContentTypeCheck::Off,
+            naming: upload::Naming::Random,
+            slug_length: 8,
+            slug_alphabet: upload::SlugAlphabet::Alphanumeric,
+            slug_rng: Default::default(),
+            max_filename_length: 200,
+            on_conflict: upload::OnConflict::Rename,
+            blocked_extensions: vec!["exe".into()],
+            max_age_days: None,
+            cleanup_interval_secs: 3600,
+            shutdown_timeout_secs: 30,
+            insecure_delete: false,
+            trash: false,
+            trash_max_age_days: 30,
+            webhook_url: None,
+            webhook_attempts: 3,
+            webhooks: Default::default(),
+            log_format: LogFormat::Text,
+            enable_metrics: false,
+            compression: false,
+            cors_allow_origin: vec![],
         }
     }
 
@@ -260,26 +1651,158 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn post_small_file() {
-        let opt = make_test_opt();
+    async fn healthz_without_auth() {
+        let mut opt = make_test_opt();
+        opt.auth_user = Some("user".into());
+        opt.auth_pass = Some("pass".into());
         let app = router("/tmp".into(), opt);
 
         let response = app
             .oneshot(
                 Request::builder()
-                    .uri("/")
-                    .method("POST")
-                    .header(
-                        axum::http::header::CONTENT_TYPE,
-                        "multipart/form-data; boundary=boundary",
-                    )
-                    .body(
-                        r#"--boundary
-Content-Disposition: form-data; name="file"; filename="original.txt"
-Content-Type: text/plain
-
-hellu this is a cute little file UwU
-
+                    .uri("/healthz")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"ok");
+    }
+
+    #[tokio::test]
+    async fn metrics_enabled() {
+        let mut opt = make_test_opt();
+        opt.enable_metrics = true;
+        let app = router("/tmp".into(), opt);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("# TYPE i_uploaded_files_total counter\n"));
+    }
+
+    #[tokio::test]
+    async fn hashed_password() {
+        let mut opt = make_test_opt();
+        opt.auth_user = Some("user".into());
+        opt.auth_pass_hash = Some(bcrypt::hash("secret", 4).unwrap());
+        let app = router("/tmp".into(), opt);
+
+        // "user:secret" and "user:wrong"
+        for (creds, status) in [
+            ("dXNlcjpzZWNyZXQ=", StatusCode::OK),
+            ("dXNlcjp3cm9uZw==", StatusCode::UNAUTHORIZED),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/")
+                        .header(
+                            axum::http::header::AUTHORIZATION,
+                            format!("Basic {}", creds),
+                        )
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), status);
+        }
+    }
+
+    #[test]
+    fn constant_time_comparison() {
+        assert!(constant_time_eq("secret", "secret"));
+        assert!(constant_time_eq("", ""));
+        assert!(!constant_time_eq("secret", "secreT"));
+        assert!(!constant_time_eq("secret", "secret2"));
+        assert!(!constant_time_eq("", "secret"));
+    }
+
+    #[tokio::test]
+    async fn plaintext_password() {
+        let mut opt = make_test_opt();
+        opt.auth_user = Some("user".into());
+        opt.auth_pass = Some("secret".into());
+        let app = router("/tmp".into(), opt);
+
+        // "user:secret", "user:wrong", "resu:secret", and no credentials at all
+        for (creds, status) in [
+            (Some("dXNlcjpzZWNyZXQ="), StatusCode::OK),
+            (Some("dXNlcjp3cm9uZw=="), StatusCode::UNAUTHORIZED),
+            (Some("cmVzdTpzZWNyZXQ="), StatusCode::UNAUTHORIZED),
+            (None, StatusCode::UNAUTHORIZED),
+        ] {
+            let mut request = Request::builder().uri("/");
+            if let Some(creds) = creds {
+                request = request.header(
+                    axum::http::header::AUTHORIZATION,
+                    format!("Basic {}", creds),
+                );
+            }
+            let response = app
+                .clone()
+                .oneshot(request.body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), status);
+        }
+    }
+
+    #[test]
+    fn pass_and_pass_hash_conflict() {
+        let result = Opt::try_parse_from(["i", "--auth-pass", "a", "--auth-pass-hash", "b"]);
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn post_small_file() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let mut opt = make_test_opt();
+        // An earlier run would have taken the name.
+        opt.base_dir = "/tmp/i-test-small-file".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        opt.slug_rng = upload::SlugRng::seeded(1);
+        let filename =
+            upload::generate_random_filename_with(&mut StdRng::seed_from_u64(1), Some("txt"), &opt);
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .method("POST")
+                    .header(
+                        axum::http::header::CONTENT_TYPE,
+                        "multipart/form-data; boundary=boundary",
+                    )
+                    .body(
+                        r#"--boundary
+Content-Disposition: form-data; name="file"; filename="original.txt"
+Content-Type: text/plain
+
+hellu this is a cute little file UwU
+
 --boundary--
 "#
                         .replace('\n', "\r\n"),
@@ -289,18 +1812,35 @@ hellu this is a cute little file UwU
             .await
             .unwrap();
 
+        let url = format!("http://test.example.com/{}", filename);
         assert_eq!(response.status(), StatusCode::SEE_OTHER);
-        assert!(response.headers().get(LOCATION).is_some());
+        assert_eq!(url, response.headers()[LOCATION]);
 
         let body = response.into_body().collect().await.unwrap().to_bytes();
         let body: Value = serde_json::from_slice(&body).unwrap();
-        assert!(body.get("url").is_some())
+        assert_eq!(Some(url.as_str()), body.get("url").and_then(Value::as_str));
+        assert_eq!(
+            Some("hellu this is a cute little file UwU\r\n".len() as u64),
+            body.get("size").and_then(Value::as_u64)
+        );
+        assert_eq!(
+            Some("text/plain"),
+            body.get("contentType").and_then(Value::as_str)
+        );
+        assert_eq!(
+            Some(64),
+            body.get("hash").and_then(Value::as_str).map(str::len)
+        );
     }
 
     #[tokio::test]
     async fn post_small_file_original() {
-        let opt = make_test_opt();
-        let app = router("/tmp".into(), opt);
+        let mut opt = make_test_opt();
+        // An earlier run would have taken the name.
+        opt.base_dir = "/tmp/i-test-original".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
 
         let response = app
             .oneshot(
@@ -341,6 +1881,359 @@ Content-Disposition: form-data; name="options"
         );
     }
 
+    fn custom_filename_request(options: &str) -> Request<Body> {
+        Request::builder()
+            .uri("/")
+            .method("POST")
+            .header(
+                axum::http::header::CONTENT_TYPE,
+                "multipart/form-data; boundary=boundary",
+            )
+            .body(
+                format!(
+                    r#"--boundary
+Content-Disposition: form-data; name="file"; filename="original.txt"
+Content-Type: text/plain
+
+quarterly numbers
+--boundary
+Content-Disposition: form-data; name="options"
+
+{}
+--boundary--
+"#,
+                    options
+                )
+                .replace('\n', "\r\n")
+                .into(),
+            )
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn post_custom_filename() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-custom-filename".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let response = app
+            .clone()
+            .oneshot(custom_filename_request(r#"{"filename":"report-2024.pdf"}"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            Some("http://test.example.com/report-2024.pdf"),
+            body.get("url").map(|v| v.as_str().unwrap())
+        );
+
+        // Path components are not allowed.
+        let response = app
+            .clone()
+            .oneshot(custom_filename_request(r#"{"filename":"../report.pdf"}"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn post_custom_filename_conflict() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-custom-filename-conflict".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let existing = Path::new(&opt.base_dir).join("report.pdf");
+        std::fs::write(&existing, "old report").unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let response = app
+            .clone()
+            .oneshot(custom_filename_request(r#"{"filename":"report.pdf"}"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert_eq!("old report", std::fs::read_to_string(&existing).unwrap());
+
+        let response = app
+            .clone()
+            .oneshot(custom_filename_request(
+                r#"{"filename":"report.pdf","overwrite":true}"#,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            "quarterly numbers",
+            std::fs::read_to_string(&existing).unwrap()
+        );
+    }
+
+    /**
+     * Serves a small file and a missing file on a random local port, and returns its address.
+     */
+    async fn spawn_remote_server() -> SocketAddr {
+        let remote = Router::new()
+            .route(
+                "/cat.png",
+                get(|| async { ([(CONTENT_TYPE, "image/png")], "not really a png") }),
+            )
+            .route(
+                "/moved",
+                get(|| async { axum::response::Redirect::to("/cat.png") }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, remote).await });
+        addr
+    }
+
+    fn upload_url_request(url: &str) -> Request<Body> {
+        Request::builder()
+            .uri("/upload-url")
+            .method("POST")
+            .header(CONTENT_TYPE, "application/json")
+            .body(format!(r#"{{"url":"{}","redirect":false}}"#, url).into())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn upload_from_url() {
+        let addr = spawn_remote_server().await;
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-upload-url".into();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+
+        // Local addresses are not allowed by default.
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let response = app
+            .oneshot(upload_url_request(&format!("http://{}/cat.png", addr)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        opt.allow_private_urls = true;
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let response = app
+            .clone()
+            .oneshot(upload_url_request(&format!("http://{}/moved", addr)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(Some(16), body.get("size").and_then(Value::as_u64));
+        assert_eq!(
+            Some("image/png"),
+            body.get("contentType").and_then(Value::as_str)
+        );
+        let url = body.get("url").and_then(Value::as_str).unwrap();
+        assert!(url.ends_with(".png"));
+        let filename = url.rsplit('/').next().unwrap();
+        assert!(Path::new(&opt.base_dir).join(filename).exists());
+
+        let response = app
+            .clone()
+            .oneshot(upload_url_request(&format!("http://{}/missing", addr)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+        let response = app
+            .oneshot(upload_url_request("file:///etc/passwd"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    fn tus_request(method: &str, uri: &str, headers: &[(&str, &str)], body: &str) -> Request<Body> {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Tus-Resumable", "1.0.0");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(body.to_string().into()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn resumable_upload() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-tus".into();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        // "aGVsbG8udHh0" is "hello.txt" in base64.
+        let response = app
+            .clone()
+            .oneshot(tus_request(
+                "POST",
+                "/files",
+                &[
+                    ("Upload-Length", "11"),
+                    ("Upload-Metadata", "filename aGVsbG8udHh0"),
+                ],
+                "",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let location = response.headers()[LOCATION].to_str().unwrap();
+        let path = location.strip_prefix("http://test.example.com").unwrap();
+        let patch = |offset: &'static str, body: &'static str| {
+            tus_request(
+                "PATCH",
+                path,
+                &[
+                    ("Upload-Offset", offset),
+                    ("Content-Type", "application/offset+octet-stream"),
+                ],
+                body,
+            )
+        };
+
+        let response = app.clone().oneshot(patch("0", "hello ")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!("6", response.headers()["Upload-Offset"]);
+
+        let response = app
+            .clone()
+            .oneshot(tus_request("HEAD", path, &[], ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!("6", response.headers()["Upload-Offset"]);
+        assert_eq!("11", response.headers()["Upload-Length"]);
+
+        // Appending at the wrong offset is a conflict.
+        let response = app.clone().oneshot(patch("0", "world")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let response = app.clone().oneshot(patch("6", "world")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let url = response.headers()["Content-Location"].to_str().unwrap();
+        assert!(url.ends_with(".txt"));
+        let filename = url.rsplit('/').next().unwrap();
+        assert_eq!(
+            "hello world",
+            std::fs::read_to_string(Path::new(&opt.base_dir).join(filename)).unwrap()
+        );
+
+        // The upload is gone once it has been stored.
+        let response = app
+            .oneshot(tus_request("HEAD", path, &[], ""))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn concurrent_resumable_appends_conflict() {
+        use futures::SinkExt;
+
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-tus-concurrent".into();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let response = app
+            .clone()
+            .oneshot(tus_request("POST", "/files", &[("Upload-Length", "5")], ""))
+            .await
+            .unwrap();
+        let location = response.headers()[LOCATION].to_str().unwrap();
+        let path = location
+            .strip_prefix("http://test.example.com")
+            .unwrap()
+            .to_string();
+        let patch = |body: Body| {
+            let mut request = tus_request(
+                "PATCH",
+                &path,
+                &[
+                    ("Upload-Offset", "0"),
+                    ("Content-Type", "application/offset+octet-stream"),
+                ],
+                "",
+            );
+            *request.body_mut() = body;
+            request
+        };
+
+        // The first request appends slowly. A channel without room only takes the second chunk
+        // once the first has been read, so the request is appending by then.
+        let (mut sender, receiver) = futures::channel::mpsc::channel::<Result<_, Infallible>>(0);
+        let slow = tokio::spawn(app.clone().oneshot(patch(Body::from_stream(receiver))));
+        sender.send(Ok("hel")).await.unwrap();
+        sender.send(Ok("lo")).await.unwrap();
+
+        let response = app
+            .clone()
+            .oneshot(patch(Body::from("hello")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        drop(sender);
+        let response = slow.await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!("5", response.headers()["Upload-Offset"]);
+        assert!(response.headers().contains_key("Content-Location"));
+    }
+
+    #[tokio::test]
+    async fn put_raw_body() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-raw".into();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/?redirect=false")
+                    .method("PUT")
+                    .header("X-Filename", "notes.txt")
+                    .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+                    .body(Body::from("raw notes"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(Some(9), body.get("size").and_then(Value::as_u64));
+        let url = body.get("url").and_then(Value::as_str).unwrap();
+        assert!(url.ends_with(".txt"));
+        let filename = url.rsplit('/').next().unwrap();
+        assert_eq!(
+            "raw notes",
+            std::fs::read_to_string(Path::new(&opt.base_dir).join(filename)).unwrap()
+        );
+
+        // Without a filename, the extension comes from the content type.
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .method("PUT")
+                    .header(CONTENT_TYPE, "image/png")
+                    .body(Body::from("not really a png"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let location = response.headers()[LOCATION].to_str().unwrap();
+        assert!(location.ends_with(".png"));
+    }
+
     #[tokio::test]
     async fn post_small_file_no_redirect() {
         let opt = make_test_opt();
@@ -384,7 +2277,7 @@ Content-Disposition: form-data; name="options"
     }
 
     #[tokio::test]
-    async fn post_big_file() {
+    async fn post_multiple_files() {
         let opt = make_test_opt();
         let app = router("/tmp".into(), opt);
 
@@ -398,17 +2291,25 @@ Content-Disposition: form-data; name="options"
                         "multipart/form-data; boundary=boundary",
                     )
                     .body(
-                        format!(
-                            r#"--boundary
-Content-Disposition: form-data; name="file"; filename="original.txt"
+                        r#"--boundary
+Content-Disposition: form-data; name="file"; filename="first.txt"
 Content-Type: text/plain
 
-{}
+first file
+
+--boundary
+Content-Disposition: form-data; name="file"; filename="empty.txt"
+Content-Type: text/plain
+
+
+--boundary
+Content-Disposition: form-data; name="file"; filename="second.txt"
+Content-Type: text/plain
+
+second file
 
 --boundary--
-"#,
-                            "1234567890abcdef\n".repeat(64 * 1024 * 20)
-                        )
+"#
                         .replace('\n', "\r\n"),
                     )
                     .unwrap(),
@@ -417,9 +2318,3831 @@ Content-Type: text/plain
             .unwrap();
 
         assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            Some("http://test.example.com/recent"),
+            response
+                .headers()
+                .get(LOCATION)
+                .map(|v| v.to_str().unwrap())
+        );
 
         let body = response.into_body().collect().await.unwrap().to_bytes();
         let body: Value = serde_json::from_slice(&body).unwrap();
-        assert!(body.get("url").is_some())
+        assert!(body.get("url").is_none());
+        let files = body.get("files").and_then(Value::as_array).unwrap();
+        assert_eq!(2, files.len());
+        assert!(files.iter().all(|f| f.get("url").is_some()));
+    }
+
+    #[tokio::test]
+    async fn post_blocked_extension() {
+        let opt = make_test_opt();
+        let app = router("/tmp".into(), opt);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .method("POST")
+                    .header(
+                        axum::http::header::CONTENT_TYPE,
+                        "multipart/form-data; boundary=boundary",
+                    )
+                    .body(
+                        r#"--boundary
+Content-Disposition: form-data; name="file"; filename="virus.EXE"
+Content-Type: application/octet-stream
+
+definitely not a virus
+
+--boundary--
+"#
+                        .replace('\n', "\r\n"),
+                    )
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn recent_json() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-recent-json".into();
+        let app = router(opt.base_dir.clone().into(), opt);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .method("POST")
+                    .header(
+                        axum::http::header::CONTENT_TYPE,
+                        "multipart/form-data; boundary=boundary",
+                    )
+                    .body(
+                        r#"--boundary
+Content-Disposition: form-data; name="file"; filename="original.txt"
+Content-Type: text/plain
+
+hellu this is a cute little file UwU
+
+--boundary--
+"#
+                        .replace('\n', "\r\n"),
+                    )
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/recent.json?page=1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let recents = body.as_array().unwrap();
+        assert_eq!(1, recents.len());
+        let timestamp = recents[0].get("timestamp").and_then(Value::as_str).unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(timestamp).is_ok());
+        assert!(recents[0].get("size").and_then(Value::as_u64).is_some());
+    }
+
+    #[tokio::test]
+    async fn expired_file_not_served() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-expiry".into();
+        let app = router(opt.base_dir.clone().into(), opt);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .method("POST")
+                    .header(
+                        axum::http::header::CONTENT_TYPE,
+                        "multipart/form-data; boundary=boundary",
+                    )
+                    .body(
+                        r#"--boundary
+Content-Disposition: form-data; name="file"; filename="original.txt"
+Content-Type: text/plain
+
+hellu this is a cute little file UwU
+
+--boundary
+Content-Disposition: form-data; name="options"
+
+{"expiresInSecs":0}
+--boundary--
+"#
+                        .replace('\n', "\r\n"),
+                    )
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let location = response.headers().get(LOCATION).unwrap().to_str().unwrap();
+        let path = url::Url::parse(location).unwrap().path().to_string();
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert!(body.get("expiresAt").is_some());
+
+        let response = app
+            .oneshot(Request::builder().uri(path).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn extensionless_file_served_with_stored_content_type() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-content-type".into();
+        let app = router(opt.base_dir.clone().into(), opt);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .method("POST")
+                    .header(
+                        axum::http::header::CONTENT_TYPE,
+                        "multipart/form-data; boundary=boundary",
+                    )
+                    .body(
+                        r#"--boundary
+Content-Disposition: form-data; name="file"; filename="image"
+Content-Type: image/png
+
+not really a png
+--boundary--
+"#
+                        .replace('\n', "\r\n"),
+                    )
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let location = response.headers().get(LOCATION).unwrap().to_str().unwrap();
+        let path = url::Url::parse(location).unwrap().path().to_string();
+        assert!(!path.contains('.'));
+
+        let response = app
+            .oneshot(Request::builder().uri(path).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!("image/png", response.headers()[CONTENT_TYPE]);
+    }
+
+    #[tokio::test]
+    async fn files_streamed_from_storage_backend() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-storage-backend".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        std::fs::write(Path::new(&opt.base_dir).join("video.mp4"), "0123456789").unwrap();
+        // Any backend but the default is served from storage instead of the directory.
+        opt.storage_backend =
+            storage::Backend::new(Arc::new(storage::LocalStorage::new(&opt.base_dir)));
+        let app = router(opt.base_dir.clone().into(), opt);
+        let request = |method: Method, range: Option<&str>| {
+            let mut request = Request::builder().method(method).uri("/video.mp4");
+            if let Some(range) = range {
+                request = request.header(RANGE, range);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let response = request(Method::GET, None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!("video/mp4", response.headers()[CONTENT_TYPE]);
+        assert_eq!("bytes", response.headers()[ACCEPT_RANGES]);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&b"0123456789"[..], &body[..]);
+
+        let response = request(Method::GET, Some("bytes=2-4")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!("bytes 2-4/10", response.headers()[CONTENT_RANGE]);
+        assert_eq!("3", response.headers()[CONTENT_LENGTH]);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&b"234"[..], &body[..]);
+
+        let response = request(Method::HEAD, None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!("10", response.headers()[CONTENT_LENGTH]);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn strip_metadata_removes_gps() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-strip".into();
+        opt.strip_metadata = true;
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let img = image::RgbImage::from_pixel(64, 48, image::Rgb([200, 100, 50]));
+        let mut jpeg = Vec::new();
+        img.write_to(
+            &mut std::io::Cursor::new(&mut jpeg),
+            image::ImageFormat::Jpeg,
+        )
+        .unwrap();
+        #[rustfmt::skip]
+        let exif: &[u8] = &[
+            0xFF, 0xE1, 0x00, 0x34, // APP1 marker and segment length
+            b'E', b'x', b'i', b'f', 0x00, 0x00,
+            b'I', b'I', 0x2A, 0x00, 0x08, 0x00, 0x00, 0x00, // little endian TIFF header
+            0x01, 0x00, // one IFD entry, pointing to the GPS IFD
+            0x25, 0x88, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00, 0x1A, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, // no next IFD
+            0x01, 0x00, // one GPS entry, GPSLatitudeRef "N"
+            0x01, 0x00, 0x02, 0x00, 0x02, 0x00, 0x00, 0x00, b'N', 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, // no next IFD
+        ];
+        jpeg.splice(2..2, exif.iter().copied());
+
+        let mut body = b"--boundary\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"photo.jpg\"\r\n\
+Content-Type: image/jpeg\r\n\r\n"
+            .to_vec();
+        body.extend_from_slice(&jpeg);
+        body.extend_from_slice(b"\r\n--boundary--\r\n");
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .method("POST")
+                    .header(CONTENT_TYPE, "multipart/form-data; boundary=boundary")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let url = body.get("url").and_then(Value::as_str).unwrap();
+        let filename = url.rsplit('/').next().unwrap();
+        let stored = std::fs::read(Path::new(&opt.base_dir).join(filename)).unwrap();
+
+        assert_eq!(
+            Some(stored.len() as u64),
+            body.get("size").and_then(Value::as_u64)
+        );
+        assert!(!stored.windows(4).any(|w| w == b"Exif"));
+        assert_eq!(
+            (64, 48),
+            image::load_from_memory(&stored)
+                .unwrap()
+                .to_rgb8()
+                .dimensions()
+        );
+
+        // An image that can't be decoded would keep its metadata, so it isn't stored.
+        let mut broken = vec![0xFF, 0xD8];
+        broken.extend_from_slice(exif);
+        broken.extend_from_slice(b"\xFF\xC0 not really image data");
+        let mut body = b"--boundary\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"broken.jpg\"\r\n\
+Content-Type: image/jpeg\r\n\r\n"
+            .to_vec();
+        body.extend_from_slice(&broken);
+        // The files after it aren't stored either, nor left behind in the staging directory.
+        body.extend_from_slice(
+            b"\r\n--boundary\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"notes.txt\"\r\n\
+Content-Type: text/plain\r\n\r\n\
+notes",
+        );
+        body.extend_from_slice(b"\r\n--boundary--\r\n");
+        let files_before = std::fs::read_dir(&opt.base_dir).unwrap().count();
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .method("POST")
+                    .header(CONTENT_TYPE, "multipart/form-data; boundary=boundary")
+                    .body(Body::from(body))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(
+            files_before,
+            std::fs::read_dir(&opt.base_dir).unwrap().count()
+        );
+        let staging_dir = Path::new(&opt.base_dir).join(".staging");
+        assert_eq!(0, std::fs::read_dir(staging_dir).unwrap().count());
+    }
+
+    #[tokio::test]
+    async fn delete_by_token() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-delete".into();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .method("POST")
+                    .header(
+                        axum::http::header::CONTENT_TYPE,
+                        "multipart/form-data; boundary=boundary",
+                    )
+                    .body(
+                        r#"--boundary
+Content-Disposition: form-data; name="file"; filename="original.txt"
+Content-Type: text/plain
+
+hellu this is a cute little file UwU
+
+--boundary--
+"#
+                        .replace('\n', "\r\n"),
+                    )
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let token = body.get("deleteToken").and_then(Value::as_str).unwrap();
+        let url = body.get("url").and_then(Value::as_str).unwrap();
+        let filename = url.rsplit('/').next().unwrap();
+        assert!(Path::new(&opt.base_dir).join(filename).exists());
+
+        // The sidecar with the deletion token is not served.
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(format!("/{}/{}.json", META_SUBDIR, filename))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Forms must carry the CSRF token of the cookie set by the recent page.
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/recent")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let cookie = response.headers()[SET_COOKIE].to_str().unwrap();
+        let cookie = cookie.split(';').next().unwrap().to_string();
+        let csrf = cookie.split_once('=').unwrap().1.to_string();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(String::from_utf8_lossy(&body).contains(&csrf));
+
+        for (cookie, body) in [
+            (None, format!("token={}", token)),
+            (
+                Some("i_csrf=other"),
+                format!("token={}&csrf={}", token, csrf),
+            ),
+        ] {
+            let mut request = Request::builder()
+                .uri("/delete")
+                .method("POST")
+                .header(CONTENT_TYPE, "application/x-www-form-urlencoded");
+            if let Some(cookie) = cookie {
+                request = request.header(COOKIE, cookie);
+            }
+            let response = app
+                .clone()
+                .oneshot(request.body(body).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+            assert!(Path::new(&opt.base_dir).join(filename).exists());
+        }
+
+        // Deleting by filename is not allowed by default.
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/delete")
+                    .method("POST")
+                    .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+                    .header(COOKIE, &cookie)
+                    .body(format!("filename={}&csrf={}", filename, csrf))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(Path::new(&opt.base_dir).join(filename).exists());
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/delete")
+                    .method("POST")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(format!(r#"{{"token":"{}"}}"#, token))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert!(!Path::new(&opt.base_dir).join(filename).exists());
+    }
+
+    #[tokio::test]
+    async fn per_user_directories() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-users".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        opt.users_file = Some("users.json".into());
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        opt.users = std::sync::Arc::new(
+            [("alice".into(), hash.clone()), ("bob".into(), hash)]
+                .into_iter()
+                .collect(),
+        );
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        // "alice:secret" and "bob:secret"
+        let alice = "Basic YWxpY2U6c2VjcmV0";
+        let bob = "Basic Ym9iOnNlY3JldA==";
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .method("POST")
+                    .header(axum::http::header::AUTHORIZATION, alice)
+                    .header(
+                        axum::http::header::CONTENT_TYPE,
+                        "multipart/form-data; boundary=boundary",
+                    )
+                    .body(
+                        r#"--boundary
+Content-Disposition: form-data; name="file"; filename="original.txt"
+Content-Type: text/plain
+
+hellu this is a cute little file UwU
+
+--boundary
+Content-Disposition: form-data; name="options"
+
+{"useOriginalFilename":true}
+--boundary--
+"#
+                        .replace('\n', "\r\n"),
+                    )
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            Some("http://test.example.com/alice/original.txt"),
+            body.get("url").and_then(Value::as_str)
+        );
+        assert!(Path::new("/tmp/i-test-users/alice/original.txt").exists());
+
+        for (creds, expected) in [(alice, 1), (bob, 0)] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/recent.json")
+                        .header(axum::http::header::AUTHORIZATION, creds)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(expected, body.as_array().unwrap().len());
+        }
+
+        // Unknown users can't log in, even with a password that is valid for someone else.
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/recent.json")
+                    .header(axum::http::header::AUTHORIZATION, "Basic ZXZlOnNlY3JldA==")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn api_token() {
+        let mut opt = make_test_opt();
+        opt.auth_user = Some("user".into());
+        opt.auth_pass = Some("secret".into());
+        opt.api_tokens = vec!["token1".into(), "token2".into()];
+        let app = router("/tmp".into(), opt);
+
+        for (token, status) in [
+            ("token2", StatusCode::SEE_OTHER),
+            ("token3", StatusCode::UNAUTHORIZED),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/")
+                        .method("POST")
+                        .header(
+                            axum::http::header::AUTHORIZATION,
+                            format!("Bearer {}", token),
+                        )
+                        .header(
+                            axum::http::header::CONTENT_TYPE,
+                            "multipart/form-data; boundary=boundary",
+                        )
+                        .body(
+                            r#"--boundary
+Content-Disposition: form-data; name="file"; filename="original.txt"
+Content-Type: text/plain
+
+hellu this is a cute little file UwU
+
+--boundary--
+"#
+                            .replace('\n', "\r\n"),
+                        )
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), status);
+            if status == StatusCode::UNAUTHORIZED {
+                let challenges: Vec<_> = response
+                    .headers()
+                    .get_all(WWW_AUTHENTICATE)
+                    .iter()
+                    .collect();
+                assert_eq!(2, challenges.len());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn rate_limited_upload() {
+        let mut opt = make_test_opt();
+        opt.rate_limit = Some(1);
+        let app = router("/tmp".into(), opt);
+
+        for status in [StatusCode::SEE_OTHER, StatusCode::TOO_MANY_REQUESTS] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/")
+                        .method("POST")
+                        .extension(axum::extract::ConnectInfo(SocketAddr::from((
+                            [192, 0, 2, 1],
+                            1234,
+                        ))))
+                        .header(
+                            axum::http::header::CONTENT_TYPE,
+                            "multipart/form-data; boundary=boundary",
+                        )
+                        .body(
+                            r#"--boundary
+Content-Disposition: form-data; name="file"; filename="original.txt"
+Content-Type: text/plain
+
+hellu this is a cute little file UwU
+
+--boundary--
+"#
+                            .replace('\n', "\r\n"),
+                        )
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), status);
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                assert!(response.headers().get(RETRY_AFTER).is_some());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reserved_original_filename_rejected() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-reserved".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        for name in [THUMBNAIL_SUBDIR, META_SUBDIR] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/")
+                        .method("POST")
+                        .header(
+                            axum::http::header::CONTENT_TYPE,
+                            "multipart/form-data; boundary=boundary",
+                        )
+                        .body(
+                            format!(
+                                r#"--boundary
+Content-Disposition: form-data; name="file"; filename="{}"
+Content-Type: text/plain
+
+hellu this is a cute little file UwU
+
+--boundary
+Content-Disposition: form-data; name="options"
+
+{{"useOriginalFilename":true}}
+--boundary--
+"#,
+                                name
+                            )
+                            .replace('\n', "\r\n"),
+                        )
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            assert!(!std::path::Path::new(&opt.base_dir).join(name).is_file());
+        }
+    }
+
+    #[tokio::test]
+    async fn post_big_file() {
+        let opt = make_test_opt();
+        let app = router("/tmp".into(), opt);
+        let content = "1234567890abcdef\n".repeat(64 * 1024 * 20);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .method("POST")
+                    .header(
+                        axum::http::header::CONTENT_TYPE,
+                        "multipart/form-data; boundary=boundary",
+                    )
+                    .body(
+                        format!(
+                            r#"--boundary
+Content-Disposition: form-data; name="file"; filename="original.txt"
+Content-Type: text/plain
+
+{}
+
+--boundary--
+"#,
+                            content
+                        )
+                        .replace('\n', "\r\n"),
+                    )
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert!(body.get("url").is_some());
+        // Every newline is sent as CRLF, and the part ends with one extra CRLF.
+        assert_eq!(
+            Some(content.replace('\n', "\r\n").len() as u64 + 2),
+            body.get("size").and_then(Value::as_u64)
+        );
+    }
+
+    #[tokio::test]
+    async fn regenerate_thumbnails_in_background() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-regenerate".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let base_dir = Path::new(&opt.base_dir);
+        image::RgbImage::new(300, 200)
+            .save(base_dir.join("image.png"))
+            .unwrap();
+        std::fs::write(base_dir.join("notes.txt"), "not an image").unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/admin/regenerate-thumbnails")
+                    .method("POST")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let location = response.headers()[LOCATION].to_str().unwrap();
+        let path = url::Url::parse(location).unwrap().path().to_string();
+
+        let mut status = Value::Null;
+        for _ in 0..100 {
+            let response = app
+                .clone()
+                .oneshot(Request::builder().uri(&path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            status = serde_json::from_slice(&body).unwrap();
+            if status["done"] == Value::Bool(true) {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+
+        assert_eq!(Value::Bool(true), status["done"]);
+        assert_eq!(2, status["processed"]);
+        assert_eq!(2, status["total"]);
+        let thumbnails = base_dir.join(THUMBNAIL_SUBDIR);
+        assert!(thumbnails.join("image.png_150.webp").exists());
+        assert!(!thumbnails.join("notes.txt_150.webp").exists());
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/admin/jobs/12345")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn thumbnail_generated_on_first_request() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-lazy-thumbnail".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let base_dir = Path::new(&opt.base_dir);
+        image::RgbImage::new(300, 200)
+            .save(base_dir.join("image.png"))
+            .unwrap();
+        std::fs::write(base_dir.join("notes.txt"), "not an image").unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let get = |uri: &str| {
+            app.clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        };
+
+        let thumbnail = base_dir.join(THUMBNAIL_SUBDIR).join("image.png_150.webp");
+        assert!(!thumbnail.exists());
+        let response = get("/thumbnail/image.png?size=150").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!("image/webp", response.headers()[CONTENT_TYPE]);
+        assert!(thumbnail.exists());
+
+        let response = get("/thumbnail/notes.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!("image/png", response.headers()[CONTENT_TYPE]);
+
+        let response = get("/thumbnail/missing.png").await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = get("/thumbnail/image.png?size=4000").await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Files that expired or were downloaded as often as allowed, but are still there until the
+        // cleanup task runs, have no thumbnails.
+        std::fs::create_dir_all(base_dir.join(META_SUBDIR)).unwrap();
+        for (name, meta) in [
+            ("expired.png", r#"{"expiresAt":"2020-01-01T00:00:00Z"}"#),
+            ("used-up.png", r#"{"maxDownloads":1,"downloads":1}"#),
+        ] {
+            std::fs::copy(base_dir.join("image.png"), base_dir.join(name)).unwrap();
+            let meta_path = base_dir.join(META_SUBDIR).join(format!("{}.json", name));
+            std::fs::write(meta_path, meta).unwrap();
+            let response = get(&format!("/thumbnail/{}?size=150", name)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+
+        // Stored thumbnails are only served through the route that checks their file.
+        let expired_thumbnail = base_dir.join(THUMBNAIL_SUBDIR).join("expired.png_150.webp");
+        std::fs::copy(&thumbnail, expired_thumbnail).unwrap();
+        for name in ["image.png_150.webp", "expired.png_150.webp"] {
+            let uri = format!("/{}/{}", THUMBNAIL_SUBDIR, name);
+            let response = get(&uri).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+
+        // Files with limited downloads only get a placeholder, which doesn't show the image.
+        std::fs::copy(base_dir.join("image.png"), base_dir.join("limited.png")).unwrap();
+        let meta_path = base_dir.join(META_SUBDIR).join("limited.png.json");
+        std::fs::write(meta_path, r#"{"maxDownloads":1}"#).unwrap();
+        let response = get("/thumbnail/limited.png?size=150").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!("image/png", response.headers()[CONTENT_TYPE]);
+        assert!(!base_dir
+            .join(THUMBNAIL_SUBDIR)
+            .join("limited.png_150.webp")
+            .exists());
+    }
+
+    #[tokio::test]
+    async fn broken_image_upload_counts_thumbnail_error() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-broken-image".into();
+        opt.enable_metrics = true;
+        opt.eager_thumbnails = true;
+        opt.thumbnail_queue = thumbnail::start_workers(&opt);
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let thumbnail_errors = || async {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/metrics")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            body.lines()
+                .find_map(|line| line.strip_prefix("i_thumbnail_errors_total "))
+                .unwrap()
+                .parse::<u64>()
+                .unwrap()
+        };
+        let before = thumbnail_errors().await;
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .method("POST")
+                    .header(CONTENT_TYPE, "multipart/form-data; boundary=boundary")
+                    .body(Body::from(
+                        "--boundary\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"broken.png\"\r\n\
+Content-Type: image/png\r\n\r\n\
+not really a png\r\n\
+--boundary--\r\n",
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+        // Other tests may fail thumbnails too, so only check that the counter increased.
+        for _ in 0..100 {
+            if thumbnail_errors().await > before {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        panic!("thumbnail error was not recorded");
+    }
+
+    #[tokio::test]
+    async fn cache_headers_on_thumbnails_and_files() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-cache-headers".into();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let mut png = Vec::new();
+        image::RgbImage::new(300, 200)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let upload = |options: &str| {
+            let mut body = format!(
+                "--boundary\r\n\
+Content-Disposition: form-data; name=\"options\"\r\n\r\n\
+{}\r\n\
+--boundary\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"image.png\"\r\n\
+Content-Type: image/png\r\n\r\n",
+                options
+            )
+            .into_bytes();
+            body.extend_from_slice(&png);
+            body.extend_from_slice(b"\r\n--boundary--\r\n");
+            Request::builder()
+                .uri("/")
+                .method("POST")
+                .header(CONTENT_TYPE, "multipart/form-data; boundary=boundary")
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let get = |uri: &str, etag: Option<&str>| {
+            let mut request = Request::builder().uri(uri);
+            if let Some(etag) = etag {
+                request = request.header(axum::http::header::IF_NONE_MATCH, etag);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        let response = app
+            .clone()
+            .oneshot(upload(r#"{"redirect":false}"#))
+            .await
+            .unwrap();
+        let body_bytes = response.into_body().collect().await.unwrap().to_bytes();
+        let uploaded: Value = serde_json::from_slice(&body_bytes).unwrap();
+        let url = uploaded["url"].as_str().unwrap();
+        let filename = url.rsplit('/').next().unwrap();
+
+        let response = get(&format!("/thumbnail/{}", filename), None)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            "public, max-age=31536000, immutable",
+            response.headers()[axum::http::header::CACHE_CONTROL]
+        );
+        let etag = response.headers()[axum::http::header::ETAG]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let response = get(&format!("/thumbnail/{}", filename), Some(&etag))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+        let response = get(&format!("/{}", filename), None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            "public, max-age=31536000, immutable",
+            response.headers()[axum::http::header::CACHE_CONTROL]
+        );
+        assert_eq!(
+            format!("\"{}\"", uploaded["hash"].as_str().unwrap()),
+            response.headers()[axum::http::header::ETAG]
+        );
+
+        // Files stored under their original name may be overwritten.
+        let response = app
+            .clone()
+            .oneshot(upload(r#"{"redirect":false,"useOriginalFilename":true}"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = get("/image.png", None).await.unwrap();
+        assert_eq!(
+            "public, max-age=300",
+            response.headers()[axum::http::header::CACHE_CONTROL]
+        );
+    }
+
+    #[tokio::test]
+    async fn recent_json_search() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-recent-search".into();
+        opt.recents = 15;
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        for name in ["Report-2024.pdf", "old-report.txt", "cat.png"] {
+            std::fs::write(Path::new(&opt.base_dir).join(name), name).unwrap();
+        }
+        let app = router(opt.base_dir.clone().into(), opt);
+        let search = |q: &str| {
+            let request = Request::builder()
+                .uri(format!("/recent.json?sort=name&q={}", q))
+                .body(Body::empty())
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                let body: Value = serde_json::from_slice(&body).unwrap();
+                body.as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|entry| entry["url"].as_str().unwrap().to_string())
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(
+            vec![
+                "http://test.example.com/Report-2024.pdf",
+                "http://test.example.com/old-report.txt"
+            ],
+            search("REPORT").await
+        );
+        assert!(search("nothing").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn recent_atom_feed() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-recent-atom".into();
+        opt.recents = 2;
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let now = std::time::SystemTime::now();
+        for (age, name) in [(3, "first.txt"), (2, "second.txt"), (1, "a&b.txt")] {
+            let path = Path::new(&opt.base_dir).join(name);
+            std::fs::write(&path, name).unwrap();
+            std::fs::File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(now - std::time::Duration::from_secs(age))
+                .unwrap();
+        }
+        let app = router(opt.base_dir.clone().into(), opt);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/recent.atom")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!("application/atom+xml", response.headers()[CONTENT_TYPE]);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let feed = String::from_utf8(body.to_vec()).unwrap();
+        assert!(feed.starts_with("<?xml"));
+        assert_eq!(2, feed.matches("<entry>").count());
+        assert!(feed.contains("<title>a&amp;b.txt</title>"));
+        assert!(!feed.contains("first.txt"));
+    }
+
+    #[tokio::test]
+    async fn bulk_delete() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-bulk-delete".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let mut uploads = vec![];
+        for _ in 0..2 {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/")
+                        .method("POST")
+                        .header(CONTENT_TYPE, "multipart/form-data; boundary=boundary")
+                        .body(
+                            "--boundary\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"file.txt\"\r\n\r\n\
+some text\r\n\
+--boundary--\r\n"
+                                .to_string(),
+                        )
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            let url = body["url"].as_str().unwrap();
+            let filename = url.rsplit('/').next().unwrap().to_string();
+            uploads.push((filename, body["deleteToken"].as_str().unwrap().to_string()));
+        }
+
+        // The form of the recent page repeats the field for every checked file.
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/delete-bulk")
+                    .method("POST")
+                    .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+                    .header(COOKIE, "i_csrf=secret")
+                    .body(format!(
+                        "tokens={}&tokens={}&tokens=unknown&csrf=secret",
+                        uploads[0].1, uploads[1].1
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(2, body["deleted"].as_array().unwrap().len());
+        assert_eq!(1, body["failed"].as_array().unwrap().len());
+        assert_eq!("unknown", body["failed"][0]["token"]);
+        for (filename, _) in &uploads {
+            assert!(!Path::new(&opt.base_dir).join(filename).exists());
+        }
+
+        // Deleting by filename is not allowed by default.
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/delete-bulk")
+                    .method("POST")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(r#"{"filenames":["file.txt"]}"#.to_string())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn delete_to_trash_and_restore() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-trash-restore".into();
+        opt.trash = true;
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/?redirect=false")
+                    .method("PUT")
+                    .header("X-Filename", "notes.txt")
+                    .body(Body::from("some notes"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let token = body["deleteToken"].as_str().unwrap();
+        let filename = body["url"].as_str().unwrap().rsplit('/').next().unwrap();
+        let file = Path::new(&opt.base_dir).join(filename);
+        let trashed = Path::new(&opt.base_dir).join(TRASH_SUBDIR).join(filename);
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/delete")
+                    .method("POST")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(format!(r#"{{"token":"{}"}}"#, token))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert!(!file.exists());
+        assert!(trashed.exists());
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/restore")
+                    .method("POST")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(format!(r#"{{"filename":"{}"}}"#, filename))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert!(file.exists());
+        assert!(!trashed.exists());
+    }
+
+    #[tokio::test]
+    async fn cors_preflight() {
+        let mut opt = make_test_opt();
+        opt.auth_user = Some("user".into());
+        opt.auth_pass = Some("pass".into());
+        let preflight = || {
+            Request::builder()
+                .uri("/")
+                .method("OPTIONS")
+                .header("Origin", "https://app.example.com")
+                .header("Access-Control-Request-Method", "POST")
+                .header("Access-Control-Request-Headers", "authorization")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // No CORS headers unless enabled.
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let response = app.oneshot(preflight()).await.unwrap();
+        assert!(!response
+            .headers()
+            .contains_key("access-control-allow-origin"));
+
+        // Preflight requests don't carry credentials, so they are answered before authentication.
+        opt.cors_allow_origin = vec!["https://app.example.com".into()];
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let response = app.oneshot(preflight()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            "https://app.example.com",
+            response.headers()["access-control-allow-origin"]
+        );
+        let allowed = response.headers()["access-control-allow-headers"]
+            .to_str()
+            .unwrap();
+        assert!(allowed.contains("authorization"));
+
+        assert!(parse_cors_origin("*").is_ok());
+        assert!(parse_cors_origin("not an origin").is_err());
+    }
+
+    #[test]
+    fn parse_host_accepts_ipv4_and_ipv6() {
+        assert_eq!(Ok([127, 0, 0, 1].into()), parse_host("127.0.0.1"));
+        assert_eq!(Ok(std::net::Ipv6Addr::LOCALHOST.into()), parse_host("::1"));
+        assert_eq!(
+            Ok(std::net::Ipv6Addr::UNSPECIFIED.into()),
+            parse_host("[::]")
+        );
+        assert!(parse_host("localhost").is_err());
+        assert!(parse_host("[127.0.0.1").is_err());
+    }
+
+    #[tokio::test]
+    async fn content_hash_naming() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-content-hash".into();
+        opt.naming = upload::Naming::ContentHash;
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let upload = |text: &'static str| {
+            app.clone().oneshot(
+                Request::builder()
+                    .uri("/?redirect=false")
+                    .method("PUT")
+                    .header("X-Filename", "notes.txt")
+                    .body(Body::from(text))
+                    .unwrap(),
+            )
+        };
+
+        // A different file already stored under the short hash makes it use a longer one.
+        let hash = format!("{:x}", Sha256::digest("same notes"));
+        std::fs::write(
+            Path::new(&opt.base_dir).join(format!("{}.txt", &hash[..8])),
+            "other",
+        )
+        .unwrap();
+
+        let mut responses = vec![];
+        for _ in 0..2 {
+            let response = upload("same notes").await.unwrap();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            responses.push(body);
+        }
+        let url = format!("http://test.example.com/{}.txt", &hash[..9]);
+        assert_eq!(url, responses[0]["url"]);
+        // Identical files are stored once, but each upload gets its own deletion token.
+        assert_eq!(url, responses[1]["url"]);
+        assert_ne!(responses[0]["deleteToken"], responses[1]["deleteToken"]);
+
+        let response = upload("other notes").await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_ne!(url, body["url"]);
+
+        // Files protected by a password or with limited downloads are never shared.
+        for options in ["viewPassword=hunter2", "maxDownloads=1"] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri(format!("/?redirect=false&{}", options))
+                        .method("PUT")
+                        .header("X-Filename", "notes.txt")
+                        .body(Body::from("same notes"))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            assert!(body["url"].as_str().unwrap().ends_with(".txt"));
+            assert_ne!(url, body["url"]);
+        }
+
+        // Deleting one upload of a shared file keeps it for the other.
+        let path = url.strip_prefix("http://test.example.com").unwrap();
+        for (token, status) in [
+            (&responses[0]["deleteToken"], StatusCode::OK),
+            (&responses[1]["deleteToken"], StatusCode::NOT_FOUND),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/delete")
+                        .method("POST")
+                        .header(CONTENT_TYPE, "application/json")
+                        .body(Body::from(
+                            serde_json::json!({ "token": token }).to_string(),
+                        ))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::SEE_OTHER);
+            let response = app
+                .clone()
+                .oneshot(Request::builder().uri(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), status);
+        }
+    }
+
+    #[tokio::test]
+    async fn qr_code_of_upload() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-qr".into();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/?redirect=false")
+                    .method("PUT")
+                    .header("X-Filename", "notes.txt")
+                    .body(Body::from("notes"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let url = body["url"].as_str().unwrap();
+        let qr_url = body["qrUrl"].as_str().unwrap();
+        let path = qr_url.strip_prefix("http://test.example.com").unwrap();
+        assert_eq!(url.replace("example.com/", "example.com/qr/"), qr_url);
+
+        let response = app
+            .clone()
+            .oneshot(Request::builder().uri(path).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!("image/svg+xml", response.headers()[CONTENT_TYPE]);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/qr/missing.txt")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn paste_text() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-paste".into();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        for (uri, content_type, body, extension) in [
+            ("/paste?redirect=false", "text/plain", "a log line", ".txt"),
+            (
+                "/paste?redirect=false",
+                "application/x-www-form-urlencoded",
+                "content=fn+main%28%29+%7B%7D&language=rs",
+                ".rs",
+            ),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri(uri)
+                        .method("POST")
+                        .header(CONTENT_TYPE, content_type)
+                        .body(body.to_string())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let body: Value = serde_json::from_slice(&body).unwrap();
+            let url = body["url"].as_str().unwrap();
+            assert!(url.ends_with(extension));
+        }
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/paste?language=../exe")
+                    .method("POST")
+                    .body("text".to_string())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn code_is_highlighted_in_browsers() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-highlight".into();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        std::fs::write(
+            format!("{}/hello.rs", opt.base_dir),
+            "fn main() { println!(\"hi\"); }\n",
+        )
+        .unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let get = |uri: &str, accept: &str| {
+            Request::builder()
+                .uri(uri)
+                .header(ACCEPT, accept)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(get("/hello.rs", "text/html,*/*;q=0.8"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers()[CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("<pre"));
+        assert!(body.contains("hello.rs"));
+
+        for (uri, accept) in [("/hello.rs?raw=1", "text/html"), ("/hello.rs", "*/*")] {
+            let response = app.clone().oneshot(get(uri, accept)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(&body[..], b"fn main() { println!(\"hi\"); }\n");
+        }
+    }
+
+    #[tokio::test]
+    async fn password_protected_upload() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-protected".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let response = app
+            .clone()
+            .oneshot(custom_filename_request(
+                r#"{"filename":"numbers.txt","viewPassword":"hunter2"}"#,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+        let get = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+        for uri in ["/numbers.txt", "/numbers.txt?pw=wrong"] {
+            let response = app.clone().oneshot(get(uri)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            assert!(body.contains("protected by a password"));
+            assert!(!body.contains("quarterly numbers"));
+        }
+
+        let response = app
+            .clone()
+            .oneshot(get("/numbers.txt?pw=hunter2"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[axum::http::header::CACHE_CONTROL],
+            "private, no-store"
+        );
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"quarterly numbers");
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/numbers.txt")
+                    .header(AUTHORIZATION, "Basic Omh1bnRlcjI=") // :hunter2
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app.oneshot(get("/recent.json")).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(Some(true), body[0]["protected"].as_bool());
+    }
+
+    #[tokio::test]
+    async fn wrong_view_passwords_rate_limited() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-protected-rate-limit".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(Path::new(&opt.base_dir).join(META_SUBDIR)).unwrap();
+        std::fs::write(Path::new(&opt.base_dir).join("numbers.txt"), "numbers").unwrap();
+        let meta = serde_json::json!({ "viewPasswordHash": bcrypt::hash("hunter2", 4).unwrap() });
+        std::fs::write(
+            Path::new(&opt.base_dir)
+                .join(META_SUBDIR)
+                .join("numbers.txt.json"),
+            meta.to_string(),
+        )
+        .unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let get = |client: [u8; 4], password: &str| {
+            app.clone().oneshot(
+                Request::builder()
+                    .uri(format!("/numbers.txt?pw={}", password))
+                    .extension(axum::extract::ConnectInfo(SocketAddr::from((client, 1234))))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        // The right password can be given as often as needed.
+        for _ in 0..15 {
+            let response = get([192, 0, 2, 1], "hunter2").await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        for _ in 0..10 {
+            let response = get([192, 0, 2, 1], "wrong").await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        let response = get([192, 0, 2, 1], "wrong").await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.headers().get(RETRY_AFTER).is_some());
+        let response = get([192, 0, 2, 1], "hunter2").await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Other clients are not affected.
+        let response = get([192, 0, 2, 2], "hunter2").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn limited_downloads() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-limited-downloads".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let request = |method: &str, uri: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let upload = |filename: &str, max_downloads: u64| {
+            let options = format!(
+                r#"{{"filename":"{}","maxDownloads":{}}}"#,
+                filename, max_downloads
+            );
+            app.clone().oneshot(custom_filename_request(&options))
+        };
+
+        let response = upload("twice.txt", 2).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let response = app.clone().oneshot(request("HEAD", "/twice.txt")).await;
+        assert_eq!(response.unwrap().status(), StatusCode::OK);
+        for _ in 0..2 {
+            let response = app.clone().oneshot(request("GET", "/twice.txt")).await;
+            let response = response.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(&body[..], b"quarterly numbers");
+        }
+        let response = app.clone().oneshot(request("GET", "/twice.txt")).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NOT_FOUND);
+        assert!(!Path::new(&opt.base_dir).join("twice.txt").exists());
+
+        // Ranges are ignored, so that a range covering the whole file can't get around the limit.
+        let response = upload("video.txt", 1).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let mut range_request = request("GET", "/video.txt");
+        range_request
+            .headers_mut()
+            .insert(RANGE, HeaderValue::from_static("bytes=0-"));
+        let response = app.clone().oneshot(range_request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"quarterly numbers");
+        let response = app.clone().oneshot(request("GET", "/video.txt")).await;
+        assert_eq!(response.unwrap().status(), StatusCode::NOT_FOUND);
+
+        // Only one of several concurrent downloads gets the file.
+        let response = upload("once.txt", 1).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let responses = futures::future::join_all(
+            (0..5).map(|_| app.clone().oneshot(request("GET", "/once.txt"))),
+        )
+        .await;
+        let served = responses
+            .into_iter()
+            .filter(|response| response.as_ref().unwrap().status() == StatusCode::OK)
+            .count();
+        assert_eq!(1, served);
+
+        let response = upload("never.txt", 0).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn oversized_image_is_rejected() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-type-limits".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        opt.type_limits = vec!["image/*=16".parse().unwrap()];
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let upload = |filename: &str| {
+            Request::builder()
+                .uri("/")
+                .method("POST")
+                .header(CONTENT_TYPE, "multipart/form-data; boundary=boundary")
+                .body(Body::from(
+                    format!(
+                        r#"--boundary
+Content-Disposition: form-data; name="file"; filename="{}"
+
+more than sixteen bytes
+--boundary
+Content-Disposition: form-data; name="options"
+
+{{"redirect":false}}
+--boundary--
+"#,
+                        filename
+                    )
+                    .replace('\n', "\r\n"),
+                ))
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(upload("cat.png")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(
+            &body[..],
+            b"upload is larger than the limit of 16 for image/*"
+        );
+        let staging_dir = storage::backend(&opt).staging_dir();
+        let staged = std::fs::read_dir(&staging_dir).map_or(0, |dir| dir.count());
+        assert_eq!(0, staged);
+
+        let response = app.oneshot(upload("notes.txt")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn zip_named_png_is_rejected() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-zip-named-png".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        opt.enforce_content_type = sniff::ContentTypeCheck::Lenient;
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/?redirect=false")
+                    .method("PUT")
+                    .header(upload::X_FILENAME.clone(), "cat.png")
+                    .body(Body::from(&b"PK\x03\x04\x14\0\0\0\x08\0not a cat"[..]))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(
+            &body[..],
+            b"content of type application/zip does not match .png"
+        );
+        let staging_dir = storage::backend(&opt).staging_dir();
+        let staged = std::fs::read_dir(&staging_dir).map_or(0, |dir| dir.count());
+        assert_eq!(0, staged);
+    }
+
+    #[tokio::test]
+    async fn webhook_on_upload_and_delete() {
+        // The first delivery fails, so that it has to be retried.
+        let (events, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let failed = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let hook = Router::new().route(
+            "/hook",
+            post(move |axum::Json(event): axum::Json<Value>| async move {
+                if !failed.swap(true, std::sync::atomic::Ordering::SeqCst) {
+                    return StatusCode::INTERNAL_SERVER_ERROR;
+                }
+                events.send(event).unwrap();
+                StatusCode::OK
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, hook).await });
+
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-webhook".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        opt.webhook_url = Some(format!("http://{}/hook", addr).parse().unwrap());
+        opt.webhooks = webhook::start_worker(&opt);
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let response = app
+            .clone()
+            .oneshot(custom_filename_request(r#"{"filename":"hooked.txt"}"#))
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let token = body["deleteToken"].as_str().unwrap();
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/delete")
+                    .method("POST")
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(format!(r#"{{"token":"{}"}}"#, token))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+        let timeout = Duration::from_secs(10);
+        let upload = tokio::time::timeout(timeout, received.recv()).await;
+        let upload = upload.unwrap().unwrap();
+        assert_eq!("upload", upload["event"]);
+        assert_eq!("http://test.example.com/hooked.txt", upload["url"]);
+        assert_eq!("hooked.txt", upload["filename"]);
+        assert_eq!(17, upload["size"]);
+        let delete = tokio::time::timeout(timeout, received.recv()).await;
+        let delete = delete.unwrap().unwrap();
+        assert_eq!("delete", delete["event"]);
+        assert_eq!("hooked.txt", delete["filename"]);
+    }
+
+    #[tokio::test]
+    async fn stats_are_cached() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-stats-cached".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        std::fs::write(Path::new(&opt.base_dir).join("first.txt"), "first").unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let stats = |app: Router| async move {
+            let response = app
+                .oneshot(
+                    Request::builder()
+                        .uri("/admin/stats")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            serde_json::from_slice::<Value>(&body).unwrap()
+        };
+
+        let first = stats(app.clone()).await;
+        assert_eq!(1, first["files"]);
+        assert_eq!(5, first["bytes"]);
+        assert_eq!(1, first["byExtension"]["txt"]["files"]);
+
+        std::fs::write(Path::new(&opt.base_dir).join("second.txt"), "second").unwrap();
+        let cached = stats(app).await;
+        assert_eq!(first, cached);
+    }
+
+    #[tokio::test]
+    async fn upload_is_rejected_when_disk_is_full() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-disk-full".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        opt.min_free_bytes = u64::MAX;
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let response = app
+            .oneshot(custom_filename_request(r#"{"filename":"full.txt"}"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+        assert!(!Path::new(&opt.base_dir).join("full.txt").exists());
+        let staging = Path::new(&opt.base_dir).join(".staging");
+        assert_eq!(0, std::fs::read_dir(staging).unwrap().count());
+    }
+
+    #[tokio::test]
+    async fn index_shows_upload_form_to_browsers() {
+        let opt = make_test_opt();
+        let app = router("/tmp".into(), opt);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .header(ACCEPT, "text/html,application/xhtml+xml,*/*;q=0.8")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[VARY], "accept");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#"action="http://test.example.com/""#));
+        assert!(body.contains(r#"type="file" name="file""#));
+        assert!(body.contains("use-original-filename"));
+        assert!(body.contains("http://test.example.com/recent/upload.js"));
+    }
+
+    #[tokio::test]
+    async fn upload_script_without_auth() {
+        let mut opt = make_test_opt();
+        opt.auth_user = Some("user".to_string());
+        opt.auth_pass = Some("pass".to_string());
+        let app = router("/tmp".into(), opt);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/recent/upload.js")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[CONTENT_TYPE], "text/javascript");
+    }
+
+    #[tokio::test]
+    async fn recent_page_has_copy_buttons() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-recent-copy".into();
+        opt.recents = 10;
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        image::RgbImage::new(10, 10)
+            .save(Path::new(&opt.base_dir).join("image.png"))
+            .unwrap();
+        std::fs::write(Path::new(&opt.base_dir).join("notes.txt"), "notes").unwrap();
+        let app = router(opt.base_dir.clone().into(), opt);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/recent")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#"data-url="image.png" data-name="image.png" data-image="true""#));
+        assert!(body.contains(r#"data-url="notes.txt" data-name="notes.txt" data-image="false""#));
+        assert!(body.contains(r#"src="recent/copy.js""#));
+    }
+
+    #[tokio::test]
+    async fn openapi_without_auth() {
+        let mut opt = make_test_opt();
+        opt.auth_user = Some("user".to_string());
+        opt.auth_pass = Some("pass".to_string());
+        let app = router("/tmp".into(), opt);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/openapi.json")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let openapi: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!("http://test.example.com", openapi["servers"][0]["url"]);
+        let upload = &openapi["paths"]["/"]["post"]["requestBody"]["content"];
+        assert!(upload["multipart/form-data"].is_object());
+        let options = &openapi["components"]["schemas"]["Options"]["properties"];
+        assert!(options["useOriginalFilename"].is_object());
+        assert!(options["maxDownloads"].is_object());
+        for path in ["/delete", "/delete-bulk", "/recent.json", "/exists/{name}"] {
+            assert!(openapi["paths"][path].is_object(), "{} is missing", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn rate_limit_behind_trusted_proxy() {
+        let mut opt = make_test_opt();
+        opt.rate_limit = Some(1);
+        opt.trusted_proxies = vec!["10.0.0.0/8".parse().unwrap()];
+        let app = router("/tmp".into(), opt);
+        let upload = |peer: [u8; 4], forwarded_for: &str| {
+            let mut request = custom_filename_request("{}");
+            request
+                .extensions_mut()
+                .insert(axum::extract::ConnectInfo(SocketAddr::from((peer, 1234))));
+            request
+                .headers_mut()
+                .insert("x-forwarded-for", forwarded_for.parse().unwrap());
+            app.clone().oneshot(request)
+        };
+
+        // Clients behind the proxy are limited separately.
+        let response = upload([10, 0, 0, 1], "192.0.2.1").await.unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let response = upload([10, 0, 0, 1], "192.0.2.2").await.unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let response = upload([10, 0, 0, 1], "192.0.2.1").await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Others can't pretend to be someone else.
+        let response = upload([198, 51, 100, 1], "192.0.2.3").await.unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        let response = upload([198, 51, 100, 1], "192.0.2.4").await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn malformed_uploads_are_rejected() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-malformed".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let file = "--boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\r\ncontent\r\n";
+        let options = |json: &str| {
+            format!(
+                "--boundary\r\nContent-Disposition: form-data; name=\"options\"\r\n\r\n{json}\r\n"
+            )
+        };
+
+        for (body, message) in [
+            (
+                format!("{}--boundary--\r\n", options("{}")),
+                "no file field found in the upload",
+            ),
+            (
+                format!("{}{}--boundary--\r\n", file, options("{\"redirect\":")),
+                "invalid upload options: EOF while parsing a value",
+            ),
+            (
+                "--boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\r\ntruncat".to_string(),
+                "malformed multipart body",
+            ),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/")
+                        .method("POST")
+                        .header(CONTENT_TYPE, "multipart/form-data; boundary=boundary")
+                        .body(body)
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            assert!(body.starts_with(message), "{} is not {}", body, message);
+        }
+        let staging = Path::new(&opt.base_dir).join(".staging");
+        assert_eq!(0, std::fs::read_dir(staging).unwrap().count());
+    }
+
+    #[tokio::test]
+    async fn interrupted_uploads_are_discarded() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-interrupted".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        // The client sends the start of the file, and then the connection breaks.
+        let interrupted = |start: &'static str| {
+            Body::from_stream(futures::stream::iter([
+                Ok(axum::body::Bytes::from(start)),
+                Err(std::io::Error::from(std::io::ErrorKind::ConnectionReset)),
+            ]))
+        };
+
+        let multipart = Request::builder()
+            .uri("/")
+            .method("POST")
+            .header(CONTENT_TYPE, "multipart/form-data; boundary=boundary")
+            .body(interrupted(
+                "--boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\r\nthe start",
+            ))
+            .unwrap();
+        let raw = Request::builder()
+            .uri("/")
+            .method("PUT")
+            .header("x-filename", "a.txt")
+            .body(interrupted("the start"))
+            .unwrap();
+        for request in [multipart, raw] {
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+
+        let staging = Path::new(&opt.base_dir).join(".staging");
+        assert_eq!(0, std::fs::read_dir(staging).unwrap().count());
+        assert!(!Path::new(&opt.base_dir).join("a.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn file_without_filename_is_named_randomly() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-unnamed".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let request = |options: &str| {
+            Request::builder()
+                .uri("/")
+                .method("POST")
+                .header(CONTENT_TYPE, "multipart/form-data; boundary=boundary")
+                .body(format!(
+                    "--boundary\r\nContent-Disposition: form-data; name=\"file\"\r\nContent-Type: text/plain\r\n\r\nno name\r\n\
+                     --boundary\r\nContent-Disposition: form-data; name=\"options\"\r\n\r\n{options}\r\n--boundary--\r\n"
+                ))
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request("{\"redirect\":false}"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let url = json["url"].as_str().unwrap();
+        assert!(url.ends_with(".txt"), "{} has no extension", url);
+        let name = url.rsplit('/').next().unwrap();
+        assert_eq!(
+            "no name",
+            std::fs::read_to_string(Path::new(&opt.base_dir).join(name)).unwrap()
+        );
+
+        // The original filename can't be kept when there is none.
+        let response = app
+            .oneshot(request("{\"useOriginalFilename\":true}"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let staging = Path::new(&opt.base_dir).join(".staging");
+        assert_eq!(0, std::fs::read_dir(staging).unwrap().count());
+    }
+
+    #[tokio::test]
+    async fn recent_listing_is_cached_until_upload() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-recent-cached".into();
+        opt.recents = 10;
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        std::fs::write(Path::new(&opt.base_dir).join("first.txt"), "first").unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let recents = |app: Router| async move {
+            let response = app
+                .oneshot(
+                    Request::builder()
+                        .uri("/recent.json")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            serde_json::from_slice::<Vec<Value>>(&body).unwrap().len()
+        };
+        assert_eq!(1, recents(app.clone()).await);
+
+        // Files added behind our back are only seen once the listing expires.
+        std::fs::write(Path::new(&opt.base_dir).join("second.txt"), "second").unwrap();
+        assert_eq!(1, recents(app.clone()).await);
+
+        let response = app
+            .clone()
+            .oneshot(custom_filename_request(r#"{"filename":"third.txt"}"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(3, recents(app).await);
+    }
+
+    #[tokio::test]
+    async fn routes_below_base_path() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-base-path".into();
+        opt.base_path = parse_base_path("/i/").unwrap();
+        opt.recents = 10;
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let get = |uri: &str| {
+            app.clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        };
+
+        let mut request = custom_filename_request(r#"{"filename":"report.txt"}"#);
+        *request.uri_mut() = "/i/".parse().unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            "http://test.example.com/i/report.txt",
+            response.headers()[LOCATION]
+        );
+
+        let response = get("/i/report.txt").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"quarterly numbers");
+
+        let response = get("/i/recent.json").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let recents: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!("http://test.example.com/i/report.txt", recents[0]["url"]);
+        assert_eq!(
+            "http://test.example.com/i/thumbnail/report.txt?size=150",
+            recents[0]["thumbnailUrl"]
+        );
+
+        for path in ["/i/healthz", "/i/recent/placeholder.png"] {
+            assert_eq!(
+                get(path).await.unwrap().status(),
+                StatusCode::OK,
+                "{}",
+                path
+            );
+        }
+        for path in ["/healthz", "/report.txt"] {
+            let response = get(path).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+        assert!(parse_base_path("/").unwrap().is_empty());
+        assert!(parse_base_path("/i//x").is_err());
+    }
+
+    #[tokio::test]
+    async fn thumbnail_urls_of_nested_files() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-nested-thumbnails".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        opt.users_file = Some("users.json".into());
+        opt.users = std::sync::Arc::new(
+            [("alice".into(), bcrypt::hash("secret", 4).unwrap())]
+                .into_iter()
+                .collect(),
+        );
+        let user_dir = Path::new(&opt.base_dir).join("alice");
+        std::fs::create_dir_all(user_dir.join("nested")).unwrap();
+        image::RgbImage::new(300, 200)
+            .save(user_dir.join("image.png"))
+            .unwrap();
+        // Only files directly in the directory of the user are uploads.
+        image::RgbImage::new(300, 200)
+            .save(user_dir.join("nested").join("other.png"))
+            .unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/recent.json")
+                    .header(AUTHORIZATION, "Basic YWxpY2U6c2VjcmV0")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let recents: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(1, recents.as_array().unwrap().len());
+        let thumbnail_url = recents[0]["thumbnailUrl"].as_str().unwrap();
+        assert_eq!(
+            "http://test.example.com/thumbnail/alice/image.png?size=150",
+            thumbnail_url
+        );
+
+        // The linked thumbnail is generated in the thumbnail directory of the user.
+        let path = thumbnail_url.trim_start_matches("http://test.example.com");
+        let response = app
+            .oneshot(Request::builder().uri(path).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!("image/webp", response.headers()[CONTENT_TYPE]);
+        assert!(user_dir
+            .join(THUMBNAIL_SUBDIR)
+            .join("image.png_150.webp")
+            .exists());
+    }
+
+    #[tokio::test]
+    async fn long_original_filename_is_shortened() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-long-filename".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let filename = format!("{}.txt", "a".repeat(296));
+        let upload = |options: String| {
+            Request::builder()
+                .uri("/")
+                .method("POST")
+                .header(CONTENT_TYPE, "multipart/form-data; boundary=boundary")
+                .body(format!(
+                    "--boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{filename}\"\r\n\r\nlong\r\n\
+                     --boundary\r\nContent-Disposition: form-data; name=\"options\"\r\n\r\n{options}\r\n--boundary--\r\n"
+                ))
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(upload(
+                r#"{"useOriginalFilename":true,"redirect":false}"#.to_string(),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        let stored = format!("{}.txt", "a".repeat(196));
+        assert_eq!(format!("http://test.example.com/{}", stored), json["url"]);
+        assert!(Path::new(&opt.base_dir).join(&stored).exists());
+
+        // A requested filename is used as is, or not at all.
+        let response = app
+            .oneshot(upload(format!(r#"{{"filename":"{}"}}"#, filename)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"filename is longer than 200 bytes");
+    }
+
+    #[tokio::test]
+    async fn upload_response_has_id_and_time() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-upload-id".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let before = chrono::Utc::now();
+
+        let response = app
+            .oneshot(custom_filename_request(r#"{"redirect":false}"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+
+        let id = json["id"].as_str().unwrap();
+        assert_eq!(opt.slug_length, id.len());
+        assert_eq!(
+            format!("http://test.example.com/{}.txt", id),
+            json["url"].as_str().unwrap()
+        );
+        let uploaded_at =
+            chrono::DateTime::parse_from_rfc3339(json["uploadedAt"].as_str().unwrap()).unwrap();
+        assert!(uploaded_at >= before);
+        assert!(uploaded_at <= chrono::Utc::now());
+    }
+
+    #[tokio::test]
+    async fn recent_can_be_disabled() {
+        for disable_recent in [false, true] {
+            let mut opt = make_test_opt();
+            opt.base_dir = "/tmp/i-test-disable-recent".into();
+            std::fs::create_dir_all(&opt.base_dir).unwrap();
+            opt.disable_recent = disable_recent;
+            let app = router(opt.base_dir.clone().into(), opt.clone());
+
+            let expected = match disable_recent {
+                true => StatusCode::NOT_FOUND,
+                false => StatusCode::OK,
+            };
+            for path in ["/recent", "/recent.json", "/recent.atom", "/gallery"] {
+                let response = app
+                    .clone()
+                    .oneshot(Request::builder().uri(path).body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), expected, "{}", path);
+            }
+
+            // Uploading still works.
+            let response = app
+                .oneshot(custom_filename_request(r#"{"redirect":false}"#))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn auth_for_upload_and_view_separately() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-auth-scopes".into();
+        opt.recents = 10;
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        std::fs::write(Path::new(&opt.base_dir).join("shared.txt"), "shared").unwrap();
+        opt.auth_user = Some("user".into());
+        opt.auth_pass = Some("pass".into());
+        // "user:pass"
+        let credentials = "Basic dXNlcjpwYXNz";
+
+        let status = |app: Router, uri: &str, authorized: bool| {
+            let mut request = Request::builder().uri(uri);
+            if authorized {
+                request = request.header(AUTHORIZATION, credentials);
+            }
+            let request = request.body(Body::empty()).unwrap();
+            async move { app.oneshot(request).await.unwrap().status() }
+        };
+        let upload = |app: Router, authorized: bool| {
+            let mut request = custom_filename_request(r#"{"redirect":false}"#);
+            if authorized {
+                request
+                    .headers_mut()
+                    .insert(AUTHORIZATION, HeaderValue::from_static(credentials));
+            }
+            async move { app.oneshot(request).await.unwrap().status() }
+        };
+
+        // A public gallery, that only those with credentials can add to.
+        opt.auth_upload = true;
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        assert_eq!(StatusCode::UNAUTHORIZED, upload(app.clone(), false).await);
+        assert_eq!(StatusCode::OK, upload(app.clone(), true).await);
+        for uri in ["/recent.json", "/shared.txt", "/thumbnail/shared.txt"] {
+            assert_eq!(
+                StatusCode::OK,
+                status(app.clone(), uri, false).await,
+                "{}",
+                uri
+            );
+        }
+        let response = status(app.clone(), "/admin/stats", false).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, response);
+
+        // A drop box, where anyone can upload, but only those with credentials can look.
+        opt.auth_upload = false;
+        opt.auth_view = true;
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        assert_eq!(StatusCode::OK, upload(app.clone(), false).await);
+        for uri in ["/recent.json", "/shared.txt", "/thumbnail/shared.txt"] {
+            let response = status(app.clone(), uri, false).await;
+            assert_eq!(StatusCode::UNAUTHORIZED, response, "{}", uri);
+            assert_eq!(
+                StatusCode::OK,
+                status(app.clone(), uri, true).await,
+                "{}",
+                uri
+            );
+        }
+        let response = status(app.clone(), "/admin/stats", false).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, response);
+    }
+
+    #[tokio::test]
+    async fn errors_as_json() {
+        let mut opt = make_test_opt();
+        opt.auth_user = Some("user".into());
+        opt.auth_pass = Some("pass".into());
+        let app = router("/tmp".into(), opt);
+        let request = |accept: &str| {
+            Request::builder()
+                .uri("/recent.json")
+                .header(ACCEPT, accept)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request("application/json"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(response.headers().contains_key(WWW_AUTHENTICATE));
+        assert_eq!("application/json", response.headers()[CONTENT_TYPE]);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            serde_json::json!({"error": "unauthorized", "code": "AUTHENTICATION_FAILED"}),
+            json
+        );
+
+        let response = app.oneshot(request("text/html")).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"unauthorized");
+
+        // Messages with details keep them.
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-json-errors".into();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let app = router(opt.base_dir.clone().into(), opt);
+        let mut request = custom_filename_request(r#"{"redirect":"#);
+        request
+            .headers_mut()
+            .insert(ACCEPT, HeaderValue::from_static("application/json"));
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!("INVALID_OPTIONS", json["code"]);
+        assert!(json["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid upload options: "));
+    }
+
+    #[tokio::test]
+    async fn chunked_upload_over_limit_is_rejected() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-chunked-limit".into();
+        opt.max_upload_size = 64 * 1024;
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        // Bodies without a length, which go on for much longer than the limit.
+        let chunks = |head: &'static str| {
+            let chunks = (0..1024).map(|_| axum::body::Bytes::from(vec![b'a'; 8 * 1024]));
+            let chunks = std::iter::once(axum::body::Bytes::from(head)).chain(chunks);
+            Body::from_stream(futures::stream::iter(chunks.map(Ok::<_, std::io::Error>)))
+        };
+
+        let raw = Request::builder()
+            .uri("/")
+            .method("PUT")
+            .header("x-filename", "big.txt")
+            .body(chunks(""))
+            .unwrap();
+        let multipart = Request::builder()
+            .uri("/")
+            .method("POST")
+            .header(CONTENT_TYPE, "multipart/form-data; boundary=boundary")
+            .body(chunks(
+                "--boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"big.txt\"\r\n\r\n",
+            ))
+            .unwrap();
+        for request in [raw, multipart] {
+            assert!(!request.headers().contains_key(CONTENT_LENGTH));
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        }
+
+        let staging = Path::new(&opt.base_dir).join(".staging");
+        assert_eq!(0, std::fs::read_dir(staging).unwrap().count());
+        assert!(!Path::new(&opt.base_dir).join("big.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn exists_tells_whether_filename_is_taken() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-exists".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(Path::new(&opt.base_dir).join(THUMBNAIL_SUBDIR)).unwrap();
+        std::fs::write(Path::new(&opt.base_dir).join("taken.txt"), "taken").unwrap();
+        opt.auth_user = Some("user".into());
+        opt.auth_pass = Some("pass".into());
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let status = |method: &str, uri: &str| {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                // "user:pass"
+                .header(AUTHORIZATION, "Basic dXNlcjpwYXNz")
+                .body(Body::empty())
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                assert!(body.is_empty());
+                status
+            }
+        };
+
+        assert_eq!(StatusCode::OK, status("GET", "/exists/taken.txt").await);
+        assert_eq!(StatusCode::OK, status("HEAD", "/exists/taken.txt").await);
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            status("GET", "/exists/free.txt").await
+        );
+        // Names are sanitized like requested filenames, and never match anything but uploads.
+        assert_eq!(StatusCode::OK, status("GET", "/exists/ta%3Aken.txt").await);
+        let uri = "/exists/..%2Ftaken.txt";
+        assert_eq!(StatusCode::NOT_FOUND, status("GET", uri).await);
+        let uri = format!("/exists/{}", THUMBNAIL_SUBDIR);
+        assert_eq!(StatusCode::NOT_FOUND, status("GET", &uri).await);
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            status("GET", "/exists/.staging").await
+        );
+
+        // It reveals which files exist, so it needs the same credentials as uploading.
+        let request = Request::builder()
+            .uri("/exists/taken.txt")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+    }
+
+    #[tokio::test]
+    async fn meta_of_uploaded_file() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-meta".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        std::fs::write(Path::new(&opt.base_dir).join("plain.txt"), "plain").unwrap();
+        opt.auth_user = Some("user".into());
+        opt.auth_pass = Some("pass".into());
+        // "user:pass"
+        let credentials = HeaderValue::from_static("Basic dXNlcjpwYXNz");
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let mut request = custom_filename_request(
+            r#"{"filename":"report.txt","viewPassword":"secret","maxDownloads":3,"redirect":false}"#,
+        );
+        request
+            .headers_mut()
+            .insert(AUTHORIZATION, credentials.clone());
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let uploaded: Value = serde_json::from_slice(&body).unwrap();
+
+        let meta = |uri: &str, authorized: bool| {
+            let mut request = Request::builder().uri(uri);
+            if authorized {
+                request = request.header(AUTHORIZATION, credentials.clone());
+            }
+            let request = request.body(Body::empty()).unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                (status, serde_json::from_slice::<Value>(&body).ok())
+            }
+        };
+
+        let (status, meta_json) = meta("/meta/report.txt", true).await;
+        assert_eq!(StatusCode::OK, status);
+        let meta_json = meta_json.unwrap();
+        assert_eq!(uploaded["deleteToken"], meta_json["deleteToken"]);
+        assert_eq!(3, meta_json["maxDownloads"]);
+        assert_eq!(true, meta_json["protected"]);
+        assert!(meta_json.get("viewPasswordHash").is_none());
+
+        // Files stored without metadata have empty metadata.
+        let (status, meta_json) = meta("/meta/plain.txt", true).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(serde_json::json!({"protected": false}), meta_json.unwrap());
+        let (status, _) = meta("/meta/missing.txt", true).await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+        let (status, _) = meta("/meta/report.txt", false).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, status);
+
+        // Deleting the file also deletes its metadata.
+        let request = Request::builder()
+            .uri("/delete")
+            .method("POST")
+            .header(CONTENT_TYPE, "application/json")
+            .header(AUTHORIZATION, credentials.clone())
+            .body(Body::from(
+                serde_json::json!({ "token": uploaded["deleteToken"] }).to_string(),
+            ))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        assert!(response.status().is_success() || response.status().is_redirection());
+        let (status, _) = meta("/meta/report.txt", true).await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+        let sidecar = Path::new(&opt.base_dir)
+            .join(META_SUBDIR)
+            .join("report.txt.json");
+        assert!(!sidecar.exists());
+    }
+
+    #[tokio::test]
+    async fn content_disposition_of_served_files() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-disposition".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let base_dir = Path::new(&opt.base_dir);
+        std::fs::write(base_dir.join("drawing.svg"), "<svg></svg>").unwrap();
+        std::fs::write(base_dir.join("notes.txt"), "notes").unwrap();
+        std::fs::write(base_dir.join("résumé.html"), "<p>hi</p>").unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let disposition = |uri: &str| {
+            let request = Request::builder()
+                .uri(uri)
+                .header(ACCEPT, "text/html,*/*;q=0.8")
+                .body(Body::empty())
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(StatusCode::OK, response.status());
+                response.headers()[CONTENT_DISPOSITION].clone()
+            }
+        };
+
+        // Types that could run scripts are always downloaded.
+        let svg = disposition("/drawing.svg").await;
+        assert_eq!("attachment; filename=\"drawing.svg\"", svg);
+        assert_eq!("inline", disposition("/notes.txt").await);
+        assert_eq!(
+            "attachment; filename=\"r_sum_.html\"; filename*=UTF-8''r%C3%A9sum%C3%A9%2Ehtml",
+            disposition("/r%C3%A9sum%C3%A9.html").await
+        );
+
+        // Anything else is downloaded when asked for, and then isn't highlighted either.
+        for (filename, content_type) in [("report.txt", "text/plain"), ("main.rs", "text/x-rust")] {
+            let request = custom_filename_request(&format!(
+                r#"{{"filename":"{}","forceDownload":true,"redirect":false}}"#,
+                filename
+            ));
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(StatusCode::OK, response.status());
+            let expected = format!("attachment; filename=\"{}\"", filename);
+            assert_eq!(expected, disposition(&format!("/{}", filename)).await);
+
+            let request = Request::builder()
+                .uri(format!("/{}", filename))
+                .header(ACCEPT, "text/html")
+                .body(Body::empty())
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            let served_type = response.headers()[CONTENT_TYPE].to_str().unwrap();
+            assert!(served_type.starts_with(content_type), "{}", served_type);
+        }
+    }
+
+    #[tokio::test]
+    async fn svg_uploads_are_sanitized() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-sanitize-svg".into();
+        opt.sanitize_svg = true;
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let malicious = r#"<svg xmlns="http://www.w3.org/2000/svg"><script>alert(document.cookie)</script><circle r="5" onmouseover="alert(1)"/></svg>"#;
+        let upload = |app: Router| {
+            let request = Request::builder()
+                .uri("/?redirect=false")
+                .method("PUT")
+                .header("x-filename", "drawing.svg")
+                .body(Body::from(malicious))
+                .unwrap();
+            async move { app.oneshot(request).await.unwrap() }
+        };
+
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let response = upload(app).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let uploaded: Value = serde_json::from_slice(&body).unwrap();
+        let url = uploaded["url"].as_str().unwrap();
+        let filename = url.rsplit('/').next().unwrap();
+        let stored = std::fs::read_to_string(Path::new(&opt.base_dir).join(filename)).unwrap();
+        assert!(!stored.contains("script"), "{}", stored);
+        assert!(!stored.contains("alert"), "{}", stored);
+        assert!(stored.contains(r#"<circle r="5"/>"#), "{}", stored);
+        // The response describes the file as it was stored.
+        assert_eq!(stored.len() as u64, uploaded["size"].as_u64().unwrap());
+
+        opt.svg_policy = svg::SvgPolicy::Reject;
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let response = upload(app).await;
+        assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, response.status());
+        let staging = Path::new(&opt.base_dir).join(".staging");
+        assert_eq!(0, std::fs::read_dir(staging).unwrap().count());
+    }
+
+    #[tokio::test]
+    async fn multipart_field_limits() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-field-limits".into();
+        opt.max_field_size = 1024;
+        opt.max_fields = 3;
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let upload = |options: &[String]| {
+            let mut body = "--boundary\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\r\nsmall file\r\n".to_string();
+            for options in options {
+                body += &format!(
+                    "--boundary\r\nContent-Disposition: form-data; name=\"options\"\r\n\r\n{}\r\n",
+                    options
+                );
+            }
+            body += "--boundary--\r\n";
+            let request = Request::builder()
+                .uri("/")
+                .method("POST")
+                .header(CONTENT_TYPE, "multipart/form-data; boundary=boundary")
+                .header(ACCEPT, "application/json")
+                .body(Body::from(body))
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                (status, serde_json::from_slice::<Value>(&body).unwrap())
+            }
+        };
+
+        let options = r#"{"redirect":false}"#.to_string();
+        let (status, _) = upload(&[options.clone(), options.clone()]).await;
+        assert_eq!(StatusCode::OK, status);
+
+        // Options are read into memory, so they can't be larger than the limit.
+        let padded = format!(r#"{{"redirect":false,"filename":"{}"}}"#, "a".repeat(2048));
+        let (status, error) = upload(&[padded]).await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        assert_eq!("FIELD_TOO_LARGE", error["code"]);
+
+        let (status, error) = upload(&vec![options; 3]).await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        assert_eq!("TOO_MANY_FIELDS", error["code"]);
+        let staging = Path::new(&opt.base_dir).join(".staging");
+        assert_eq!(0, std::fs::read_dir(staging).unwrap().count());
+    }
+
+    #[tokio::test]
+    async fn info_of_uploaded_files() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-info".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        opt.auth_user = Some("user".into());
+        opt.auth_pass = Some("pass".into());
+        // "user:pass"
+        let credentials = HeaderValue::from_static("Basic dXNlcjpwYXNz");
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let mut png = Vec::new();
+        image::RgbaImage::new(30, 20)
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let upload = |filename: &str, body: Vec<u8>| {
+            let request = Request::builder()
+                .uri("/?redirect=false")
+                .method("PUT")
+                .header("X-Filename", filename)
+                .header(AUTHORIZATION, credentials.clone())
+                .body(Body::from(body))
+                .unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(StatusCode::OK, response.status());
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                let uploaded: Value = serde_json::from_slice(&body).unwrap();
+                let url = uploaded["url"].as_str().unwrap();
+                url.rsplit('/').next().unwrap().to_string()
+            }
+        };
+        let info = |uri: String, authorized: bool| {
+            let mut request = Request::builder().uri(uri);
+            if authorized {
+                request = request.header(AUTHORIZATION, credentials.clone());
+            }
+            let request = request.body(Body::empty()).unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                let status = response.status();
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                (status, serde_json::from_slice::<Value>(&body).ok())
+            }
+        };
+
+        let image = upload("image.png", png.clone()).await;
+        let (status, info_json) = info(format!("/info/{}", image), true).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(
+            serde_json::json!({
+                "size": png.len(),
+                "contentType": "image/png",
+                "image": {"width": 30, "height": 20, "format": "png", "colorType": "Rgba8"},
+            }),
+            info_json.unwrap()
+        );
+        // The result is kept in the metadata, and not read again.
+        let meta_path = Path::new(&opt.base_dir)
+            .join("meta")
+            .join(format!("{}.json", image));
+        let meta: Value = serde_json::from_slice(&std::fs::read(&meta_path).unwrap()).unwrap();
+        assert_eq!(30, meta["info"]["image"]["width"]);
+        std::fs::write(Path::new(&opt.base_dir).join(&image), "no longer a png").unwrap();
+        let (_, info_json) = info(format!("/info/{}", image), true).await;
+        assert_eq!(20, info_json.unwrap()["image"]["height"]);
+
+        let text = upload("notes.txt", b"some notes".to_vec()).await;
+        let (status, info_json) = info(format!("/info/{}", text), true).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(
+            serde_json::json!({"size": 10, "contentType": "text/plain"}),
+            info_json.unwrap()
+        );
+
+        let (status, _) = info("/info/missing.png".into(), true).await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+        let (status, _) = info(format!("/info/{}", image), false).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, status);
+    }
+
+    #[tokio::test]
+    async fn gallery_shows_thumbnails() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-gallery".into();
+        opt.recents = 2;
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        for name in ["a.png", "b.txt", "c.png"] {
+            std::fs::write(Path::new(&opt.base_dir).join(name), name).unwrap();
+        }
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let page = |uri: &str| {
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            let app = app.clone();
+            async move {
+                let response = app.oneshot(request).await.unwrap();
+                assert_eq!(StatusCode::OK, response.status());
+                let body = response.into_body().collect().await.unwrap().to_bytes();
+                String::from_utf8(body.to_vec()).unwrap()
+            }
+        };
+
+        let first = page("/gallery?sort=name").await;
+        assert!(first.contains(r#"<a href="a.png" title="a.png" data-lightbox>"#));
+        assert!(first.contains(r#"<a href="b.txt" title="b.txt">"#));
+        assert!(first.contains(r#"src="thumbnail/a.png?size=150""#));
+        assert!(!first.contains("c.png"));
+        assert!(first.contains("gallery?page=2&amp;sort=name&amp;order=asc&amp;filter=all"));
+        assert!(first.contains("recent/gallery.js"));
+
+        let second = page("/gallery?page=2&sort=name").await;
+        assert!(second.contains(r#"<a href="c.png" title="c.png" data-lightbox>"#));
+        assert!(!second.contains("a.png"));
+
+        let script = page("/recent/gallery.js").await;
+        assert!(script.contains("lightbox"));
+    }
+
+    #[tokio::test]
+    async fn bundled_files_are_revalidated() {
+        let app = router("/tmp".into(), make_test_opt());
+        let get = |uri: &str, headers: &[(HeaderName, &str)]| {
+            let mut request = Request::builder().uri(uri);
+            for (name, value) in headers {
+                request = request.header(name, *value);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        for uri in ["/recent/bulma.min.css", "/recent/placeholder.png"] {
+            let response = get(uri, &[]).await.unwrap();
+            assert_eq!(StatusCode::OK, response.status());
+            let etag = response.headers()[ETAG].to_str().unwrap().to_string();
+            let last_modified = response.headers()[LAST_MODIFIED]
+                .to_str()
+                .unwrap()
+                .to_string();
+            assert_eq!(format!("\"i-{}\"", env!("CARGO_PKG_VERSION")), etag);
+
+            let response = get(uri, &[(IF_NONE_MATCH, &etag)]).await.unwrap();
+            assert_eq!(StatusCode::NOT_MODIFIED, response.status(), "{}", uri);
+            assert_eq!(etag, response.headers()[ETAG]);
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert!(body.is_empty());
+
+            let response = get(uri, &[(IF_MODIFIED_SINCE, &last_modified)])
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::NOT_MODIFIED, response.status(), "{}", uri);
+            let old = "Sat, 01 Jan 2000 00:00:00 GMT";
+            let response = get(uri, &[(IF_MODIFIED_SINCE, old)]).await.unwrap();
+            assert_eq!(StatusCode::OK, response.status(), "{}", uri);
+
+            // A different ETag wins over a matching date.
+            let headers = [
+                (IF_NONE_MATCH, "\"other\""),
+                (IF_MODIFIED_SINCE, last_modified.as_str()),
+            ];
+            let response = get(uri, &headers).await.unwrap();
+            assert_eq!(StatusCode::OK, response.status(), "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn text_responses_are_compressed() {
+        for compression in [true, false] {
+            let mut opt = make_test_opt();
+            opt.compression = compression;
+            let app = router("/tmp".into(), opt);
+            let get = |uri: &str, accept_encoding: Option<&str>| {
+                let mut request = Request::builder().uri(uri);
+                if let Some(accept_encoding) = accept_encoding {
+                    request = request.header(ACCEPT_ENCODING, accept_encoding);
+                }
+                app.clone().oneshot(request.body(Body::empty()).unwrap())
+            };
+            let encoding = |response: &Response| {
+                response
+                    .headers()
+                    .get(CONTENT_ENCODING)
+                    .map(|value| value.to_str().unwrap().to_string())
+            };
+
+            let response = get("/recent/bulma.min.css", Some("gzip")).await.unwrap();
+            assert_eq!(StatusCode::OK, response.status());
+            if !compression {
+                assert_eq!(None, encoding(&response));
+                continue;
+            }
+            assert_eq!(Some("gzip".to_string()), encoding(&response));
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!([0x1f, 0x8b], body[..2]);
+            assert!(body.len() < include_bytes!("../dist/bulma.min.css").len());
+
+            let response = get("/recent/bulma.min.css", Some("br")).await.unwrap();
+            assert_eq!(Some("br".to_string()), encoding(&response));
+            let response = get("/recent/bulma.min.css", None).await.unwrap();
+            assert_eq!(None, encoding(&response));
+            // Images are already compressed.
+            let response = get("/recent/placeholder.png", Some("gzip")).await.unwrap();
+            assert_eq!(None, encoding(&response));
+        }
+    }
+
+    #[tokio::test]
+    async fn files_below_prefix() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-files-prefix".into();
+        opt.files_prefix = parse_base_path("/f/").unwrap();
+        opt.recents = 10;
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let get = |uri: &str| {
+            app.clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        };
+
+        // A file named like a route doesn't clash with it.
+        let response = app
+            .clone()
+            .oneshot(custom_filename_request(r#"{"filename":"recent"}"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            "http://test.example.com/f/recent",
+            response.headers()[LOCATION]
+        );
+
+        let response = get("/f/recent").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"quarterly numbers");
+
+        let response = get("/recent").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(String::from_utf8_lossy(&body).contains(r#"<a href="f/recent">"#));
+
+        let response = get("/recent.json").await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let recents: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!("http://test.example.com/f/recent", recents[0]["url"]);
+        assert_eq!(
+            "http://test.example.com/thumbnail/recent?size=150",
+            recents[0]["thumbnailUrl"]
+        );
+
+        // Files are no longer served at the root.
+        std::fs::write(Path::new(&opt.base_dir).join("report.txt"), "report").unwrap();
+        assert_eq!(StatusCode::OK, get("/f/report.txt").await.unwrap().status());
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            get("/report.txt").await.unwrap().status()
+        );
+    }
+
+    #[tokio::test]
+    async fn path_traversal_is_rejected_and_logged() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-path-traversal".into();
+        opt.insecure_delete = true;
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+
+        let logs = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let writer = LogWriter(logs.clone());
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let from_client = |mut request: Request<Body>| {
+            let client = SocketAddr::from(([192, 0, 2, 7], 4711));
+            request
+                .extensions_mut()
+                .insert(axum::extract::ConnectInfo(client));
+            request
+        };
+        let rejected = |response: Response| async move {
+            assert_eq!(StatusCode::BAD_REQUEST, response.status());
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!("filename must be a plain name, without a path", body);
+        };
+
+        let request = Request::builder()
+            .uri("/")
+            .method("POST")
+            .header(CONTENT_TYPE, "multipart/form-data; boundary=boundary")
+            .body(Body::from(
+                "--boundary\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"../../etc/passwd\"\r\n\r\n\
+root:x:0:0\r\n\
+--boundary--\r\n",
+            ))
+            .unwrap();
+        rejected(app.clone().oneshot(from_client(request)).await.unwrap()).await;
+
+        let request = Request::builder()
+            .uri("/")
+            .method("PUT")
+            .header("X-Filename", "../../etc/passwd")
+            .body(Body::from("root:x:0:0"))
+            .unwrap();
+        rejected(app.clone().oneshot(from_client(request)).await.unwrap()).await;
+
+        let request = custom_filename_request(r#"{"filename":"/etc/passwd"}"#);
+        rejected(app.clone().oneshot(from_client(request)).await.unwrap()).await;
+
+        let request = Request::builder()
+            .uri("/delete")
+            .method("POST")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"filename":"../../etc/passwd"}"#))
+            .unwrap();
+        rejected(app.clone().oneshot(from_client(request)).await.unwrap()).await;
+
+        let request = Request::builder()
+            .uri("/delete-bulk")
+            .method("POST")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"filenames":["../../etc/passwd"]}"#))
+            .unwrap();
+        let response = app.clone().oneshot(from_client(request)).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            "filename must be a plain name, without a path",
+            body["failed"][0]["error"]
+        );
+
+        let logs = String::from_utf8(logs.lock().unwrap().clone()).unwrap();
+        let warnings: Vec<&str> = logs
+            .lines()
+            .filter(|line| line.contains("rejected filename with a path traversal"))
+            .collect();
+        assert_eq!(5, warnings.len(), "{}", logs);
+        assert!(warnings.iter().all(|line| line.contains("WARN")));
+        assert!(warnings
+            .iter()
+            .all(|line| line.contains("client_ip=192.0.2.7")));
+        assert!(warnings[0].contains(r#"filename="../../etc/passwd""#));
+        assert!(std::fs::read_dir(&opt.base_dir).unwrap().all(|entry| entry
+            .unwrap()
+            .file_type()
+            .unwrap()
+            .is_dir()));
+    }
+
+    /// Collects the log lines written by a test.
+    #[derive(Clone)]
+    struct LogWriter(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn webdav_manages_files() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-webdav".into();
+        opt.enable_webdav = true;
+        opt.auth_user = Some("user".into());
+        opt.auth_pass = Some("pass".into());
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let request = |method: &str, uri: &str, body: &'static str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                // "user:pass"
+                .header(AUTHORIZATION, "Basic dXNlcjpwYXNz")
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let send = |request: Request<Body>| app.clone().oneshot(request);
+
+        let response = send(request("PUT", "/notes.txt", "first")).await.unwrap();
+        assert_eq!(StatusCode::CREATED, response.status());
+        let response = send(request("PUT", "/notes.txt", "second")).await.unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, response.status());
+        let response = send(request("GET", "/notes.txt", "")).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"second");
+        let response = send(request("PUT", "/empty.txt", "")).await.unwrap();
+        assert_eq!(StatusCode::CREATED, response.status());
+
+        let response = send(request("PROPFIND", "/", "")).await.unwrap();
+        assert_eq!(StatusCode::MULTI_STATUS, response.status());
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("<D:href>/</D:href>"));
+        assert!(body.contains("<D:href>/notes.txt</D:href>"));
+        assert!(body.contains("<D:getcontentlength>6</D:getcontentlength>"));
+        assert!(body.contains("<D:href>/empty.txt</D:href>"));
+
+        let mut propfind = request("PROPFIND", "/", "");
+        propfind
+            .headers_mut()
+            .insert("depth", HeaderValue::from_static("0"));
+        let response = send(propfind).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(!String::from_utf8_lossy(&body).contains("notes.txt"));
+
+        let response = send(request("OPTIONS", "/", "")).await.unwrap();
+        assert_eq!("1", response.headers()["dav"]);
+
+        let response = send(request("DELETE", "/notes.txt", "")).await.unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, response.status());
+        let response = send(request("DELETE", "/notes.txt", "")).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        let response = send(request("PROPFIND", "/notes.txt", "")).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, response.status());
+        let response = send(request("PUT", "/..%2Fescape.txt", "")).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+
+        // Credentials are required, as for deleting files.
+        let mut unauthorized = request("PUT", "/other.txt", "x");
+        unauthorized.headers_mut().remove(AUTHORIZATION);
+        let response = send(unauthorized).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        assert!(!Path::new(&opt.base_dir).join("other.txt").exists());
+
+        // Without --enable-webdav, there is nothing to PROPFIND.
+        opt.enable_webdav = false;
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let response = app.oneshot(request("PROPFIND", "/", "")).await.unwrap();
+        assert_ne!(StatusCode::MULTI_STATUS, response.status());
+    }
+
+    #[tokio::test]
+    async fn files_without_thumbnails_get_icons() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-placeholder-icons".into();
+        opt.recents = 10;
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        std::fs::write(Path::new(&opt.base_dir).join("report.pdf"), "%PDF-1.4").unwrap();
+        std::fs::write(Path::new(&opt.base_dir).join("notes.txt"), "notes").unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let get = |uri: &str| {
+            app.clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        };
+
+        let response = get("/recent.json").await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let recents: Value = serde_json::from_slice(&body).unwrap();
+        let thumbnail = |name: &str| {
+            recents
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|recent| recent["url"] == format!("http://test.example.com/{}", name))
+                .map(|recent| recent["thumbnailUrl"].clone())
+                .unwrap()
+        };
+        assert_eq!(
+            "http://test.example.com/recent/icons/pdf.png",
+            thumbnail("report.pdf")
+        );
+        assert_eq!(
+            "http://test.example.com/thumbnail/notes.txt?size=150",
+            thumbnail("notes.txt")
+        );
+
+        let icon = get("/recent/icons/pdf.png").await.unwrap();
+        assert_eq!(StatusCode::OK, icon.status());
+        assert_eq!("image/png", icon.headers()[CONTENT_TYPE]);
+        let icon = icon.into_body().collect().await.unwrap().to_bytes();
+        assert_ne!(&icon[..], include_bytes!("../dist/placeholder.png"));
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            get("/recent/icons/unknown.png").await.unwrap().status()
+        );
+
+        // The thumbnail route serves the icon too, and the generic placeholder for other files.
+        let response = get("/thumbnail/report.pdf").await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(icon, body);
+        let response = get("/thumbnail/notes.txt").await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], include_bytes!("../dist/placeholder.png"));
+    }
+
+    #[tokio::test]
+    async fn manifest_lists_every_file() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-manifest".into();
+        opt.recents = 1;
+        opt.auth_user = Some("user".into());
+        opt.auth_pass = Some("pass".into());
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        for name in ["c.txt", "a.txt", "b.txt"] {
+            std::fs::write(Path::new(&opt.base_dir).join(name), name).unwrap();
+        }
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let request = |credentials: Option<&'static str>| {
+            let mut request = Request::builder().uri("/admin/manifest");
+            if let Some(credentials) = credentials {
+                request = request.header(AUTHORIZATION, credentials);
+            }
+            request.body(Body::empty()).unwrap()
+        };
+
+        let response = app.clone().oneshot(request(None)).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+
+        // "user:pass"
+        let response = app
+            .oneshot(request(Some("Basic dXNlcjpwYXNz")))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("3", response.headers()["x-total-count"]);
+        assert_eq!("application/json", response.headers()[CONTENT_TYPE]);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let manifest: Value = serde_json::from_slice(&body).unwrap();
+        let manifest = manifest.as_array().unwrap();
+        // Everything, not only a page of recent uploads, in the order of the directory.
+        let mut names: Vec<_> = manifest.iter().map(|file| file["name"].as_str()).collect();
+        names.sort();
+        assert_eq!(vec![Some("a.txt"), Some("b.txt"), Some("c.txt")], names);
+        let a = manifest
+            .iter()
+            .find(|file| file["name"] == "a.txt")
+            .unwrap();
+        assert_eq!(5, a["size"]);
+        assert!(a["modified"].is_string());
+        // Files without metadata are hashed when listed.
+        assert_eq!(format!("{:x}", Sha256::digest(b"a.txt")), a["hash"]);
+    }
+
+    #[tokio::test]
+    async fn import_restores_files_from_archives() {
+        use async_compression::tokio::bufread::{DeflateEncoder, GzipEncoder};
+        use tokio::io::AsyncReadExt;
+
+        fn tar_entry(name: &str, kind: u8, data: &[u8]) -> Vec<u8> {
+            let mut entry = tar_header(name, kind, data.len() as u64);
+            entry.extend_from_slice(data);
+            entry.resize(entry.len().next_multiple_of(512), 0);
+            entry
+        }
+        fn tar_header(name: &str, kind: u8, size: u64) -> Vec<u8> {
+            let mut header = [0u8; 512];
+            header[..name.len()].copy_from_slice(name.as_bytes());
+            header[100..108].copy_from_slice(b"0000644\0");
+            header[124..136].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+            header[136..148].copy_from_slice(format!("{:011o}\0", 1_600_000_000).as_bytes());
+            header[156] = kind;
+            header[257..265].copy_from_slice(b"ustar\x0000");
+            header[148..156].copy_from_slice(b"        ");
+            let sum: u32 = header.iter().map(|&b| b as u32).sum();
+            header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+            header.to_vec()
+        }
+        async fn deflate(data: &[u8]) -> Vec<u8> {
+            let mut compressed = Vec::new();
+            DeflateEncoder::new(data)
+                .read_to_end(&mut compressed)
+                .await
+                .unwrap();
+            compressed
+        }
+        /// A local header, with the sizes in a data descriptor after the content if deflated.
+        fn zip_entry(name: &str, data: &[u8], deflated: Option<&[u8]>) -> Vec<u8> {
+            let mut entry = 0x04034b50u32.to_le_bytes().to_vec();
+            entry.extend_from_slice(&20u16.to_le_bytes());
+            let (flags, method, size) = match deflated {
+                Some(_) => (0x08u16, 8u16, 0u32),
+                None => (0, 0, data.len() as u32),
+            };
+            entry.extend_from_slice(&flags.to_le_bytes());
+            entry.extend_from_slice(&method.to_le_bytes());
+            // 2024-05-01 10:30:00
+            entry.extend_from_slice(&((10u16 << 11) | (30 << 5)).to_le_bytes());
+            entry.extend_from_slice(&(((2024 - 1980) << 9) | (5 << 5) | 1u16).to_le_bytes());
+            entry.extend_from_slice(&0u32.to_le_bytes());
+            entry.extend_from_slice(&size.to_le_bytes());
+            entry.extend_from_slice(&size.to_le_bytes());
+            entry.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            entry.extend_from_slice(&0u16.to_le_bytes());
+            entry.extend_from_slice(name.as_bytes());
+            match deflated {
+                Some(compressed) => {
+                    entry.extend_from_slice(compressed);
+                    entry.extend_from_slice(&0x08074b50u32.to_le_bytes());
+                    entry.extend_from_slice(&0u32.to_le_bytes());
+                    entry.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+                    entry.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                }
+                None => entry.extend_from_slice(data),
+            }
+            entry
+        }
+
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-import".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(&opt.base_dir).unwrap();
+        let base_dir = Path::new(&opt.base_dir).to_path_buf();
+        std::fs::write(base_dir.join("exists.txt"), "old").unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let import = |uri: &str, archive: Vec<u8>| {
+            app.clone().oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri(uri)
+                    .body(Body::from(archive))
+                    .unwrap(),
+            )
+        };
+        let summary = |response: Response| async move {
+            assert_eq!(StatusCode::OK, response.status());
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            serde_json::from_slice::<Value>(&body).unwrap()
+        };
+
+        let mut tar = [
+            tar_entry("./", b'5', b""),
+            tar_entry("./notes.txt", b'0', b"notes"),
+            tar_entry("./exists.txt", b'0', b"new"),
+            tar_entry("./thumbnails/notes.png_150.webp", b'0', b"thumbnail"),
+            tar_entry("../escape.txt", b'0', b"escape"),
+            tar_entry("./link.txt", b'2', b""),
+        ]
+        .concat();
+        tar.extend_from_slice(&[0; 1024]);
+        let response = import("/admin/import", tar.clone()).await.unwrap();
+        let summary_of_tar = summary(response).await;
+        assert_eq!(
+            serde_json::json!(["./notes.txt"]),
+            summary_of_tar["imported"]
+        );
+        assert_eq!(
+            serde_json::json!([
+                "./exists.txt",
+                "./thumbnails/notes.png_150.webp",
+                "./link.txt"
+            ]),
+            summary_of_tar["skipped"]
+        );
+        assert_eq!("../escape.txt", summary_of_tar["failed"][0]["name"]);
+        assert_eq!(
+            "notes",
+            std::fs::read_to_string(base_dir.join("notes.txt")).unwrap()
+        );
+        assert_eq!(
+            "old",
+            std::fs::read_to_string(base_dir.join("exists.txt")).unwrap()
+        );
+        assert!(!Path::new("/tmp/escape.txt").exists());
+        let modified = std::fs::metadata(base_dir.join("notes.txt"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert_eq!(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+            modified
+        );
+
+        // Gzipped, and replacing existing files.
+        let mut gzipped = Vec::new();
+        GzipEncoder::new(&tar[..])
+            .read_to_end(&mut gzipped)
+            .await
+            .unwrap();
+        let response = import("/admin/import?overwrite=true", gzipped)
+            .await
+            .unwrap();
+        let summary_of_gzip = summary(response).await;
+        assert_eq!(
+            serde_json::json!(["./notes.txt", "./exists.txt"]),
+            summary_of_gzip["imported"]
+        );
+        assert_eq!(
+            "new",
+            std::fs::read_to_string(base_dir.join("exists.txt")).unwrap()
+        );
+
+        let poem = "the same line again\n".repeat(100);
+        let zip = [
+            zip_entry("stored.txt", b"stored", None),
+            zip_entry(
+                "poem.txt",
+                poem.as_bytes(),
+                Some(&deflate(poem.as_bytes()).await),
+            ),
+            zip_entry("folder/", b"", None),
+            0x02014b50u32.to_le_bytes().to_vec(),
+        ]
+        .concat();
+        let response = import("/admin/import", zip).await.unwrap();
+        let summary_of_zip = summary(response).await;
+        assert_eq!(
+            serde_json::json!(["stored.txt", "poem.txt"]),
+            summary_of_zip["imported"]
+        );
+        assert_eq!(
+            poem,
+            std::fs::read_to_string(base_dir.join("poem.txt")).unwrap()
+        );
+
+        let response = import("/admin/import", b"not an archive at all".repeat(30))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+
+        // A truncated archive keeps what was imported, and tells what went wrong.
+        let response = import("/admin/import?overwrite=true", tar[..1600].to_vec())
+            .await
+            .unwrap();
+        let summary_of_truncated = summary(response).await;
+        assert_eq!(
+            serde_json::json!(["./notes.txt"]),
+            summary_of_truncated["imported"]
+        );
+        assert!(summary_of_truncated["failed"][0]["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid archive"));
+
+        // A file cut short isn't stored, whether stored or deflated.
+        let mut cut = tar_entry("./whole.txt", b'0', b"whole");
+        cut.extend_from_slice(&tar_entry("./cut.txt", b'0', poem.as_bytes())[..1000]);
+        let response = import("/admin/import", cut).await.unwrap();
+        let summary_of_cut = summary(response).await;
+        assert_eq!(
+            serde_json::json!(["./whole.txt"]),
+            summary_of_cut["imported"]
+        );
+        assert_eq!(
+            "invalid archive: archive is truncated",
+            summary_of_cut["failed"][0]["error"]
+        );
+        let compressed = deflate(poem.as_bytes()).await;
+        let mut cut = zip_entry("whole.zip.txt", b"whole", None);
+        cut.extend_from_slice(
+            &zip_entry("cut.txt", poem.as_bytes(), Some(&compressed))
+                [..30 + 7 + compressed.len() / 2],
+        );
+        let response = import("/admin/import", cut).await.unwrap();
+        let summary_of_cut = summary(response).await;
+        assert_eq!(
+            serde_json::json!(["whole.zip.txt"]),
+            summary_of_cut["imported"]
+        );
+        assert!(summary_of_cut["failed"][0]["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid archive"));
+        assert!(!base_dir.join("cut.txt").exists());
+
+        // Long names come before their entry, and one cut short breaks the archive.
+        let name = format!("./{}.txt", "long".repeat(30));
+        let mut long = tar_entry("././@LongLink", b'L', name.as_bytes());
+        long.extend_from_slice(&tar_entry("./truncated-name", b'0', b"long"));
+        long.extend_from_slice(&tar_header("././@LongLink", b'L', 1 << 32));
+        let response = import("/admin/import", long).await.unwrap();
+        let summary_of_long = summary(response).await;
+        assert_eq!(serde_json::json!([name]), summary_of_long["imported"]);
+        assert!(summary_of_long["failed"][0]["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid archive"));
+    }
+
+    #[tokio::test]
+    async fn export_zips_all_files() {
+        let mut opt = make_test_opt();
+        opt.base_dir = "/tmp/i-test-export".into();
+        std::fs::remove_dir_all(&opt.base_dir).ok();
+        std::fs::create_dir_all(Path::new(&opt.base_dir).join(THUMBNAIL_SUBDIR)).unwrap();
+        let base_dir = Path::new(&opt.base_dir).to_path_buf();
+        std::fs::write(base_dir.join("notes.txt"), "notes ".repeat(1000)).unwrap();
+        std::fs::write(base_dir.join("image.png"), b"\x89PNG\r\n\x1a\n").unwrap();
+        std::fs::write(base_dir.join("empty.txt"), "").unwrap();
+        std::fs::write(
+            base_dir.join(THUMBNAIL_SUBDIR).join("image.png_150.webp"),
+            "webp",
+        )
+        .unwrap();
+        // A protected file with limited downloads, and the metadata of an old file.
+        std::fs::create_dir_all(base_dir.join(META_SUBDIR)).unwrap();
+        let protected = serde_json::json!({
+            "deleteToken": "token",
+            "viewPasswordHash": bcrypt::hash("secret", 4).unwrap(),
+            "maxDownloads": 1,
+        });
+        std::fs::write(
+            base_dir.join(META_SUBDIR).join("notes.txt.json"),
+            protected.to_string(),
+        )
+        .unwrap();
+        std::fs::write(
+            base_dir.join(META_SUBDIR).join("image.png.json"),
+            r#"{"deleteToken":"old"}"#,
+        )
+        .unwrap();
+        let old = std::fs::File::options()
+            .write(true)
+            .open(base_dir.join("image.png"))
+            .unwrap();
+        old.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000))
+            .unwrap();
+        let app = router(opt.base_dir.clone().into(), opt.clone());
+        let export = |uri: &str| {
+            app.clone()
+                .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+        };
+        /// Names in the central directory, which is listed after all files.
+        fn zip_names(zip: &[u8]) -> Vec<String> {
+            let end = zip.len() - 22;
+            assert_eq!(&0x06054b50u32.to_le_bytes(), &zip[end..end + 4]);
+            let count = u16::from_le_bytes([zip[end + 10], zip[end + 11]]);
+            let mut offset =
+                u32::from_le_bytes(zip[end + 16..end + 20].try_into().unwrap()) as usize;
+            let mut names = Vec::new();
+            for _ in 0..count {
+                let record = &zip[offset..];
+                assert_eq!(&0x02014b50u32.to_le_bytes(), &record[..4]);
+                let name_len = u16::from_le_bytes([record[28], record[29]]) as usize;
+                let extra_len = u16::from_le_bytes([record[30], record[31]]) as usize;
+                names.push(String::from_utf8(record[46..46 + name_len].to_vec()).unwrap());
+                offset += 46 + name_len + extra_len;
+            }
+            names
+        }
+
+        let response = export("/admin/export.zip").await.unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        assert_eq!("application/zip", response.headers()[CONTENT_TYPE]);
+        let disposition = response.headers()[CONTENT_DISPOSITION].to_str().unwrap();
+        assert!(disposition.starts_with("attachment; filename=\"i-"));
+        assert!(disposition.contains(".zip\""));
+        let zip = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(
+            vec![
+                "empty.txt",
+                "image.png",
+                "meta/image.png.json",
+                "meta/notes.txt.json",
+                "notes.txt"
+            ],
+            zip_names(&zip)
+        );
+        // Deflated, since the notes repeat.
+        assert!(zip.len() < 1500);
+
+        // Importing the archive elsewhere restores the files.
+        let mut restored = opt.clone();
+        restored.base_dir = "/tmp/i-test-export-restored".into();
+        std::fs::remove_dir_all(&restored.base_dir).ok();
+        std::fs::create_dir_all(&restored.base_dir).unwrap();
+        let restored_app = router(restored.base_dir.clone().into(), restored.clone());
+        let response = restored_app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/admin/import")
+                    .body(Body::from(zip))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let summary: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            serde_json::json!(["empty.txt", "image.png", "notes.txt"]),
+            summary["imported"]
+        );
+        for name in ["empty.txt", "image.png", "notes.txt"] {
+            assert_eq!(
+                std::fs::read(base_dir.join(name)).unwrap(),
+                std::fs::read(Path::new(&restored.base_dir).join(name)).unwrap()
+            );
+        }
+        assert_eq!(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+            std::fs::metadata(Path::new(&restored.base_dir).join("image.png"))
+                .unwrap()
+                .modified()
+                .unwrap()
+        );
+
+        // The metadata is restored with the files, so the notes are still protected.
+        for name in ["image.png", "notes.txt"] {
+            let meta = |dir: &str| {
+                let path = Path::new(dir)
+                    .join(META_SUBDIR)
+                    .join(format!("{}.json", name));
+                serde_json::from_slice::<Value>(&std::fs::read(path).unwrap()).unwrap()
+            };
+            assert_eq!(meta(&opt.base_dir), meta(&restored.base_dir));
+        }
+        let response = restored_app
+            .oneshot(
+                Request::builder()
+                    .uri("/notes.txt")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+
+        let response = export("/admin/export.zip?since=2021-01-01T00:00:00Z")
+            .await
+            .unwrap();
+        let zip = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(
+            vec!["empty.txt", "meta/notes.txt.json", "notes.txt"],
+            zip_names(&zip)
+        );
+
+        let response = export("/admin/export.zip?thumbnails=true&since=1610000000")
+            .await
+            .unwrap();
+        let zip = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(
+            vec![
+                "empty.txt",
+                "meta/notes.txt.json",
+                "notes.txt",
+                "thumbnails/image.png_150.webp"
+            ],
+            zip_names(&zip)
+        );
+
+        let response = export("/admin/export.zip?since=yesterday").await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, response.status());
+
+        opt.auth_user = Some("user".into());
+        opt.auth_pass = Some("secret".into());
+        let response = router(opt.base_dir.clone().into(), opt)
+            .oneshot(
+                Request::builder()
+                    .uri("/admin/export.zip")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
     }
 }
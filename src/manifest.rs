@@ -0,0 +1,120 @@
+use axum::body::{Body, Bytes};
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::HeaderName;
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt, TryStreamExt};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::WebError;
+
+use super::{
+    helpers::{file_key, user_dir},
+    meta::read_meta,
+    storage::{self, StoredObject},
+    upload::hash_file,
+    users::User,
+    Opt,
+};
+
+pub static X_TOTAL_COUNT: HeaderName = HeaderName::from_static("x-total-count");
+
+/**
+ * An uploaded file, as listed in the manifest.
+ */
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ManifestEntry {
+    name: String,
+    size: u64,
+    modified: DateTime<Utc>,
+    /// SHA-256 of the content, in hex.
+    hash: Option<String>,
+}
+
+/**
+ * Lists every uploaded file of the current user as a JSON array, for backups and migrations. The
+ * array is written while the storage is listed and the entries are looked up, so that neither is
+ * ever held in memory as a whole. The number of files is given in the X-Total-Count header.
+ */
+pub async fn handle_manifest(State(opt): State<Opt>, user: User) -> Result<Response, WebError> {
+    // Listing isn't recursive, so thumbnails, metadata, and the trash are not included.
+    let storage = storage::backend(&opt);
+    // The files are counted on a listing of their own, since the header is sent before the array.
+    let count = storage
+        .list_stream(user_dir(&user))
+        .try_fold(0, |count, _| async move { Ok(count + 1) })
+        .await?;
+
+    let entries = storage
+        .list_stream(user_dir(&user))
+        .and_then(move |file| {
+            let (user, opt) = (user.clone(), opt.clone());
+            async move { manifest_entry(file, &user, &opt).await }
+        })
+        .enumerate()
+        .map(|(i, entry)| {
+            let mut chunk = match i {
+                0 => Vec::new(),
+                _ => b",".to_vec(),
+            };
+            serde_json::to_writer(&mut chunk, &entry?).map_err(std::io::Error::from)?;
+            Ok::<_, WebError>(Bytes::from(chunk))
+        });
+    let body = stream::once(async { Ok(Bytes::from_static(b"[")) })
+        .chain(entries)
+        .chain(stream::once(async { Ok(Bytes::from_static(b"]")) }));
+
+    Ok((
+        [
+            (CONTENT_TYPE, "application/json".to_string()),
+            (X_TOTAL_COUNT.clone(), count.to_string()),
+        ],
+        Body::from_stream(body),
+    )
+        .into_response())
+}
+
+/**
+ * Describes a file, with the hash stored on upload, or else computed from its content.
+ */
+async fn manifest_entry(
+    file: StoredObject,
+    user: &User,
+    opt: &Opt,
+) -> Result<ManifestEntry, WebError> {
+    let hash = match read_meta(&file.name, user, opt)
+        .await?
+        .and_then(|meta| meta.hash)
+    {
+        Some(hash) => Some(hash),
+        None => content_hash(&file.name, user, opt).await?,
+    };
+    Ok(ManifestEntry {
+        modified: file.modified.into(),
+        size: file.size,
+        name: file.name,
+        hash,
+    })
+}
+
+/**
+ * Hashes the content of a file, or returns `None` if it has been removed since it was listed.
+ */
+async fn content_hash(filename: &str, user: &User, opt: &Opt) -> Result<Option<String>, WebError> {
+    let storage = storage::backend(opt);
+    let key = file_key(filename, user);
+    if let Some(path) = storage.local_path(&key) {
+        return match hash_file(&path).await {
+            Ok((_, hash)) => Ok(Some(hash)),
+            Err(WebError::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        };
+    }
+    Ok(storage
+        .get(&key)
+        .await?
+        .map(|data| format!("{:x}", Sha256::digest(data))))
+}
@@ -0,0 +1,441 @@
+use crate::WebError;
+use axum::extract::{Path as UrlPath, Request, State};
+use axum::http::{
+    header::{CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_TYPE, ETAG, IF_NONE_MATCH, RANGE},
+    HeaderMap, HeaderValue, Method, StatusCode,
+};
+use axum::middleware;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::{DateTime, Utc};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+
+use super::client_ip::client_ip;
+use super::downloads::{give_back_download, remove_used_up, take_download, Download};
+use super::helpers::{file_key, meta_key, user_dir};
+use super::info::FileInfo;
+use super::protect::{check_view_password, given_password};
+use super::storage::{self, join_key};
+use super::users::User;
+use super::{Opt, META_SUBDIR, THUMBNAIL_SUBDIR};
+
+/// Cache-Control of files stored under a random name, which are never changed.
+const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// Cache-Control of files stored under a chosen name, which may be overwritten.
+const OVERWRITABLE_CACHE_CONTROL: &str = "public, max-age=300";
+
+/// Content types that can run scripts in the origin of the server when shown by a browser, which
+/// are therefore always downloaded.
+const ATTACHMENT_TYPES: &[&str] = &[
+    "text/html",
+    "application/xhtml+xml",
+    "image/svg+xml",
+    "text/xml",
+    "application/xml",
+];
+
+/// Cache-Control of files that are protected by a password or have limited downloads, which must
+/// not be kept by browsers or proxies, since they would serve them to anyone.
+pub const PRIVATE_CACHE_CONTROL: &str = "private, no-store";
+
+/**
+ * Metadata stored in a sidecar file next to an uploaded file, in the meta subdirectory.
+ */
+#[derive(Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delete_token: Option<String>,
+    /// Content type of the file, as given by the uploader or guessed from its extension.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// SHA-256 hash of the contents of the file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// Whether the file was stored under a random name, so that it can't be overwritten.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub immutable: bool,
+    /// When the file was moved to the trash, if it is there.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// bcrypt hash of the password needed to view the file, if it is protected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub view_password_hash: Option<String>,
+    /// How many times the file may be downloaded before it is deleted, if limited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_downloads: Option<u64>,
+    /// How many times the file has been downloaded, if its downloads are limited.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub downloads: u64,
+    /// Whether browsers are told to download the file instead of showing it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub force_download: bool,
+    /// Size, type, and image headers of the file, once they have been asked for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info: Option<FileInfo>,
+    /// Deletion tokens of later uploads of the same file with `--naming content-hash`. Each of
+    /// them only takes back its own upload, and the file is deleted with the last one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shared_delete_tokens: Vec<String>,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+impl FileMeta {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub fn is_used_up(&self) -> bool {
+        self.max_downloads
+            .is_some_and(|max_downloads| self.downloads >= max_downloads)
+    }
+
+    pub fn is_protected(&self) -> bool {
+        self.view_password_hash.is_some()
+    }
+}
+
+/**
+ * Reads the metadata of the given file. Returns `None` if the file has no metadata.
+ */
+pub async fn read_meta(
+    filename: &str,
+    user: &User,
+    opt: &Opt,
+) -> Result<Option<FileMeta>, WebError> {
+    let data = storage::backend(opt).get(&meta_key(filename, user)).await?;
+    Ok(data.and_then(|data| serde_json::from_slice(&data).ok()))
+}
+
+/**
+ * Returns the name of the file with the given deletion token, if any.
+ */
+pub async fn find_by_delete_token(
+    token: &str,
+    user: &User,
+    opt: &Opt,
+) -> Result<Option<String>, WebError> {
+    let dir = join_key(user_dir(user), META_SUBDIR);
+    for object in storage::backend(opt).list(&dir).await? {
+        let Some(filename) = object.name.strip_suffix(".json") else {
+            continue;
+        };
+        let matches = read_meta(filename, user, opt).await?.is_some_and(|meta| {
+            meta.delete_token.as_deref() == Some(token)
+                || meta
+                    .shared_delete_tokens
+                    .iter()
+                    .any(|shared| shared == token)
+        });
+        if matches {
+            return Ok(Some(filename.to_string()));
+        }
+    }
+
+    Ok(None)
+}
+
+/**
+ * Takes back one of several uploads of the same file by forgetting its deletion token. Returns
+ * false if it is the only upload left, in which case the file itself is to be deleted.
+ */
+pub async fn release_delete_token(
+    filename: &str,
+    token: &str,
+    user: &User,
+    opt: &Opt,
+) -> Result<bool, WebError> {
+    let Some(mut meta) = read_meta(filename, user, opt).await? else {
+        return Ok(false);
+    };
+    if meta.shared_delete_tokens.is_empty() {
+        return Ok(false);
+    }
+    if meta.delete_token.as_deref() == Some(token) {
+        meta.delete_token = Some(meta.shared_delete_tokens.remove(0));
+    } else {
+        meta.shared_delete_tokens.retain(|shared| shared != token);
+    }
+    write_meta(filename, &meta, user, opt).await?;
+    Ok(true)
+}
+
+pub async fn write_meta(
+    filename: &str,
+    meta: &FileMeta,
+    user: &User,
+    opt: &Opt,
+) -> Result<(), WebError> {
+    let data = serde_json::to_vec(meta).map_err(std::io::Error::from)?;
+    storage::backend(opt)
+        .put(&meta_key(filename, user), data)
+        .await
+}
+
+pub async fn remove_meta(filename: &str, user: &User, opt: &Opt) -> Result<(), WebError> {
+    storage::backend(opt)
+        .delete(&meta_key(filename, user))
+        .await
+}
+
+/**
+ * Metadata of a file as returned by `/meta`, which tells whether the file is protected by a
+ * password, but not the hash of the password, nor the deletion tokens of later uploads of it.
+ */
+#[derive(Serialize)]
+struct MetaResponse {
+    #[serde(flatten)]
+    meta: FileMeta,
+    protected: bool,
+}
+
+/**
+ * Returns the metadata of an uploaded file. A file stored without any metadata has empty metadata.
+ */
+pub async fn handle_meta(
+    State(opt): State<Opt>,
+    user: User,
+    UrlPath(name): UrlPath<String>,
+) -> Result<Response, WebError> {
+    let filename = sanitize_filename::sanitize(&name);
+    let meta = match read_meta(&filename, &user, &opt).await? {
+        Some(meta) => meta,
+        None if storage::backend(&opt)
+            .exists(&file_key(&filename, &user))
+            .await? =>
+        {
+            FileMeta::default()
+        }
+        None => return Ok(StatusCode::NOT_FOUND.into_response()),
+    };
+    let protected = meta.is_protected();
+    // The deletion tokens of later uploads of the file belong to their uploaders.
+    let meta = FileMeta {
+        view_password_hash: None,
+        shared_delete_tokens: Vec::new(),
+        ..meta
+    };
+    Ok(Json(MetaResponse { meta, protected }).into_response())
+}
+
+/**
+ * Returns true if the content type can't be known from the extension of the filename, either
+ * because it has none, or because the extension is used for several content types.
+ */
+fn needs_stored_content_type(filename: &str, content_type: &str) -> bool {
+    let guesses = mime_guess::from_path(filename);
+    guesses.is_empty() || (guesses.count() > 1 && guesses.iter().any(|guess| guess == content_type))
+}
+
+/**
+ * Adds a Content-Disposition header to a successful response, which makes browsers download the
+ * file if `force_download` is set or it is of a type that could run scripts, and show it
+ * otherwise.
+ */
+fn apply_disposition(mut response: Response, filename: &str, force_download: bool) -> Response {
+    if !response.status().is_success() {
+        return response;
+    }
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let attachment = force_download || ATTACHMENT_TYPES.contains(&content_type.as_str());
+    let disposition = match attachment {
+        true => attachment_disposition(filename),
+        false => HeaderValue::from_static("inline"),
+    };
+    response
+        .headers_mut()
+        .insert(CONTENT_DISPOSITION, disposition);
+    response
+}
+
+/**
+ * Returns `attachment` with the filename to save the file as. Names that aren't plain ASCII are
+ * also given in the extended form, with the plain form as a fallback for old browsers.
+ */
+pub fn attachment_disposition(filename: &str) -> HeaderValue {
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    let disposition = if fallback == filename {
+        format!("attachment; filename=\"{}\"", filename)
+    } else {
+        let encoded = utf8_percent_encode(filename, NON_ALPHANUMERIC);
+        format!(
+            "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+            fallback, encoded
+        )
+    };
+    HeaderValue::from_str(&disposition).unwrap_or(HeaderValue::from_static("attachment"))
+}
+
+/**
+ * Adds Cache-Control and ETag headers to a successful response. If the client already has the
+ * same version, as told by If-None-Match, responds with 304 Not Modified instead.
+ */
+pub fn apply_caching(
+    mut response: Response,
+    request_headers: &HeaderMap,
+    etag: Option<&str>,
+    immutable: bool,
+) -> Response {
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+    let etag = etag.and_then(|etag| HeaderValue::from_str(&format!("\"{}\"", etag)).ok());
+    let not_modified = etag.as_ref().is_some_and(|etag| {
+        request_headers
+            .get_all(IF_NONE_MATCH)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag.as_bytes() == etag.as_bytes())
+    });
+    if not_modified {
+        response = StatusCode::NOT_MODIFIED.into_response();
+    }
+
+    let cache_control = if immutable {
+        IMMUTABLE_CACHE_CONTROL
+    } else {
+        OVERWRITABLE_CACHE_CONTROL
+    };
+    let headers = response.headers_mut();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static(cache_control));
+    if let Some(etag) = etag {
+        headers.insert(ETAG, etag);
+    }
+    response
+}
+
+/**
+ * Middleware in front of the served files, which pretends that expired files no longer exist, even
+ * if the cleanup task hasn't removed them yet. Metadata, which contains deletion tokens, and
+ * partial uploads, in hidden directories or still being copied into place, are never served.
+ * Neither are stored thumbnails, which are served by their own route once their file is checked.
+ * Files whose extension doesn't tell their content type are served with the content type stored in
+ * their metadata. Files with metadata can be cached, for long if they were stored under a random
+ * name. Files protected by a password are only served if it is given, and are never cached.
+ * Downloads of files with limited downloads are counted, and the file is deleted after the last
+ * one. Such files are always sent whole, so that requests for ranges can't get around the limit.
+ * Files are shown by browsers, unless they were uploaded with `forceDownload` or could run scripts.
+ */
+pub async fn apply_meta(
+    State(opt): State<Opt>,
+    mut request: Request,
+    next: middleware::Next,
+) -> Response {
+    let path = percent_decode_str(request.uri().path().trim_start_matches('/'))
+        .decode_utf8_lossy()
+        .into_owned();
+    let hidden = path
+        .rsplit('/')
+        .skip(1)
+        .any(|dir| dir == META_SUBDIR || dir == THUMBNAIL_SUBDIR || dir.starts_with('.'))
+        || path.rsplit('/').next().is_some_and(storage::is_partial);
+    if hidden {
+        return super::handle_404().await.into_response();
+    }
+    // Uploads are stored directly in the base directory, or in the directory of their user.
+    let (user, filename) = match path.split_once('/') {
+        None => (User::default(), path.as_str()),
+        Some((user, filename)) if opt.users.contains_key(user) && !filename.contains('/') => {
+            (User(Some(user.to_string())), filename)
+        }
+        Some(_) => {
+            let name = path.rsplit('/').next().unwrap_or_default();
+            return apply_disposition(next.run(request).await, name, false);
+        }
+    };
+
+    let Ok(Some(meta)) = read_meta(filename, &user, &opt).await else {
+        return apply_disposition(next.run(request).await, filename, false);
+    };
+    if meta.is_expired(Utc::now()) || meta.is_used_up() {
+        return super::handle_404().await.into_response();
+    }
+    let protected = meta.is_protected();
+    if let Some(hash) = meta.view_password_hash.clone() {
+        let password = given_password(&request);
+        let client = client_ip(
+            request.extensions(),
+            request.headers(),
+            &opt.trusted_proxies,
+        );
+        match check_view_password(hash, password, client, filename, &opt).await {
+            Ok(None) => {}
+            Ok(Some(prompt)) => return prompt,
+            Err(e) => return e.into_response(),
+        }
+    }
+
+    // Only downloads count, not checking whether the file is there. Ranges are ignored, as each of
+    // them would otherwise be a download, or none of them, which leaves the limit open.
+    let limited = meta.max_downloads.is_some();
+    if limited {
+        request.headers_mut().remove(RANGE);
+    }
+    let counted = limited && request.method() == Method::GET;
+    let mut last_download = false;
+    if counted {
+        match take_download(filename, &user, &opt).await {
+            Ok(Download::Allowed(left)) => last_download = left == 0,
+            Ok(Download::UsedUp) => return super::handle_404().await.into_response(),
+            Err(e) => return e.into_response(),
+        }
+    }
+
+    let request_headers = request.headers().clone();
+    let mut response = next.run(request).await;
+    if counted {
+        let result = if response.status() != StatusCode::OK {
+            give_back_download(filename, &user, &opt).await
+        } else if last_download {
+            remove_used_up(filename, &user, &opt).await
+        } else {
+            Ok(())
+        };
+        if let Err(e) = result {
+            log::error!("could not count download of {}: {}", filename, e);
+        }
+    }
+    let stored_type = meta
+        .content_type
+        .filter(|content_type| needs_stored_content_type(filename, content_type))
+        .and_then(|content_type| HeaderValue::from_str(&content_type).ok());
+    if let (StatusCode::OK, Some(content_type)) = (response.status(), stored_type) {
+        response.headers_mut().insert(CONTENT_TYPE, content_type);
+    }
+    let mut response = apply_disposition(response, filename, meta.force_download);
+    if protected || limited {
+        response.headers_mut().insert(
+            CACHE_CONTROL,
+            HeaderValue::from_static(PRIVATE_CACHE_CONTROL),
+        );
+        return response;
+    }
+    apply_caching(
+        response,
+        &request_headers,
+        meta.hash.as_deref(),
+        meta.immutable,
+    )
+}
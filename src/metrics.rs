@@ -0,0 +1,116 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use axum::extract::Request;
+use axum::http::header::CONTENT_TYPE;
+use axum::middleware;
+use axum::response::{IntoResponse, Response};
+
+/**
+ * Counters exposed in Prometheus text format at `/metrics`.
+ */
+pub struct Metrics {
+    uploaded_files: AtomicU64,
+    uploaded_bytes: AtomicU64,
+    thumbnails_generated: AtomicU64,
+    thumbnail_errors: AtomicU64,
+    deletes: AtomicU64,
+    upload_requests: Mutex<BTreeMap<u16, u64>>,
+}
+
+pub static METRICS: Metrics = Metrics {
+    uploaded_files: AtomicU64::new(0),
+    uploaded_bytes: AtomicU64::new(0),
+    thumbnails_generated: AtomicU64::new(0),
+    thumbnail_errors: AtomicU64::new(0),
+    deletes: AtomicU64::new(0),
+    upload_requests: Mutex::new(BTreeMap::new()),
+};
+
+impl Metrics {
+    pub fn record_upload(&self, size: usize) {
+        self.uploaded_files.fetch_add(1, Ordering::Relaxed);
+        self.uploaded_bytes
+            .fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_thumbnail(&self, result: &Result<bool, crate::WebError>) {
+        match result {
+            Ok(true) => self.thumbnails_generated.fetch_add(1, Ordering::Relaxed),
+            Ok(false) => 0,
+            Err(_) => self.thumbnail_errors.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    pub fn record_delete(&self) {
+        self.deletes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_upload_request(&self, status: u16) {
+        let mut requests = self.upload_requests.lock().unwrap();
+        *requests.entry(status).or_default() += 1;
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "i_uploaded_files_total",
+                "Number of files stored.",
+                &self.uploaded_files,
+            ),
+            (
+                "i_uploaded_bytes_total",
+                "Number of bytes stored.",
+                &self.uploaded_bytes,
+            ),
+            (
+                "i_thumbnails_generated_total",
+                "Number of thumbnails generated.",
+                &self.thumbnails_generated,
+            ),
+            (
+                "i_thumbnail_errors_total",
+                "Number of failed thumbnail generations.",
+                &self.thumbnail_errors,
+            ),
+            ("i_deletes_total", "Number of deleted files.", &self.deletes),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+
+        let name = "i_upload_requests_total";
+        let _ = writeln!(
+            out,
+            "# HELP {} Number of upload requests by status code.",
+            name
+        );
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for (status, count) in self.upload_requests.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}{{status=\"{}\"}} {}", name, status, count);
+        }
+
+        out
+    }
+}
+
+/**
+ * Middleware counting upload requests by the status code of their response.
+ */
+pub async fn track_upload(request: Request, next: middleware::Next) -> Response {
+    let response = next.run(request).await;
+    METRICS.record_upload_request(response.status().as_u16());
+    response
+}
+
+pub async fn metrics() -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.render(),
+    )
+}
@@ -0,0 +1,57 @@
+use axum::extract::State;
+use axum::response::IntoResponse;
+use axum::Json;
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+use utoipa::openapi::server::Server;
+use utoipa::{Modify, OpenApi};
+
+use super::{delete, recent, upload, Opt};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "i",
+        description = "A simple file uploader web service.",
+        license(name = "AGPL-3.0"),
+    ),
+    paths(
+        upload::handle_upload,
+        upload::handle_exists,
+        delete::handle_delete,
+        delete::handle_bulk_delete,
+        recent::recent_json,
+    ),
+    modifiers(&Authentication),
+    security(("basic" = []), ("bearer" = [])),
+    tags((name = "files", description = "Uploading, listing, and deleting files")),
+)]
+struct ApiDoc;
+
+/**
+ * Adds the basic auth and API token authentication, which are required unless the server runs
+ * without authentication.
+ */
+struct Authentication;
+
+impl Modify for Authentication {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "basic",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Basic)),
+        );
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+    }
+}
+
+/**
+ * Returns the OpenAPI 3 description of the API, with this server as its only server.
+ */
+pub async fn openapi_json(State(opt): State<Opt>) -> impl IntoResponse {
+    let mut openapi = ApiDoc::openapi();
+    openapi.servers = Some(vec![Server::new(opt.server_url)]);
+    Json(openapi)
+}
@@ -0,0 +1,137 @@
+use axum::body::Body;
+use axum::extract::{FromRequest, Query, Request, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use axum::Form;
+use futures::TryStreamExt;
+use serde::Deserialize;
+
+use crate::WebError;
+
+use super::client_ip::ClientIp;
+use super::diskspace::SpaceGuard;
+use super::limits::size_limit;
+use super::sniff::ContentCheck;
+use super::upload::{
+    check_custom_filename, detect_content_type, discard, generate_random_filename,
+    is_blocked_extension, staging_path, store_file, upload_response, write_staged, FileUpload,
+    Options,
+};
+use super::users::User;
+use super::Opt;
+
+/// Longest extension accepted as a language hint.
+const MAX_LANGUAGE_LENGTH: usize = 16;
+
+/**
+ * A snippet of text submitted as a form, such as from a textarea.
+ */
+#[derive(Deserialize)]
+pub struct PasteForm {
+    content: String,
+    language: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PasteQuery {
+    language: Option<String>,
+}
+
+/**
+ * Returns the extension to store a paste with, which is the language hint if it is a plain
+ * extension such as `rs` or `log`, or `txt` if there is none.
+ */
+fn paste_extension(language: Option<&str>) -> Result<String, WebError> {
+    match language.filter(|language| !language.is_empty()) {
+        None => Ok("txt".to_string()),
+        Some(language)
+            if language.len() <= MAX_LANGUAGE_LENGTH
+                && language.chars().all(|c| c.is_ascii_alphanumeric()) =>
+        {
+            Ok(language.to_ascii_lowercase())
+        }
+        Some(_) => Err(WebError::BadRequest),
+    }
+}
+
+/**
+ * Stores a snippet of text as a file, like a pastebin. The text is either the whole request body,
+ * or the `content` field of a form. A `language` in the query string or the form sets the
+ * extension, and options are given in the query string, like for raw uploads.
+ */
+pub async fn handle_paste(
+    State(opt): State<Opt>,
+    user: User,
+    ClientIp(client_ip): ClientIp,
+    Query(options): Query<Options>,
+    Query(query): Query<PasteQuery>,
+    request: Request,
+) -> Result<impl IntoResponse, WebError> {
+    let is_form = request
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/x-www-form-urlencoded"));
+    let (language, body) = if is_form {
+        let Form(form): Form<PasteForm> = Form::from_request(request, &())
+            .await
+            .map_err(|_| WebError::BadRequest)?;
+        (form.language.or(query.language), Body::from(form.content))
+    } else {
+        (query.language, request.into_body())
+    };
+
+    let extension = paste_extension(language.as_deref())?;
+    if is_blocked_extension(&extension, &opt) {
+        return Err(WebError::BlockedExtension(extension));
+    }
+    let random_filename = generate_random_filename(Some(&extension), &opt);
+    let content_type = detect_content_type(&random_filename, Some("text/plain"));
+
+    let staging_path = staging_path(&random_filename, &opt).await?;
+    let chunks = body
+        .into_data_stream()
+        .map_err(|_| WebError::IncompleteBody);
+    let limit = size_limit(Some(&extension), &content_type, &opt);
+    let check = ContentCheck::new(Some(&extension), &content_type, &opt);
+    let (size, hash) =
+        write_staged(&staging_path, chunks, &limit, check, SpaceGuard::new(&opt)).await?;
+    if size == 0 {
+        std::fs::remove_file(&staging_path)?;
+        return Err(WebError::EmptyUpload);
+    }
+
+    let file = FileUpload {
+        original_filename: random_filename.clone(),
+        random_filename,
+        staging_path,
+        size,
+        content_type,
+        hash,
+    };
+    if let Some(filename) = &options.filename {
+        if let Err(e) =
+            check_custom_filename(filename, 1, options.overwrite, client_ip, &user, &opt).await
+        {
+            discard(&[file]);
+            return Err(e);
+        }
+    }
+
+    let uploaded = store_file(file, &options, &user, &opt).await?;
+    upload_response(vec![uploaded], &options, &opt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn language_sets_extension() {
+        assert_eq!("txt", paste_extension(None).unwrap());
+        assert_eq!("txt", paste_extension(Some("")).unwrap());
+        assert_eq!("rs", paste_extension(Some("RS")).unwrap());
+        assert!(paste_extension(Some("../rs")).is_err());
+        assert!(paste_extension(Some("tar.gz")).is_err());
+    }
+}
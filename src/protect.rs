@@ -0,0 +1,104 @@
+use std::net::IpAddr;
+use std::time::Instant;
+
+use askama_axum::Template;
+use axum::extract::Request;
+use axum::http::header::CACHE_CONTROL;
+use axum::http::{HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum_extra::headers::{authorization::Basic, Authorization, HeaderMapExt};
+use url::form_urlencoded;
+
+use crate::WebError;
+
+use super::meta::PRIVATE_CACHE_CONTROL;
+use super::{helpers::public_path, Opt};
+
+/// Query parameter with the password of a protected file, as sent by the prompt page.
+const PASSWORD_PARAM: &str = "pw";
+
+/// Wrong passwords a single IP address may give per minute, and in a burst.
+const PASSWORD_ATTEMPTS_PER_MINUTE: u32 = 10;
+
+#[derive(Template)]
+#[template(path = "password.html")]
+struct PasswordTemplate {
+    filename: String,
+    bulma_url: String,
+    /// Whether a password was given, but it was wrong.
+    wrong: bool,
+}
+
+/**
+ * Hashes the password that is needed to view a file, to store in its metadata.
+ */
+pub async fn hash_view_password(password: String) -> Result<String, WebError> {
+    // bcrypt is deliberately slow, use threadpool
+    tokio::task::spawn_blocking(move || bcrypt::hash(password, bcrypt::DEFAULT_COST))
+        .await?
+        .map_err(|e| std::io::Error::other(e).into())
+}
+
+/**
+ * Returns the password given with a request, either in the query string or as the password of
+ * basic auth. The username of basic auth is ignored.
+ */
+pub fn given_password(request: &Request) -> Option<String> {
+    let query = request.uri().query().unwrap_or_default();
+    form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == PASSWORD_PARAM)
+        .map(|(_, value)| value.into_owned())
+        .or_else(|| {
+            let creds = request.headers().typed_get::<Authorization<Basic>>()?;
+            Some(creds.password().to_string())
+        })
+}
+
+/**
+ * Checks the password given to view a protected file. Returns `None` if it was right, or otherwise
+ * a page asking for it. Clients that give too many wrong passwords have to wait before trying
+ * again, which keeps them from guessing, and from keeping the server busy checking guesses.
+ */
+pub async fn check_view_password(
+    hash: String,
+    password: Option<String>,
+    client: Option<IpAddr>,
+    filename: &str,
+    opt: &Opt,
+) -> Result<Option<Response>, WebError> {
+    let wrong = password.is_some();
+    if let Some(password) = password {
+        if let Some(ip) = client {
+            let limit = PASSWORD_ATTEMPTS_PER_MINUTE;
+            if let Err(wait) = opt
+                .password_limiter
+                .acquire(ip, limit, limit, Instant::now())
+            {
+                log::info!("rate limited password attempt from {}", ip);
+                return Err(WebError::TooManyRequests(wait.as_secs().saturating_add(1)));
+            }
+        }
+        // bcrypt is deliberately slow, use threadpool
+        let matches = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash)).await?;
+        if matches.unwrap_or(false) {
+            // Only wrong passwords count, so that the file can be viewed as often as needed.
+            if let Some(ip) = client {
+                opt.password_limiter
+                    .give_back(ip, PASSWORD_ATTEMPTS_PER_MINUTE);
+            }
+            return Ok(None);
+        }
+    }
+
+    let template = PasswordTemplate {
+        filename: filename.to_string(),
+        bulma_url: public_path("recent/bulma.min.css", opt)?,
+        wrong,
+    };
+    let mut response = (StatusCode::UNAUTHORIZED, template).into_response();
+    response.headers_mut().insert(
+        CACHE_CONTROL,
+        HeaderValue::from_static(PRIVATE_CACHE_CONTROL),
+    );
+    Ok(Some(response))
+}
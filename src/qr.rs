@@ -0,0 +1,74 @@
+use axum::extract::{Path as UrlPath, State};
+use axum::http::header::CONTENT_TYPE;
+use axum::response::{IntoResponse, Response};
+use qrcode::render::svg;
+use qrcode::QrCode;
+
+use crate::WebError;
+
+use super::helpers::{file_key, file_url_path, public_path, user_url_path};
+use super::users::User;
+use super::{storage, Opt};
+
+/// Smallest width and height of a QR code, in pixels, so that it is easy to scan from a screen.
+const QR_MIN_SIZE: u32 = 256;
+
+/**
+ * Returns the url of the QR code of the public url of the given file.
+ */
+pub fn get_qr_url(filename: &str, user: &User, opt: &Opt) -> Result<String, url::ParseError> {
+    public_path(&format!("qr/{}", user_url_path(filename, user)), opt)
+}
+
+/**
+ * Renders the data as a QR code in SVG format.
+ */
+fn render_qr(data: &str) -> Result<String, WebError> {
+    let code = QrCode::new(data.as_bytes()).map_err(|_| WebError::BadRequest)?;
+    Ok(code
+        .render::<svg::Color>()
+        .min_dimensions(QR_MIN_SIZE, QR_MIN_SIZE)
+        .build())
+}
+
+/**
+ * Serves a QR code of the public url of an uploaded file, to open it on a phone.
+ */
+pub async fn serve_qr(
+    State(opt): State<Opt>,
+    UrlPath(path): UrlPath<String>,
+) -> Result<Response, WebError> {
+    // Uploads of users are stored in their own directory, which is part of the path.
+    let (user, filename) = match path.split_once('/') {
+        Some((user, filename)) if opt.users.contains_key(user) => {
+            (User(Some(user.to_string())), filename)
+        }
+        Some(_) => return Ok(super::handle_404().await.into_response()),
+        None => (User::default(), path.as_str()),
+    };
+
+    if !sanitize_filename::is_sanitized(filename) {
+        return Err(WebError::BadRequest);
+    }
+    if !storage::backend(&opt)
+        .exists(&file_key(filename, &user))
+        .await?
+    {
+        return Ok(super::handle_404().await.into_response());
+    }
+
+    let url = public_path(&file_url_path(filename, &user, &opt), &opt)?;
+    Ok(([(CONTENT_TYPE, "image/svg+xml")], render_qr(&url)?).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_svg() {
+        let svg = render_qr("http://localhost:8088/Uake9Um7.txt").unwrap();
+        assert!(svg.starts_with("<?xml"));
+        assert!(svg.contains("<svg"));
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::extract::{Request, State};
+use axum::middleware;
+use axum::response::Response;
+
+use crate::WebError;
+
+use super::{client_ip::client_ip, Opt};
+
+/// Number of clients to keep track of before forgetting those that are no longer limited.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/**
+ * Token bucket rate limiter, with one bucket per client IP address.
+ */
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /**
+     * Takes a token from the bucket of the given client. If the bucket is empty, returns how long
+     * the client has to wait until the next token is available.
+     */
+    pub fn acquire(
+        &self,
+        ip: IpAddr,
+        per_minute: u32,
+        burst: u32,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let per_second = f64::from(per_minute) / 60.0;
+        let burst = f64::from(burst.max(1));
+        let refill = |bucket: &Bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            (bucket.tokens + elapsed * per_second).min(burst)
+        };
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            // Full buckets behave just like new ones, so there is no need to remember them.
+            buckets.retain(|_, bucket| refill(bucket) < burst);
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        bucket.tokens = refill(bucket);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else if per_second > 0.0 {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_second))
+        } else {
+            Err(Duration::MAX)
+        }
+    }
+
+    /**
+     * Returns a token taken by `acquire`, for a request that turned out not to count.
+     */
+    pub fn give_back(&self, ip: IpAddr, burst: u32) {
+        if let Some(bucket) = self.buckets.lock().unwrap().get_mut(&ip) {
+            bucket.tokens = (bucket.tokens + 1.0).min(f64::from(burst.max(1)));
+        }
+    }
+}
+
+/**
+ * Middleware limiting the number of uploads per client IP address, if `--rate-limit` is set.
+ */
+pub async fn limit_uploads(
+    State(opt): State<Opt>,
+    request: Request,
+    next: middleware::Next,
+) -> Result<Response, WebError> {
+    let Some(per_minute) = opt.rate_limit else {
+        return Ok(next.run(request).await);
+    };
+    let client = client_ip(
+        request.extensions(),
+        request.headers(),
+        &opt.trusted_proxies,
+    );
+
+    if let Some(ip) = client {
+        let burst = opt.rate_limit_burst.unwrap_or(per_minute);
+        if let Err(wait) = opt
+            .rate_limiter
+            .acquire(ip, per_minute, burst, Instant::now())
+        {
+            log::info!("rate limited upload from {}", ip);
+            return Err(WebError::TooManyRequests(wait.as_secs().saturating_add(1)));
+        }
+    }
+
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refills_over_time() {
+        let limiter = RateLimiter::default();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "2001:db8::1".parse().unwrap();
+        let now = Instant::now();
+
+        // 6 per minute is one every ten seconds, with a burst of two.
+        assert!(limiter.acquire(ip, 6, 2, now).is_ok());
+        assert!(limiter.acquire(ip, 6, 2, now).is_ok());
+        assert_eq!(Err(Duration::from_secs(10)), limiter.acquire(ip, 6, 2, now));
+        assert!(limiter.acquire(other, 6, 2, now).is_ok());
+
+        assert!(limiter
+            .acquire(ip, 6, 2, now + Duration::from_secs(10))
+            .is_ok());
+        assert!(limiter
+            .acquire(ip, 6, 2, now + Duration::from_secs(10))
+            .is_err());
+
+        let later = now + Duration::from_secs(20);
+        assert!(limiter.acquire(ip, 6, 2, later).is_ok());
+        limiter.give_back(ip, 2);
+        assert!(limiter.acquire(ip, 6, 2, later).is_ok());
+        assert!(limiter.acquire(ip, 6, 2, later).is_err());
+    }
+}
@@ -1,98 +1,740 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
 use askama_axum::Template;
-use axum::extract::State;
+use axum::extract::{Query, State};
+use axum::http::header::CONTENT_TYPE;
 use axum::response::IntoResponse;
+use axum::Json;
+use axum_extra::extract::cookie::CookieJar;
 use chrono::offset::Local;
-use chrono::DateTime;
-use std::io;
-use std::path::Path;
-use std::time::SystemTime;
-use std::{fs, fs::DirEntry};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
 
 use crate::WebError;
 
-use super::{get_base_dir, Opt};
+use super::{
+    csrf::csrf_token,
+    helpers::{file_url_path, public_path, user_dir},
+    meta::{read_meta, FileMeta},
+    storage::{self, StoredObject},
+    users::User,
+    Opt,
+};
+
+struct RecentEntry {
+    thumbnail_url: String,
+    timestamp: String,
+    url: String,
+    filename: String,
+    delete_token: Option<String>,
+    /// Size of the file, formatted for humans.
+    size: String,
+    /// Content type of the file, as stored on upload or guessed from its extension.
+    mime: String,
+    kind: FileKind,
+    /// Whether the file is an image, which is linked as an image in copied markdown.
+    is_image: bool,
+    /// Whether a password is needed to view the file.
+    protected: bool,
+}
 
-struct DirEntryModTimePair {
-    dir_entry: DirEntry,
-    mod_time: SystemTime,
+/// Broad type of a file, to show an icon for files without a thumbnail.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FileKind {
+    Image,
+    Video,
+    Audio,
+    Text,
+    Other,
 }
 
-struct RecentEntry {
+impl FileKind {
+    fn from_mime(mime: &str) -> FileKind {
+        match mime.split('/').next() {
+            Some("image") => FileKind::Image,
+            Some("video") => FileKind::Video,
+            Some("audio") => FileKind::Audio,
+            Some("text") => FileKind::Text,
+            _ => FileKind::Other,
+        }
+    }
+
+    fn icon(self) -> &'static str {
+        match self {
+            FileKind::Image => "🖼️",
+            FileKind::Video => "🎞️",
+            FileKind::Audio => "🎵",
+            FileKind::Text => "📄",
+            FileKind::Other => "📦",
+        }
+    }
+}
+
+/**
+ * Formats a number of bytes for humans, such as "2.3 MiB".
+ */
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = UNITS[0];
+    for next in &UNITS[1..] {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next;
+    }
+    format!("{:.1} {}", size, unit)
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+struct RecentJsonEntry {
+    url: String,
     thumbnail_url: String,
+    /// Modification time of the file, in RFC 3339 format.
     timestamp: String,
+    size: u64,
+    protected: bool,
+}
+
+#[derive(Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct Pagination {
+    /// Page to list, starting at 1.
+    page: Option<usize>,
+    #[serde(default)]
+    #[param(inline)]
+    sort: SortBy,
+    /// Defaults to ascending for names, and descending for dates and sizes.
+    #[param(inline)]
+    order: Option<Order>,
+    #[serde(default)]
+    #[param(inline)]
+    filter: Filter,
+    /// Only list files whose name contains this, ignoring case.
+    q: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum SortBy {
+    Name,
+    #[default]
+    Date,
+    Size,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum Order {
+    Asc,
+    Desc,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum Filter {
+    Images,
+    #[default]
+    All,
+}
+
+impl SortBy {
+    fn as_str(self) -> &'static str {
+        match self {
+            SortBy::Name => "name",
+            SortBy::Date => "date",
+            SortBy::Size => "size",
+        }
+    }
+
+    /// Names are listed alphabetically, and dates and sizes largest first, unless asked otherwise.
+    fn default_order(self) -> Order {
+        match self {
+            SortBy::Name => Order::Asc,
+            SortBy::Date | SortBy::Size => Order::Desc,
+        }
+    }
+}
+
+impl Order {
+    fn as_str(self) -> &'static str {
+        match self {
+            Order::Asc => "asc",
+            Order::Desc => "desc",
+        }
+    }
+
+    fn reversed(self) -> Order {
+        match self {
+            Order::Asc => Order::Desc,
+            Order::Desc => Order::Asc,
+        }
+    }
+}
+
+impl Filter {
+    fn as_str(self) -> &'static str {
+        match self {
+            Filter::Images => "images",
+            Filter::All => "all",
+        }
+    }
+}
+
+impl Pagination {
+    fn order(&self) -> Order {
+        self.order.unwrap_or_else(|| self.sort.default_order())
+    }
+
+    fn search(&self) -> Option<&str> {
+        self.q.as_deref().filter(|q| !q.is_empty())
+    }
+
+    /// Query string of the sort order, filter, and search, to keep them when changing pages.
+    fn query_string(&self) -> String {
+        format!(
+            "&{}",
+            query_string(self.sort, self.order(), self.filter, self.search())
+        )
+    }
+}
+
+fn query_string(sort: SortBy, order: Order, filter: Filter, search: Option<&str>) -> String {
+    let mut query = url::form_urlencoded::Serializer::new(String::new());
+    query
+        .append_pair("sort", sort.as_str())
+        .append_pair("order", order.as_str())
+        .append_pair("filter", filter.as_str());
+    if let Some(search) = search {
+        query.append_pair("q", search);
+    }
+    query.finish()
+}
+
+/// Link to the list sorted or filtered differently, shown as a button.
+struct QueryLink {
+    label: String,
+    href: String,
+    active: bool,
+}
+
+fn query_link(
+    label: String,
+    query: &Pagination,
+    sort: SortBy,
+    order: Order,
+    filter: Filter,
+    active: bool,
+) -> QueryLink {
+    QueryLink {
+        label,
+        href: format!(
+            "recent?{}",
+            query_string(sort, order, filter, query.search())
+        ),
+        active,
+    }
+}
+
+/**
+ * Links to sort the list by each column. The link of the current column toggles the order.
+ */
+fn sort_links(query: &Pagination) -> Vec<QueryLink> {
+    [SortBy::Name, SortBy::Date, SortBy::Size]
+        .into_iter()
+        .map(|sort| {
+            if sort != query.sort {
+                let label = format!("Sort by {}", sort.as_str());
+                return query_link(
+                    label,
+                    query,
+                    sort,
+                    sort.default_order(),
+                    query.filter,
+                    false,
+                );
+            }
+            let arrow = match query.order() {
+                Order::Asc => "↑",
+                Order::Desc => "↓",
+            };
+            let label = format!("Sort by {} {}", sort.as_str(), arrow);
+            let order = query.order().reversed();
+            query_link(label, query, sort, order, query.filter, true)
+        })
+        .collect()
+}
+
+fn filter_links(query: &Pagination) -> Vec<QueryLink> {
+    [(Filter::All, "All files"), (Filter::Images, "Images")]
+        .into_iter()
+        .map(|(filter, label)| {
+            let active = filter == query.filter;
+            let label = label.to_string();
+            query_link(label, query, query.sort, query.order(), filter, active)
+        })
+        .collect()
+}
+
+struct FeedEntry {
+    filename: String,
     url: String,
+    thumbnail_url: String,
+    updated: String,
+}
+
+#[derive(Template)]
+#[template(path = "recent.xml")]
+struct FeedTemplate {
+    feed_url: String,
+    updated: String,
+    entries: Vec<FeedEntry>,
+}
+
+/// Page numbers to render in the pagination bar. Pages are numbered from 1.
+struct PaginationBar {
+    current: usize,
+    max: usize,
+    prev: Option<usize>,
+    next: Option<usize>,
+    /// Index of the first file on the current page, counted from 0.
+    offset: usize,
+    per_page: usize,
 }
 
 #[derive(Template)]
 #[template(path = "recent.html")]
 struct RecentTemplate {
     recents: Vec<RecentEntry>,
+    pagination: PaginationBar,
+    insecure_delete: bool,
+    sort_links: Vec<QueryLink>,
+    filter_links: Vec<QueryLink>,
+    /// Sort order, filter, and search, to append to the links of the pagination bar.
+    query: String,
+    csrf_token: String,
+    sort: &'static str,
+    order: &'static str,
+    filter: &'static str,
+    search: String,
+}
+
+#[derive(Template)]
+#[template(path = "gallery.html")]
+struct GalleryTemplate {
+    recents: Vec<RecentEntry>,
+    pagination: PaginationBar,
+    /// Sort order, filter, and search, to append to the links of the pagination bar.
+    query: String,
 }
 
-fn build_recent_html_page(
-    files: &[&DirEntryModTimePair],
-    prefix_length: usize,
+/**
+ * Picks the page to show, out of pages of `per_page` files each. Pages past the last one show the
+ * last page, and there is always at least one, possibly empty, page.
+ */
+fn build_pagination(page: usize, n_of_files: usize, per_page: usize) -> PaginationBar {
+    let per_page = per_page.max(1);
+    let max = n_of_files.div_ceil(per_page).max(1);
+    let current = page.clamp(1, max);
+    PaginationBar {
+        current,
+        max,
+        prev: (current > 1).then(|| current - 1),
+        next: (current < max).then(|| current + 1),
+        offset: (current - 1) * per_page,
+        per_page,
+    }
+}
+
+fn current_page<'a>(
+    files: &'a [StoredObject],
+    pagination: &PaginationBar,
+) -> Vec<&'a StoredObject> {
+    files
+        .iter()
+        .skip(pagination.offset)
+        .take(pagination.per_page)
+        .collect()
+}
+
+/**
+ * Entries of the recent page and the gallery, with the metadata of each file.
+ */
+async fn recent_entries(
+    files: &[&StoredObject],
+    user: &User,
     opt: &Opt,
-) -> Result<impl IntoResponse, WebError> {
-    // Stringify DirEntryModTimePair
+) -> Result<Vec<RecentEntry>, WebError> {
+    // Stringify StoredObject
     // TODO: can we make some magic converter Trait to do this outside this function?
     let mut recents: Vec<RecentEntry> = Vec::new();
     for entry in files {
-        if let Some(x) = entry.dir_entry.path().to_str() {
-            let path = &x[prefix_length..];
-            let datetime: DateTime<Local> = entry.mod_time.into();
-            recents.push(RecentEntry {
-                timestamp: datetime.format("%Y-%m-%d %T").to_string(),
-                url: path.to_string(),
-                thumbnail_url: super::thumbnail::get_thumbnail_url(path, opt)?,
+        let datetime: DateTime<Local> = entry.modified.into();
+        let meta = read_meta(&entry.name, user, opt).await?;
+        let mime = meta
+            .as_ref()
+            .and_then(|meta| meta.content_type.clone())
+            .unwrap_or_else(|| {
+                mime_guess::from_path(&entry.name)
+                    .first_or_octet_stream()
+                    .to_string()
             });
-        }
+        let protected = meta.as_ref().is_some_and(FileMeta::is_protected);
+        let kind = FileKind::from_mime(&mime);
+        recents.push(RecentEntry {
+            timestamp: datetime.format("%Y-%m-%d %T").to_string(),
+            thumbnail_url: super::thumbnail::get_thumbnail_url(&entry.name, None, user, opt),
+            delete_token: meta.and_then(|meta| meta.delete_token),
+            url: file_url_path(&entry.name, user, opt),
+            filename: entry.name.clone(),
+            size: human_size(entry.size),
+            kind,
+            is_image: kind == FileKind::Image,
+            mime,
+            protected,
+        });
     }
+    Ok(recents)
+}
 
-    let template = RecentTemplate { recents };
+async fn build_recent_html_page(
+    files: &[&StoredObject],
+    pagination: PaginationBar,
+    query: &Pagination,
+    csrf_token: String,
+    user: &User,
+    opt: &Opt,
+) -> Result<impl IntoResponse, WebError> {
+    let template = RecentTemplate {
+        recents: recent_entries(files, user, opt).await?,
+        pagination,
+        insecure_delete: opt.insecure_delete,
+        sort_links: sort_links(query),
+        filter_links: filter_links(query),
+        query: query.query_string(),
+        csrf_token,
+        sort: query.sort.as_str(),
+        order: query.order().as_str(),
+        filter: query.filter.as_str(),
+        search: query.search().unwrap_or_default().to_string(),
+    };
     Ok(template)
 }
 
-pub async fn recent(State(opt): State<Opt>) -> Result<impl IntoResponse, WebError> {
-    let mut files = Vec::new();
-
-    let base_dir = get_base_dir(&opt)?;
-    visit_dirs(&base_dir, &mut files)?;
+async fn build_recent_json(
+    files: &[&StoredObject],
+    user: &User,
+    opt: &Opt,
+) -> Result<Vec<RecentJsonEntry>, WebError> {
+    let mut recents = Vec::new();
+    for entry in files {
+        let datetime: DateTime<Local> = entry.modified.into();
+        let thumbnail_url = super::thumbnail::get_thumbnail_url(&entry.name, None, user, opt);
+        let protected = read_meta(&entry.name, user, opt)
+            .await?
+            .is_some_and(|meta| meta.is_protected());
+        recents.push(RecentJsonEntry {
+            url: public_path(&file_url_path(&entry.name, user, opt), opt)?,
+            thumbnail_url: public_path(&thumbnail_url, opt)?,
+            timestamp: datetime.to_rfc3339(),
+            size: entry.size,
+            protected,
+        });
+    }
 
-    // note the order of the partial_cmp
-    files.sort_by(|a, b| b.mod_time.partial_cmp(&a.mod_time).unwrap());
+    Ok(recents)
+}
 
-    let n_of_recent_files = opt.recents;
-    let latest_n_files: Vec<&DirEntryModTimePair> = files.iter().take(n_of_recent_files).collect();
+/// Uploaded files of a user, as listed from storage.
+type Listing = Arc<Vec<StoredObject>>;
 
-    build_recent_html_page(&latest_n_files, base_dir.to_string_lossy().len() + 1, &opt)
-    // + 1 for the dir separator
+/**
+ * Recent listings of the uploads of each user, so that the storage isn't listed on every request.
+ * A listing is dropped as soon as a file is uploaded or deleted, and is otherwise reused for
+ * `--recent-cache-ttl` seconds, after which changes made outside of i are seen too.
+ */
+#[derive(Debug, Default)]
+pub struct RecentCache {
+    listings: Mutex<HashMap<User, (Instant, Listing)>>,
+    /// Counts invalidations, so that a listing started before a change isn't cached after it.
+    generation: AtomicU64,
 }
 
-// Inspired by first example here https://doc.rust-lang.org/std/fs/fn.read_dir.html
-fn visit_dirs(dir: &Path, files: &mut Vec<DirEntryModTimePair>) -> io::Result<()> {
-    // TODO: Check error handling when I know more about error handling in Rust.
-    if dir.is_dir() {
-        for entry in fs::read_dir(dir)? {
-            let dir_entry = entry?;
-            let path = dir_entry.path();
-            if path.is_dir() {
-                if !path.ends_with(crate::THUMBNAIL_SUBDIR) {
-                    visit_dirs(&path, files)?
-                }
-            } else {
-                let mod_time = match dir_entry.metadata()?.modified() {
-                    Ok(n) => n,
-                    Err(_) => panic!("SystemTime before UNIX EPOCH!"),
-                };
+impl RecentCache {
+    fn get(&self, user: &User, max_age: Duration) -> Option<Listing> {
+        let listings = self.listings.lock().unwrap();
+        let (listed, files) = listings.get(user)?;
+        (listed.elapsed() < max_age).then(|| files.clone())
+    }
 
-                files.push(DirEntryModTimePair {
-                    dir_entry,
-                    mod_time,
-                });
-            }
+    fn insert(&self, user: User, generation: u64, files: Listing) {
+        let mut listings = self.listings.lock().unwrap();
+        if self.generation.load(Ordering::SeqCst) == generation {
+            listings.insert(user, (Instant::now(), files));
         }
     }
 
-    Ok(())
+    /**
+     * Drops the listing of `user`, whose uploads have changed.
+     */
+    pub fn invalidate(&self, user: &User) {
+        let mut listings = self.listings.lock().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        listings.remove(user);
+    }
+}
+
+/**
+ * Lists all uploaded files of the current user, or reuses a recent listing.
+ */
+pub async fn list_files(user: &User, opt: &Opt) -> Result<Listing, WebError> {
+    let max_age = Duration::from_secs(opt.recent_cache_ttl);
+    if let Some(files) = opt.recent_cache.get(user, max_age) {
+        return Ok(files);
+    }
+
+    let generation = opt.recent_cache.generation.load(Ordering::SeqCst);
+    // Listing isn't recursive, so thumbnails, metadata, and the trash are not included.
+    let files = Arc::new(storage::backend(opt).list(user_dir(user)).await?);
+    opt.recent_cache
+        .insert(user.clone(), generation, files.clone());
+    Ok(files)
+}
+
+/**
+ * Collects all uploaded files, filtered, searched, and sorted as asked for. Files are sorted by their
+ * modification time, most recent first, by default.
+ */
+async fn sorted_files(
+    query: &Pagination,
+    user: &User,
+    opt: &Opt,
+) -> Result<Vec<StoredObject>, WebError> {
+    let mut files = list_files(user, opt).await?.to_vec();
+
+    if query.filter == Filter::Images {
+        files.retain(|file| is_image(&file.name));
+    }
+    if let Some(search) = query.search() {
+        let search = search.to_lowercase();
+        files.retain(|file| file.name.to_lowercase().contains(&search));
+    }
+    sort_files(&mut files, query.sort, query.order());
+
+    Ok(files)
+}
+
+fn is_image(filename: &str) -> bool {
+    mime_guess::from_path(filename)
+        .first()
+        .is_some_and(|mime| mime.type_() == mime_guess::mime::IMAGE)
+}
+
+fn sort_files(files: &mut [StoredObject], sort: SortBy, order: Order) {
+    match sort {
+        SortBy::Name => files.sort_by(|a, b| a.name.cmp(&b.name)),
+        SortBy::Date => files.sort_by_key(|file| file.modified),
+        SortBy::Size => files.sort_by_key(|file| file.size),
+    }
+    if order == Order::Desc {
+        files.reverse();
+    }
+}
+
+pub async fn recent(
+    State(opt): State<Opt>,
+    user: User,
+    Query(query): Query<Pagination>,
+    jar: CookieJar,
+) -> Result<impl IntoResponse, WebError> {
+    let files = sorted_files(&query, &user, &opt).await?;
+
+    let pagination = build_pagination(query.page.unwrap_or(1), files.len(), opt.recents);
+    let page_files = current_page(&files, &pagination);
+
+    let (jar, csrf_token) = csrf_token(jar, &opt);
+    let page =
+        build_recent_html_page(&page_files, pagination, &query, csrf_token, &user, &opt).await?;
+    Ok((jar, page))
+}
+
+/**
+ * Grid of the thumbnails of the recent uploads, with the same pages, sort orders, and filters as
+ * the recent page.
+ */
+pub async fn gallery(
+    State(opt): State<Opt>,
+    user: User,
+    Query(query): Query<Pagination>,
+) -> Result<impl IntoResponse, WebError> {
+    let files = sorted_files(&query, &user, &opt).await?;
+
+    let pagination = build_pagination(query.page.unwrap_or(1), files.len(), opt.recents);
+    let page_files = current_page(&files, &pagination);
+
+    Ok(GalleryTemplate {
+        recents: recent_entries(&page_files, &user, &opt).await?,
+        pagination,
+        query: query.query_string(),
+    })
+}
+
+#[utoipa::path(
+    get,
+    path = "/recent.json",
+    tag = "files",
+    summary = "List the most recent uploads",
+    params(Pagination),
+    responses(
+        (status = 200, description = "A page of the most recent uploads", body = [RecentJsonEntry]),
+    )
+)]
+pub async fn recent_json(
+    State(opt): State<Opt>,
+    user: User,
+    Query(query): Query<Pagination>,
+) -> Result<impl IntoResponse, WebError> {
+    let files = sorted_files(&query, &user, &opt).await?;
+
+    let pagination = build_pagination(query.page.unwrap_or(1), files.len(), opt.recents);
+    let page_files = current_page(&files, &pagination);
+
+    Ok(Json(build_recent_json(&page_files, &user, &opt).await?))
+}
+
+/**
+ * Atom feed of the most recent uploads, with as many entries as a page of the recent page.
+ */
+pub async fn recent_atom(
+    State(opt): State<Opt>,
+    user: User,
+) -> Result<impl IntoResponse, WebError> {
+    let files = sorted_files(&Pagination::default(), &user, &opt).await?;
+
+    let mut entries = Vec::new();
+    for entry in files.iter().take(opt.recents) {
+        let datetime: DateTime<Utc> = entry.modified.into();
+        let thumbnail_url = super::thumbnail::get_thumbnail_url(&entry.name, None, &user, &opt);
+        entries.push(FeedEntry {
+            filename: entry.name.clone(),
+            url: public_path(&file_url_path(&entry.name, &user, &opt), &opt)?,
+            thumbnail_url: public_path(&thumbnail_url, &opt)?,
+            updated: datetime.to_rfc3339(),
+        });
+    }
+
+    // An empty feed was last updated now, as far as anyone can tell.
+    let updated = files
+        .first()
+        .map(|file| DateTime::<Utc>::from(file.modified))
+        .unwrap_or_else(Utc::now);
+    let feed = FeedTemplate {
+        feed_url: public_path("recent.atom", &opt)?,
+        updated: updated.to_rfc3339(),
+        entries,
+    };
+    Ok(([(CONTENT_TYPE, "application/atom+xml")], feed.render()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bar(page: usize, n_of_files: usize) -> (usize, usize, Option<usize>, Option<usize>) {
+        let bar = build_pagination(page, n_of_files, 15);
+        (bar.current, bar.max, bar.prev, bar.next)
+    }
+
+    #[test]
+    fn pagination_without_files() {
+        assert_eq!((1, 1, None, None), bar(1, 0));
+        assert_eq!((1, 1, None, None), bar(2, 0));
+    }
+
+    #[test]
+    fn pagination_with_one_full_page() {
+        assert_eq!((1, 1, None, None), bar(1, 15));
+        assert_eq!((1, 1, None, None), bar(2, 15));
+    }
+
+    #[test]
+    fn pagination_with_one_more_than_a_page() {
+        assert_eq!((1, 2, None, Some(2)), bar(1, 16));
+        assert_eq!((2, 2, Some(1), None), bar(2, 16));
+        assert_eq!((2, 2, Some(1), None), bar(3, 16));
+        assert_eq!((1, 2, None, Some(2)), bar(0, 16));
+    }
+
+    #[test]
+    fn current_page_has_remaining_files() {
+        let now = std::time::SystemTime::now();
+        let files: Vec<StoredObject> = (0..16)
+            .map(|i| StoredObject {
+                key: i.to_string(),
+                name: i.to_string(),
+                modified: now,
+                size: 0,
+            })
+            .collect();
+
+        let pagination = build_pagination(2, files.len(), 15);
+        let page: Vec<&str> = current_page(&files, &pagination)
+            .iter()
+            .map(|file| file.name.as_str())
+            .collect();
+        assert_eq!(vec!["15"], page);
+
+        // Zero files per page still shows one file per page.
+        let pagination = build_pagination(3, files.len(), 0);
+        assert_eq!(16, pagination.max);
+        assert_eq!(1, current_page(&files, &pagination).len());
+    }
+
+    #[test]
+    fn human_sizes() {
+        assert_eq!("0 B", human_size(0));
+        assert_eq!("1023 B", human_size(1023));
+        assert_eq!("1.0 KiB", human_size(1024));
+        assert_eq!("2.3 MiB", human_size(2_411_725));
+        assert_eq!("1024.0 PiB", human_size(1 << 60));
+    }
+
+    #[test]
+    fn file_kinds() {
+        assert_eq!(FileKind::Image, FileKind::from_mime("image/webp"));
+        assert_eq!(FileKind::Video, FileKind::from_mime("video/mp4"));
+        assert_eq!(FileKind::Other, FileKind::from_mime("application/pdf"));
+    }
+
+    #[test]
+    fn sort_by_name_ascending() {
+        let now = std::time::SystemTime::now();
+        let mut files: Vec<StoredObject> = ["b.png", "c.txt", "a.jpg"]
+            .into_iter()
+            .map(|name| StoredObject {
+                key: name.to_string(),
+                name: name.to_string(),
+                modified: now,
+                size: 0,
+            })
+            .collect();
+        let query: Pagination = serde_json::from_str(r#"{"sort":"name"}"#).unwrap();
+        assert_eq!(Order::Asc, query.order());
+
+        sort_files(&mut files, query.sort, query.order());
+        let names: Vec<&str> = files.iter().map(|file| file.name.as_str()).collect();
+        assert_eq!(vec!["a.jpg", "b.png", "c.txt"], names);
+
+        files.retain(|file| is_image(&file.name));
+        assert_eq!(2, files.len());
+    }
 }
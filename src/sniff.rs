@@ -0,0 +1,190 @@
+use std::path::Path;
+
+use infer::MatcherType;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+use crate::WebError;
+
+use super::Opt;
+
+/// Number of bytes at the start of a file that its type is sniffed from.
+const SNIFF_SIZE: usize = 8 * 1024;
+
+/// Extensions of formats that are zip archives, and are sniffed as such.
+const ZIP_EXTENSIONS: &[&str] = &[
+    "apk", "cbz", "docx", "epub", "jar", "kmz", "odp", "ods", "odt", "pptx", "xlsx", "xpi",
+];
+
+/// Whether uploads are rejected if their content doesn't match their extension or content type.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ContentTypeCheck {
+    /// Uploads are not checked.
+    #[default]
+    Off,
+    /// Uploads that look like another known type are rejected. Text and unknown types pass.
+    Lenient,
+    /// Like lenient, but also rejects uploads that don't look like anything, while their
+    /// extension or content type is one that can be recognized, such as a PNG image.
+    Strict,
+}
+
+/**
+ * Checks that the content of an upload matches its extension and content type, by sniffing the
+ * magic bytes at its start. Only the first few KiB are kept, however large the upload is.
+ */
+pub struct ContentCheck<'a> {
+    extension: Option<String>,
+    content_type: &'a str,
+    mode: ContentTypeCheck,
+    head: Vec<u8>,
+    checked: bool,
+}
+
+impl<'a> ContentCheck<'a> {
+    pub fn new(extension: Option<&str>, content_type: &'a str, opt: &Opt) -> Self {
+        ContentCheck {
+            extension: extension.map(str::to_ascii_lowercase),
+            content_type,
+            mode: opt.enforce_content_type,
+            head: Vec::new(),
+            checked: opt.enforce_content_type == ContentTypeCheck::Off,
+        }
+    }
+
+    /**
+     * Takes the next chunk of the upload, and checks its type once enough has been received.
+     */
+    pub fn feed(&mut self, data: &[u8]) -> Result<(), WebError> {
+        if self.checked {
+            return Ok(());
+        }
+        let wanted = SNIFF_SIZE - self.head.len();
+        self.head.extend_from_slice(&data[..wanted.min(data.len())]);
+        if self.head.len() < SNIFF_SIZE {
+            return Ok(());
+        }
+        self.check()
+    }
+
+    /**
+     * Checks the type of an upload that was shorter than what is needed for sniffing.
+     */
+    pub fn finish(&mut self) -> Result<(), WebError> {
+        if self.checked || self.head.is_empty() {
+            return Ok(());
+        }
+        self.check()
+    }
+
+    fn check(&mut self) -> Result<(), WebError> {
+        self.checked = true;
+        let sniffed =
+            infer::get(&self.head).filter(|kind| kind.matcher_type() != MatcherType::Text);
+        match sniffed {
+            Some(kind) if !self.matches(kind.mime_type(), kind.extension()) => Err(
+                WebError::ContentMismatch(kind.mime_type().to_string(), self.expected()),
+            ),
+            None if self.mode == ContentTypeCheck::Strict
+                && infer::is_mime_supported(self.content_type) =>
+            {
+                Err(WebError::ContentMismatch(
+                    "unknown".to_string(),
+                    self.expected(),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn matches(&self, mime: &str, sniffed_extension: &str) -> bool {
+        let Some(extension) = self.extension.as_deref() else {
+            return mime == self.content_type || self.content_type == "application/octet-stream";
+        };
+        extension == sniffed_extension
+            || mime_guess::from_ext(extension).iter().any(|guess| guess == mime)
+            || (mime == "application/zip" && ZIP_EXTENSIONS.contains(&extension))
+            // Nothing is known about the extension, so the content type is all there is.
+            || (mime_guess::from_ext(extension).is_empty() && mime == self.content_type)
+    }
+
+    /// Describes what the upload claims to be, for error messages.
+    fn expected(&self) -> String {
+        match &self.extension {
+            Some(extension) => format!(".{}", extension),
+            None => self.content_type.to_string(),
+        }
+    }
+}
+
+/**
+ * Checks the content of a file that was received in pieces, such as a resumable upload.
+ */
+pub async fn check_file(
+    path: &Path,
+    extension: Option<&str>,
+    content_type: &str,
+    opt: &Opt,
+) -> Result<(), WebError> {
+    let mut check = ContentCheck::new(extension, content_type, opt);
+    if check.checked {
+        return Ok(());
+    }
+    let mut head = Vec::with_capacity(SNIFF_SIZE);
+    File::open(path)
+        .await?
+        .take(SNIFF_SIZE as u64)
+        .read_to_end(&mut head)
+        .await?;
+    check.feed(&head)?;
+    check.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+    const ZIP: &[u8] = b"PK\x03\x04\x14\0\0\0\x08\0";
+
+    fn check(
+        mode: &str,
+        extension: Option<&str>,
+        content_type: &str,
+        data: &[u8],
+    ) -> Result<(), WebError> {
+        let opt = Opt::parse_from(["i", "--enforce-content-type", mode]);
+        let mut check = ContentCheck::new(extension, content_type, &opt);
+        check.feed(data)?;
+        check.finish()
+    }
+
+    #[test]
+    fn mismatched_content_is_rejected() {
+        assert!(check("lenient", Some("png"), "image/png", PNG).is_ok());
+        assert!(check("lenient", Some("PNG"), "image/png", PNG).is_ok());
+        assert!(check("lenient", Some("png"), "image/png", ZIP).is_err());
+        assert!(check("lenient", None, "image/png", ZIP).is_err());
+        assert!(check("lenient", Some("docx"), "application/zip", ZIP).is_ok());
+        assert!(check("off", Some("png"), "image/png", ZIP).is_ok());
+    }
+
+    #[test]
+    fn text_passes_unless_strict() {
+        assert!(check("lenient", Some("txt"), "text/plain", b"hello").is_ok());
+        assert!(check("lenient", Some("png"), "image/png", b"hello").is_ok());
+        assert!(check("strict", Some("png"), "image/png", b"hello").is_err());
+        assert!(check("strict", Some("txt"), "text/plain", b"<?xml version").is_ok());
+    }
+
+    #[test]
+    fn only_the_start_is_kept() {
+        let opt = Opt::parse_from(["i", "--enforce-content-type"]);
+        let mut check = ContentCheck::new(Some("png"), "image/png", &opt);
+        check.feed(&[0; SNIFF_SIZE * 2]).unwrap();
+        assert_eq!(SNIFF_SIZE, check.head.len());
+        check.feed(ZIP).unwrap();
+        assert_eq!(SNIFF_SIZE, check.head.len());
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::extract::State;
+use axum::response::IntoResponse;
+use axum::Json;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::WebError;
+
+use super::{
+    helpers::user_dir,
+    storage::{self, join_key},
+    users::User,
+    Opt, THUMBNAIL_SUBDIR,
+};
+
+/**
+ * Storage usage of the uploads of a user.
+ */
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Stats {
+    files: usize,
+    bytes: u64,
+    thumbnail_bytes: u64,
+    oldest_upload: Option<DateTime<Utc>>,
+    newest_upload: Option<DateTime<Utc>>,
+    /// Files and bytes by lowercase extension, with an empty key for files without one.
+    by_extension: BTreeMap<String, ExtensionStats>,
+    computed_at: DateTime<Utc>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ExtensionStats {
+    files: usize,
+    bytes: u64,
+}
+
+/**
+ * Recently computed stats of each user, so that the storage isn't listed on every request.
+ */
+#[derive(Debug, Default)]
+pub struct StatsCache(Mutex<HashMap<User, (Instant, Stats)>>);
+
+impl StatsCache {
+    fn get(&self, user: &User, max_age: Duration) -> Option<Stats> {
+        let cache = self.0.lock().unwrap();
+        let (computed, stats) = cache.get(user)?;
+        (computed.elapsed() < max_age).then(|| stats.clone())
+    }
+
+    fn insert(&self, user: User, stats: Stats) {
+        self.0.lock().unwrap().insert(user, (Instant::now(), stats));
+    }
+}
+
+/**
+ * Adds up the sizes of all uploaded files and thumbnails of the current user.
+ */
+async fn compute_stats(user: &User, opt: &Opt) -> Result<Stats, WebError> {
+    let storage = storage::backend(opt);
+    let mut stats = Stats {
+        computed_at: Utc::now(),
+        ..Default::default()
+    };
+
+    // Listing isn't recursive, so thumbnails, metadata, and the trash are not included.
+    for file in storage.list(user_dir(user)).await? {
+        let modified: DateTime<Utc> = file.modified.into();
+        stats.files += 1;
+        stats.bytes += file.size;
+        stats.oldest_upload = Some(stats.oldest_upload.map_or(modified, |t| t.min(modified)));
+        stats.newest_upload = Some(stats.newest_upload.map_or(modified, |t| t.max(modified)));
+
+        let extension = Path::new(&file.name)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let by_extension = stats.by_extension.entry(extension).or_default();
+        by_extension.files += 1;
+        by_extension.bytes += file.size;
+    }
+
+    let thumbnail_dir = join_key(user_dir(user), THUMBNAIL_SUBDIR);
+    stats.thumbnail_bytes = storage
+        .list(&thumbnail_dir)
+        .await?
+        .iter()
+        .map(|thumbnail| thumbnail.size)
+        .sum();
+
+    Ok(stats)
+}
+
+/**
+ * Returns the storage usage of the current user. The stats are computed at most once every
+ * `--stats-cache-secs`.
+ */
+pub async fn handle_stats(
+    State(opt): State<Opt>,
+    user: User,
+) -> Result<impl IntoResponse, WebError> {
+    let max_age = Duration::from_secs(opt.stats_cache_secs);
+    let stats = match opt.stats_cache.get(&user, max_age) {
+        Some(stats) => stats,
+        None => {
+            let stats = compute_stats(&user, &opt).await?;
+            opt.stats_cache.insert(user, stats.clone());
+            stats
+        }
+    };
+    Ok(Json(stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[tokio::test]
+    async fn adds_up_files_by_extension() {
+        let base_dir = std::env::temp_dir().join("i-test-stats");
+        std::fs::remove_dir_all(&base_dir).ok();
+        std::fs::create_dir_all(base_dir.join(THUMBNAIL_SUBDIR)).unwrap();
+        std::fs::write(base_dir.join("a.png"), "12345").unwrap();
+        std::fs::write(base_dir.join("b.PNG"), "123").unwrap();
+        std::fs::write(base_dir.join("notes"), "1").unwrap();
+        std::fs::write(base_dir.join(THUMBNAIL_SUBDIR).join("a.png_150.webp"), "12").unwrap();
+        let opt = Opt::parse_from(["i", "--base-dir", base_dir.to_str().unwrap()]);
+
+        let stats = compute_stats(&User::default(), &opt).await.unwrap();
+
+        assert_eq!(3, stats.files);
+        assert_eq!(9, stats.bytes);
+        assert_eq!(2, stats.thumbnail_bytes);
+        assert_eq!(
+            Some(&ExtensionStats { files: 2, bytes: 8 }),
+            stats.by_extension.get("png")
+        );
+        assert_eq!(
+            Some(&ExtensionStats { files: 1, bytes: 1 }),
+            stats.by_extension.get("")
+        );
+        assert!(stats.oldest_upload <= stats.newest_upload);
+        assert!(stats.oldest_upload.is_some());
+    }
+}
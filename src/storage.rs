@@ -0,0 +1,678 @@
+use std::fmt;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use axum::async_trait;
+use axum::body::Body;
+use futures::stream::{self, BoxStream};
+use futures::{StreamExt, TryStreamExt};
+use tokio::fs::ReadDir;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+use crate::WebError;
+
+use super::upload::generate_random_string;
+use super::Opt;
+
+/// Where uploaded files, thumbnails, and metadata are stored.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageKind {
+    Local,
+    S3,
+}
+
+/**
+ * An object in storage. Keys are paths relative to the root of the storage, separated by `/`.
+ */
+#[derive(Clone, Debug)]
+pub struct StoredObject {
+    pub key: String,
+    pub name: String,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+/**
+ * The contents of an object, which are read while they are sent.
+ */
+pub struct ObjectStream {
+    pub body: Body,
+    /// Length of the body.
+    pub length: u64,
+    /// Value of the Content-Range header, if the body is only a range of the object.
+    pub content_range: Option<String>,
+}
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Stores `data` under `key`, replacing any existing object.
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), WebError>;
+
+    /// Moves the local file at `source` into storage under `key`, replacing any existing object.
+    async fn put_file(&self, key: &str, source: &Path) -> Result<(), WebError>;
+
+    /// Returns the contents of the object, or `None` if it doesn't exist.
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, WebError>;
+
+    /// Returns the contents of the object as a stream, or `None` if it doesn't exist. With the
+    /// value of a Range header, only that range is returned, if the backend supports it.
+    async fn get_stream(
+        &self,
+        key: &str,
+        _range: Option<&str>,
+    ) -> Result<Option<ObjectStream>, WebError> {
+        Ok(self.get(key).await?.map(|data| ObjectStream {
+            length: data.len() as u64,
+            body: Body::from(data),
+            content_range: None,
+        }))
+    }
+
+    /// Deletes the object. Deleting an object that doesn't exist is not an error.
+    async fn delete(&self, key: &str) -> Result<(), WebError>;
+
+    /// Moves the object at `from` to `to`, replacing any existing object. Moving an object that
+    /// doesn't exist is not an error.
+    async fn rename(&self, from: &str, to: &str) -> Result<(), WebError> {
+        if let Some(data) = self.get(from).await? {
+            self.put(to, data).await?;
+            self.delete(from).await?;
+        }
+        Ok(())
+    }
+
+    /// Lists the objects directly in the directory `dir`, which is `""` for the root.
+    async fn list(&self, dir: &str) -> Result<Vec<StoredObject>, WebError> {
+        self.list_stream(dir).try_collect().await
+    }
+
+    /// Lists the objects directly in the directory `dir` while they are found, so that a large
+    /// directory is never held in memory as a whole.
+    fn list_stream(&self, dir: &str) -> BoxStream<'static, Result<StoredObject, WebError>>;
+
+    async fn exists(&self, key: &str) -> Result<bool, WebError>;
+
+    /// Returns the path of the object on the local file system, if it is stored there.
+    fn local_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+
+    /// Local directory where uploads are written while they are being received.
+    fn staging_dir(&self) -> PathBuf;
+}
+
+/**
+ * The storage backend selected on startup, if it is not the local file system.
+ */
+#[derive(Clone, Default)]
+pub struct Backend(Option<Arc<dyn Storage>>);
+
+impl Backend {
+    #[cfg_attr(not(feature = "s3"), allow(dead_code))]
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Backend(Some(storage))
+    }
+
+    pub fn is_local(&self) -> bool {
+        self.0.is_none()
+    }
+}
+
+impl fmt::Debug for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.0.is_some() {
+            "Backend(remote)"
+        } else {
+            "Backend(local)"
+        })
+    }
+}
+
+/**
+ * Returns the storage to use for the given options.
+ */
+pub fn backend(opt: &Opt) -> Arc<dyn Storage> {
+    match &opt.storage_backend.0 {
+        Some(storage) => storage.clone(),
+        None => Arc::new(LocalStorage::new(&opt.base_dir)),
+    }
+}
+
+/**
+ * Whether the name is that of a file that is still being copied into place, which is not an
+ * object yet.
+ */
+pub fn is_partial(name: &str) -> bool {
+    name.starts_with('.') && name.ends_with(PARTIAL_SUFFIX)
+}
+
+const PARTIAL_SUFFIX: &str = ".part";
+
+/**
+ * Copies `source` to a hidden partial file next to `path`, and renames it to `path` once it is
+ * complete, so that a copy cut short is never seen as the object.
+ */
+async fn copy_into_place(source: &Path, path: &Path) -> Result<(), WebError> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let partial = path.with_file_name(format!(".{}{}", name, PARTIAL_SUFFIX));
+    let copied = async {
+        tokio::fs::copy(source, &partial).await?;
+        tokio::fs::rename(&partial, path).await
+    };
+    if let Err(e) = copied.await {
+        tokio::fs::remove_file(&partial).await.ok();
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/**
+ * Parses a Range header of a single range of bytes, into its start and exclusive end in an object
+ * of `size` bytes. Multiple ranges, and ranges outside of the object, give `None`.
+ */
+fn parse_range(range: &str, size: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.strip_prefix("bytes=")?.trim().split_once('-')?;
+    let (start, end) = match (start, end) {
+        ("", suffix) => (size.saturating_sub(suffix.parse().ok()?), size),
+        (start, "") => (start.parse().ok()?, size),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<u64>().ok()?.saturating_add(1).min(size),
+        ),
+    };
+    (start < end).then_some((start, end))
+}
+
+/**
+ * Stores objects as files below a directory on the local file system.
+ */
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        LocalStorage {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Keys are always separated by `/`, which is turned into the separator of the platform.
+    fn path(&self, key: &str) -> PathBuf {
+        key.split('/')
+            .filter(|part| !part.is_empty())
+            .fold(self.root.clone(), |path, part| path.join(part))
+    }
+
+    async fn create_parent(path: &Path) -> Result<(), WebError> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), WebError> {
+        let path = self.path(key);
+        Self::create_parent(&path).await?;
+        // Write to the staging directory first and rename it into place, so that the object is
+        // never seen half written, even by readers that race with the write.
+        let staging_dir = self.staging_dir();
+        tokio::fs::create_dir_all(&staging_dir).await?;
+        let temporary = staging_dir.join(format!("put-{}", generate_random_string(16)));
+        tokio::fs::write(&temporary, data).await?;
+        if let Err(e) = tokio::fs::rename(&temporary, path).await {
+            tokio::fs::remove_file(&temporary).await.ok();
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    async fn put_file(&self, key: &str, source: &Path) -> Result<(), WebError> {
+        let path = self.path(key);
+        Self::create_parent(&path).await?;
+        // Renaming fails if the staging directory is on another file system, copy instead.
+        if tokio::fs::rename(source, &path).await.is_err() {
+            copy_into_place(source, &path).await?;
+            tokio::fs::remove_file(source).await?;
+        }
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, WebError> {
+        match tokio::fs::read(self.path(key)).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn get_stream(
+        &self,
+        key: &str,
+        range: Option<&str>,
+    ) -> Result<Option<ObjectStream>, WebError> {
+        let mut file = match tokio::fs::File::open(self.path(key)).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let metadata = file.metadata().await?;
+        if !metadata.is_file() {
+            return Ok(None);
+        }
+        let size = metadata.len();
+        let range = range.and_then(|range| parse_range(range, size));
+        let (start, end) = range.unwrap_or((0, size));
+        file.seek(SeekFrom::Start(start)).await?;
+        Ok(Some(ObjectStream {
+            body: Body::from_stream(ReaderStream::new(file.take(end - start))),
+            length: end - start,
+            content_range: range
+                .map(|(start, end)| format!("bytes {}-{}/{}", start, end - 1, size)),
+        }))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), WebError> {
+        match tokio::fs::remove_file(self.path(key)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<(), WebError> {
+        let path = self.path(to);
+        Self::create_parent(&path).await?;
+        match tokio::fs::rename(self.path(from), path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn list_stream(&self, dir: &str) -> BoxStream<'static, Result<StoredObject, WebError>> {
+        let path = self.path(dir);
+        let dir = dir.to_string();
+        // The directory is only read once the first object is asked for.
+        stream::try_unfold(None, move |entries: Option<ReadDir>| {
+            let path = path.clone();
+            let dir = dir.clone();
+            async move {
+                let mut entries = match entries {
+                    Some(entries) => entries,
+                    None => match tokio::fs::read_dir(path).await {
+                        Ok(entries) => entries,
+                        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                        Err(e) => return Err(e.into()),
+                    },
+                };
+                while let Some(entry) = entries.next_entry().await? {
+                    let metadata = entry.metadata().await?;
+                    let Ok(name) = entry.file_name().into_string() else {
+                        continue;
+                    };
+                    if !metadata.is_file() || is_partial(&name) {
+                        continue;
+                    }
+                    let object = StoredObject {
+                        key: join_key(&dir, &name),
+                        name,
+                        size: metadata.len(),
+                        modified: metadata.modified()?,
+                    };
+                    return Ok(Some((object, Some(entries))));
+                }
+                Ok(None)
+            }
+        })
+        .boxed()
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, WebError> {
+        Ok(tokio::fs::try_exists(self.path(key)).await?)
+    }
+
+    fn local_path(&self, key: &str) -> Option<PathBuf> {
+        Some(self.path(key))
+    }
+
+    fn staging_dir(&self) -> PathBuf {
+        self.root.join(STAGING_SUBDIR)
+    }
+}
+
+/// Directory below the storage root where local uploads are received, before being moved.
+const STAGING_SUBDIR: &str = ".staging";
+
+/**
+ * Joins a directory and a name into a key.
+ */
+pub fn join_key(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir.trim_end_matches('/'), name)
+    }
+}
+
+#[cfg(feature = "s3")]
+pub use s3::S3Storage;
+
+#[cfg(feature = "s3")]
+mod s3 {
+    use std::collections::VecDeque;
+    use std::path::{Path, PathBuf};
+    use std::time::SystemTime;
+
+    use aws_config::{BehaviorVersion, Region};
+    use aws_sdk_s3::error::ProvideErrorMetadata;
+    use aws_sdk_s3::primitives::ByteStream;
+    use aws_sdk_s3::Client;
+    use axum::body::Body;
+    use futures::stream::{self, BoxStream};
+    use futures::StreamExt;
+    use tokio_util::io::ReaderStream;
+
+    use crate::WebError;
+
+    use super::{ObjectStream, Storage, StoredObject};
+
+    fn storage_error<E: std::fmt::Display>(e: E) -> WebError {
+        WebError::StorageError(e.to_string())
+    }
+
+    /**
+     * Stores objects in an S3 compatible bucket. Credentials are read from the environment, as
+     * with the AWS CLI.
+     */
+    pub struct S3Storage {
+        client: Client,
+        bucket: String,
+    }
+
+    impl S3Storage {
+        pub async fn new(bucket: String, region: Option<String>, endpoint: Option<String>) -> Self {
+            let mut config = aws_config::defaults(BehaviorVersion::latest());
+            if let Some(region) = region {
+                config = config.region(Region::new(region));
+            }
+            if let Some(endpoint) = endpoint {
+                config = config.endpoint_url(endpoint);
+            }
+            let config = config.load().await;
+            // Path style addressing works with both AWS and self-hosted S3 compatible services.
+            let s3_config = aws_sdk_s3::config::Builder::from(&config)
+                .force_path_style(true)
+                .build();
+
+            S3Storage {
+                client: Client::from_conf(s3_config),
+                bucket,
+            }
+        }
+    }
+
+    #[axum::async_trait]
+    impl Storage for S3Storage {
+        async fn put(&self, key: &str, data: Vec<u8>) -> Result<(), WebError> {
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .body(ByteStream::from(data))
+                .send()
+                .await
+                .map_err(storage_error)?;
+            Ok(())
+        }
+
+        async fn put_file(&self, key: &str, source: &Path) -> Result<(), WebError> {
+            let body = ByteStream::from_path(source).await.map_err(storage_error)?;
+            self.client
+                .put_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .body(body)
+                .send()
+                .await
+                .map_err(storage_error)?;
+            tokio::fs::remove_file(source).await?;
+            Ok(())
+        }
+
+        async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, WebError> {
+            let response = self
+                .client
+                .get_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .send()
+                .await;
+            match response {
+                Ok(output) => {
+                    let data = output.body.collect().await.map_err(storage_error)?;
+                    Ok(Some(data.into_bytes().to_vec()))
+                }
+                Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => Ok(None),
+                Err(e) => Err(storage_error(e)),
+            }
+        }
+
+        async fn get_stream(
+            &self,
+            key: &str,
+            range: Option<&str>,
+        ) -> Result<Option<ObjectStream>, WebError> {
+            let response = self
+                .client
+                .get_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .set_range(range.map(str::to_string))
+                .send()
+                .await;
+            let output = match response {
+                Ok(output) => output,
+                Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => {
+                    return Ok(None)
+                }
+                // The whole object answers a range outside of it as well.
+                Err(e) if range.is_some() && e.code() == Some("InvalidRange") => {
+                    return self.get_stream(key, None).await
+                }
+                Err(e) => return Err(storage_error(e)),
+            };
+            Ok(Some(ObjectStream {
+                length: output.content_length().unwrap_or(0).try_into().unwrap_or(0),
+                content_range: output.content_range().map(str::to_string),
+                body: Body::from_stream(ReaderStream::new(output.body.into_async_read())),
+            }))
+        }
+
+        async fn delete(&self, key: &str) -> Result<(), WebError> {
+            self.client
+                .delete_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .send()
+                .await
+                .map_err(storage_error)?;
+            Ok(())
+        }
+
+        fn list_stream(&self, dir: &str) -> BoxStream<'static, Result<StoredObject, WebError>> {
+            let prefix = if dir.is_empty() {
+                String::new()
+            } else {
+                format!("{}/", dir.trim_end_matches('/'))
+            };
+
+            let pages = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(&prefix)
+                .delimiter("/")
+                .into_paginator()
+                .send();
+            // Only the objects of the page being listed are held in memory.
+            stream::try_unfold((pages, VecDeque::new()), move |(mut pages, mut objects)| {
+                let prefix = prefix.clone();
+                async move {
+                    while objects.is_empty() {
+                        let Some(page) = pages.next().await else {
+                            return Ok(None);
+                        };
+                        let page = page.map_err(storage_error)?;
+                        for object in page.contents() {
+                            let Some(key) = object.key() else {
+                                continue;
+                            };
+                            let modified = object
+                                .last_modified()
+                                .and_then(|t| SystemTime::try_from(*t).ok())
+                                .unwrap_or(SystemTime::UNIX_EPOCH);
+                            objects.push_back(StoredObject {
+                                key: key.to_string(),
+                                name: key[prefix.len()..].to_string(),
+                                size: object.size().unwrap_or(0).try_into().unwrap_or(0),
+                                modified,
+                            });
+                        }
+                    }
+                    let next = objects.pop_front();
+                    Ok(next.map(|object| (object, (pages, objects))))
+                }
+            })
+            .boxed()
+        }
+
+        async fn exists(&self, key: &str) -> Result<bool, WebError> {
+            let response = self
+                .client
+                .head_object()
+                .bucket(&self.bucket)
+                .key(key)
+                .send()
+                .await;
+            match response {
+                Ok(_) => Ok(true),
+                Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(false),
+                Err(e) => Err(storage_error(e)),
+            }
+        }
+
+        fn staging_dir(&self) -> PathBuf {
+            std::env::temp_dir().join("i-staging")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn local_storage_round_trip() {
+        let root = std::env::temp_dir().join("i-test-storage");
+        std::fs::remove_dir_all(&root).ok();
+        let storage = LocalStorage::new(&root);
+
+        storage.put("a.txt", b"hello".to_vec()).await.unwrap();
+        storage.put("sub/b.txt", b"nested".to_vec()).await.unwrap();
+        assert!(storage.exists("a.txt").await.unwrap());
+        assert_eq!(Some(b"hello".to_vec()), storage.get("a.txt").await.unwrap());
+        assert_eq!(None, storage.get("missing.txt").await.unwrap());
+        // Objects are written to the staging directory first, and replaced in one go.
+        storage.put("a.txt", b"hello again".to_vec()).await.unwrap();
+        storage.put("a.txt", b"hello".to_vec()).await.unwrap();
+        assert_eq!(Some(b"hello".to_vec()), storage.get("a.txt").await.unwrap());
+        let staged = std::fs::read_dir(storage.staging_dir()).unwrap();
+        assert_eq!(0, staged.count());
+
+        // Listing only includes objects directly in the directory.
+        let names: Vec<_> = storage
+            .list("")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|o| o.name)
+            .collect();
+        assert_eq!(vec!["a.txt"], names);
+        let nested = storage.list("sub").await.unwrap();
+        assert_eq!("sub/b.txt", nested[0].key);
+        assert_eq!(6, nested[0].size);
+
+        let staged = storage.staging_dir().join("c.txt");
+        std::fs::create_dir_all(storage.staging_dir()).unwrap();
+        std::fs::write(&staged, "staged").unwrap();
+        storage.put_file("c.txt", &staged).await.unwrap();
+        assert!(!staged.exists());
+        assert!(storage.exists("c.txt").await.unwrap());
+
+        storage.rename("c.txt", "sub/d.txt").await.unwrap();
+        storage.rename("c.txt", "sub/d.txt").await.unwrap();
+        assert!(!storage.exists("c.txt").await.unwrap());
+        assert_eq!(
+            Some(b"staged".to_vec()),
+            storage.get("sub/d.txt").await.unwrap()
+        );
+
+        storage.delete("a.txt").await.unwrap();
+        storage.delete("a.txt").await.unwrap();
+        assert!(!storage.exists("a.txt").await.unwrap());
+    }
+
+    #[test]
+    fn ranges_are_parsed() {
+        assert_eq!(Some((0, 5)), parse_range("bytes=0-4", 10));
+        assert_eq!(Some((5, 10)), parse_range("bytes=5-", 10));
+        assert_eq!(Some((7, 10)), parse_range("bytes=-3", 10));
+        assert_eq!(Some((8, 10)), parse_range("bytes=8-100", 10));
+        assert_eq!(Some((0, 10)), parse_range("bytes=-100", 10));
+        assert_eq!(None, parse_range("bytes=10-", 10));
+        assert_eq!(None, parse_range("bytes=5-4", 10));
+        assert_eq!(None, parse_range("bytes=0-1,5-6", 10));
+        assert_eq!(None, parse_range("lines=0-1", 10));
+    }
+
+    #[tokio::test]
+    async fn copies_are_hidden_until_complete() {
+        let root = std::env::temp_dir().join("i-test-storage-copy");
+        std::fs::remove_dir_all(&root).ok();
+        std::fs::create_dir_all(&root).unwrap();
+        let storage = LocalStorage::new(&root);
+        let source = std::env::temp_dir().join("i-test-storage-copy-source");
+        std::fs::write(&source, "copied").unwrap();
+
+        // A copy that was cut short, such as by a crash, is not an object.
+        std::fs::write(root.join(".e.txt.part"), "cop").unwrap();
+        assert!(storage.list("").await.unwrap().is_empty());
+
+        copy_into_place(&source, &root.join("e.txt")).await.unwrap();
+        assert_eq!(
+            Some(b"copied".to_vec()),
+            storage.get("e.txt").await.unwrap()
+        );
+        assert!(!root.join(".e.txt.part").exists());
+        let names: Vec<_> = storage
+            .list("")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|o| o.name)
+            .collect();
+        assert_eq!(vec!["e.txt"], names);
+
+        let missing = std::env::temp_dir().join("i-test-storage-copy-missing");
+        assert!(copy_into_place(&missing, &root.join("f.txt"))
+            .await
+            .is_err());
+        assert!(!root.join("f.txt").exists());
+        assert!(!root.join(".f.txt.part").exists());
+    }
+}
@@ -0,0 +1,108 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{PngDecoder, PngEncoder};
+use image::codecs::webp::{WebPDecoder, WebPEncoder};
+use image::{DynamicImage, ImageDecoder, ImageEncoder, ImageFormat, ImageReader, ImageResult};
+
+use crate::WebError;
+
+use super::Opt;
+
+/**
+ * Re-encodes a JPEG, PNG, or WebP image without its EXIF, XMP, and other metadata. The EXIF
+ * orientation is applied to the pixels, and the color profile is kept. Returns false if the file
+ * was left untouched, because it isn't such an image, or because it is animated. Images that can't
+ * be decoded are an error, since their metadata would be kept.
+ */
+pub fn strip_metadata(path: &Path, opt: &Opt) -> Result<bool, WebError> {
+    let format = match ImageReader::open(path)?.with_guessed_format()?.format() {
+        Some(format @ (ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP)) => format,
+        _ => return Ok(false),
+    };
+    // Only the first frame would survive re-encoding. Broken images fail when decoded below.
+    if is_animated(path, format).unwrap_or(false) {
+        return Ok(false);
+    }
+    let (img, icc_profile) = match open_without_metadata(path) {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            log::warn!("could not strip metadata of {}: {}", path.display(), e);
+            return Err(WebError::UnstrippableImage);
+        }
+    };
+
+    // Write next to the original, and replace it only once the new image is complete.
+    let mut stripped_path = path.as_os_str().to_os_string();
+    stripped_path.push(".stripped");
+    let stripped_path = PathBuf::from(stripped_path);
+    let result = File::create(&stripped_path)
+        .map_err(WebError::from)
+        .and_then(|f| {
+            let mut writer = BufWriter::new(f);
+            encode(img, icc_profile, format, &mut writer, opt)?;
+            writer.flush()?;
+            Ok(())
+        });
+    if let Err(e) = result {
+        std::fs::remove_file(&stripped_path).ok();
+        return Err(e);
+    }
+    std::fs::rename(&stripped_path, path)?;
+
+    Ok(true)
+}
+
+fn is_animated(path: &Path, format: ImageFormat) -> ImageResult<bool> {
+    let reader = BufReader::new(File::open(path)?);
+    match format {
+        ImageFormat::Png => PngDecoder::new(reader)?.is_apng(),
+        ImageFormat::WebP => Ok(WebPDecoder::new(reader)?.has_animation()),
+        _ => Ok(false),
+    }
+}
+
+fn open_without_metadata(path: &Path) -> ImageResult<(DynamicImage, Option<Vec<u8>>)> {
+    let mut decoder = ImageReader::open(path)?
+        .with_guessed_format()?
+        .into_decoder()?;
+    let icc_profile = decoder.icc_profile()?;
+    let orientation = decoder.orientation()?;
+    let mut img = DynamicImage::from_decoder(decoder)?;
+    img.apply_orientation(orientation);
+    Ok((img, icc_profile))
+}
+
+fn encode<W: Write>(
+    img: DynamicImage,
+    icc_profile: Option<Vec<u8>>,
+    format: ImageFormat,
+    writer: W,
+    opt: &Opt,
+) -> Result<(), WebError> {
+    // Not every encoder can embed a color profile, which is fine.
+    fn with_profile<E: ImageEncoder>(mut encoder: E, icc_profile: Option<Vec<u8>>) -> E {
+        if let Some(icc_profile) = icc_profile {
+            encoder.set_icc_profile(icc_profile).ok();
+        }
+        encoder
+    }
+
+    match format {
+        // JPEG has no alpha channel.
+        ImageFormat::Jpeg => DynamicImage::from(img.to_rgb8()).write_with_encoder(with_profile(
+            JpegEncoder::new_with_quality(writer, opt.jpeg_quality),
+            icc_profile,
+        ))?,
+        ImageFormat::Png => {
+            img.write_with_encoder(with_profile(PngEncoder::new(writer), icc_profile))?
+        }
+        // The WebP encoder is lossless, and only handles 8-bit colors.
+        _ => DynamicImage::from(img.to_rgba8())
+            .write_with_encoder(with_profile(WebPEncoder::new_lossless(writer), icc_profile))?,
+    }
+
+    Ok(())
+}
@@ -0,0 +1,394 @@
+use std::path::{Path, PathBuf};
+
+use quick_xml::events::attributes::Attribute;
+use quick_xml::events::{BytesRef, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer, XmlVersion};
+
+use crate::WebError;
+
+/// What to do with an SVG upload that contains scripts or external references.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SvgPolicy {
+    /// Remove them, and store what is left.
+    #[default]
+    Sanitize,
+    /// Reject the upload.
+    Reject,
+}
+
+/// Elements that only draw, which are kept. Anything else, such as `script` and `foreignObject`,
+/// is removed together with its content.
+const ALLOWED_ELEMENTS: &[&str] = &[
+    "svg",
+    "g",
+    "defs",
+    "desc",
+    "title",
+    "symbol",
+    "use",
+    "switch",
+    "view",
+    "path",
+    "rect",
+    "circle",
+    "ellipse",
+    "line",
+    "polyline",
+    "polygon",
+    "text",
+    "tspan",
+    "textPath",
+    "image",
+    "marker",
+    "pattern",
+    "clipPath",
+    "mask",
+    "linearGradient",
+    "radialGradient",
+    "stop",
+    "style",
+    "animate",
+    "animateMotion",
+    "animateTransform",
+    "mpath",
+    "filter",
+    "feBlend",
+    "feColorMatrix",
+    "feComponentTransfer",
+    "feComposite",
+    "feConvolveMatrix",
+    "feDiffuseLighting",
+    "feDisplacementMap",
+    "feDistantLight",
+    "feDropShadow",
+    "feFlood",
+    "feFuncA",
+    "feFuncB",
+    "feFuncG",
+    "feFuncR",
+    "feGaussianBlur",
+    "feImage",
+    "feMerge",
+    "feMergeNode",
+    "feMorphology",
+    "feOffset",
+    "fePointLight",
+    "feSpecularLighting",
+    "feSpotLight",
+    "feTile",
+    "feTurbulence",
+];
+
+/// Images that may be embedded as data URLs. SVG isn't one of them, since it could have scripts.
+const ALLOWED_DATA_URLS: &[&str] = &[
+    "data:image/png",
+    "data:image/jpeg",
+    "data:image/gif",
+    "data:image/webp",
+];
+
+/**
+ * Sanitizes the SVG file at `path`, following `policy`. Returns false if the file was left
+ * untouched, because it had nothing to remove.
+ */
+pub fn sanitize_svg_file(path: &Path, policy: SvgPolicy) -> Result<bool, WebError> {
+    let svg = std::fs::read(path)?;
+    let Some(sanitized) = sanitize_svg(&svg)? else {
+        return Ok(false);
+    };
+    if policy == SvgPolicy::Reject {
+        return Err(WebError::UnsafeSvg);
+    }
+
+    // Write next to the original, and replace it only once the new file is complete.
+    let mut sanitized_path = path.as_os_str().to_os_string();
+    sanitized_path.push(".sanitized");
+    let sanitized_path = PathBuf::from(sanitized_path);
+    if let Err(e) = std::fs::write(&sanitized_path, sanitized) {
+        std::fs::remove_file(&sanitized_path).ok();
+        return Err(e.into());
+    }
+    std::fs::rename(&sanitized_path, path)?;
+
+    Ok(true)
+}
+
+/**
+ * Removes everything from an SVG image that could run scripts or load something from elsewhere:
+ * elements that aren't known to only draw, event handler attributes, links other than to the
+ * image itself or to embedded raster images, style sheets that import or link to anything, and
+ * processing instructions and document types. Returns `None` if there was nothing to remove. An
+ * image that can't be parsed can't be sanitized, and is rejected.
+ */
+pub fn sanitize_svg(svg: &[u8]) -> Result<Option<Vec<u8>>, WebError> {
+    let svg = std::str::from_utf8(svg).map_err(|_| WebError::UnsafeSvg)?;
+    let mut reader = Reader::from_str(svg);
+    let mut writer = Writer::new(Vec::with_capacity(svg.len()));
+    let mut removed = false;
+    let mut seen_root = false;
+    // How deep into a removed element the reader is.
+    let mut skipping = 0;
+    // The content of the style element the reader is in, which is only checked once it is
+    // complete, since references and comments could hide an import in pieces of it.
+    let mut style: Option<String> = None;
+
+    loop {
+        let event = reader.read_event().map_err(|_| WebError::UnsafeSvg)?;
+        if skipping > 0 {
+            match event {
+                Event::Start(_) => skipping += 1,
+                Event::End(_) => skipping -= 1,
+                Event::Eof => return Err(WebError::UnsafeSvg),
+                _ => {}
+            }
+            continue;
+        }
+        if let Some(content) = style.as_mut() {
+            match event {
+                Event::Text(text) => content.push_str(&text),
+                Event::CData(data) => content.push_str(&data),
+                Event::GeneralRef(reference) => content.push(resolve(&reference)?),
+                Event::Comment(_) => {}
+                Event::End(end) => {
+                    if is_safe_style(content) {
+                        writer.write_event(Event::Text(BytesText::new(content)))?;
+                    } else {
+                        removed = true;
+                    }
+                    writer.write_event(Event::End(end))?;
+                    style = None;
+                }
+                // Style elements only have text.
+                _ => return Err(WebError::UnsafeSvg),
+            }
+            continue;
+        }
+
+        let kept = match event {
+            Event::Start(ref element) | Event::Empty(ref element) if !seen_root => {
+                // Anything but an SVG image, such as an HTML page, is not sanitized.
+                if element.local_name().as_ref() != "svg" {
+                    return Err(WebError::UnsafeSvg);
+                }
+                seen_root = true;
+                let (element, stripped) = sanitize_element(element)?;
+                removed |= stripped;
+                match event {
+                    Event::Start(_) => Event::Start(element),
+                    _ => Event::Empty(element),
+                }
+            }
+            Event::Start(ref element) | Event::Empty(ref element)
+                if !ALLOWED_ELEMENTS.contains(&element.local_name().as_ref()) =>
+            {
+                removed = true;
+                if matches!(event, Event::Start(_)) {
+                    skipping = 1;
+                }
+                continue;
+            }
+            Event::Start(element) => {
+                if element.local_name().as_ref() == "style" {
+                    style = Some(String::new());
+                }
+                let (element, stripped) = sanitize_element(&element)?;
+                removed |= stripped;
+                Event::Start(element)
+            }
+            Event::Empty(element) => {
+                let (element, stripped) = sanitize_element(&element)?;
+                removed |= stripped;
+                Event::Empty(element)
+            }
+            Event::GeneralRef(reference) => {
+                // Only numeric and predefined entities are known without the document type.
+                resolve(&reference)?;
+                Event::GeneralRef(reference)
+            }
+            // A style sheet instruction could load anything, and entities of a document type could
+            // expand to anything.
+            Event::PI(_) | Event::DocType(_) => {
+                removed = true;
+                continue;
+            }
+            Event::Comment(_) => continue,
+            Event::Eof => break,
+            event => event,
+        };
+        writer.write_event(kept)?;
+    }
+
+    if !seen_root {
+        return Err(WebError::UnsafeSvg);
+    }
+    Ok(removed.then(|| writer.into_inner()))
+}
+
+/**
+ * Returns the character of a numeric or predefined entity. Other entities would be defined by the
+ * document type, which is removed, so they can't be kept.
+ */
+fn resolve(reference: &BytesRef) -> Result<char, WebError> {
+    let resolved = match reference.resolve_char_ref() {
+        Ok(Some(c)) => Some(c),
+        Ok(None) => match reference.as_ref() {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => None,
+        },
+        Err(_) => None,
+    };
+    resolved.ok_or(WebError::UnsafeSvg)
+}
+
+/**
+ * Copies an element without its unsafe attributes. Returns the copy, and whether any attribute was
+ * removed.
+ */
+fn sanitize_element(element: &BytesStart) -> Result<(BytesStart<'static>, bool), WebError> {
+    let mut sanitized = element.to_owned();
+    sanitized.clear_attributes();
+    let mut removed = false;
+    for attribute in element.attributes() {
+        let attribute = attribute.map_err(|_| WebError::UnsafeSvg)?;
+        if is_safe_attribute(&attribute)? {
+            sanitized.push_attribute(attribute);
+        } else {
+            removed = true;
+        }
+    }
+    Ok((sanitized, removed))
+}
+
+fn is_safe_attribute(attribute: &Attribute) -> Result<bool, WebError> {
+    let name = attribute.key.local_name().as_ref().to_ascii_lowercase();
+    let value = attribute
+        .normalized_value(XmlVersion::Implicit1_0)
+        .map_err(|_| WebError::UnsafeSvg)?;
+    let value = normalize(&value);
+    if name.starts_with("on") || value.contains("javascript:") {
+        return Ok(false);
+    }
+    let safe = match name.as_str() {
+        "href" | "src" => is_local_reference(&value),
+        // Animations must not turn links into scripts, or add event handlers.
+        "attributename" => {
+            let target = value.rsplit(':').next().unwrap_or_default();
+            target != "href" && !target.starts_with("on")
+        }
+        _ => is_safe_style(&value),
+    };
+    Ok(safe)
+}
+
+/**
+ * Links may only point into the image itself, or embed a raster image.
+ */
+fn is_local_reference(value: &str) -> bool {
+    value.starts_with('#')
+        || ALLOWED_DATA_URLS
+            .iter()
+            .any(|prefix| value.starts_with(prefix))
+}
+
+/**
+ * Returns true if the style, or attribute value, doesn't import or link to anything outside the
+ * image. Escapes could spell out anything, so styles with escapes are not safe either.
+ */
+fn is_safe_style(style: &str) -> bool {
+    let style = normalize(style);
+    let unsafe_parts = ["@import", "javascript:", "image-set(", "\\"];
+    if unsafe_parts.iter().any(|part| style.contains(part)) {
+        return false;
+    }
+    style.split("url(").skip(1).all(|reference| {
+        let reference = reference.trim_start_matches(['"', '\'']);
+        is_local_reference(reference)
+    })
+}
+
+/**
+ * Lowercases a value and removes whitespace and control characters, which browsers ignore in
+ * places such as `java script:`.
+ */
+fn normalize(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| !c.is_whitespace() && !c.is_control())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sanitized(svg: &str) -> String {
+        String::from_utf8(sanitize_svg(svg.as_bytes()).unwrap().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn keeps_safe_images() {
+        let svg = r##"<?xml version="1.0"?>
+<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" viewBox="0 0 10 10">
+  <defs><linearGradient id="g"><stop offset="0" stop-color="#fff"/></linearGradient></defs>
+  <style>rect { fill: url(#g); }</style>
+  <rect width="10" height="10" style="fill: url('#g')"/>
+  <use xlink:href="#g"/>
+  <text>Fish &amp; chips</text>
+</svg>"##;
+        assert!(sanitize_svg(svg.as_bytes()).unwrap().is_none());
+    }
+
+    #[test]
+    fn removes_scripts_and_external_references() {
+        let svg = sanitized(
+            r#"<!DOCTYPE svg [<!ENTITY x "y">]>
+<svg xmlns="http://www.w3.org/2000/svg" onload="alert(1)">
+  <script>alert(1)</script>
+  <foreignObject><div><script>alert(2)</script></div></foreignObject>
+  <a href="javascript:alert(3)"><rect/></a>
+  <image href="https://example.com/tracker.png" width="1"/>
+  <image href="data:image/png;base64,AAAA"/>
+  <rect width="1" onclick="alert(4)" fill="url(https://example.com/x)"/>
+  <use href="&#106;avascript:alert(5)"/>
+  <animate attributeName="href" values="javascript:alert(6)"/>
+  <style>@imp<!-- -->ort url(https://example.com/x.css);</style>
+  <circle style="fill: \75 rl(https://example.com/x)"/>
+</svg>"#,
+        );
+        for unsafe_part in [
+            "alert",
+            "script",
+            "foreignObject",
+            "example.com",
+            "onclick",
+            "DOCTYPE",
+            "ENTITY",
+            "import",
+            "<a ",
+        ] {
+            assert!(!svg.contains(unsafe_part), "{} in {}", unsafe_part, svg);
+        }
+        assert!(svg.contains(r#"<image href="data:image/png;base64,AAAA"/>"#));
+        assert!(svg.contains(r#"<rect width="1"/>"#));
+        assert!(svg.contains("<style></style>"));
+        assert!(svg.contains("<animate/>"));
+        assert!(svg.contains("<circle/>"));
+    }
+
+    #[test]
+    fn rejects_what_cant_be_sanitized() {
+        for svg in [
+            "<html><script>alert(1)</script></html>",
+            "<svg><rect></svg>",
+            "<!DOCTYPE svg [<!ENTITY x \"y\">]><svg><text>&x;</text></svg>",
+            "not an image",
+        ] {
+            assert!(sanitize_svg(svg.as_bytes()).is_err(), "{}", svg);
+        }
+    }
+}
@@ -1,43 +1,1050 @@
+use std::ffi::OsStr;
 use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::extract::{Path as UrlPath, Query, State};
+use axum::http::{header::CONTENT_TYPE, HeaderMap};
+use axum::response::{IntoResponse, Response};
+use chrono::Utc;
+use image::codecs::gif::{GifDecoder, GifEncoder, Repeat};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{
+    AnimationDecoder, DynamicImage, Frame, ImageDecoder, ImageFormat, ImageReader, ImageResult,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::{mpsc, Mutex};
 
 use crate::WebError;
 
-use super::Opt;
+use super::helpers::{file_key, thumbnail_key, user_url_path};
+use super::meta::{apply_caching, read_meta};
+use super::metrics::METRICS;
+use super::upload::generate_random_string;
+use super::users::User;
+use super::{storage, Opt};
+
+/// Number of files that may wait for a thumbnail before new uploads are skipped.
+const QUEUE_SIZE: usize = 1024;
+/// How long an upload waits for room in a full thumbnail queue, with `--thumbnail-queue-full wait`.
+const QUEUE_WAIT: Duration = Duration::from_secs(5);
+
+/// Extensions of files that ffmpeg is used for, when configured.
+const VIDEO_EXTENSIONS: &[&str] = &["mp4", "m4v", "mov", "webm", "mkv", "avi"];
+
+/// Extensions of archives, which the content types of compressed files don't tell apart.
+const ARCHIVE_EXTENSIONS: &[&str] = &["zip", "tar", "gz", "tgz", "bz2", "xz", "zst", "7z", "rar"];
+
+/// Image format used when encoding thumbnails.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThumbnailFormat {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl ThumbnailFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ThumbnailFormat::Png => "png",
+            ThumbnailFormat::Jpeg => "jpeg",
+            ThumbnailFormat::Webp => "webp",
+        }
+    }
+
+    fn image_format(self) -> ImageFormat {
+        match self {
+            ThumbnailFormat::Png => ImageFormat::Png,
+            ThumbnailFormat::Jpeg => ImageFormat::Jpeg,
+            ThumbnailFormat::Webp => ImageFormat::WebP,
+        }
+    }
+}
+
+/// How images are fitted into the square of the thumbnail size.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThumbnailMode {
+    /// Crop the image to fill the whole square.
+    Fill,
+    /// Scale the image to fit within the square, keeping its aspect ratio.
+    Fit,
+}
+
+/// Filter used when scaling images down to thumbnails, from fastest to best looking.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThumbnailFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl ThumbnailFilter {
+    fn filter_type(self) -> FilterType {
+        match self {
+            ThumbnailFilter::Nearest => FilterType::Nearest,
+            ThumbnailFilter::Triangle => FilterType::Triangle,
+            ThumbnailFilter::CatmullRom => FilterType::CatmullRom,
+            ThumbnailFilter::Gaussian => FilterType::Gaussian,
+            ThumbnailFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// What to do with the thumbnails of an upload when the queue of the thumbnail workers is full.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QueueFull {
+    /// Skip the upload, whose thumbnails are then generated when they are first requested.
+    #[default]
+    Skip,
+    /// Hold the upload back for a few seconds until there is room, and skip it after that.
+    Wait,
+}
+
+/// Icon shown instead of a thumbnail for types of files that thumbnails can't be made of. Other
+/// files get the generic placeholder.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlaceholderIcon {
+    Pdf,
+    Video,
+    Audio,
+    Archive,
+}
+
+impl PlaceholderIcon {
+    const ALL: [PlaceholderIcon; 4] = [
+        PlaceholderIcon::Pdf,
+        PlaceholderIcon::Video,
+        PlaceholderIcon::Audio,
+        PlaceholderIcon::Archive,
+    ];
+
+    /// Picks the icon by the extension of the file, or by the type of content it suggests.
+    pub fn for_filename(filename: &str) -> Option<PlaceholderIcon> {
+        let extension = Path::new(filename)
+            .extension()
+            .and_then(OsStr::to_str)?
+            .to_ascii_lowercase();
+        if extension == "pdf" {
+            return Some(PlaceholderIcon::Pdf);
+        }
+        if ARCHIVE_EXTENSIONS.contains(&extension.as_str()) {
+            return Some(PlaceholderIcon::Archive);
+        }
+        match mime_guess::from_ext(&extension).first()?.type_() {
+            mime_guess::mime::VIDEO => Some(PlaceholderIcon::Video),
+            mime_guess::mime::AUDIO => Some(PlaceholderIcon::Audio),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            PlaceholderIcon::Pdf => "pdf",
+            PlaceholderIcon::Video => "video",
+            PlaceholderIcon::Audio => "audio",
+            PlaceholderIcon::Archive => "archive",
+        }
+    }
+
+    fn contents(self) -> &'static [u8] {
+        match self {
+            PlaceholderIcon::Pdf => include_bytes!("../dist/icons/pdf.png"),
+            PlaceholderIcon::Video => include_bytes!("../dist/icons/video.png"),
+            PlaceholderIcon::Audio => include_bytes!("../dist/icons/audio.png"),
+            PlaceholderIcon::Archive => include_bytes!("../dist/icons/archive.png"),
+        }
+    }
+
+    /// Relative url of the icon, served with the other bundled files of the recent page.
+    fn url(self) -> String {
+        format!("recent/icons/{}.png", self.name())
+    }
+}
+
+/**
+ * Serves a bundled placeholder icon, by its name as in its url.
+ */
+pub async fn serve_icon(UrlPath(name): UrlPath<String>, headers: HeaderMap) -> Response {
+    let icon = name.strip_suffix(".png").and_then(|name| {
+        PlaceholderIcon::ALL
+            .into_iter()
+            .find(|icon| icon.name() == name)
+    });
+    match icon {
+        Some(icon) => super::bundled_file(&headers, "image/png", icon.contents()),
+        None => super::handle_404().await.into_response(),
+    }
+}
+
+/**
+ * Serves the icon of the type of the file instead of its thumbnail, or the generic placeholder.
+ */
+async fn placeholder(filename: &str, headers: HeaderMap) -> Response {
+    match PlaceholderIcon::for_filename(filename) {
+        Some(icon) => super::bundled_file(&headers, "image/png", icon.contents()),
+        None => super::placeholder_thumbnail(headers).await,
+    }
+}
+
+/**
+ * Returns the name of the thumbnail file of the given size belonging to the given file, relative
+ * to the thumbnail directory.
+ */
+pub fn thumbnail_filename(filename: &str, size: u32, opt: &Opt) -> String {
+    // GIFs get GIF thumbnails, so that animations are kept.
+    let extension = if is_gif(Path::new(filename)) {
+        "gif"
+    } else {
+        opt.thumbnail_format.extension()
+    };
+    format!("{}_{}.{}", filename, size, extension)
+}
+
+/**
+ * Returns the name of the file a thumbnail belongs to, given the name of the thumbnail file.
+ * Returns `None` if the name isn't of the form `<name>_<size>.<ext>`.
+ */
+pub fn thumbnail_source(thumbnail: &str) -> Option<&str> {
+    let (stem, _ext) = thumbnail.rsplit_once('.')?;
+    let (source, size) = stem.rsplit_once('_')?;
+    size.parse::<u32>().ok().map(|_| source)
+}
+
+/**
+ * Returns the smallest configured thumbnail size, which is used when no size is asked for.
+ */
+pub fn smallest_size(opt: &Opt) -> u32 {
+    opt.thumbnail_sizes.iter().copied().min().unwrap_or(150)
+}
 
 /**
- * Tries to generate a thumbnail of the given filename. Returns false if it wasn't an image.
+ * Opens an image and rotates/flips it according to its EXIF orientation, if it has one.
  */
-pub fn generate_thumbnail<P>(path: P, thumb_path: P, opt: &Opt) -> Result<bool, WebError>
+fn open_oriented<P: AsRef<Path>>(path: P) -> ImageResult<DynamicImage> {
+    let mut decoder = ImageReader::open(path)?
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut img = DynamicImage::from_decoder(decoder)?;
+    img.apply_orientation(orientation);
+    Ok(img)
+}
+
+/**
+ * Tries to generate thumbnails of the given filename, one of each configured size, in `thumb_dir`.
+ * Returns false if it wasn't an image, or a video from which a frame could be extracted, and an
+ * error if it has the extension of an image but can't be decoded.
+ */
+pub fn generate_thumbnail<P>(path: P, thumb_dir: P, opt: &Opt) -> Result<bool, WebError>
 where
     P: AsRef<Path>,
 {
-    if let Ok(img) = image::open(path) {
-        let thumb = img.resize_to_fill(
-            opt.thumbnail_size,
-            opt.thumbnail_size,
-            image::imageops::Triangle,
-        );
-        thumb.save(thumb_path)?;
+    let Some(name) = path.as_ref().file_name().and_then(OsStr::to_str) else {
+        return Ok(false);
+    };
+
+    // Check the size in the header before decoding, which allocates all the pixels.
+    if let Some((width, height)) = image_dimensions(path.as_ref()) {
+        let pixels = u64::from(width) * u64::from(height);
+        if pixels > opt.max_thumbnail_pixels {
+            log::info!(
+                "not generating thumbnail of {}, {}x{} is larger than the maximum of {} pixels",
+                name,
+                width,
+                height,
+                opt.max_thumbnail_pixels
+            );
+            return Ok(false);
+        }
+    }
 
+    if is_gif(path.as_ref()) {
+        let frames = read_gif_frames(path.as_ref(), opt)?;
+        for &size in &opt.thumbnail_sizes {
+            let thumb_path = thumb_dir.as_ref().join(thumbnail_filename(name, size, opt));
+            write_gif_thumbnail(&frames, size, &thumb_path, opt)?;
+        }
         return Ok(true);
     }
 
-    Ok(false)
+    let img = match open_oriented(&path) {
+        Ok(img) => img,
+        Err(_) if is_video(path.as_ref()) => match extract_video_frame(path.as_ref(), opt) {
+            Some(img) => img,
+            None => return Ok(false),
+        },
+        Err(e) if ImageFormat::from_path(&path).is_ok() => return Err(e.into()),
+        Err(_) => return Ok(false),
+    };
+
+    for &size in &opt.thumbnail_sizes {
+        let thumb = resize(&img, size, opt);
+        // JPEG has no alpha channel, and the WebP encoder only handles 8-bit colors.
+        let thumb = match opt.thumbnail_format {
+            ThumbnailFormat::Png => thumb,
+            ThumbnailFormat::Jpeg => thumb.to_rgb8().into(),
+            ThumbnailFormat::Webp => thumb.to_rgba8().into(),
+        };
+        let thumb_path = thumb_dir.as_ref().join(thumbnail_filename(name, size, opt));
+        write_thumbnail(&thumb, &thumb_path, opt)?;
+    }
+
+    Ok(true)
 }
 
 /**
- * Returns relative url to thumbnail, or a placeholder image if it doesn't exist
+ * Encodes a thumbnail in the configured format, with the configured quality for JPEG.
  */
-pub fn get_thumbnail_url<P: AsRef<Path>>(path: P, opt: &Opt) -> Result<String, WebError> {
-    let thumbnail_path = super::get_thumbnail_dir(opt)?.join(&path);
-    return if thumbnail_path.exists() {
-        let url = std::path::Path::new(crate::THUMBNAIL_SUBDIR);
-        Ok(url
-            .join(&path)
-            .into_os_string()
-            .into_string()
-            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "invalid path"))?)
-    } else {
-        Ok("/recent/placeholder.png".to_string())
+fn write_thumbnail(thumb: &DynamicImage, thumb_path: &Path, opt: &Opt) -> Result<(), WebError> {
+    match opt.thumbnail_format {
+        ThumbnailFormat::Jpeg => {
+            let file = std::io::BufWriter::new(std::fs::File::create(thumb_path)?);
+            thumb.write_with_encoder(JpegEncoder::new_with_quality(
+                file,
+                opt.thumbnail_jpeg_quality,
+            ))?;
+        }
+        format => thumb.save_with_format(thumb_path, format.image_format())?,
+    }
+    Ok(())
+}
+
+/**
+ * Reads the dimensions of an image from its header, without decoding it. Returns `None` if it isn't
+ * an image that can be decoded.
+ */
+fn image_dimensions(path: &Path) -> Option<(u32, u32)> {
+    ImageReader::open(path)
+        .ok()?
+        .with_guessed_format()
+        .ok()?
+        .into_dimensions()
+        .ok()
+}
+
+fn resize(img: &DynamicImage, size: u32, opt: &Opt) -> DynamicImage {
+    let filter = opt.thumbnail_filter.filter_type();
+    match opt.thumbnail_mode {
+        ThumbnailMode::Fill => img.resize_to_fill(size, size, filter),
+        ThumbnailMode::Fit => img.resize(size, size, filter),
+    }
+}
+
+fn is_gif(path: &Path) -> bool {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("gif"))
+}
+
+/**
+ * Reads at most `--max-thumbnail-frames` frames of a GIF. A static GIF has a single frame.
+ */
+fn read_gif_frames(path: &Path, opt: &Opt) -> ImageResult<Vec<Frame>> {
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    GifDecoder::new(file)?
+        .into_frames()
+        .take(opt.max_thumbnail_frames)
+        .collect()
+}
+
+/**
+ * Writes a GIF thumbnail of the given size, with every frame resized and its delay kept.
+ */
+fn write_gif_thumbnail(
+    frames: &[Frame],
+    size: u32,
+    thumb_path: &Path,
+    opt: &Opt,
+) -> Result<(), WebError> {
+    let file = std::io::BufWriter::new(std::fs::File::create(thumb_path)?);
+    let mut encoder = GifEncoder::new_with_speed(file, 10);
+    encoder.set_repeat(Repeat::Infinite)?;
+    encoder.encode_frames(frames.iter().map(|frame| {
+        let img = DynamicImage::from(frame.buffer().clone());
+        Frame::from_parts(resize(&img, size, opt).into_rgba8(), 0, 0, frame.delay())
+    }))?;
+    Ok(())
+}
+
+/**
+ * Generates a thumbnail of the given uploaded file, and stores it next to the file. Files that
+ * aren't on the local file system are downloaded to the staging directory first.
+ */
+pub async fn store_thumbnail(filename: String, user: User, opt: Opt) -> Result<bool, WebError> {
+    // Thumbnails are public, and would show what a protected file contains.
+    if read_meta(&filename, &user, &opt)
+        .await?
+        .is_some_and(|meta| meta.is_protected())
+    {
+        return Ok(false);
+    }
+
+    let storage = storage::backend(&opt);
+    let staging_dir = storage.staging_dir();
+    tokio::fs::create_dir_all(&staging_dir).await?;
+
+    let key = file_key(&filename, &user);
+    let staging_name = format!("thumbnail-{}", key.replace('/', "_"));
+    let (path, downloaded) = match storage.local_path(&key) {
+        Some(path) => (path, false),
+        None => {
+            let Some(data) = storage.get(&key).await? else {
+                return Ok(false);
+            };
+            let path = staging_dir.join(&staging_name);
+            tokio::fs::write(&path, data).await?;
+            (path, true)
+        }
     };
+    // The thumbnails are named after the file they were generated from, which might be the
+    // downloaded copy in the staging directory.
+    let source_name = path
+        .file_name()
+        .and_then(OsStr::to_str)
+        .unwrap_or(&staging_name)
+        .to_string();
+
+    // Decoding and resizing images is blocking, use threadpool
+    let result = tokio::task::spawn_blocking({
+        let (path, staging_dir, opt) = (path.clone(), staging_dir.clone(), opt.clone());
+        move || generate_thumbnail(&path, &staging_dir, &opt)
+    })
+    .await?;
+    if downloaded {
+        tokio::fs::remove_file(&path).await.ok();
+    }
+
+    if let Ok(true) = result {
+        for &size in &opt.thumbnail_sizes {
+            let thumb_path = staging_dir.join(thumbnail_filename(&source_name, size, &opt));
+            storage
+                .put_file(&thumbnail_key(&filename, size, &user, &opt), &thumb_path)
+                .await?;
+        }
+    }
+    result
+}
+
+/**
+ * Sends uploaded files to the thumbnail workers, if they have been started.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct ThumbnailQueue {
+    sender: Option<mpsc::Sender<(String, User, Opt)>>,
+    when_full: QueueFull,
+}
+
+impl ThumbnailQueue {
+    /**
+     * Queues generation of the thumbnails of the given file. If the queue is full, and stays full
+     * with `--thumbnail-queue-full wait`, the thumbnails are instead generated when they are first
+     * requested. Returns whether the file was queued.
+     */
+    pub async fn push(&self, filename: String, user: User, opt: Opt) -> bool {
+        let Some(sender) = &self.sender else {
+            log::warn!("thumbnail workers not started, skipping {}", filename);
+            return false;
+        };
+        let (filename, _, _) = match self.when_full {
+            QueueFull::Skip => match sender.try_send((filename, user, opt)) {
+                Ok(()) => return true,
+                Err(e) => e.into_inner(),
+            },
+            QueueFull::Wait => match sender.send_timeout((filename, user, opt), QUEUE_WAIT).await {
+                Ok(()) => return true,
+                Err(e) => e.into_inner(),
+            },
+        };
+        log::warn!("thumbnail queue full, skipping {}", filename);
+        false
+    }
+}
+
+/**
+ * Starts `--thumbnail-workers` workers, which generate the thumbnails of queued files. At most
+ * a fixed number of files wait in the queue, so that bursts of uploads can't use all resources.
+ */
+pub fn start_workers(opt: &Opt) -> ThumbnailQueue {
+    let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..opt.thumbnail_workers {
+        tokio::task::spawn(thumbnail_worker(receiver.clone()));
+    }
+    ThumbnailQueue {
+        sender: Some(sender),
+        when_full: opt.thumbnail_queue_full,
+    }
+}
+
+async fn thumbnail_worker(receiver: Arc<Mutex<mpsc::Receiver<(String, User, Opt)>>>) {
+    loop {
+        // Release the lock before working, so that the other workers can take the next file.
+        let Some((filename, user, opt)) = receiver.lock().await.recv().await else {
+            return;
+        };
+        let result = store_thumbnail(filename.clone(), user, opt).await;
+        METRICS.record_thumbnail(&result);
+        if let Err(e) = result {
+            log::error!("could not generate thumbnail of {}: {}", filename, e);
+        }
+    }
+}
+
+/**
+ * Removes all thumbnails of the given file.
+ */
+pub async fn remove_thumbnails(filename: &str, user: &User, opt: &Opt) -> Result<(), WebError> {
+    let storage = storage::backend(opt);
+    for &size in &opt.thumbnail_sizes {
+        storage
+            .delete(&thumbnail_key(filename, size, user, opt))
+            .await?;
+    }
+    Ok(())
+}
+
+fn is_video(path: &Path) -> bool {
+    path.extension().and_then(OsStr::to_str).is_some_and(|ext| {
+        VIDEO_EXTENSIONS
+            .iter()
+            .any(|video| video.eq_ignore_ascii_case(ext))
+    })
+}
+
+/**
+ * Extracts the frame at one second into the video using ffmpeg, if it is configured. Returns
+ * `None` if ffmpeg is not configured, fails, or doesn't finish in time.
+ */
+fn extract_video_frame(path: &Path, opt: &Opt) -> Option<DynamicImage> {
+    let ffmpeg = opt.ffmpeg_path.as_ref()?;
+
+    // Let ffmpeg write the frame to a file, since waiting with a timeout while reading a large
+    // frame from a pipe could deadlock. It is kept in the staging directory, which isn't served.
+    let staging_dir = storage::backend(opt).staging_dir();
+    let frame_path = staging_dir.join(format!("frame-{}.png", generate_random_string(16)));
+
+    let result = std::fs::create_dir_all(&staging_dir)
+        .map_err(|e| e.to_string())
+        .and_then(|()| run_ffmpeg(ffmpeg, path, &frame_path, opt))
+        .and_then(|()| image::open(&frame_path).map_err(|e| e.to_string()));
+    std::fs::remove_file(&frame_path).ok();
+
+    result
+        .map_err(|e| log::warn!("could not extract frame from {}: {}", path.display(), e))
+        .ok()
+}
+
+fn run_ffmpeg(ffmpeg: &str, path: &Path, frame_path: &Path, opt: &Opt) -> Result<(), String> {
+    let mut child = Command::new(ffmpeg)
+        .args(["-nostdin", "-loglevel", "error", "-y", "-ss", "1", "-i"])
+        .arg(path)
+        .args(["-frames:v", "1", "-f", "image2", "-c:v", "png"])
+        .arg(frame_path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| e.to_string())?;
+
+    let deadline = Instant::now() + Duration::from_secs(opt.ffmpeg_timeout_secs);
+    loop {
+        match child.try_wait().map_err(|e| e.to_string())? {
+            Some(status) if status.success() => return Ok(()),
+            Some(status) => return Err(format!("ffmpeg exited with {}", status)),
+            None if Instant::now() >= deadline => {
+                child.kill().ok();
+                child.wait().ok();
+                return Err("ffmpeg timed out".to_string());
+            }
+            None => std::thread::sleep(Duration::from_millis(50)),
+        }
+    }
+}
+
+/**
+ * Returns relative url to the thumbnail of the given size, which is generated when it is first
+ * requested. The smallest configured size is used if no size is given. Files that never get a
+ * thumbnail, such as PDFs and archives, link to the icon of their type instead.
+ */
+pub fn get_thumbnail_url(filename: &str, size: Option<u32>, user: &User, opt: &Opt) -> String {
+    match PlaceholderIcon::for_filename(filename) {
+        // Videos get thumbnails of a frame when ffmpeg is configured.
+        Some(PlaceholderIcon::Video) if opt.ffmpeg_path.is_some() => {}
+        Some(icon) => return icon.url(),
+        None => {}
+    }
+    let size = size.unwrap_or_else(|| smallest_size(opt));
+    format!("thumbnail/{}?size={}", user_url_path(filename, user), size)
+}
+
+#[derive(Deserialize)]
+pub struct ThumbnailQuery {
+    size: Option<u32>,
+}
+
+/**
+ * Serves the thumbnail of an uploaded file, generating and storing it first if it doesn't exist
+ * yet. Serves the placeholder image if the file can't be thumbnailed.
+ */
+pub async fn serve_thumbnail(
+    State(opt): State<Opt>,
+    UrlPath(path): UrlPath<String>,
+    Query(query): Query<ThumbnailQuery>,
+    headers: HeaderMap,
+) -> Result<Response, WebError> {
+    // Uploads of users are stored in their own directory, which is part of the path.
+    let (user, filename) = match path.split_once('/') {
+        Some((user, filename)) if opt.users.contains_key(user) => {
+            (User(Some(user.to_string())), filename)
+        }
+        Some(_) => return Ok(super::handle_404().await.into_response()),
+        None => (User::default(), path.as_str()),
+    };
+
+    // Only configured sizes, so that thumbnails of arbitrary sizes can't be requested.
+    let size = query.size.unwrap_or_else(|| smallest_size(&opt));
+    if !sanitize_filename::is_sanitized(filename) || !opt.thumbnail_sizes.contains(&size) {
+        return Err(WebError::BadRequest);
+    }
+
+    // Files that can't be downloaded anymore, but haven't been removed yet, don't show either.
+    let meta = read_meta(filename, &user, &opt).await?;
+    if meta
+        .as_ref()
+        .is_some_and(|meta| meta.is_expired(Utc::now()) || meta.is_used_up())
+    {
+        return Ok(super::handle_404().await.into_response());
+    }
+    // Thumbnails would show images without using up any of their downloads.
+    if meta
+        .as_ref()
+        .is_some_and(|meta| meta.max_downloads.is_some())
+    {
+        return Ok(placeholder(filename, headers).await);
+    }
+
+    let storage = storage::backend(&opt);
+    let key = thumbnail_key(filename, size, &user, &opt);
+    let data = match storage.get(&key).await? {
+        Some(data) => data,
+        None => {
+            if !storage.exists(&file_key(filename, &user)).await? {
+                return Ok(super::handle_404().await.into_response());
+            }
+            let result = store_thumbnail(filename.to_string(), user, opt.clone()).await;
+            METRICS.record_thumbnail(&result);
+            match result {
+                Ok(true) => {}
+                Ok(false) => return Ok(placeholder(filename, headers).await),
+                Err(WebError::InvalidImage(e)) => {
+                    log::error!("could not generate thumbnail of {}: {}", filename, e);
+                    return Ok(placeholder(filename, headers).await);
+                }
+                Err(e) => return Err(e),
+            }
+            storage.get(&key).await?.ok_or_else(|| {
+                WebError::StorageError(format!("thumbnail {} missing after generation", key))
+            })?
+        }
+    };
+
+    // Thumbnails change only when their file is overwritten.
+    let immutable = meta.is_some_and(|meta| meta.immutable);
+    let etag = format!("{:x}", Sha256::digest(&data));
+    let content_type = mime_guess::from_path(&key).first_or_octet_stream();
+    let response = ([(CONTENT_TYPE, content_type.to_string())], data).into_response();
+    Ok(apply_caching(response, &headers, Some(&etag), immutable))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use image::GenericImageView;
+
+    #[test]
+    fn png_gets_webp_thumbnail() {
+        let dir = std::env::temp_dir().join("i-test-thumbnail");
+        std::fs::create_dir_all(&dir).unwrap();
+        let opt = Opt::parse_from(["i", "--base-dir", dir.to_str().unwrap()]);
+
+        let path = dir.join("image.png");
+        image::RgbaImage::new(300, 200).save(&path).unwrap();
+        let thumb_path = dir.join(thumbnail_filename("image.png", 150, &opt));
+
+        assert!(generate_thumbnail(&path, &dir, &opt).unwrap());
+        assert!(thumb_path.ends_with("image.png_150.webp"));
+        assert_eq!(
+            ImageFormat::WebP,
+            ImageReader::open(&thumb_path)
+                .unwrap()
+                .with_guessed_format()
+                .unwrap()
+                .format()
+                .unwrap()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn video_frame_is_extracted_in_staging_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join("i-test-video-frame");
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(&dir).unwrap();
+        let frame = dir.join("frame.png");
+        image::RgbaImage::new(32, 24).save(&frame).unwrap();
+        // Copies the frame to the output file, which is the last argument, tells where it was, and
+        // exits with the status in a file.
+        let ffmpeg = std::env::temp_dir().join("i-test-video-frame-ffmpeg");
+        let script = format!(
+            r#"#!/bin/sh
+for out; do :; done
+echo "$out" > {0}/out
+cp {0}/frame.png "$out"
+exit $(cat {0}/status)
+"#,
+            dir.display()
+        );
+        std::fs::write(&ffmpeg, script).unwrap();
+        std::fs::set_permissions(&ffmpeg, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let mut opt = Opt::parse_from(["i", "--base-dir", dir.to_str().unwrap()]);
+        opt.ffmpeg_path = Some(ffmpeg.to_str().unwrap().to_string());
+        let video = dir.join("video.mp4");
+        std::fs::write(&video, "not really a video").unwrap();
+        let staging_dir = storage::backend(&opt).staging_dir();
+
+        for (status, extracted) in [("0", true), ("1", false)] {
+            std::fs::write(dir.join("status"), status).unwrap();
+            let img = extract_video_frame(&video, &opt);
+            assert_eq!(extracted, img.is_some());
+            let out = std::fs::read_to_string(dir.join("out")).unwrap();
+            assert!(Path::new(out.trim()).starts_with(&staging_dir));
+            assert_eq!(0, std::fs::read_dir(&staging_dir).unwrap().count());
+        }
+    }
+
+    #[test]
+    fn exif_orientation_is_applied() {
+        let dir = std::env::temp_dir().join("i-test-thumbnail-exif");
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut opt = Opt::parse_from(["i", "--base-dir", dir.to_str().unwrap()]);
+        opt.thumbnail_format = ThumbnailFormat::Png;
+
+        // A wide image, stored with EXIF orientation 6 (rotate 90 degrees clockwise), is tall when
+        // displayed. Use a left half that is white, which should end up as the top half.
+        let img = image::RgbImage::from_fn(200, 100, |x, _| {
+            if x < 100 {
+                image::Rgb([255, 255, 255])
+            } else {
+                image::Rgb([0, 0, 0])
+            }
+        });
+        let mut jpeg = Vec::new();
+        img.write_to(&mut std::io::Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+            .unwrap();
+
+        #[rustfmt::skip]
+        let exif: &[u8] = &[
+            0xFF, 0xE1, 0x00, 0x22, // APP1 marker and segment length
+            b'E', b'x', b'i', b'f', 0x00, 0x00,
+            b'I', b'I', 0x2A, 0x00, 0x08, 0x00, 0x00, 0x00, // little endian TIFF header
+            0x01, 0x00, // one IFD entry
+            0x12, 0x01, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00, // orientation 6
+            0x00, 0x00, 0x00, 0x00, // no next IFD
+        ];
+        // Insert the EXIF segment right after the start of image marker.
+        jpeg.splice(2..2, exif.iter().copied());
+
+        let path = dir.join("photo.jpg");
+        std::fs::write(&path, &jpeg).unwrap();
+        let oriented = open_oriented(&path).unwrap().to_rgb8();
+        assert_eq!((100, 200), oriented.dimensions());
+        assert!(oriented.get_pixel(50, 50)[0] > 200);
+        assert!(oriented.get_pixel(50, 150)[0] < 50);
+
+        assert!(generate_thumbnail(&path, &dir, &opt).unwrap());
+        assert!(dir
+            .join(thumbnail_filename("photo.jpg", 150, &opt))
+            .exists());
+    }
+
+    #[test]
+    fn all_sizes_are_written() {
+        let dir = std::env::temp_dir().join("i-test-thumbnail-sizes");
+        std::fs::create_dir_all(&dir).unwrap();
+        let opt = Opt::parse_from([
+            "i",
+            "--base-dir",
+            dir.to_str().unwrap(),
+            "--thumbnail-sizes",
+            "150,320,640",
+        ]);
+
+        let path = dir.join("image.png");
+        image::RgbaImage::new(800, 600).save(&path).unwrap();
+
+        assert!(generate_thumbnail(&path, &dir, &opt).unwrap());
+        for size in [150, 320, 640] {
+            let thumb = image::open(dir.join(thumbnail_filename("image.png", size, &opt))).unwrap();
+            assert_eq!((size, size), (thumb.width(), thumb.height()));
+        }
+    }
+
+    #[test]
+    fn huge_image_is_not_decoded() {
+        let dir = std::env::temp_dir().join("i-test-thumbnail-huge");
+        std::fs::create_dir_all(&dir).unwrap();
+        let opt = Opt::parse_from(["i", "--base-dir", dir.to_str().unwrap()]);
+
+        // Only the header of a PNG of 20000x20000 pixels, which can't be decoded.
+        fn crc32(data: &[u8]) -> u32 {
+            let mut crc = !0u32;
+            for &byte in data {
+                crc ^= u32::from(byte);
+                for _ in 0..8 {
+                    crc = if crc & 1 == 1 {
+                        (crc >> 1) ^ 0xEDB8_8320
+                    } else {
+                        crc >> 1
+                    };
+                }
+            }
+            !crc
+        }
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        let mut chunk = |kind: &[u8], data: &[u8]| {
+            let typed = [kind, data].concat();
+            png.extend((data.len() as u32).to_be_bytes());
+            png.extend(&typed);
+            png.extend(crc32(&typed).to_be_bytes());
+        };
+        let size = 20_000u32.to_be_bytes();
+        chunk(b"IHDR", &[&size[..], &size[..], &[8, 6, 0, 0, 0]].concat());
+        chunk(b"IDAT", &[]);
+        let path = dir.join("huge.png");
+        std::fs::write(&path, png).unwrap();
+        assert_eq!(Some((20_000, 20_000)), image_dimensions(&path));
+
+        assert!(!generate_thumbnail(&path, &dir, &opt).unwrap());
+        assert!(!dir.join(thumbnail_filename("huge.png", 150, &opt)).exists());
+    }
+
+    #[test]
+    fn nearest_filter_keeps_colors() {
+        let mut opt = Opt::parse_from(["i", "--thumbnail-filter", "nearest"]);
+        let stripes = image::RgbImage::from_fn(300, 300, |x, _| {
+            image::Rgb(if x % 2 == 0 {
+                [0, 0, 0]
+            } else {
+                [255, 255, 255]
+            })
+        });
+        let img = DynamicImage::from(stripes);
+        let is_black_or_white = |img: &DynamicImage| {
+            img.to_rgb8()
+                .pixels()
+                .all(|p| p.0 == [0, 0, 0] || p.0 == [255, 255, 255])
+        };
+
+        assert!(is_black_or_white(&resize(&img, 150, &opt)));
+        opt.thumbnail_filter = ThumbnailFilter::Triangle;
+        assert!(!is_black_or_white(&resize(&img, 150, &opt)));
+    }
+
+    #[test]
+    fn jpeg_quality_changes_size() {
+        let dir = std::env::temp_dir().join("i-test-thumbnail-jpeg-quality");
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut opt = Opt::parse_from([
+            "i",
+            "--base-dir",
+            dir.to_str().unwrap(),
+            "--thumbnail-format",
+            "jpeg",
+        ]);
+
+        let path = dir.join("noise.png");
+        image::RgbImage::from_fn(300, 300, |x, y| {
+            image::Rgb([(x * 7 + y * 13) as u8, (x * y) as u8, (x ^ y) as u8])
+        })
+        .save(&path)
+        .unwrap();
+        let thumb_path = dir.join(thumbnail_filename("noise.png", 150, &opt));
+        let thumbnail_size = |opt: &Opt| {
+            assert!(generate_thumbnail(&path, &dir, opt).unwrap());
+            std::fs::metadata(&thumb_path).unwrap().len()
+        };
+
+        opt.thumbnail_jpeg_quality = 95;
+        let high = thumbnail_size(&opt);
+        opt.thumbnail_jpeg_quality = 30;
+        let low = thumbnail_size(&opt);
+        assert!(low < high, "{} bytes at quality 30, {} at 95", low, high);
+    }
+
+    #[test]
+    fn fit_mode_keeps_aspect_ratio() {
+        let dir = std::env::temp_dir().join("i-test-thumbnail-fit");
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut opt = Opt::parse_from(["i", "--base-dir", dir.to_str().unwrap()]);
+
+        let path = dir.join("wide.png");
+        image::RgbaImage::new(600, 300).save(&path).unwrap();
+        let thumb_path = dir.join(thumbnail_filename("wide.png", 150, &opt));
+
+        assert!(generate_thumbnail(&path, &dir, &opt).unwrap());
+        assert_eq!((150, 150), image::open(&thumb_path).unwrap().dimensions());
+
+        opt.thumbnail_mode = ThumbnailMode::Fit;
+        assert!(generate_thumbnail(&path, &dir, &opt).unwrap());
+        assert_eq!((150, 75), image::open(&thumb_path).unwrap().dimensions());
+    }
+
+    #[test]
+    fn animated_gif_stays_animated() {
+        let dir = std::env::temp_dir().join("i-test-thumbnail-gif");
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut opt = Opt::parse_from(["i", "--base-dir", dir.to_str().unwrap()]);
+
+        let path = dir.join("anim.gif");
+        {
+            let mut encoder = GifEncoder::new(std::fs::File::create(&path).unwrap());
+            for color in [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255]] {
+                let img = image::RgbaImage::from_pixel(300, 200, image::Rgba(color));
+                encoder.encode_frame(Frame::new(img)).unwrap();
+            }
+        }
+        let thumb_path = dir.join(thumbnail_filename("anim.gif", 150, &opt));
+        assert!(thumb_path.ends_with("anim.gif_150.gif"));
+        let frame_count = |path: &Path| {
+            let file = std::io::BufReader::new(std::fs::File::open(path).unwrap());
+            let frames = GifDecoder::new(file).unwrap().into_frames();
+            frames.collect_frames().unwrap()
+        };
+
+        assert!(generate_thumbnail(&path, &dir, &opt).unwrap());
+        let frames = frame_count(&thumb_path);
+        assert_eq!(3, frames.len());
+        assert_eq!((150, 150), frames[0].buffer().dimensions());
+
+        opt.max_thumbnail_frames = 1;
+        assert!(generate_thumbnail(&path, &dir, &opt).unwrap());
+        assert_eq!(1, frame_count(&thumb_path).len());
+    }
+
+    #[test]
+    fn smallest_size_is_default() {
+        let opt = Opt::parse_from(["i", "-t", "640,150,320"]);
+        assert_eq!(150, smallest_size(&opt));
+    }
+
+    #[test]
+    fn thumbnail_source_is_parsed() {
+        assert_eq!(Some("image.png"), thumbnail_source("image.png_150.webp"));
+        assert_eq!(Some("a_b.txt"), thumbnail_source("a_b.txt_640.png"));
+        assert_eq!(None, thumbnail_source("image.png.webp"));
+    }
+
+    #[test]
+    fn files_without_thumbnails_get_icon_of_their_type() {
+        let mut opt = Opt::parse_from(["i"]);
+        let user = User::default();
+        assert_eq!(
+            Some(PlaceholderIcon::Pdf),
+            PlaceholderIcon::for_filename("report.PDF")
+        );
+        assert_eq!(
+            Some(PlaceholderIcon::Archive),
+            PlaceholderIcon::for_filename("backup.tar.gz")
+        );
+        assert_eq!(
+            Some(PlaceholderIcon::Audio),
+            PlaceholderIcon::for_filename("song.mp3")
+        );
+        assert_eq!(None, PlaceholderIcon::for_filename("notes.txt"));
+        assert_eq!(None, PlaceholderIcon::for_filename("archive"));
+
+        assert_eq!(
+            "recent/icons/pdf.png",
+            get_thumbnail_url("report.pdf", None, &user, &opt)
+        );
+        assert_eq!(
+            "thumbnail/photo.png?size=150",
+            get_thumbnail_url("photo.png", None, &user, &opt)
+        );
+        assert_eq!(
+            "recent/icons/video.png",
+            get_thumbnail_url("clip.mp4", None, &user, &opt)
+        );
+        // Unless ffmpeg makes a thumbnail of a frame.
+        opt.ffmpeg_path = Some("ffmpeg".into());
+        assert_eq!(
+            "thumbnail/clip.mp4?size=150",
+            get_thumbnail_url("clip.mp4", None, &user, &opt)
+        );
+    }
+
+    #[test]
+    fn video_without_ffmpeg_gets_no_thumbnail() {
+        let dir = std::env::temp_dir().join("i-test-thumbnail-video");
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut opt = Opt::parse_from(["i", "--base-dir", dir.to_str().unwrap()]);
+
+        let path = dir.join("clip.mp4");
+        std::fs::write(&path, "not really a video").unwrap();
+        let thumb_path = dir.join(thumbnail_filename("clip.mp4", 150, &opt));
+
+        assert!(!generate_thumbnail(&path, &dir, &opt).unwrap());
+
+        opt.ffmpeg_path = Some("/nonexistent/ffmpeg".into());
+        assert!(!generate_thumbnail(&path, &dir, &opt).unwrap());
+        assert!(!thumb_path.exists());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn full_queue_waits_or_skips() {
+        let opt = Opt::parse_from(["i"]);
+        let (sender, mut receiver) = mpsc::channel(1);
+        let mut queue = ThumbnailQueue {
+            sender: Some(sender),
+            when_full: QueueFull::Skip,
+        };
+        assert!(
+            queue
+                .push("first.png".to_string(), User::default(), opt.clone())
+                .await
+        );
+        assert!(
+            !queue
+                .push("second.png".to_string(), User::default(), opt.clone())
+                .await
+        );
+
+        // A worker taking the first file in time makes room for the waiting one.
+        queue.when_full = QueueFull::Wait;
+        let worker = tokio::spawn(async move {
+            tokio::time::sleep(QUEUE_WAIT / 2).await;
+            let (first, _, _) = receiver.recv().await.unwrap();
+            assert_eq!("first.png", first);
+            receiver
+        });
+        assert!(
+            queue
+                .push("third.png".to_string(), User::default(), opt.clone())
+                .await
+        );
+        let _receiver = worker.await.unwrap();
+        assert!(
+            !queue
+                .push("fourth.png".to_string(), User::default(), opt)
+                .await
+        );
+    }
 }
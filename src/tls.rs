@@ -0,0 +1,152 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::http::Uri;
+use axum::response::{IntoResponse, Redirect};
+use axum::Router;
+use axum_server::tls_rustls::RustlsConfig;
+use axum_server::Handle;
+
+use crate::WebError;
+
+use super::{helpers::public_path, shutdown_signal, Opt};
+
+/**
+ * Loads the certificate and private key given with `--tls-cert` and `--tls-key`. Returns `None`
+ * if TLS is not enabled.
+ */
+pub async fn load_config(opt: &Opt) -> Result<Option<RustlsConfig>, WebError> {
+    let (Some(cert), Some(key)) = (&opt.tls_cert, &opt.tls_key) else {
+        return Ok(None);
+    };
+
+    // Both ring and aws-lc-rs may be enabled through other dependencies, so pick one.
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .ok();
+    let config = RustlsConfig::from_pem_file(cert, key).await.map_err(|e| {
+        std::io::Error::other(format!(
+            "could not load TLS certificate {:?} and key {:?}: {}",
+            cert, key, e
+        ))
+    })?;
+    Ok(Some(config))
+}
+
+/**
+ * Serves the app over HTTPS until the process is asked to stop. Uploads in progress are then given
+ * `--shutdown-timeout-secs` to finish.
+ */
+pub async fn serve(
+    addr: SocketAddr,
+    app: Router,
+    config: RustlsConfig,
+    opt: &Opt,
+) -> Result<(), WebError> {
+    let handle = Handle::new();
+    let timeout = Duration::from_secs(opt.shutdown_timeout_secs);
+    tokio::task::spawn({
+        let handle = handle.clone();
+        async move {
+            shutdown_signal().await;
+            log::info!("shutting down, waiting for uploads in progress to finish");
+            handle.graceful_shutdown(Some(timeout));
+        }
+    });
+
+    if let Some(port) = opt.https_redirect_port {
+        let redirect_addr = SocketAddr::new(addr.ip(), port);
+        log::info!("redirecting http://{} to {}", redirect_addr, opt.server_url);
+        let redirect = Router::new()
+            .fallback(redirect_to_https)
+            .with_state(opt.clone());
+        tokio::task::spawn(
+            axum_server::bind(redirect_addr)
+                .handle(handle.clone())
+                .serve(redirect.into_make_service()),
+        );
+    }
+
+    axum_server::bind_rustls(addr, config)
+        .handle(handle)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await?;
+    Ok(())
+}
+
+/**
+ * Redirects a plain HTTP request to the same path below the server url.
+ */
+async fn redirect_to_https(
+    State(opt): State<Opt>,
+    uri: Uri,
+) -> Result<impl IntoResponse, WebError> {
+    // The base path is added back by public_path.
+    let path = uri
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or_default();
+    let path = match path.strip_prefix(opt.base_path.as_str()) {
+        Some(rest) if rest.is_empty() || rest.starts_with(['/', '?']) => rest,
+        _ => path,
+    };
+    let path = path.trim_start_matches('/');
+    Ok(Redirect::permanent(&public_path(path, &opt)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::{header::LOCATION, Request, StatusCode};
+    use clap::Parser;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn redirects_to_server_url() {
+        let opt = Opt::parse_from(["i", "--server-url", "https://example.com/i/"]);
+        let app = Router::new().fallback(redirect_to_https).with_state(opt);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/recent?page=2")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            "https://example.com/i/recent?page=2",
+            response.headers()[LOCATION]
+        );
+    }
+
+    #[tokio::test]
+    async fn redirects_below_base_path() {
+        let opt = Opt::parse_from([
+            "i",
+            "--server-url",
+            "https://example.com",
+            "--base-path",
+            "/i",
+        ]);
+        let app = Router::new().fallback(redirect_to_https).with_state(opt);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/i/recent?page=2")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            "https://example.com/i/recent?page=2",
+            response.headers()[LOCATION]
+        );
+    }
+}
@@ -0,0 +1,241 @@
+use std::time::{Duration, SystemTime};
+
+use axum::extract::{Request, State};
+use axum::http::{header::LOCATION, StatusCode};
+use axum::response::IntoResponse;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::WebError;
+
+use super::{
+    delete::{parse_request, CsrfProtected},
+    helpers::{file_key, meta_key, thumbnail_key, trash_key, user_dir},
+    meta::{read_meta, write_meta, FileMeta},
+    storage::{self, join_key},
+    users::User,
+    Opt, TRASH_SUBDIR,
+};
+
+/// Identifies the file to move back from the trash.
+#[derive(Deserialize)]
+pub struct RestoreRequest {
+    pub filename: String,
+    /// CSRF token, required when submitted as a form.
+    pub csrf: Option<String>,
+}
+
+impl CsrfProtected for RestoreRequest {
+    fn csrf(&self) -> Option<&str> {
+        self.csrf.as_deref()
+    }
+}
+
+/**
+ * Returns the keys of an uploaded file and everything belonging to it, which are moved together.
+ */
+fn keys(filename: &str, user: &User, opt: &Opt) -> Vec<String> {
+    let mut keys = vec![file_key(filename, user)];
+    keys.extend(
+        opt.thumbnail_sizes
+            .iter()
+            .map(|&size| thumbnail_key(filename, size, user, opt)),
+    );
+    keys
+}
+
+/**
+ * Moves an uploaded file, its thumbnails, and its metadata to the trash, and records when it was
+ * deleted. A file of the same name already in the trash is replaced.
+ */
+pub async fn move_to_trash(filename: &str, user: &User, opt: &Opt) -> Result<(), WebError> {
+    let storage = storage::backend(opt);
+    for key in keys(filename, user, opt) {
+        storage.rename(&key, &trash_key(&key, user)).await?;
+    }
+
+    let mut meta = read_meta(filename, user, opt).await?.unwrap_or_default();
+    meta.deleted_at = Some(Utc::now());
+    let data = serde_json::to_vec(&meta).map_err(std::io::Error::from)?;
+    let key = meta_key(filename, user);
+    storage.put(&trash_key(&key, user), data).await?;
+    storage.delete(&key).await
+}
+
+/**
+ * Reads the metadata of a file in the trash. Returns `None` if it has no metadata.
+ */
+async fn read_trash_meta(
+    filename: &str,
+    user: &User,
+    opt: &Opt,
+) -> Result<Option<FileMeta>, WebError> {
+    let key = trash_key(&meta_key(filename, user), user);
+    let data = storage::backend(opt).get(&key).await?;
+    Ok(data.and_then(|data| serde_json::from_slice(&data).ok()))
+}
+
+/**
+ * Moves a file, its thumbnails, and its metadata back from the trash. Returns false if there is
+ * no such file in the trash.
+ */
+async fn restore_file(filename: &str, user: &User, opt: &Opt) -> Result<bool, WebError> {
+    if !sanitize_filename::is_sanitized(filename) {
+        return Ok(false);
+    }
+
+    let storage = storage::backend(opt);
+    let key = file_key(filename, user);
+    if !storage.exists(&trash_key(&key, user)).await? {
+        return Ok(false);
+    }
+    // The name may have been taken by a new upload since the file was deleted.
+    if storage.exists(&key).await? {
+        return Err(WebError::Conflict(filename.to_string()));
+    }
+
+    if let Some(mut meta) = read_trash_meta(filename, user, opt).await? {
+        meta.deleted_at = None;
+        write_meta(filename, &meta, user, opt).await?;
+    }
+    storage
+        .delete(&trash_key(&meta_key(filename, user), user))
+        .await?;
+    for key in keys(filename, user, opt) {
+        storage.rename(&trash_key(&key, user), &key).await?;
+    }
+    opt.recent_cache.invalidate(user);
+
+    Ok(true)
+}
+
+pub async fn handle_restore(
+    State(opt): State<Opt>,
+    user: User,
+    request: Request,
+) -> Result<impl IntoResponse, WebError> {
+    let (form, _): (RestoreRequest, _) = parse_request(request).await?;
+
+    if !restore_file(&form.filename, &user, &opt).await? {
+        return Err(WebError::BadRequest);
+    }
+
+    Ok((StatusCode::SEE_OTHER, [(LOCATION, "recent")], "restored"))
+}
+
+/**
+ * Permanently deletes every file of the current user that was moved to the trash before
+ * `now - max_age`, together with its thumbnails and metadata.
+ */
+pub async fn purge_trash(
+    max_age: Duration,
+    now: SystemTime,
+    user: &User,
+    opt: &Opt,
+) -> Result<(), WebError> {
+    let cutoff: DateTime<Utc> = now
+        .checked_sub(max_age)
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+        .into();
+    let storage = storage::backend(opt);
+
+    // Listing isn't recursive, so only the trashed files themselves are considered here.
+    let dir = join_key(user_dir(user), TRASH_SUBDIR);
+    for file in storage.list(&dir).await? {
+        // Every trashed file should have metadata, but fall back to when it was last modified.
+        let deleted_at = read_trash_meta(&file.name, user, opt)
+            .await?
+            .and_then(|meta| meta.deleted_at)
+            .unwrap_or_else(|| file.modified.into());
+        if deleted_at >= cutoff {
+            continue;
+        }
+
+        log::info!("purging {} from the trash", file.key);
+        for key in keys(&file.name, user, opt) {
+            storage.delete(&trash_key(&key, user)).await?;
+        }
+        storage
+            .delete(&trash_key(&meta_key(&file.name, user), user))
+            .await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[tokio::test]
+    async fn purges_only_old_trash() {
+        let base_dir = std::env::temp_dir().join("i-test-trash");
+        std::fs::remove_dir_all(&base_dir).ok();
+        let mut opt = Opt::parse_from(["i", "--base-dir", base_dir.to_str().unwrap()]);
+        opt.trash = true;
+        let user = User::default();
+        std::fs::create_dir_all(&base_dir).unwrap();
+
+        for name in ["old.txt", "new.txt"] {
+            std::fs::write(base_dir.join(name), name).unwrap();
+            std::fs::create_dir_all(base_dir.join(crate::THUMBNAIL_SUBDIR)).unwrap();
+            std::fs::write(
+                base_dir.join(thumbnail_key(name, 150, &user, &opt)),
+                "thumb",
+            )
+            .unwrap();
+            move_to_trash(name, &user, &opt).await.unwrap();
+            assert!(!base_dir.join(name).exists());
+            assert!(!base_dir
+                .join(thumbnail_key(name, 150, &user, &opt))
+                .exists());
+        }
+        let trash = base_dir.join(TRASH_SUBDIR);
+        assert!(trash.join("old.txt").exists());
+        assert!(trash
+            .join(thumbnail_key("old.txt", 150, &user, &opt))
+            .exists());
+
+        // Pretend that the old file was deleted two days ago.
+        let mut meta = read_trash_meta("old.txt", &user, &opt)
+            .await
+            .unwrap()
+            .unwrap();
+        meta.deleted_at = Some(Utc::now() - chrono::Duration::days(2));
+        std::fs::write(
+            base_dir.join(trash_key(&meta_key("old.txt", &user), &user)),
+            serde_json::to_vec(&meta).unwrap(),
+        )
+        .unwrap();
+
+        purge_trash(
+            Duration::from_secs(24 * 60 * 60),
+            SystemTime::now(),
+            &user,
+            &opt,
+        )
+        .await
+        .unwrap();
+
+        assert!(!trash.join("old.txt").exists());
+        assert!(!trash
+            .join(thumbnail_key("old.txt", 150, &user, &opt))
+            .exists());
+        assert!(!base_dir
+            .join(trash_key(&meta_key("old.txt", &user), &user))
+            .exists());
+        assert!(trash.join("new.txt").exists());
+
+        assert!(restore_file("new.txt", &user, &opt).await.unwrap());
+        assert!(base_dir.join("new.txt").exists());
+        assert!(base_dir
+            .join(thumbnail_key("new.txt", 150, &user, &opt))
+            .exists());
+        assert!(read_meta("new.txt", &user, &opt)
+            .await
+            .unwrap()
+            .is_some_and(|meta| meta.deleted_at.is_none()));
+        assert!(!restore_file("old.txt", &user, &opt).await.unwrap());
+    }
+}
@@ -0,0 +1,395 @@
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use axum::body::Body;
+use axum::extract::{Path, State};
+use axum::http::header::{CACHE_CONTROL, CONTENT_LOCATION, CONTENT_TYPE, LOCATION};
+use axum::http::{HeaderMap, HeaderName, StatusCode};
+use axum::response::{IntoResponse, Response};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncWriteExt, BufWriter};
+
+use crate::WebError;
+
+use super::diskspace::SpaceGuard;
+use super::helpers::public_path;
+use super::limits::size_limit;
+use super::sniff::check_file;
+use super::upload::{
+    detect_content_type, generate_random_filename, generate_random_string, hash_file,
+    is_blocked_extension, store_file, FileUpload, Options,
+};
+use super::users::User;
+use super::{storage, Opt};
+
+const TUS_VERSION: &str = "1.0.0";
+
+static TUS_RESUMABLE: HeaderName = HeaderName::from_static("tus-resumable");
+pub static TUS_VERSION_HEADER: HeaderName = HeaderName::from_static("tus-version");
+static TUS_EXTENSION: HeaderName = HeaderName::from_static("tus-extension");
+static TUS_MAX_SIZE: HeaderName = HeaderName::from_static("tus-max-size");
+static UPLOAD_LENGTH: HeaderName = HeaderName::from_static("upload-length");
+static UPLOAD_OFFSET: HeaderName = HeaderName::from_static("upload-offset");
+static UPLOAD_METADATA: HeaderName = HeaderName::from_static("upload-metadata");
+
+const ID_LENGTH: usize = 32;
+
+/// Partial uploads that haven't been appended to for this long are removed by the cleanup task.
+const STALE_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Partial uploads that a request is appending to, so that two requests can't append at once.
+static APPENDING: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+/**
+ * State of a partial upload, stored next to its data in the staging directory.
+ */
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct UploadInfo {
+    length: u64,
+    filename: Option<String>,
+    user: Option<String>,
+}
+
+fn data_path(id: &str, opt: &Opt) -> PathBuf {
+    storage::backend(opt)
+        .staging_dir()
+        .join(format!("tus-{}", id))
+}
+
+fn info_path(id: &str, opt: &Opt) -> PathBuf {
+    storage::backend(opt)
+        .staging_dir()
+        .join(format!("tus-{}.json", id))
+}
+
+fn header_u64(headers: &HeaderMap, name: &HeaderName) -> Result<u64, WebError> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .ok_or(WebError::BadRequest)
+}
+
+/**
+ * Every request except OPTIONS must state the version of the protocol that the client uses.
+ */
+fn check_version(headers: &HeaderMap) -> Result<(), WebError> {
+    if headers
+        .get(&TUS_RESUMABLE)
+        .is_some_and(|v| v == TUS_VERSION)
+    {
+        Ok(())
+    } else {
+        Err(WebError::UnsupportedTusVersion(TUS_VERSION))
+    }
+}
+
+/**
+ * Returns the filename from the Upload-Metadata header, which is a comma-separated list of keys
+ * and base64 encoded values.
+ */
+fn metadata_filename(headers: &HeaderMap) -> Option<String> {
+    let metadata = headers.get(&UPLOAD_METADATA)?.to_str().ok()?;
+    metadata.split(',').find_map(|pair| {
+        let (key, value) = pair.trim().split_once(' ')?;
+        if key != "filename" {
+            return None;
+        }
+        let filename = String::from_utf8(STANDARD.decode(value).ok()?).ok()?;
+        Some(sanitize_filename::sanitize(filename))
+    })
+}
+
+/**
+ * Reads the state of a partial upload, if it exists and belongs to the current user.
+ */
+async fn read_info(id: &str, user: &User, opt: &Opt) -> Result<Option<UploadInfo>, WebError> {
+    if id.len() != ID_LENGTH || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Ok(None);
+    }
+    let data = match tokio::fs::read(info_path(id, opt)).await {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let info: UploadInfo = serde_json::from_slice(&data).map_err(std::io::Error::from)?;
+    Ok((info.user == user.0).then_some(info))
+}
+
+/**
+ * Marks a partial upload as being appended to, until dropped.
+ */
+struct AppendGuard(PathBuf);
+
+impl AppendGuard {
+    /**
+     * Returns None if another request is already appending to the upload.
+     */
+    fn acquire(data_path: PathBuf) -> Option<AppendGuard> {
+        let acquired = APPENDING.lock().unwrap().insert(data_path.clone());
+        acquired.then_some(AppendGuard(data_path))
+    }
+}
+
+impl Drop for AppendGuard {
+    fn drop(&mut self) {
+        APPENDING.lock().unwrap().remove(&self.0);
+    }
+}
+
+async fn current_offset(id: &str, opt: &Opt) -> Result<u64, WebError> {
+    Ok(tokio::fs::metadata(data_path(id, opt)).await?.len())
+}
+
+/**
+ * Describes the supported protocol version and extensions.
+ */
+pub async fn options(State(opt): State<Opt>) -> impl IntoResponse {
+    (
+        StatusCode::NO_CONTENT,
+        [
+            (TUS_RESUMABLE.clone(), TUS_VERSION.to_string()),
+            (TUS_VERSION_HEADER.clone(), TUS_VERSION.to_string()),
+            (TUS_EXTENSION.clone(), "creation".to_string()),
+            (TUS_MAX_SIZE.clone(), opt.max_upload_size.to_string()),
+        ],
+    )
+}
+
+/**
+ * Creates a new, empty upload of the length given in the Upload-Length header.
+ */
+pub async fn create(
+    State(opt): State<Opt>,
+    user: User,
+    headers: HeaderMap,
+) -> Result<Response, WebError> {
+    check_version(&headers)?;
+    let length = header_u64(&headers, &UPLOAD_LENGTH)?;
+    let filename = metadata_filename(&headers);
+    let extension = filename
+        .as_deref()
+        .and_then(|f| std::path::Path::new(f).extension())
+        .and_then(|ext| ext.to_str());
+    if let Some(ext) = extension {
+        if is_blocked_extension(ext, &opt) {
+            return Err(WebError::BlockedExtension(ext.to_string()));
+        }
+    }
+    let content_type = detect_content_type(filename.as_deref().unwrap_or_default(), None);
+    let limit = size_limit(extension, &content_type, &opt);
+    if length > limit.bytes as u64 {
+        return Err(limit.exceeded());
+    }
+
+    let id = generate_random_string(ID_LENGTH);
+    tokio::fs::create_dir_all(storage::backend(&opt).staging_dir()).await?;
+    SpaceGuard::new(&opt).check(0)?;
+    tokio::fs::File::create(data_path(&id, &opt)).await?;
+    let info = UploadInfo {
+        length,
+        filename,
+        user: user.0,
+    };
+    let info = serde_json::to_vec(&info).map_err(std::io::Error::from)?;
+    tokio::fs::write(info_path(&id, &opt), info).await?;
+
+    let location = public_path(&format!("files/{}", id), &opt)?;
+    Ok((
+        StatusCode::CREATED,
+        [
+            (LOCATION, location),
+            (TUS_RESUMABLE.clone(), TUS_VERSION.to_string()),
+        ],
+    )
+        .into_response())
+}
+
+/**
+ * Returns how much of the upload has been received.
+ */
+pub async fn offset(
+    State(opt): State<Opt>,
+    user: User,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, WebError> {
+    check_version(&headers)?;
+    let Some(info) = read_info(&id, &user, &opt).await? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+
+    Ok((
+        StatusCode::OK,
+        [
+            (
+                UPLOAD_OFFSET.clone(),
+                current_offset(&id, &opt).await?.to_string(),
+            ),
+            (UPLOAD_LENGTH.clone(), info.length.to_string()),
+            (CACHE_CONTROL, "no-store".to_string()),
+            (TUS_RESUMABLE.clone(), TUS_VERSION.to_string()),
+        ],
+    )
+        .into_response())
+}
+
+/**
+ * Appends the body to the upload, at the offset given in the Upload-Offset header. Once the whole
+ * upload has been received, it is stored like a regular upload, and its url is returned in the
+ * Content-Location header.
+ */
+pub async fn append(
+    State(opt): State<Opt>,
+    user: User,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, WebError> {
+    check_version(&headers)?;
+    let is_offset_stream = headers
+        .get(CONTENT_TYPE)
+        .is_some_and(|v| v == "application/offset+octet-stream");
+    if !is_offset_stream {
+        return Err(WebError::UnsupportedContentType(
+            "application/offset+octet-stream",
+        ));
+    }
+    let Some(info) = read_info(&id, &user, &opt).await? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    // A request still appending will move the offset, so this one can't be at the right one.
+    let Some(_guard) = AppendGuard::acquire(data_path(&id, &opt)) else {
+        return Err(WebError::OffsetMismatch(current_offset(&id, &opt).await?));
+    };
+    let offset = current_offset(&id, &opt).await?;
+    if header_u64(&headers, &UPLOAD_OFFSET)? != offset {
+        return Err(WebError::OffsetMismatch(offset));
+    }
+
+    // Keep whatever was received, even if the connection breaks, so that the client can resume.
+    let file = OpenOptions::new()
+        .append(true)
+        .open(data_path(&id, &opt))
+        .await?;
+    let mut f = BufWriter::new(file);
+    let mut offset = offset;
+    let mut chunks = body.into_data_stream();
+    let mut space = SpaceGuard::new(&opt);
+    let mut received = 0;
+    let mut result = Ok(());
+    while let Some(chunk) = chunks.next().await {
+        let Ok(data) = chunk else {
+            result = Err(WebError::IncompleteBody);
+            break;
+        };
+        if offset + data.len() as u64 > info.length {
+            result = Err(WebError::PayloadTooLarge);
+            break;
+        }
+        if let Err(e) = space.check(received) {
+            result = Err(e);
+            break;
+        }
+        f.write_all(&data).await?;
+        offset += data.len() as u64;
+        received += data.len();
+    }
+    f.flush().await?;
+    drop(f);
+    result?;
+
+    let mut response = (
+        StatusCode::NO_CONTENT,
+        [
+            (UPLOAD_OFFSET.clone(), offset.to_string()),
+            (TUS_RESUMABLE.clone(), TUS_VERSION.to_string()),
+        ],
+    )
+        .into_response();
+    if offset == info.length {
+        let url = finish(&id, info, &user, &opt).await?;
+        response
+            .headers_mut()
+            .insert(CONTENT_LOCATION, url.parse().unwrap());
+    }
+    Ok(response)
+}
+
+/**
+ * Stores a completely received upload under a random filename, and returns its url.
+ */
+async fn finish(id: &str, info: UploadInfo, user: &User, opt: &Opt) -> Result<String, WebError> {
+    let staging_path = data_path(id, opt);
+    let extension = info
+        .filename
+        .as_deref()
+        .and_then(|f| std::path::Path::new(f).extension())
+        .and_then(|ext| ext.to_str());
+    let random_filename = generate_random_filename(extension, opt);
+    let original_filename = info.filename.clone().unwrap_or(random_filename.clone());
+
+    let content_type = detect_content_type(&original_filename, None);
+    if let Err(e) = check_file(&staging_path, extension, &content_type, opt).await {
+        tokio::fs::remove_file(&staging_path).await.ok();
+        tokio::fs::remove_file(info_path(id, opt)).await.ok();
+        return Err(e);
+    }
+    let (size, hash) = hash_file(&staging_path).await?;
+
+    let file = FileUpload {
+        content_type,
+        original_filename,
+        random_filename,
+        staging_path,
+        size,
+        hash,
+    };
+    tokio::fs::remove_file(info_path(id, opt)).await?;
+    let uploaded = store_file(file, &Options::default(), user, opt).await?;
+    Ok(uploaded.url)
+}
+
+/**
+ * Returns true if the file in the staging directory belongs to a partial tus upload.
+ */
+pub fn is_tus_file(name: &str) -> bool {
+    name.starts_with("tus-")
+}
+
+/**
+ * Removes partial uploads that haven't been appended to in a long time.
+ */
+pub async fn remove_stale(now: SystemTime, opt: &Opt) -> Result<(), WebError> {
+    let staging_dir = storage::backend(opt).staging_dir();
+    let mut entries = match tokio::fs::read_dir(&staging_dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let Some(id) = name.to_str().and_then(|n| n.strip_prefix("tus-")) else {
+            continue;
+        };
+        if id.ends_with(".json") {
+            continue;
+        }
+        let modified = entry.metadata().await?.modified()?;
+        if now.duration_since(modified).unwrap_or_default() > STALE_AFTER {
+            log::info!("deleting stale partial upload {}", id);
+            tokio::fs::remove_file(entry.path()).await?;
+            tokio::fs::remove_file(staging_dir.join(format!("tus-{}.json", id)))
+                .await
+                .ok();
+        }
+    }
+
+    Ok(())
+}
@@ -1,169 +1,1309 @@
 use askama_axum::IntoResponse;
-use axum::extract::multipart::Field;
-use axum::extract::{Multipart, State};
-use axum::http::header::LOCATION;
-use axum::http::{HeaderMap, StatusCode};
+use axum::body::{Body, Bytes};
+use axum::extract::multipart::{Field, MultipartError};
+use axum::extract::{Multipart, Path as UrlPath, Query, State};
+use axum::http::header::{CONTENT_TYPE, LOCATION};
+use axum::http::{HeaderMap, HeaderName, StatusCode};
 use axum::Json;
-use futures::StreamExt;
+use chrono::{TimeDelta, Utc};
+use futures::{Stream, StreamExt, TryStreamExt};
 use rand::distributions::Alphanumeric;
-use rand::{thread_rng, Rng};
+use rand::rngs::StdRng;
+use rand::{thread_rng, Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::ffi::OsStr;
-use std::io::Write;
+use std::fmt;
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use utoipa::ToSchema;
 
 use crate::WebError;
 
-use super::helpers::{filename_path, thumbnail_filename_path};
-use super::{thumbnail::generate_thumbnail, Opt};
+use super::client_ip::ClientIp;
+use super::diskspace::SpaceGuard;
+use super::helpers::{file_key, file_url_path, public_path, reject_path_traversal};
+use super::limits::{size_limit, SizeLimit};
+use super::meta::{read_meta, write_meta, FileMeta};
+use super::metrics::METRICS;
+use super::protect::hash_view_password;
+use super::qr::get_qr_url;
+use super::sniff::ContentCheck;
+use super::strip::strip_metadata;
+use super::svg::sanitize_svg_file;
+use super::users::User;
+use super::webhook::{Event, EventKind};
+use super::{storage, thumbnail::remove_thumbnails, Opt};
 
-struct FileUpload {
-    original_filename: String,
-    random_filename: String,
-    random_filename_path: PathBuf,
+pub struct FileUpload {
+    pub original_filename: String,
+    pub random_filename: String,
+    /// Where the file was received, before being moved into storage.
+    pub staging_path: PathBuf,
+    pub size: usize,
+    pub content_type: String,
+    pub hash: String,
+}
+
+/// How uploaded files are named, unless a filename is requested in the upload options.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Naming {
+    /// A random name of `--slug-length` characters.
+    #[default]
+    Random,
+    /// The start of the SHA-256 hash of the contents, so that identical files are stored once.
+    ContentHash,
+    /// The filename given by the uploader.
+    Original,
+}
+
+/// What to do when a file is uploaded under its original filename, and a file of that name exists.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OnConflict {
+    /// Add a number to the name, as in `report-1.pdf`.
+    #[default]
+    Rename,
+    /// Reject the upload with `409 Conflict`.
+    Reject,
+    /// Replace the existing file.
+    Overwrite,
+}
+
+/// Characters of random filenames. Each character of an alphabet of `n` characters adds
+/// `log2(n)` bits to the name.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SlugAlphabet {
+    /// Upper and lower case letters and digits, about 5.95 bits per character.
+    #[default]
+    #[value(alias = "url-safe-base62")]
+    Alphanumeric,
+    /// Digits and the letters a to f, 4 bits per character.
+    LowercaseHex,
+    /// Lower case letters and the digits 2 to 7, 5 bits per character.
+    Base32Lowercase,
+}
+
+impl SlugAlphabet {
+    fn chars(self) -> &'static [u8] {
+        match self {
+            SlugAlphabet::Alphanumeric => {
+                b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789"
+            }
+            SlugAlphabet::LowercaseHex => b"0123456789abcdef",
+            SlugAlphabet::Base32Lowercase => b"abcdefghijklmnopqrstuvwxyz234567",
+        }
+    }
+}
+
+/**
+ * Source of the randomness of random filenames. Filenames come from the thread-local generator,
+ * unless a seeded generator is set, which makes them the same every time for tests.
+ */
+#[derive(Clone, Default)]
+pub struct SlugRng(Option<Arc<Mutex<StdRng>>>);
+
+impl SlugRng {
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn seeded(seed: u64) -> Self {
+        SlugRng(Some(Arc::new(Mutex::new(StdRng::seed_from_u64(seed)))))
+    }
+}
+
+impl fmt::Debug for SlugRng {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.0.is_some() {
+            "SlugRng(seeded)"
+        } else {
+            "SlugRng(thread)"
+        })
+    }
 }
 
 fn default_as_true() -> bool {
     true
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
-struct Options {
+pub struct Options {
+    /// Store the file under its original filename, instead of a random one.
     #[serde(default)]
-    use_original_filename: bool, // default for bool is false.
+    pub use_original_filename: bool, // default for bool is false.
+    /// Redirect to the stored file with `303 See Other`, instead of returning `200 OK`.
     #[serde(default = "default_as_true")] // semi-ugly hack to get true as default.
-    redirect: bool,
+    #[schema(default = true)]
+    pub redirect: bool,
+    /// Delete the file after this many seconds.
+    #[serde(default)]
+    pub expires_in_secs: Option<u64>,
+    /// Name to store the file as, instead of a random or the original filename.
+    #[serde(default)]
+    pub filename: Option<String>,
+    /// Replace an existing file with the requested `filename`, or with the original filename.
+    #[serde(default)]
+    pub overwrite: bool,
+    /// Password needed to view the file.
+    #[serde(default)]
+    pub view_password: Option<String>,
+    /// Delete the file after it has been downloaded this many times.
+    #[serde(default)]
+    pub max_downloads: Option<u64>,
+    /// Make browsers download the file instead of showing it.
+    #[serde(default)]
+    pub force_download: bool,
 }
 
-#[derive(Serialize)]
+/// Multipart form of an upload, only used to describe it in the API documentation.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+pub struct UploadForm {
+    /// File to upload. Repeat the field to upload several files.
+    #[schema(value_type = Vec<String>, format = Binary)]
+    file: Vec<Vec<u8>>,
+    /// Options of the upload, as JSON.
+    options: Option<Options>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            use_original_filename: false,
+            redirect: true,
+            expires_in_secs: None,
+            filename: None,
+            overwrite: false,
+            view_password: None,
+            max_downloads: None,
+            force_download: false,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
 #[serde(rename_all = "camelCase")]
-struct UploadResponse {
-    url: String,
+pub struct UploadResponse {
+    pub url: String,
+    /// Name of the stored file without its extension.
+    id: String,
+    /// When the file was stored, in RFC 3339 format.
+    uploaded_at: String,
+    size: usize,
+    content_type: String,
+    /// SHA-256 hash of the file, in hex.
+    hash: String,
+    /// When the file expires, in RFC 3339 format.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<String>,
+    /// Secret token to delete the file with.
+    delete_token: String,
+    /// Where a QR code of `url` can be fetched.
+    qr_url: String,
+}
+
+/// A single stored file, or the list of files if several were uploaded.
+#[derive(Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum UploadResult {
+    Single(UploadResponse),
+    Multiple { files: Vec<UploadResponse> },
 }
 
-fn generate_random_filename(extension: Option<&str>) -> String {
+const DELETE_TOKEN_LENGTH: usize = 32;
+
+/// Number of random filenames to try before giving up on finding one that isn't taken.
+const RANDOM_FILENAME_ATTEMPTS: usize = 10;
+/// Highest number added to an original filename that is taken, with `--on-conflict rename`.
+const MAX_NAME_SUFFIX: usize = 1000;
+
+/// Header with the filename of a raw upload.
+pub static X_FILENAME: HeaderName = HeaderName::from_static("x-filename");
+
+/// Extensions to use for common content types, which have several possible extensions.
+const PREFERRED_EXTENSIONS: &[(&str, &str)] = &[
+    ("image/jpeg", "jpg"),
+    ("text/plain", "txt"),
+    ("text/html", "html"),
+    ("audio/mpeg", "mp3"),
+    ("video/mp4", "mp4"),
+    ("video/quicktime", "mov"),
+];
+
+/// Size of the buffer used when writing uploads to disk.
+const WRITE_BUFFER_SIZE: usize = 256 * 1024;
+
+pub fn generate_random_string(length: usize) -> String {
     let mut rng = thread_rng();
-    let random_string: String = std::iter::repeat(())
+    std::iter::repeat(())
         .map(|()| rng.sample(Alphanumeric))
         .map(char::from)
-        .take(8)
-        .collect();
+        .take(length)
+        .collect()
+}
+
+/**
+ * Returns a random name of `--slug-length` characters of the `--slug-alphabet`.
+ */
+fn generate_slug<R: Rng + ?Sized>(rng: &mut R, opt: &Opt) -> String {
+    let chars = opt.slug_alphabet.chars();
+    (0..opt.slug_length)
+        .map(|_| char::from(chars[rng.gen_range(0..chars.len())]))
+        .collect()
+}
+
+pub fn generate_random_filename(extension: Option<&str>, opt: &Opt) -> String {
+    match &opt.slug_rng.0 {
+        Some(rng) => {
+            let mut rng = rng.lock().unwrap();
+            generate_random_filename_with(&mut *rng, extension, opt)
+        }
+        None => generate_random_filename_with(&mut thread_rng(), extension, opt),
+    }
+}
+
+/**
+ * Returns a random filename with the given extension, taking the randomness from `rng`.
+ */
+pub fn generate_random_filename_with<R: Rng + ?Sized>(
+    rng: &mut R,
+    extension: Option<&str>,
+    opt: &Opt,
+) -> String {
+    let random_string = generate_slug(rng, opt);
     match extension {
         Some(ext) => format!("{}.{}", random_string, ext),
         None => random_string,
     }
 }
 
-fn get_extension_from_filename(filename: &str) -> Option<&str> {
+/**
+ * Returns a filename made of the first `--slug-length` characters of the hash of the file, and
+ * whether an identical file is already stored under it. If a different file is stored under that
+ * name, a longer part of the hash is used. So it is if the identical file can't be shared, because
+ * either upload is protected by a password or has limited downloads.
+ */
+async fn content_hash_filename(
+    file: &FileUpload,
+    shareable: bool,
+    user: &User,
+    opt: &Opt,
+) -> Result<(String, bool), WebError> {
+    let storage = storage::backend(opt);
+    let extension = get_extension_from_filename(&file.random_filename);
+    for length in opt.slug_length.min(file.hash.len())..=file.hash.len() {
+        let prefix = &file.hash[..length];
+        let filename = match extension {
+            Some(ext) => format!("{}.{}", prefix, ext),
+            None => prefix.to_string(),
+        };
+        if !storage.exists(&file_key(&filename, user)).await? {
+            return Ok((filename, false));
+        }
+        let Some(meta) = read_meta(&filename, user, opt).await? else {
+            log::warn!("hash prefix {} collides, trying a longer prefix", filename);
+            continue;
+        };
+        if meta.hash.as_deref() != Some(&file.hash) {
+            log::warn!("hash prefix {} collides, trying a longer prefix", filename);
+        } else if shareable && !meta.is_protected() && meta.max_downloads.is_none() {
+            return Ok((filename, true));
+        }
+    }
+    Err(WebError::NoFreeFilename)
+}
+
+/**
+ * Returns the random filename if no file is stored under it yet, or else a new random filename
+ * with the same extension that is free. Gives up after a few attempts, which only happens if
+ * nearly every name of the configured length is taken.
+ */
+pub async fn free_random_filename(
+    random_filename: &str,
+    user: &User,
+    opt: &Opt,
+) -> Result<String, WebError> {
+    let storage = storage::backend(opt);
+    let extension = get_extension_from_filename(random_filename);
+    let mut filename = random_filename.to_string();
+    for _ in 0..RANDOM_FILENAME_ATTEMPTS {
+        if !storage.exists(&file_key(&filename, user)).await? {
+            return Ok(filename);
+        }
+        log::warn!("random filename {} is already taken", filename);
+        filename = generate_random_filename(extension, opt);
+    }
+    Err(WebError::NoFreeFilename)
+}
+
+/// Guesses the content type from the filename, falling back to what the client claimed.
+pub fn detect_content_type(filename: &str, claimed: Option<&str>) -> String {
+    match mime_guess::from_path(filename).first() {
+        Some(mime) => mime.to_string(),
+        None => claimed.unwrap_or("application/octet-stream").to_string(),
+    }
+}
+
+/**
+ * Shortens a filename to at most `max` bytes, keeping its extension. Returns `None` if not even
+ * a single character of the name fits next to the extension.
+ */
+fn shorten_filename(filename: &str, max: usize) -> Option<String> {
+    with_suffix(filename, "", max)
+}
+
+/**
+ * Adds `suffix` to a filename before its extension, shortening the name to keep it at most `max`
+ * bytes. Returns `None` if not even a single character of the name fits.
+ */
+fn with_suffix(filename: &str, suffix: &str, max: usize) -> Option<String> {
+    let extension =
+        get_extension_from_filename(filename).map_or(String::new(), |ext| format!(".{}", ext));
+    let stem = &filename[..filename.len() - extension.len()];
+    let mut end = max
+        .checked_sub(suffix.len() + extension.len())?
+        .min(stem.len());
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    if end == 0 {
+        return None;
+    }
+    Some(format!("{}{}{}", &stem[..end], suffix, extension))
+}
+
+/**
+ * Returns the name to store a file under its original filename, which is shortened to
+ * `--max-filename-length`. If a file of that name exists, it is replaced if `overwrite` is set,
+ * or else as `--on-conflict` says.
+ */
+async fn original_filename(
+    filename: &str,
+    overwrite: bool,
+    user: &User,
+    opt: &Opt,
+) -> Result<String, WebError> {
+    let max = opt.max_filename_length;
+    let filename = shorten_filename(filename, max).ok_or(WebError::FilenameTooLong(max))?;
+    let storage = storage::backend(opt);
+    if overwrite
+        || opt.on_conflict == OnConflict::Overwrite
+        || !storage.exists(&file_key(&filename, user)).await?
+    {
+        return Ok(filename);
+    }
+    if opt.on_conflict == OnConflict::Reject {
+        return Err(WebError::Conflict(filename));
+    }
+
+    for n in 1..=MAX_NAME_SUFFIX {
+        let renamed = with_suffix(&filename, &format!("-{}", n), max)
+            .ok_or(WebError::FilenameTooLong(max))?;
+        if !storage.exists(&file_key(&renamed, user)).await? {
+            return Ok(renamed);
+        }
+    }
+    Err(WebError::NoFreeFilename)
+}
+
+pub fn get_extension_from_filename(filename: &str) -> Option<&str> {
     Path::new(filename).extension().and_then(OsStr::to_str)
 }
 
+/**
+ * Picks an extension for the content type. The extension of the filename is kept if it matches the
+ * content type.
+ */
+pub fn extension_for(content_type: Option<&str>, filename: Option<&str>) -> Option<String> {
+    let content_type = content_type?;
+    let extensions = mime_guess::get_mime_extensions_str(content_type)?;
+    let filename_extension = filename
+        .and_then(get_extension_from_filename)
+        .map(str::to_ascii_lowercase);
+    if let Some(ext) = filename_extension.filter(|ext| extensions.contains(&ext.as_str())) {
+        return Some(ext);
+    }
+
+    PREFERRED_EXTENSIONS
+        .iter()
+        .find(|(preferred_type, _)| *preferred_type == content_type)
+        .map(|(_, ext)| *ext)
+        .or_else(|| extensions.first().copied())
+        .map(str::to_string)
+}
+
+pub fn is_blocked_extension(extension: &str, opt: &Opt) -> bool {
+    opt.blocked_extensions.iter().any(|blocked| {
+        blocked
+            .trim_start_matches('.')
+            .eq_ignore_ascii_case(extension)
+    })
+}
+
+/**
+ * Whether the filename is taken by one of the directories kept next to the uploads, which an
+ * upload must not replace.
+ */
+fn is_reserved_filename(filename: &str) -> bool {
+    filename == crate::THUMBNAIL_SUBDIR || filename == crate::META_SUBDIR
+}
+
+#[utoipa::path(
+    post,
+    path = "/",
+    tag = "files",
+    summary = "Upload one or more files",
+    request_body(content = UploadForm, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "Files stored, with `redirect: false`", body = UploadResult),
+        (status = 303, description = "Files stored, redirecting to the file, or to the recent uploads if several were uploaded", body = UploadResult),
+        (status = 400, description = "Empty upload, or invalid options"),
+        (status = 409, description = "A file with the requested filename already exists"),
+        (status = 413, description = "Upload is larger than the size limit"),
+        (status = 415, description = "Blocked extension, or content that doesn't match the extension"),
+        (status = 429, description = "Too many uploads from this address"),
+        (status = 507, description = "Not enough free storage space"),
+    )
+)]
 pub async fn handle_upload(
     State(opt): State<Opt>,
+    user: User,
+    ClientIp(client_ip): ClientIp,
     mut payload: Multipart,
 ) -> Result<impl IntoResponse, WebError> {
-    let mut file_field: Option<FileUpload> = None;
+    let mut files: Vec<FileUpload> = Vec::new();
+    let mut skipped_empty = 0;
+    let mut unnamed = false;
     // Use default options field if we don't wish to include it.
-    let mut options_field: Option<Options> = Some(Options {
-        use_original_filename: false,
-        redirect: true,
-    });
+    let mut options = Options::default();
 
     // iterate over multipart stream
-    while let Ok(Some(mut field)) = payload.next_field().await {
+    let mut fields = 0;
+    loop {
+        let field = match payload.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => {
+                discard(&files);
+                return Err(multipart_error(e));
+            }
+        };
+        fields += 1;
+        if fields > opt.max_fields {
+            discard(&files);
+            return Err(WebError::TooManyFields(opt.max_fields));
+        }
         match field.name() {
             Some("file") => {
-                // Save to temporary filename, we might later rename it to original.
-                let original_filename = field.file_name().unwrap().to_string();
-                let extension = get_extension_from_filename(&original_filename);
-                let random_filename = generate_random_filename(extension);
-
-                let filepath = filename_path(&random_filename, &opt)?;
-                let random_filename_path = filepath.clone();
-                // File::create is blocking operation, use threadpool
-                let mut f =
-                    tokio::task::spawn_blocking(|| std::fs::File::create(filepath)).await??;
-                // Field in turn is stream of *Bytes* object
-                let mut written_bytes = 0;
-                while let Some(chunk) = field.next().await {
-                    let data = chunk.unwrap();
-                    written_bytes += data.len();
-                    // filesystem operations are blocking, we have to use threadpool
-                    f = tokio::task::spawn_blocking(move || f.write_all(&data).map(|_| f))
-                        .await??;
-                }
-
-                // If uploaded file had a length of zero, delete the (zero length) file, return error
-                // and delete temporary (empty) file.
-                if written_bytes == 0 {
-                    log::info!(
-                        "tried to upload empty file {}, aborting.",
-                        random_filename_path.display()
-                    );
-                    std::fs::remove_file(random_filename_path)?;
-                    return Err(WebError::EmptyUpload);
+                let named = field.file_name().is_some();
+                match receive_file(field, client_ip, &opt).await {
+                    Ok(Some(file)) => {
+                        unnamed |= !named;
+                        files.push(file);
+                    }
+                    Ok(None) => skipped_empty += 1,
+                    Err(e) => {
+                        // Don't leave earlier files of a rejected request behind.
+                        discard(&files);
+                        return Err(e);
+                    }
                 }
-
-                file_field = Some(FileUpload {
-                    original_filename,
-                    random_filename,
-                    random_filename_path,
-                });
             }
-            Some("options") => options_field = parse_field_options(field).await.ok(),
+            Some("options") => match parse_field_options(field, opt.max_field_size).await {
+                Ok(parsed) => options = parsed,
+                Err(e) => {
+                    discard(&files);
+                    return Err(e);
+                }
+            },
             _ => { /* TODO: show error or something */ }
         }
     }
 
-    // Check if we received both file itself and data.
-    if let (Some(file), Some(options)) = (file_field, options_field) {
-        let final_filename: &str = if options.use_original_filename {
-            // Rename from temporary random filename to original. Will overwrite if filename already exists.
-            let original_filename_path = filename_path(&file.original_filename, &opt)?;
-            std::fs::rename(&file.random_filename_path, original_filename_path)?;
-            &file.original_filename
+    if files.is_empty() {
+        // If every file in the request was empty, there is nothing to store.
+        return Err(if skipped_empty > 0 {
+            WebError::EmptyUpload
         } else {
-            &file.random_filename
-        };
+            WebError::MissingFile
+        });
+    }
 
-        // Derive url of newly created file.
-        let url = public_path(final_filename, &opt)?;
+    // Files without a filename are named randomly, which isn't what the uploader asked for.
+    if unnamed && options.use_original_filename {
+        discard(&files);
+        return Err(WebError::InvalidOptions(
+            "useOriginalFilename requires every file to have a filename".to_string(),
+        ));
+    }
 
-        // Generate thumbnail if the upload was an image.
-        let final_path = filename_path(final_filename, &opt)?;
-        let final_thumb_path = thumbnail_filename_path(final_filename, &opt)?;
-        tokio::task::spawn(async move {
-            // TODO: replace with some mpsc channel for thumbnails
-            let _ = generate_thumbnail(&final_path, &final_thumb_path, &opt)
-                .map_err(|e| println!("Error when generating thumbnail: {}", e));
-        });
+    if let Some(filename) = &options.filename {
+        if let Err(e) = check_custom_filename(
+            filename,
+            files.len(),
+            options.overwrite,
+            client_ip,
+            &user,
+            &opt,
+        )
+        .await
+        {
+            discard(&files);
+            return Err(e);
+        }
+    }
 
-        let (status, headers) = if options.redirect {
-            (
-                StatusCode::SEE_OTHER,
-                [(LOCATION, url.parse().unwrap())].into_iter().collect(),
-            )
-        } else {
-            (StatusCode::OK, HeaderMap::new())
-        };
+    let mut uploaded = Vec::new();
+    let mut files = files.into_iter();
+    while let Some(file) = files.next() {
+        match store_file(file, &options, &user, &opt).await {
+            Ok(file) => uploaded.push(file),
+            Err(e) => {
+                // The files after it are still staged, and won't be stored.
+                discard(files.as_slice());
+                return Err(e);
+            }
+        }
+    }
+
+    upload_response(uploaded, &options, &opt)
+}
+
+/**
+ * Describes the stored files, and redirects to them unless the uploader asked not to.
+ */
+pub fn upload_response(
+    mut uploaded: Vec<UploadResponse>,
+    options: &Options,
+    opt: &Opt,
+) -> Result<impl IntoResponse, WebError> {
+    // A single upload redirects to the file itself, several uploads to the list of recent uploads,
+    // or back to the upload form if there is none.
+    let location = if uploaded.len() == 1 {
+        uploaded[0].url.clone()
+    } else if opt.disable_recent {
+        public_path("", opt)?
+    } else {
+        public_path("recent", opt)?
+    };
+
+    let (status, headers) = if options.redirect {
+        (
+            StatusCode::SEE_OTHER,
+            [(LOCATION, location.parse().unwrap())]
+                .into_iter()
+                .collect(),
+        )
+    } else {
+        (StatusCode::OK, HeaderMap::new())
+    };
+
+    let body = if uploaded.len() == 1 {
+        UploadResult::Single(uploaded.remove(0))
+    } else {
+        UploadResult::Multiple { files: uploaded }
+    };
+
+    Ok((status, headers, Json(body)))
+}
+
+/**
+ * Removes received files that won't be stored.
+ */
+pub fn discard(files: &[FileUpload]) {
+    for file in files {
+        std::fs::remove_file(&file.staging_path).ok();
+    }
+}
 
-        Ok((status, headers, Json(UploadResponse { url })))
+/**
+ * Checks that a filename requested by the uploader is a plain filename that can be used, and that
+ * it doesn't replace an existing file unless `overwrite` is set.
+ */
+pub async fn check_custom_filename(
+    filename: &str,
+    n_of_files: usize,
+    overwrite: bool,
+    client_ip: Option<IpAddr>,
+    user: &User,
+    opt: &Opt,
+) -> Result<(), WebError> {
+    reject_path_traversal(filename, client_ip)?;
+    // Several files can't be stored under the same name.
+    if n_of_files != 1 {
+        return Err(WebError::BadRequest);
+    }
+    // Sanitizing would also cut long names short.
+    if filename.len() > opt.max_filename_length {
+        return Err(WebError::FilenameTooLong(opt.max_filename_length));
+    }
+    // Sanitizing removes path separators, so a sanitized name has no path components.
+    let is_plain = sanitize_filename::sanitize(filename) == filename && is_upload_name(filename);
+    if !is_plain {
+        return Err(WebError::BadRequest);
+    }
+    if let Some(ext) = get_extension_from_filename(filename) {
+        if is_blocked_extension(ext, opt) {
+            return Err(WebError::BlockedExtension(ext.to_string()));
+        }
+    }
+
+    if !overwrite
+        && storage::backend(opt)
+            .exists(&file_key(filename, user))
+            .await?
+    {
+        return Err(WebError::Conflict(filename.to_string()));
+    }
+
+    Ok(())
+}
+
+/**
+ * Whether a sanitized filename can be the name of an upload, rather than of a hidden file or one of
+ * the directories kept next to the uploads.
+ */
+pub fn is_upload_name(filename: &str) -> bool {
+    !filename.is_empty() && !filename.starts_with('.') && !is_reserved_filename(filename)
+}
+
+/**
+ * Tells whether a file is stored under the given name, sanitized the same way as a requested
+ * filename, so that a client can pick a free name before uploading. The file itself isn't served.
+ */
+#[utoipa::path(
+    get,
+    path = "/exists/{name}",
+    tag = "files",
+    summary = "Check whether a filename is taken",
+    params(("name" = String, Path, description = "Filename to check")),
+    responses(
+        (status = 200, description = "A file with this name exists"),
+        (status = 404, description = "No file with this name exists"),
+    )
+)]
+pub async fn handle_exists(
+    State(opt): State<Opt>,
+    user: User,
+    UrlPath(name): UrlPath<String>,
+) -> Result<StatusCode, WebError> {
+    let filename = sanitize_filename::sanitize(&name);
+    if is_upload_name(&filename)
+        && storage::backend(&opt)
+            .exists(&file_key(&filename, &user))
+            .await?
+    {
+        Ok(StatusCode::OK)
+    } else {
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+/**
+ * Streams a single file field to the staging directory under a random filename. Returns `None` if
+ * the file was empty, in which case nothing is kept on disk. Some clients send files without a
+ * filename, which then get the random filename, with an extension from the Content-Type.
+ */
+async fn receive_file(
+    field: Field<'_>,
+    client_ip: Option<IpAddr>,
+    opt: &Opt,
+) -> Result<Option<FileUpload>, WebError> {
+    // Save to temporary filename, we might later rename it to original.
+    let filename = field.file_name().map(str::to_string);
+    if let Some(filename) = &filename {
+        reject_path_traversal(filename, client_ip)?;
+    }
+    let extension = match filename.as_deref().and_then(get_extension_from_filename) {
+        Some(ext) => Some(ext.to_string()),
+        None if filename.is_none() => extension_for(field.content_type(), None),
+        None => None,
+    };
+    if let Some(ext) = &extension {
+        if is_blocked_extension(ext, opt) {
+            log::info!(
+                "rejected upload of {} due to blocked extension",
+                filename.as_deref().unwrap_or("file without a filename")
+            );
+            return Err(WebError::BlockedExtension(ext.to_string()));
+        }
+    }
+    let random_filename = generate_random_filename(extension.as_deref(), opt);
+    let original_filename = filename.unwrap_or_else(|| random_filename.clone());
+    let content_type = detect_content_type(&original_filename, field.content_type());
+    let limit = size_limit(extension.as_deref(), &content_type, opt);
+    let check = ContentCheck::new(extension.as_deref(), &content_type, opt);
+
+    let staging_path = staging_path(&random_filename, opt).await?;
+    // Field in turn is stream of *Bytes* object
+    let chunks = field.map_err(multipart_error);
+    let (written_bytes, hash) =
+        write_staged(&staging_path, chunks, &limit, check, SpaceGuard::new(opt)).await?;
+
+    // If uploaded file had a length of zero, skip it and delete the temporary (empty) file.
+    if written_bytes == 0 {
+        log::warn!(
+            "skipping empty file {} ({})",
+            original_filename,
+            staging_path.display()
+        );
+        std::fs::remove_file(staging_path)?;
+        return Ok(None);
+    }
+
+    Ok(Some(FileUpload {
+        original_filename,
+        random_filename,
+        staging_path,
+        size: written_bytes,
+        content_type,
+        hash,
+    }))
+}
+
+/**
+ * Returns a path in the staging directory, where a file can be received before it is stored.
+ */
+pub async fn staging_path(filename: &str, opt: &Opt) -> Result<PathBuf, WebError> {
+    let staging_dir = storage::backend(opt).staging_dir();
+    tokio::fs::create_dir_all(&staging_dir).await?;
+    Ok(staging_dir.join(filename))
+}
+
+/**
+ * Writes a stream of chunks to `staging_path`, and returns the number of bytes written and their
+ * SHA-256 hash. The file is removed again if the stream fails, grows larger than `limit`, fails
+ * the content check, or the disk is running out of space.
+ */
+pub async fn write_staged<S>(
+    staging_path: &Path,
+    mut chunks: S,
+    limit: &SizeLimit<'_>,
+    mut check: ContentCheck<'_>,
+    mut space: SpaceGuard,
+) -> Result<(usize, String), WebError>
+where
+    S: Stream<Item = Result<Bytes, WebError>> + Unpin,
+{
+    let result = async {
+        space.check(0)?;
+        let mut f = BufWriter::with_capacity(WRITE_BUFFER_SIZE, File::create(staging_path).await?);
+        let mut written_bytes = 0;
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = chunks.next().await {
+            let data = chunk?;
+            written_bytes += data.len();
+            if written_bytes > limit.bytes {
+                return Err(limit.exceeded());
+            }
+            check.feed(&data)?;
+            space.check(written_bytes)?;
+            hasher.update(&data);
+            f.write_all(&data).await?;
+        }
+        check.finish()?;
+        f.flush().await?;
+        Ok((written_bytes, format!("{:x}", hasher.finalize())))
+    }
+    .await;
+
+    if result.is_err() {
+        tokio::fs::remove_file(staging_path).await.ok();
+    }
+    result
+}
+
+/**
+ * Returns the size and SHA-256 hash of a file.
+ */
+pub async fn hash_file(path: &Path) -> Result<(usize, String), WebError> {
+    let mut f = File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    let mut buf = vec![0; WRITE_BUFFER_SIZE];
+    loop {
+        let n = f.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        size += n;
+        hasher.update(&buf[..n]);
+    }
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+/**
+ * Stores the whole request body as a single file, for clients that can't easily send multipart
+ * requests. The filename is taken from the X-Filename header, or the extension from the
+ * Content-Type header, and options are given in the query string.
+ */
+pub async fn handle_raw_upload(
+    State(opt): State<Opt>,
+    user: User,
+    ClientIp(client_ip): ClientIp,
+    Query(options): Query<Options>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<impl IntoResponse, WebError> {
+    let filename = headers.get(&X_FILENAME).and_then(|v| v.to_str().ok());
+    if let Some(filename) = filename {
+        reject_path_traversal(filename, client_ip)?;
+    }
+    let filename = filename
+        .map(sanitize_filename::sanitize)
+        .filter(|filename| !filename.is_empty());
+    // curl sends form data unless told otherwise, which says nothing about the file.
+    let claimed_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_ascii_lowercase())
+        .filter(|v| v != "application/x-www-form-urlencoded");
+    let extension = match filename.as_deref().and_then(get_extension_from_filename) {
+        Some(ext) => Some(ext.to_string()),
+        None => extension_for(claimed_type.as_deref(), None),
+    };
+    if let Some(ext) = &extension {
+        if is_blocked_extension(ext, &opt) {
+            return Err(WebError::BlockedExtension(ext.to_string()));
+        }
+    }
+    let random_filename = generate_random_filename(extension.as_deref(), &opt);
+    let original_filename = filename.unwrap_or_else(|| random_filename.clone());
+    let content_type = detect_content_type(&original_filename, claimed_type.as_deref());
+
+    let staging_path = staging_path(&random_filename, &opt).await?;
+    let chunks = body
+        .into_data_stream()
+        .map_err(|_| WebError::IncompleteBody);
+    let limit = size_limit(extension.as_deref(), &content_type, &opt);
+    let check = ContentCheck::new(extension.as_deref(), &content_type, &opt);
+    let (size, hash) =
+        write_staged(&staging_path, chunks, &limit, check, SpaceGuard::new(&opt)).await?;
+    if size == 0 {
+        std::fs::remove_file(&staging_path)?;
+        return Err(WebError::EmptyUpload);
+    }
+
+    let file = FileUpload {
+        original_filename,
+        random_filename,
+        staging_path,
+        size,
+        content_type,
+        hash,
+    };
+    if let Some(filename) = &options.filename {
+        if let Err(e) =
+            check_custom_filename(filename, 1, options.overwrite, client_ip, &user, &opt).await
+        {
+            discard(&[file]);
+            return Err(e);
+        }
+    }
+
+    let uploaded = store_file(file, &options, &user, &opt).await?;
+    upload_response(vec![uploaded], &options, &opt)
+}
+
+/**
+ * Moves a received file into storage under its final name, starts thumbnail generation, and
+ * describes the result.
+ */
+pub async fn store_file(
+    mut file: FileUpload,
+    options: &Options,
+    user: &User,
+    opt: &Opt,
+) -> Result<UploadResponse, WebError> {
+    // A file that can never be downloaded is surely a mistake.
+    if options.max_downloads == Some(0) {
+        std::fs::remove_file(&file.staging_path).ok();
+        return Err(WebError::BadRequest);
+    }
+
+    // Remove metadata such as GPS coordinates before the file can be fetched by anyone.
+    if opt.strip_metadata {
+        let path = file.staging_path.clone();
+        let strip_opt = opt.clone();
+        // Decoding and encoding images is blocking, use threadpool
+        let stripped =
+            tokio::task::spawn_blocking(move || strip_metadata(&path, &strip_opt)).await?;
+        match stripped {
+            Ok(true) => (file.size, file.hash) = hash_file(&file.staging_path).await?,
+            Ok(false) => {}
+            Err(e) => {
+                std::fs::remove_file(&file.staging_path).ok();
+                return Err(e);
+            }
+        }
+    }
+
+    // Remove scripts from SVG images, also from those that only become SVG by the requested name.
+    let svg = file.content_type == "image/svg+xml"
+        || [Some(&file.random_filename), options.filename.as_ref()]
+            .into_iter()
+            .flatten()
+            .filter_map(|filename| get_extension_from_filename(filename))
+            .any(|ext| ext.eq_ignore_ascii_case("svg"));
+    if opt.sanitize_svg && svg {
+        let path = file.staging_path.clone();
+        let policy = opt.svg_policy;
+        let sanitized =
+            tokio::task::spawn_blocking(move || sanitize_svg_file(&path, policy)).await?;
+        match sanitized {
+            Ok(true) => (file.size, file.hash) = hash_file(&file.staging_path).await?,
+            Ok(false) => {}
+            Err(e) => {
+                std::fs::remove_file(&file.staging_path).ok();
+                return Err(e);
+            }
+        }
+    }
+
+    // Use the requested filename, the original, the hash, or the temporary random filename. Only
+    // the requested or the original filename can replace an existing file.
+    let use_original_filename = options.use_original_filename || opt.naming == Naming::Original;
+    // The directories kept next to the uploads can't be replaced by an upload.
+    if options.filename.is_none()
+        && use_original_filename
+        && is_reserved_filename(&sanitize_filename::sanitize(&file.original_filename))
+    {
+        std::fs::remove_file(&file.staging_path).ok();
+        return Err(WebError::BadRequest);
+    }
+    let generated_filename;
+    let mut duplicate = false;
+    let final_filename: &str = match &options.filename {
+        Some(filename) => filename,
+        None if use_original_filename => {
+            match original_filename(&file.original_filename, options.overwrite, user, opt).await {
+                Ok(filename) => {
+                    generated_filename = filename;
+                    &generated_filename
+                }
+                Err(e) => {
+                    std::fs::remove_file(&file.staging_path).ok();
+                    return Err(e);
+                }
+            }
+        }
+        None => {
+            let generated = match opt.naming {
+                Naming::ContentHash => {
+                    let protected = options
+                        .view_password
+                        .as_ref()
+                        .is_some_and(|password| !password.is_empty());
+                    let shareable = !protected && options.max_downloads.is_none();
+                    content_hash_filename(&file, shareable, user, opt).await
+                }
+                _ => free_random_filename(&file.random_filename, user, opt)
+                    .await
+                    .map(|filename| (filename, false)),
+            };
+            match generated {
+                Ok((filename, identical)) => {
+                    generated_filename = filename;
+                    duplicate = identical;
+                    &generated_filename
+                }
+                Err(e) => {
+                    std::fs::remove_file(&file.staging_path).ok();
+                    return Err(e);
+                }
+            }
+        }
+    };
+
+    // An identical file is already stored, so there is nothing to store.
+    let existing = if duplicate {
+        std::fs::remove_file(&file.staging_path)?;
+        read_meta(final_filename, user, opt).await?
     } else {
-        Err(WebError::BadRequest)
+        let stored = storage::backend(opt)
+            .put_file(&file_key(final_filename, user), &file.staging_path)
+            .await;
+        if let Err(e) = stored {
+            std::fs::remove_file(&file.staging_path).ok();
+            return Err(e);
+        }
+        opt.recent_cache.invalidate(user);
+        None
+    };
+
+    // Derive url of newly created file.
+    let url = public_path(&file_url_path(final_filename, user, opt), opt)?;
+    let qr_url = get_qr_url(final_filename, user, opt)?;
+
+    // An identical file keeps its metadata, and only gets another deletion token, which takes back
+    // just this upload. It expires when the last of its uploads would have. Otherwise the metadata
+    // is always rewritten, since an overwritten file must not inherit an old expiry or deletion
+    // token.
+    let expires_at = options
+        .expires_in_secs
+        .and_then(|secs| TimeDelta::try_seconds(secs.try_into().ok()?))
+        .and_then(|delta| Utc::now().checked_add_signed(delta));
+    let delete_token = generate_random_string(DELETE_TOKEN_LENGTH);
+    let meta = match existing {
+        Some(mut existing) => {
+            existing.expires_at = expires_at.zip(existing.expires_at).map(|(a, b)| a.max(b));
+            match existing.delete_token {
+                Some(_) => existing.shared_delete_tokens.push(delete_token.clone()),
+                None => existing.delete_token = Some(delete_token.clone()),
+            }
+            existing
+        }
+        None => {
+            let view_password_hash = match options.view_password.clone() {
+                Some(password) if !password.is_empty() => Some(hash_view_password(password).await?),
+                _ => None,
+            };
+            FileMeta {
+                expires_at,
+                delete_token: Some(delete_token.clone()),
+                content_type: Some(file.content_type.clone()),
+                hash: Some(file.hash.clone()),
+                immutable: options.filename.is_none() && !use_original_filename,
+                deleted_at: None,
+                view_password_hash,
+                max_downloads: options.max_downloads,
+                downloads: 0,
+                force_download: options.force_download,
+                info: None,
+                shared_delete_tokens: Vec::new(),
+            }
+        }
+    };
+    let protected = meta.is_protected();
+    let expires_at = meta.expires_at;
+    write_meta(final_filename, &meta, user, opt).await?;
+
+    // The thumbnails of an identical file are still valid.
+    if !duplicate {
+        if opt.eager_thumbnails && !protected {
+            // Generate thumbnail if the upload was an image.
+            opt.thumbnail_queue
+                .push(final_filename.to_string(), user.clone(), opt.clone())
+                .await;
+        } else {
+            // Thumbnails are generated when first requested, so those of an overwritten file must
+            // go. Protected files have none, since thumbnails are public.
+            remove_thumbnails(final_filename, user, opt).await?;
+        }
     }
+
+    METRICS.record_upload(file.size);
+    tracing::Span::current()
+        .record("filename", final_filename)
+        .record("size", file.size);
+    opt.webhooks.push(Event::new(
+        EventKind::Upload,
+        url.clone(),
+        final_filename,
+        Some(file.size),
+    ));
+
+    let id = match get_extension_from_filename(final_filename) {
+        Some(ext) => &final_filename[..final_filename.len() - ext.len() - 1],
+        None => final_filename,
+    };
+    Ok(UploadResponse {
+        url,
+        id: id.to_string(),
+        uploaded_at: Utc::now().to_rfc3339(),
+        size: file.size,
+        content_type: file.content_type,
+        hash: file.hash,
+        expires_at: expires_at.map(|t| t.to_rfc3339()),
+        delete_token,
+        qr_url,
+    })
 }
 
-async fn parse_field_options(field: Field<'_>) -> Result<Options, WebError> {
-    // Parse data in options json.
+/**
+ * Multipart bodies are limited to the maximum upload size while they are read, also when they are
+ * sent in chunks without a length, which is reported as too large rather than malformed.
+ */
+fn multipart_error(e: MultipartError) -> WebError {
+    if e.status() == StatusCode::PAYLOAD_TOO_LARGE {
+        WebError::PayloadTooLarge
+    } else {
+        WebError::MalformedMultipart(e.body_text())
+    }
+}
 
-    // First read multipart data to Vec<u8>.
-    let v = field.bytes().await.map_err(|_| WebError::BadRequest)?;
+/**
+ * Parses the JSON of the options field, which is read into memory, so it can be at most
+ * `max_size` bytes.
+ */
+async fn parse_field_options(mut field: Field<'_>, max_size: usize) -> Result<Options, WebError> {
+    let mut v = Vec::new();
+    while let Some(chunk) = field.chunk().await.map_err(multipart_error)? {
+        if v.len() + chunk.len() > max_size {
+            return Err(WebError::FieldTooLarge(max_size));
+        }
+        v.extend_from_slice(&chunk);
+    }
 
-    serde_json::from_slice(&v).map_err(|_| WebError::BadRequest)
+    serde_json::from_slice(&v).map_err(|e| WebError::InvalidOptions(e.to_string()))
 }
 
-fn public_path(filename: &str, opt: &Opt) -> Result<String, url::ParseError> {
-    let public_base = url::Url::parse(&opt.server_url)?;
-    Ok(public_base.join(filename)?.into())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn extension_from_content_type() {
+        assert_eq!(Some("jpg".into()), extension_for(Some("image/jpeg"), None));
+        assert_eq!(
+            Some("jpeg".into()),
+            extension_for(Some("image/jpeg"), Some("cat.jpeg"))
+        );
+        assert_eq!(
+            Some("png".into()),
+            extension_for(Some("image/png"), Some("cat.jpeg"))
+        );
+        assert_eq!(None, extension_for(None, Some("cat.jpeg")));
+    }
+
+    #[tokio::test]
+    async fn random_filename_collisions_are_avoided() {
+        let base_dir = std::env::temp_dir().join("i-test-free-filename");
+        std::fs::remove_dir_all(&base_dir).ok();
+        std::fs::create_dir_all(&base_dir).unwrap();
+        let mut opt = Opt::parse_from(["i", "--base-dir", base_dir.to_str().unwrap()]);
+        opt.slug_length = 1;
+
+        std::fs::write(base_dir.join("a.txt"), "taken").unwrap();
+        let filename = free_random_filename("a.txt", &User::default(), &opt)
+            .await
+            .unwrap();
+        assert_ne!("a.txt", filename);
+        assert!(filename.ends_with(".txt"));
+        assert_eq!(5, filename.len());
+
+        // With every name taken, no free name can be found.
+        for c in ('a'..='z').chain('A'..='Z').chain('0'..='9') {
+            std::fs::write(base_dir.join(format!("{}.txt", c)), "taken").unwrap();
+        }
+        assert!(matches!(
+            free_random_filename("a.txt", &User::default(), &opt).await,
+            Err(WebError::NoFreeFilename)
+        ));
+    }
+
+    #[test]
+    fn random_filenames_use_slug_alphabet() {
+        let mut opt = Opt::parse_from(["i", "--slug-alphabet", "lowercase-hex"]);
+        opt.slug_length = 32;
+        let filename = generate_random_filename(Some("png"), &opt);
+        let (slug, extension) = filename.split_once('.').unwrap();
+        assert_eq!("png", extension);
+        assert_eq!(32, slug.len());
+        assert!(slug
+            .bytes()
+            .all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase()));
+
+        opt.slug_alphabet = SlugAlphabet::Base32Lowercase;
+        let slug = generate_random_filename(None, &opt);
+        assert!(slug
+            .bytes()
+            .all(|c| c.is_ascii_lowercase() || (b'2'..=b'7').contains(&c)));
+
+        let opt = Opt::parse_from(["i", "--slug-alphabet", "url-safe-base62"]);
+        assert_eq!(SlugAlphabet::Alphanumeric, opt.slug_alphabet);
+    }
+
+    #[test]
+    fn seeded_random_filenames_are_repeatable() {
+        let mut opt = Opt::parse_from(["i"]);
+        opt.slug_rng = SlugRng::seeded(7);
+        let filenames: Vec<_> = (0..3)
+            .map(|_| generate_random_filename(Some("txt"), &opt))
+            .collect();
+
+        let mut rng = StdRng::seed_from_u64(7);
+        for filename in &filenames {
+            assert_eq!(
+                filename,
+                &generate_random_filename_with(&mut rng, Some("txt"), &opt)
+            );
+        }
+        // Clones of the options continue the same sequence.
+        let next = generate_random_filename_with(&mut rng, None, &opt);
+        assert_eq!(next, generate_random_filename(None, &opt.clone()));
+        assert_ne!(filenames[0], filenames[1]);
+    }
+
+    #[test]
+    fn long_filenames_are_shortened() {
+        let long = format!("{}.txt", "a".repeat(296));
+        let shortened = shorten_filename(&long, 200).unwrap();
+        assert_eq!(200, shortened.len());
+        assert!(shortened.ends_with("aaa.txt"));
+
+        assert_eq!(Some("short.txt".into()), shorten_filename("short.txt", 200));
+        // Multibyte characters are not cut in half.
+        assert_eq!(Some("åå.png".into()), shorten_filename("ååå.png", 9));
+        assert_eq!(None, shorten_filename(&format!("a.{}", "x".repeat(20)), 16));
+    }
+
+    #[tokio::test]
+    async fn taken_original_filename_follows_on_conflict() {
+        let base_dir = std::env::temp_dir().join("i-test-on-conflict");
+        std::fs::remove_dir_all(&base_dir).ok();
+        std::fs::create_dir_all(&base_dir).unwrap();
+        std::fs::write(base_dir.join("report.pdf"), "taken").unwrap();
+        std::fs::write(base_dir.join("report-1.pdf"), "taken").unwrap();
+        let mut opt = Opt::parse_from(["i", "--base-dir", base_dir.to_str().unwrap()]);
+        let user = User::default();
+
+        assert_eq!(
+            "report-2.pdf",
+            original_filename("report.pdf", false, &user, &opt)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            "free.pdf",
+            original_filename("free.pdf", false, &user, &opt)
+                .await
+                .unwrap()
+        );
+        // The overwrite option of the upload replaces the file whatever the policy.
+        assert_eq!(
+            "report.pdf",
+            original_filename("report.pdf", true, &user, &opt)
+                .await
+                .unwrap()
+        );
+
+        opt.on_conflict = OnConflict::Reject;
+        assert!(matches!(
+            original_filename("report.pdf", false, &user, &opt).await,
+            Err(WebError::Conflict(_))
+        ));
+
+        opt.on_conflict = OnConflict::Overwrite;
+        assert_eq!(
+            "report.pdf",
+            original_filename("report.pdf", false, &user, &opt)
+                .await
+                .unwrap()
+        );
+
+        // The number still fits when the name is as long as it can be.
+        opt.on_conflict = OnConflict::Rename;
+        opt.max_filename_length = 16;
+        std::fs::write(base_dir.join("abcdefghijkl.pdf"), "taken").unwrap();
+        assert_eq!(
+            "abcdefghij-1.pdf",
+            original_filename("abcdefghijkl.pdf", false, &user, &opt)
+                .await
+                .unwrap()
+        );
+    }
 }
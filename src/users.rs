@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::path::Path;
+use std::sync::Arc;
+
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+
+use crate::WebError;
+
+/// Usernames mapped to bcrypt hashes of their passwords, as loaded from the users file.
+pub type Users = Arc<HashMap<String, String>>;
+
+/// The authenticated user of a request, inserted as a request extension by the auth middleware.
+#[derive(Clone)]
+pub struct AuthenticatedUser(pub String);
+
+/**
+ * Loads the users file, a JSON object mapping each username to a bcrypt hash of its password.
+ * Usernames are used as directory names, so they must be valid filenames.
+ */
+pub fn load_users<P: AsRef<Path>>(path: P) -> Result<Users, WebError> {
+    let data = std::fs::read(path)?;
+    let users: HashMap<String, String> =
+        serde_json::from_slice(&data).map_err(std::io::Error::from)?;
+
+    for username in users.keys() {
+        let reserved = [
+            crate::THUMBNAIL_SUBDIR,
+            crate::META_SUBDIR,
+            crate::TRASH_SUBDIR,
+        ]
+        .contains(&username.as_str());
+        if username.is_empty() || reserved || !sanitize_filename::is_sanitized(username) {
+            return Err(std::io::Error::other(format!(
+                "invalid username in users file: {}",
+                username
+            ))
+            .into());
+        }
+    }
+
+    Ok(Arc::new(users))
+}
+
+/**
+ * The user that a request is made by, which is only known if there is a users file. Use this in
+ * handlers that store or list files, since every user has their own directory.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct User(pub Option<String>);
+
+impl User {
+    pub fn name(&self) -> Option<&str> {
+        self.0.as_deref()
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for User {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let user = parts.extensions.get::<AuthenticatedUser>();
+        Ok(User(user.map(|user| user.0.clone())))
+    }
+}
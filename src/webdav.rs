@@ -0,0 +1,300 @@
+use std::fmt::Write as _;
+
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::http::header::{ALLOW, CONTENT_TYPE};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use axum::middleware;
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use percent_encoding::percent_decode_str;
+use quick_xml::escape::escape;
+use tower::ServiceExt;
+
+use crate::WebError;
+
+use super::client_ip::ClientIp;
+use super::delete::delete_file;
+use super::diskspace::SpaceGuard;
+use super::helpers::{file_key, file_url_path, public_path, reject_path_traversal};
+use super::limits::size_limit;
+use super::recent::list_files;
+use super::sniff::ContentCheck;
+use super::storage::{self, StoredObject};
+use super::upload::{
+    check_custom_filename, detect_content_type, generate_random_filename,
+    get_extension_from_filename, is_upload_name, staging_path, store_file, write_staged,
+    FileUpload, Options,
+};
+use super::users::User;
+use super::Opt;
+
+static DAV: HeaderName = HeaderName::from_static("dav");
+static DEPTH: HeaderName = HeaderName::from_static("depth");
+
+/// Methods of the WebDAV subset that is supported, besides GET and HEAD of the served files.
+const ALLOWED_METHODS: &str = "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND";
+
+/**
+ * Middleware in front of the other routes, which passes WebDAV requests on to `webdav`, a router
+ * with `handle` behind authentication. PUT and DELETE of a file, PROPFIND, and OPTIONS are WebDAV
+ * requests. A PUT of the root is still a raw upload, and OPTIONS of /files is still answered by
+ * tus.
+ */
+pub async fn dispatch(
+    State(webdav): State<Router>,
+    request: Request,
+    next: middleware::Next,
+) -> Response {
+    let is_webdav = match *request.method() {
+        Method::PUT | Method::DELETE => request.uri().path() != "/",
+        Method::OPTIONS => request.uri().path() != "/files",
+        ref method => method.as_str() == "PROPFIND",
+    };
+    if !is_webdav {
+        return next.run(request).await;
+    }
+    match webdav.oneshot(request).await {
+        Ok(response) => response,
+        Err(infallible) => match infallible {},
+    }
+}
+
+/**
+ * Serves a WebDAV request. The uploaded files of the current user are a single collection, where
+ * the served files are: at the root, or below `--files-prefix` and the directory of the user.
+ */
+pub async fn handle(
+    State(opt): State<Opt>,
+    user: User,
+    ClientIp(client_ip): ClientIp,
+    request: Request,
+) -> Result<Response, WebError> {
+    let path = percent_decode_str(request.uri().path())
+        .decode_utf8_lossy()
+        .into_owned();
+    let Some(name) = resource_name(&path, &user, &opt) else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    if !name.is_empty() {
+        reject_path_traversal(&name, client_ip)?;
+        if name.contains('/') || !is_upload_name(&name) {
+            return Ok(StatusCode::NOT_FOUND.into_response());
+        }
+    }
+
+    match (request.method().as_str(), name.is_empty()) {
+        ("OPTIONS", _) => Ok((
+            [
+                (DAV.clone(), HeaderValue::from_static("1")),
+                (ALLOW, HeaderValue::from_static(ALLOWED_METHODS)),
+            ],
+            StatusCode::OK,
+        )
+            .into_response()),
+        ("PROPFIND", _) => propfind(&name, request.headers(), &user, &opt).await,
+        ("PUT", false) => put(name, client_ip, request.into_body(), &user, &opt).await,
+        ("DELETE", false) => match delete_file(&name, &user, &opt).await? {
+            true => Ok(StatusCode::NO_CONTENT.into_response()),
+            false => Ok(StatusCode::NOT_FOUND.into_response()),
+        },
+        // The collection itself can't be replaced or deleted.
+        _ => Ok((
+            StatusCode::METHOD_NOT_ALLOWED,
+            [(ALLOW, "OPTIONS, PROPFIND")],
+        )
+            .into_response()),
+    }
+}
+
+/**
+ * Returns the name of the file that `path` refers to, which is empty for the collection itself, or
+ * `None` if the path is outside of the collection of the current user.
+ */
+fn resource_name(path: &str, user: &User, opt: &Opt) -> Option<String> {
+    let path = path.strip_prefix(opt.files_prefix.as_str())?;
+    let path = path.strip_prefix('/').or(path.is_empty().then_some(""))?;
+    let name = match user.name() {
+        Some(user) => match path.strip_prefix(user)? {
+            "" => "",
+            rest => rest.strip_prefix('/')?,
+        },
+        None => path,
+    };
+    Some(name.to_string())
+}
+
+/**
+ * Stores the body as the file `name`, replacing the file if it exists. Files are stored the same
+ * way as uploads, except that empty files are kept, since clients create them before writing.
+ */
+async fn put(
+    name: String,
+    client_ip: Option<std::net::IpAddr>,
+    body: Body,
+    user: &User,
+    opt: &Opt,
+) -> Result<Response, WebError> {
+    let options = Options {
+        filename: Some(name.clone()),
+        overwrite: true,
+        redirect: false,
+        ..Default::default()
+    };
+    // Also checks the name, and rejects blocked extensions.
+    check_custom_filename(&name, 1, true, client_ip, user, opt).await?;
+    let existed = storage::backend(opt).exists(&file_key(&name, user)).await?;
+
+    let extension = get_extension_from_filename(&name);
+    let random_filename = generate_random_filename(extension, opt);
+    let content_type = detect_content_type(&name, None);
+    let staging_path = staging_path(&random_filename, opt).await?;
+    let chunks = body
+        .into_data_stream()
+        .map_err(|_| WebError::IncompleteBody);
+    let limit = size_limit(extension, &content_type, opt);
+    let check = ContentCheck::new(extension, &content_type, opt);
+    let (size, hash) =
+        write_staged(&staging_path, chunks, &limit, check, SpaceGuard::new(opt)).await?;
+
+    let file = FileUpload {
+        original_filename: name,
+        random_filename,
+        staging_path,
+        size,
+        content_type,
+        hash,
+    };
+    store_file(file, &options, user, opt).await?;
+    Ok(match existed {
+        true => StatusCode::NO_CONTENT,
+        false => StatusCode::CREATED,
+    }
+    .into_response())
+}
+
+/**
+ * Describes the collection, and with `Depth: 1` the files in it, or a single file. Deeper listings
+ * are the same as `Depth: 1`, since the collection has no subcollections.
+ */
+async fn propfind(
+    name: &str,
+    headers: &HeaderMap,
+    user: &User,
+    opt: &Opt,
+) -> Result<Response, WebError> {
+    let files = list_files(user, opt).await?;
+    let mut body = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n",
+    );
+    if name.is_empty() {
+        let depth = headers.get(&DEPTH).and_then(|v| v.to_str().ok());
+        write_collection(&mut body, user, opt)?;
+        if depth != Some("0") {
+            for file in files.iter() {
+                write_file(&mut body, file, user, opt)?;
+            }
+        }
+    } else {
+        let Some(file) = files.iter().find(|file| file.name == name) else {
+            return Ok(StatusCode::NOT_FOUND.into_response());
+        };
+        write_file(&mut body, file, user, opt)?;
+    }
+    body.push_str("</D:multistatus>\n");
+
+    Ok((
+        StatusCode::MULTI_STATUS,
+        [(CONTENT_TYPE, "application/xml; charset=utf-8")],
+        body,
+    )
+        .into_response())
+}
+
+/**
+ * Returns the path of the public url of `path`, to use as the href of a resource.
+ */
+fn href(path: &str, opt: &Opt) -> Result<String, WebError> {
+    Ok(url::Url::parse(&public_path(path, opt)?)?
+        .path()
+        .to_string())
+}
+
+/**
+ * Appends the response describing the collection of the files.
+ */
+fn write_collection(body: &mut String, user: &User, opt: &Opt) -> Result<(), WebError> {
+    let href = href(&file_url_path("", user, opt), opt)?;
+    writeln!(
+        body,
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>\
+         <D:resourcetype><D:collection/></D:resourcetype>\
+         </D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+        escape(&href),
+    )
+    .ok();
+    Ok(())
+}
+
+/**
+ * Appends the response describing a single file.
+ */
+fn write_file(
+    body: &mut String,
+    file: &StoredObject,
+    user: &User,
+    opt: &Opt,
+) -> Result<(), WebError> {
+    let href = href(&file_url_path(&file.name, user, opt), opt)?;
+    let modified: DateTime<Utc> = file.modified.into();
+    let content_type = mime_guess::from_path(&file.name).first_or_octet_stream();
+    writeln!(
+        body,
+        "<D:response><D:href>{}</D:href><D:propstat><D:prop>\
+         <D:displayname>{}</D:displayname>\
+         <D:resourcetype/>\
+         <D:getcontentlength>{}</D:getcontentlength>\
+         <D:getcontenttype>{}</D:getcontenttype>\
+         <D:getlastmodified>{}</D:getlastmodified>\
+         </D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>",
+        escape(&href),
+        escape(&file.name),
+        file.size,
+        escape(content_type.as_ref()),
+        modified.format("%a, %d %b %Y %H:%M:%S GMT"),
+    )
+    .ok();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn resources_are_below_prefix_and_user() {
+        let mut opt = Opt::parse_from(["i"]);
+        let user = User::default();
+        assert_eq!(Some(""), resource_name("/", &user, &opt).as_deref());
+        assert_eq!(
+            Some("a.txt"),
+            resource_name("/a.txt", &user, &opt).as_deref()
+        );
+
+        opt.files_prefix = "/f".to_string();
+        let user = User(Some("alice".to_string()));
+        assert_eq!(Some(""), resource_name("/f/alice/", &user, &opt).as_deref());
+        assert_eq!(Some(""), resource_name("/f/alice", &user, &opt).as_deref());
+        assert_eq!(
+            Some("a.txt"),
+            resource_name("/f/alice/a.txt", &user, &opt).as_deref()
+        );
+        assert_eq!(None, resource_name("/a.txt", &user, &opt));
+        assert_eq!(None, resource_name("/f/bob/a.txt", &user, &opt));
+        assert_eq!(None, resource_name("/f/alicea.txt", &user, &opt));
+        assert_eq!(None, resource_name("/fa/alice/a.txt", &user, &opt));
+    }
+}
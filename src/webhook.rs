@@ -0,0 +1,141 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use super::Opt;
+
+/// Number of events that may wait for delivery before new ones are dropped.
+const QUEUE_SIZE: usize = 1024;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time to wait before the first retry, doubled for every retry after it.
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Upload,
+    Delete,
+}
+
+/**
+ * Payload posted to the webhook. `text` and `content` are a readable summary, which Slack and
+ * Discord show as the message.
+ */
+#[derive(Clone, Debug, Serialize)]
+pub struct Event {
+    event: EventKind,
+    url: String,
+    filename: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<usize>,
+    text: String,
+    content: String,
+}
+
+impl Event {
+    pub fn new(event: EventKind, url: String, filename: &str, size: Option<usize>) -> Self {
+        let text = match event {
+            EventKind::Upload => format!("Uploaded {}", url),
+            EventKind::Delete => format!("Deleted {}", url),
+        };
+        Event {
+            event,
+            url,
+            filename: filename.to_string(),
+            size,
+            content: text.clone(),
+            text,
+        }
+    }
+}
+
+/**
+ * Sends events to the webhook worker, if a webhook is configured.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct WebhookQueue(Option<mpsc::Sender<Event>>);
+
+impl WebhookQueue {
+    /**
+     * Queues delivery of the event. If the queue is full, the event is dropped, since it must
+     * never hold up the request that caused it.
+     */
+    pub fn push(&self, event: Event) {
+        let Some(sender) = &self.0 else {
+            return;
+        };
+        if let Err(e) = sender.try_send(event) {
+            log::warn!(
+                "webhook queue full, dropping event of {}",
+                e.into_inner().url
+            );
+        }
+    }
+}
+
+/**
+ * Starts the worker delivering events to `--webhook-url`, if it is set. Events are delivered one
+ * at a time, in the order they happened.
+ */
+pub fn start_worker(opt: &Opt) -> WebhookQueue {
+    let Some(url) = opt.webhook_url.clone() else {
+        return WebhookQueue(None);
+    };
+    let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+    tokio::task::spawn(webhook_worker(url, opt.webhook_attempts, receiver));
+    WebhookQueue(Some(sender))
+}
+
+async fn webhook_worker(url: url::Url, attempts: u32, mut receiver: mpsc::Receiver<Event>) {
+    let client = match reqwest::Client::builder().timeout(TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            log::error!("could not create webhook client: {}", e);
+            return;
+        }
+    };
+    while let Some(event) = receiver.recv().await {
+        if let Err(e) = deliver(&client, &url, &event, attempts).await {
+            log::error!("could not deliver webhook for {}: {}", event.url, e);
+        }
+    }
+}
+
+/**
+ * Posts the event to the webhook, retrying with exponential backoff until it is accepted or all
+ * attempts have failed.
+ */
+async fn deliver(
+    client: &reqwest::Client,
+    url: &url::Url,
+    event: &Event,
+    attempts: u32,
+) -> Result<(), String> {
+    let mut delay = FIRST_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        let result = client
+            .post(url.clone())
+            .json(event)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status);
+        match result {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt >= attempts => return Err(e.to_string()),
+            Err(e) => log::warn!(
+                "webhook attempt {} of {} failed, retrying in {:?}: {}",
+                attempt,
+                attempts,
+                delay,
+                e
+            ),
+        }
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+        attempt += 1;
+    }
+}